@@ -0,0 +1,150 @@
+use serde::{Serialize, Deserialize};
+use hmac::{Hmac, Mac, NewMac};
+use md5::Md5;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::packet::{find_option, find_end, client_hardware, HOPS_OFFSET, GATEWAY_OFFSET};
+
+const AUTHENTICATION: u8 = 90;
+
+const PROTOCOL_DELAYED: u8 = 2;
+const ALGORITHM_HMAC_MD5: u8 = 1;
+const RDM_MONOTONIC: u8 = 0;
+const OPTION_LENGTH: usize = 31; // protocol, algorithm, rdm, replay detection(8), secret id(4), hmac(16)
+const HMAC_OFFSET: usize = 15;
+
+/// RFC 3118 delayed authentication (option 90) with a shared key
+#[derive(Serialize, Deserialize)]
+pub struct DhcpAuth {
+    key_id: u32,
+    key: String,
+    #[serde(default)]
+    required: bool,
+    /// last replay detection counter by client hardware address
+    #[serde(skip)]
+    replay: Mutex<HashMap<[u8; 6], u64>>,
+}
+
+impl DhcpAuth {
+    /// hmac over the packet with hops, giaddr and the hmac field zeroed
+    fn hmac(&self, bytes: &[u8], hmac_offset: usize) -> Hmac<Md5> {
+        let mut b = bytes.to_vec();
+        b[HOPS_OFFSET] = 0;
+        b[GATEWAY_OFFSET..GATEWAY_OFFSET + 4].copy_from_slice(&[0; 4]);
+        b[hmac_offset..hmac_offset + 16].copy_from_slice(&[0; 16]);
+
+        let mut mac = Hmac::<Md5>::new_from_slice(self.key.as_bytes()).expect("hmac accepts any key length");
+        mac.update(&b);
+        mac
+    }
+
+    pub fn verify(&self, bytes: &[u8]) -> bool {
+        let (start, length) = match find_option(bytes, AUTHENTICATION) {
+            Some(o) => o,
+            None => return !self.required,
+        };
+
+        let data = &bytes[start..start + length];
+        if length != OPTION_LENGTH || data[0] != PROTOCOL_DELAYED || data[1] != ALGORITHM_HMAC_MD5 {
+            log::debug!("unsupported authentication protocol {}", data.first().unwrap_or(&0));
+            return false;
+        }
+        if data[11..15] != self.key_id.to_be_bytes() {
+            log::debug!("unknown authentication key id");
+            return false;
+        }
+
+        let hmac_offset = start + HMAC_OFFSET;
+        if self.hmac(bytes, hmac_offset).verify(&bytes[hmac_offset..hmac_offset + 16]).is_err() {
+            return false;
+        }
+
+        let mut counter = [0u8; 8];
+        counter.copy_from_slice(&data[3..11]);
+        self.fresh(bytes, u64::from_be_bytes(counter))
+    }
+
+    /// Monotonic replay detection, a counter not greater than the last one of the client is a replayed packet
+    fn fresh(&self, bytes: &[u8], counter: u64) -> bool {
+        let client = match client_hardware(bytes) {
+            Some(client) => client,
+            None => return false,
+        };
+
+        let mut replay = self.replay.lock().unwrap_or_else(|e| e.into_inner());
+        match replay.get(&client) {
+            Some(last) if counter <= *last => {
+                log::debug!("replayed authentication counter {} (last {})", counter, last);
+                false
+            }
+            _ => {
+                replay.insert(client, counter);
+                true
+            }
+        }
+    }
+
+    /// Appends an authentication option to a serialized reply
+    pub fn sign(&self, mut bytes: Vec<u8>) -> Vec<u8> {
+        let end = match find_end(&bytes) {
+            Some(end) => end,
+            None => {
+                log::warn!("reply not signed, options end missing");
+                return bytes;
+            }
+        };
+
+        let replay = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        let mut option = vec![AUTHENTICATION, OPTION_LENGTH as u8, PROTOCOL_DELAYED, ALGORITHM_HMAC_MD5, RDM_MONOTONIC];
+        option.extend_from_slice(&replay.to_be_bytes());
+        option.extend_from_slice(&self.key_id.to_be_bytes());
+        option.extend_from_slice(&[0; 16]);
+        let tail = bytes.split_off(end);
+        bytes.extend(option);
+        bytes.extend(tail);
+
+        let hmac_offset = end + 2 + HMAC_OFFSET;
+        let hmac = self.hmac(&bytes, hmac_offset).finalize().into_bytes();
+        bytes[hmac_offset..hmac_offset + 16].copy_from_slice(&hmac);
+        bytes
+    }
+}
+
+#[test]
+fn test_auth() {
+    let auth = DhcpAuth { key_id: 1, key: "secret".to_string(), required: true, replay: Default::default() };
+    let ip = std::net::Ipv4Addr::new(10, 0, 0, 1);
+    let bytes = || crate::sources::test_packet(0, vec![]).into_bytes_with_server_ips(vec![ip]).remove(&ip).unwrap();
+
+    assert!(!auth.verify(&bytes())); // unauthenticated
+
+    let signed = auth.sign(bytes());
+    assert!(auth.verify(&signed));
+    assert!(!auth.verify(&signed)); // replayed
+
+    let mut relayed = auth.sign(bytes());
+    relayed[GATEWAY_OFFSET] = 10; // relays may change giaddr and hops
+    relayed[HOPS_OFFSET] = 1;
+    assert!(auth.verify(&relayed));
+
+    let mut tampered = auth.sign(bytes());
+    tampered[4] ^= 0xff; // tampered xid
+    assert!(!auth.verify(&tampered));
+
+    let other = DhcpAuth { key_id: 1, key: "other".to_string(), required: true, replay: Default::default() };
+    assert!(!other.verify(&auth.sign(bytes())));
+}
+
+#[test]
+fn test_auth_replay() {
+    let auth = DhcpAuth { key_id: 1, key: "secret".to_string(), required: true, replay: Default::default() };
+    let ip = std::net::Ipv4Addr::new(10, 0, 0, 1);
+    let signed = |mac: u8| auth.sign(crate::sources::test_packet_from_mac([1, 2, 3, 5, 6, mac]).into_bytes_with_server_ips(vec![ip]).remove(&ip).unwrap());
+
+    let older = signed(7);
+    let newer = signed(7);
+    assert!(auth.verify(&newer));
+    assert!(!auth.verify(&older)); // counter below the last one
+    assert!(auth.verify(&signed(8))); // counters are kept per client
+}
@@ -1,24 +1,446 @@
 use serde::{Serialize, Deserialize};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::io::Read;
 use std::fs::File;
 use crate::error::{DhcpResult, DhcpError};
-use crate::sources::DhcpHostSource;
-use crate::sources::rest::DhcpRestSource;
-use std::net::Ipv4Addr;
+use crate::sources::{DhcpHostSource, DhcpSourceResult, BoxedDhcpHostSource};
+use crate::sources::rest::{DhcpRestSource, HttpClientProfile};
+use crate::sources::chain::DhcpChainSource;
+use crate::sources::chaos::{ChaosConfig, DhcpChaosSource};
+use crate::sources::allocator::DhcpAllocatorSource;
+use crate::sources::pool::DhcpPool;
+use crate::sources::static_file::DhcpStaticSource;
+use crate::sources::sqlite::DhcpSqliteSource;
+use crate::sources::mapping::{DhcpMapping, packet_context, client_identifier, client_architecture, user_class};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::collections::HashMap;
+use dhcplib::DhcpPacket;
+use dhcplib::option::VENDOR_CLASS_IDENTIFIER;
 use structopt::StructOpt;
 use simplelog::LevelFilter;
+use pnet::ipnetwork::Ipv4Network;
+use crate::auth::DhcpAuth;
+use crate::packet;
+use crate::management::DhcpManagementConfig;
+use crate::server::Ingress;
+use crate::rate_limit::{ConcurrencyLimiter, ClientRateLimitConfig};
+use std::time::Duration;
+use regex::Regex;
+use crate::schedule::{LocalTime, TimeWindow};
+
+const CONFIG_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const CONFIG_SEARCH_PATH_ENV: &str = "DHCP_CONFIG_SEARCH_PATH";
+const CONFIG_DIR_MAIN: &str = "main.yml";
+
+/// Replaces secrets in config dumps
+const REDACTED: &str = "<redacted>";
+
+pub const SOURCE_KINDS: &[&str] = &[DhcpRestSource::NAME, DhcpChainSource::NAME, DhcpPool::NAME, DhcpStaticSource::NAME, DhcpSqliteSource::NAME];
+
+/// Regular expression configured by its pattern
+pub(crate) struct Pattern(Regex);
+
+impl Serialize for Pattern {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern).map(Pattern).map_err(serde::de::Error::custom)
+    }
+}
 
 #[derive(Serialize, Deserialize)]
-struct Sources {
+pub(crate) struct Sources {
     kind: String,
     config: serde_yaml::Value,
+    interfaces: Option<Vec<String>>,
+    match_user_class: Option<Pattern>,
+    schedule: Option<Vec<TimeWindow>>,
+    chaos: Option<ChaosConfig>,
+}
+
+impl Sources {
+    pub(crate) fn init(&self) -> DhcpResult<BoxedDhcpHostSource> {
+        let config = self.config.clone();
+        let source: BoxedDhcpHostSource = match self.kind.as_str() {
+            DhcpRestSource::NAME => Box::new(DhcpRestSource::from_config(config)?),
+            DhcpChainSource::NAME => Box::new(DhcpChainSource::from_config(config)?),
+            DhcpPool::NAME => Box::new(DhcpAllocatorSource::<DhcpPool>::from_config(config)?),
+            DhcpStaticSource::NAME => Box::new(DhcpStaticSource::from_config(config)?),
+            DhcpSqliteSource::NAME => Box::new(DhcpSqliteSource::from_config(config)?),
+            kind => return Err(Self::kind_unknown(kind))
+        };
+
+        match &self.chaos {
+            Some(chaos) => {
+                chaos.validate()?;
+                log::warn!("{} source injects faults: {:?}", self.kind, chaos);
+                Ok(Box::new(DhcpChaosSource::new(source, chaos.clone())))
+            }
+            None => Ok(source),
+        }
+    }
+
+    pub(crate) fn kind(&self) -> &str { &self.kind }
+
+    fn kind_unknown(kind: &str) -> DhcpError {
+        DhcpError::SourceKindUnknown { kind: kind.to_string(), available: SOURCE_KINDS.join(", ") }
+    }
+
+    /// Fails on an unknown kind of the source or of any child of a chain
+    fn check_kind(&self) -> DhcpResult<()> {
+        if !SOURCE_KINDS.contains(&self.kind.as_str()) {
+            return Err(Self::kind_unknown(&self.kind));
+        }
+
+        if self.kind == DhcpChainSource::NAME {
+            let children: Vec<Sources> = match self.config.get("sources") {
+                Some(children) => serde_yaml::from_value(children.clone())?,
+                None => Vec::new(),
+            };
+            children.iter().try_for_each(Sources::check_kind)?;
+        }
+        Ok(())
+    }
+
+    /// Interface names match direct packets, subnets match the giaddr of relayed packets
+    pub(crate) fn serves(&self, ingress: &Ingress) -> bool {
+        let interfaces = match &self.interfaces {
+            Some(interfaces) => interfaces,
+            None => return true,
+        };
+
+        if ingress.gateway.is_unspecified() {
+            ingress.interface.as_ref().map_or(false, |name| interfaces.contains(name))
+        } else {
+            interfaces.iter()
+                .filter_map(|i| i.parse::<Ipv4Network>().ok())
+                .any(|n| n.contains(ingress.gateway))
+        }
+    }
+
+    /// Any time window of the schedule contains the time, sources without schedule always answer
+    pub(crate) fn scheduled(&self, time: &LocalTime) -> bool {
+        self.schedule.as_ref().map_or(true, |windows| windows.iter().any(|w| w.contains(time)))
+    }
+
+    /// Any user class (option 77) of the client matches, clients without one never match a set pattern
+    pub(crate) fn matches_user_class(&self, p: &DhcpPacket) -> bool {
+        self.match_user_class.as_ref().map_or(true, |pattern| {
+            user_class(p).map_or(false, |classes| classes.split(',').any(|c| pattern.0.is_match(c)))
+        })
+    }
+}
+
+/// Handling of the client hostname (option 12) echoed in replies
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostnamePolicy {
+    Passthrough,
+    Sanitize,
+    ForceSuffix(String),
+}
+
+impl HostnamePolicy {
+    /// Lowercase dns labels of alphanumerics and hyphens
+    pub fn sanitize(hostname: &str) -> String {
+        hostname.to_ascii_lowercase()
+            .split('.')
+            .map(|label| {
+                let label = label.chars()
+                    .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+                    .collect::<String>();
+                label.trim_matches('-').chars().take(63).collect::<String>()
+            })
+            .filter(|label| !label.is_empty())
+            .collect::<Vec<String>>()
+            .join(".")
+    }
+
+    pub fn apply(&self, hostname: &str) -> String {
+        match self {
+            HostnamePolicy::Passthrough => hostname.to_string(),
+            HostnamePolicy::Sanitize => Self::sanitize(hostname),
+            HostnamePolicy::ForceSuffix(suffix) => {
+                let hostname = Self::sanitize(hostname);
+                let suffix = Self::sanitize(suffix);
+
+                if hostname == suffix || hostname.ends_with(&format!(".{}", suffix)) {
+                    hostname
+                } else {
+                    format!("{}.{}", hostname, suffix)
+                }
+            }
+        }
+    }
+}
+
+/// Boot file for pxe clients by architecture (option 93) and vendor class prefix (option 60)
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PxeBoot {
+    arch: Option<u16>,
+    vendor_class: Option<String>,
+    next_server: Option<Ipv4Addr>,
+    boot_file_name: String,
+}
+
+impl PxeBoot {
+    pub fn next_server(&self) -> Option<Ipv4Addr> { self.next_server }
+
+    pub fn boot_file_name(&self) -> &str { &self.boot_file_name }
+
+    fn matches(&self, arch: Option<u16>, vendor_class: Option<&str>) -> bool {
+        self.arch.map_or(true, |a| arch == Some(a))
+            && self.vendor_class.as_ref().map_or(true, |v| vendor_class.map_or(false, |c| c.starts_with(v.as_str())))
+    }
+}
+
+/// Relay agent information (option 82) stamped on forwarded requests
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct RelayAgent {
+    circuit_id: Option<String>,
+    remote_id: Option<String>,
+}
+
+impl RelayAgent {
+    /// Encoded sub-options, the circuit id defaults to the receiving interface
+    pub fn information(&self, interface: Option<&str>) -> Vec<u8> {
+        let circuit_id = self.circuit_id.as_deref().or(interface);
+
+        [(packet::RELAY_CIRCUIT_ID, circuit_id), (packet::RELAY_REMOTE_ID, self.remote_id.as_deref())].iter()
+            .filter_map(|(code, value)| value.map(|v| (*code, &v.as_bytes()[..v.len().min(u8::MAX as usize)])))
+            .flat_map(|(code, value)| [code, value.len() as u8].iter().chain(value).copied().collect::<Vec<u8>>())
+            .collect()
+    }
+}
+
+/// Clients answered by mac address, a trailing `*` matches any rest (e.g. the vendor prefix `aa:bb:cc:*`)
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct MacFilter {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+impl MacFilter {
+    fn matches(pattern: &str, mac: &str) -> bool {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_suffix('*') {
+            Some(prefix) => mac.starts_with(prefix),
+            None => mac == pattern,
+        }
+    }
+
+    /// Denied macs are never answered, with an allow list only listed ones are
+    pub fn permits(&self, mac: &str) -> bool {
+        let mac = mac.to_ascii_lowercase();
+        !self.deny.iter().any(|p| Self::matches(p, &mac))
+            && (self.allow.is_empty() || self.allow.iter().any(|p| Self::matches(p, &mac)))
+    }
+
+    /// Colon separated octets, exact addresses need all six and prefixes fewer
+    fn validate(&self) -> DhcpResult<()> {
+        for pattern in self.allow.iter().chain(&self.deny) {
+            let (octets, wildcard) = match pattern.strip_suffix('*') {
+                Some(prefix) => (prefix.strip_suffix(':').unwrap_or(prefix), true),
+                None => (pattern.as_str(), false),
+            };
+            let octets = match octets {
+                "" if wildcard => vec![],
+                octets => octets.split(':').collect::<Vec<&str>>(),
+            };
+
+            let valid = octets.iter().all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()))
+                && if wildcard { octets.len() < 6 } else { octets.len() == 6 };
+            if !valid {
+                return Err(DhcpError::ConfigInvalid(format!("mac_filter pattern '{}' is no mac address or prefix like aa:bb:cc:*", pattern)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Detection of an address handed to several clients, e.g. by sources with overlapping ranges
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct ConflictDetection {
+    /// seconds an assignment is held, about the lease time
+    #[serde(default = "ConflictDetection::default_window")]
+    window: u64,
+    /// no offer and a nak instead of the ack for a conflicting address
+    #[serde(default)]
+    skip: bool,
+}
+
+impl ConflictDetection {
+    fn default_window() -> u64 { 3600 }
+
+    pub fn window(&self) -> Duration { Duration::from_secs(self.window) }
+
+    pub fn skip(&self) -> bool { self.skip }
+}
+
+/// Caps on entries kept in memory, the least recently used entry is evicted when one is exceeded
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// per rest query cache (or shared cache pool)
+    cache_entries: Option<usize>,
+    /// per address pool
+    lease_entries: Option<usize>,
+    /// client stats, replaces `stats_capacity`
+    stats_entries: Option<usize>,
+}
+
+impl Limits {
+    pub fn cache_entries(&self) -> Option<usize> { self.cache_entries }
+
+    pub fn lease_entries(&self) -> Option<usize> { self.lease_entries }
+}
+
+/// Bootp flags of replies, copied from the request or forced
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplyFlags {
+    Copy,
+    Broadcast,
+    Unicast,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CopyRequest {
+    Copy,
+}
+
+/// Secs of replies, copied from the request or a fixed value
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(untagged)]
+pub enum ReplySecs {
+    Copy(CopyRequest),
+    Value(u16),
+}
+
+/// Header fields of replies for non-compliant clients and relays, unset fields are kept as built
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct ReplyHeader {
+    flags: Option<ReplyFlags>,
+    secs: Option<ReplySecs>,
+}
+
+impl ReplyHeader {
+    /// Flags and secs of the reply to the request, `None` keeps the built value
+    pub fn fields(&self, request: &[u8]) -> (Option<u16>, Option<u16>) {
+        let flags = self.flags.and_then(|f| match f {
+            ReplyFlags::Copy => packet::flags(request),
+            ReplyFlags::Broadcast => Some(packet::BROADCAST_FLAG),
+            ReplyFlags::Unicast => Some(0),
+        });
+        let secs = self.secs.and_then(|s| match s {
+            ReplySecs::Copy(_) => packet::secs(request),
+            ReplySecs::Value(secs) => Some(secs),
+        });
+        (flags, secs)
+    }
+
+    /// A forced flag must not contradict a forced delivery
+    fn validate(&self, delivery: ReplyDelivery) -> DhcpResult<()> {
+        match (self.flags, delivery) {
+            (Some(ReplyFlags::Broadcast), ReplyDelivery::Unicast) => Err(DhcpError::ConfigInvalid("reply_header flags broadcast contradicts force_unicast".to_string())),
+            (Some(ReplyFlags::Unicast), ReplyDelivery::Broadcast) => Err(DhcpError::ConfigInvalid("reply_header flags unicast contradicts force_broadcast".to_string())),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Where replies are sent to
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReplyDelivery {
+    /// unicast to clients with an address not asking for broadcast (bootp flags), broadcast otherwise
+    Flags,
+    Broadcast,
+    /// to the client address or the assigned one, needs raw socket sending for clients without an address
+    Unicast,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct DhcpConfig {
     #[serde(default = "DhcpConfig::default_port")]
     port: u16,
+    #[serde(default = "DhcpConfig::default_unprivileged_port")]
+    unprivileged_port: u16,
+    #[serde(skip)]
+    unprivileged: bool,
     listen: Option<Vec<Ipv4Addr>>,
+    networks: Option<Vec<String>>,
+    serve_subnets: Option<Vec<String>>,
+    #[serde(default = "DhcpConfig::default_max_hops")]
+    max_hops: u8,
+    recv_timeout: Option<u64>,
+    #[serde(default)]
+    leasequery_enabled: bool,
+    #[serde(default)]
+    rapid_commit: bool,
+    #[serde(default = "DhcpConfig::default_lenient_ciaddr")]
+    lenient_ciaddr: bool,
+    #[serde(default)]
+    honor_prl: bool,
+    #[serde(default)]
+    order_by_prl: bool,
+    #[serde(default)]
+    strict_mappings: bool,
+    #[serde(default)]
+    prefer_client_identifier: bool,
+    max_concurrent_scripts: Option<usize>,
+    #[serde(default = "DhcpConfig::default_script_permit_timeout")]
+    script_permit_timeout: u64,
+    #[serde(default = "DhcpConfig::default_send_retries")]
+    send_retries: u32,
+    #[serde(default = "DhcpConfig::default_release_retries")]
+    release_retries: u32,
+    #[serde(default)]
+    force_broadcast: bool,
+    #[serde(default)]
+    force_unicast: bool,
+    #[serde(default)]
+    reply_header: ReplyHeader,
+    #[serde(default = "DhcpConfig::default_stats_capacity")]
+    stats_capacity: usize,
+    #[serde(default)]
+    limits: Limits,
+    conflict_detection: Option<ConflictDetection>,
+    mac_filter: Option<MacFilter>,
+    rate_limit: Option<ClientRateLimitConfig>,
+    lease_file: Option<String>,
+    next_server: Option<Ipv4Addr>,
+    nak_message: Option<String>,
+    server_name: Option<String>,
+    forward_to: Option<Ipv4Addr>,
+    #[serde(default = "DhcpConfig::default_forward_port")]
+    forward_port: u16,
+    #[serde(default)]
+    relay_agent: RelayAgent,
+    #[serde(default)]
+    nak_delay_ms: u64,
+    #[serde(default)]
+    nak_suppress_window: u64,
+    captive_portal_url: Option<String>,
+    #[serde(default)]
+    pxe: Vec<PxeBoot>,
+    hostname_policy: Option<HostnamePolicy>,
+    auth: Option<DhcpAuth>,
+    management: Option<DhcpManagementConfig>,
+    #[serde(default)]
+    global_options: DhcpMapping,
+    #[serde(default)]
+    overrides: HashMap<String, DhcpMapping>,
+    #[serde(default)]
+    http_clients: HashMap<String, HttpClientProfile>,
+    #[serde(default)]
+    merge_sources: bool,
     sources: Vec<Sources>,
 }
 
@@ -27,54 +449,858 @@ impl DhcpConfig {
         67
     }
 
+    fn default_unprivileged_port() -> u16 {
+        6767
+    }
+
+    fn default_lenient_ciaddr() -> bool {
+        true
+    }
+
+    fn default_forward_port() -> u16 {
+        67
+    }
+
+    fn default_max_hops() -> u8 {
+        16
+    }
+
+    fn default_stats_capacity() -> usize {
+        1024
+    }
+
+    fn default_script_permit_timeout() -> u64 {
+        5
+    }
+
+    fn default_send_retries() -> u32 {
+        2
+    }
+
+    fn default_release_retries() -> u32 {
+        2
+    }
+
     pub fn port(&self) -> u16 { self.port }
 
+    /// Port bound instead of `port` if that is not permitted, only with `--unprivileged`
+    pub fn unprivileged_port(&self) -> Option<u16> { Some(self.unprivileged_port).filter(|_| self.unprivileged) }
+
+    pub fn with_unprivileged(mut self, unprivileged: bool) -> Self {
+        self.unprivileged = unprivileged;
+        self
+    }
+
+    pub fn max_hops(&self) -> u8 { self.max_hops }
+
+    /// Receiving waits at most this long before the sources' maintenance runs, indefinitely if unset
+    pub fn recv_timeout(&self) -> Option<Duration> { self.recv_timeout.filter(|t| *t > 0).map(Duration::from_secs) }
+
+    pub fn leasequery_enabled(&self) -> bool { self.leasequery_enabled }
+
+    pub fn rapid_commit(&self) -> bool { self.rapid_commit }
+
+    /// Discovers with a client address (ciaddr) are answered as if it was not set instead of dropped
+    pub fn lenient_ciaddr(&self) -> bool { self.lenient_ciaddr }
+
+    pub fn honor_prl(&self) -> bool { self.honor_prl }
+
+    pub fn order_by_prl(&self) -> bool { self.order_by_prl }
+
+    pub fn strict_mappings(&self) -> bool { self.strict_mappings }
+
+    pub fn stats_capacity(&self) -> usize { self.limits.stats_entries.unwrap_or(self.stats_capacity) }
+
+    pub fn limits(&self) -> &Limits { &self.limits }
+
+    pub fn conflict_detection(&self) -> Option<&ConflictDetection> { self.conflict_detection.as_ref() }
+
+    pub fn mac_filter(&self) -> Option<&MacFilter> { self.mac_filter.as_ref() }
+
+    /// Packets per second of each client
+    pub fn rate_limit(&self) -> Option<ClientRateLimitConfig> { self.rate_limit }
+
+    pub fn prefer_client_identifier(&self) -> bool { self.prefer_client_identifier }
+
+    pub fn send_retries(&self) -> u32 { self.send_retries }
+
+    /// Retries of a release failed at a source, clients never retransmit a release
+    pub fn release_retries(&self) -> u32 { self.release_retries }
+
+    pub fn reply_header(&self) -> &ReplyHeader { &self.reply_header }
+
+    pub fn reply_delivery(&self) -> ReplyDelivery {
+        match (self.force_broadcast, self.force_unicast) {
+            (true, _) => ReplyDelivery::Broadcast,
+            (_, true) => ReplyDelivery::Unicast,
+            _ => ReplyDelivery::Flags,
+        }
+    }
+
+    /// Client identifier (option 61) if preferred and present, the mac address otherwise
+    pub fn client_key(&self, p: &DhcpPacket) -> String {
+        client_identifier(p).filter(|_| self.prefer_client_identifier)
+            .unwrap_or_else(|| p.client_hardware().to_string())
+    }
+
+    pub fn next_server(&self) -> Option<Ipv4Addr> { self.next_server }
+
+    /// First pxe entry matching the client
+    pub fn pxe(&self, p: &DhcpPacket) -> Option<&PxeBoot> {
+        let vendor_class = p.options().try_vec_u8_option(VENDOR_CLASS_IDENTIFIER).ok()
+            .map(|v| String::from_utf8_lossy(&v).into_owned());
+        let arch = client_architecture(p);
+
+        self.pxe.iter().find(|b| b.matches(arch, vendor_class.as_deref()))
+    }
+
+    pub fn captive_portal_url(&self) -> Option<&str> { self.captive_portal_url.as_deref() }
+
+    /// Results of every answering source merged instead of the first one answering
+    pub fn merge_sources(&self) -> bool { self.merge_sources }
+
+    /// Json file the lease table is kept in across restarts
+    pub fn lease_file(&self) -> Option<&str> { self.lease_file.as_deref() }
+
+    pub fn nak_message(&self) -> Option<&str> { self.nak_message.as_deref() }
+
+    pub fn server_name(&self) -> Option<&str> { self.server_name.as_deref() }
+
+    /// Upstream server requests are relayed to instead of being answered
+    pub fn forward_to(&self) -> Option<SocketAddrV4> { self.forward_to.map(|ip| SocketAddrV4::new(ip, self.forward_port)) }
+
+    pub fn relay_agent(&self) -> &RelayAgent { &self.relay_agent }
+
+    /// Upper bound of the random delay before a nak is sent
+    pub fn nak_delay(&self) -> Duration { Duration::from_millis(self.nak_delay_ms) }
+
+    /// Repeated naks to a client within the window are not sent
+    pub fn nak_suppress_window(&self) -> Duration { Duration::from_secs(self.nak_suppress_window) }
+
+    pub fn hostname_policy(&self) -> Option<&HostnamePolicy> { self.hostname_policy.as_ref() }
+
+    pub fn auth(&self) -> Option<&DhcpAuth> { self.auth.as_ref() }
+
+    pub fn management(&self) -> Option<&DhcpManagementConfig> { self.management.as_ref() }
+
     pub fn ips(&self) -> Option<&Vec<Ipv4Addr>> {
         self.listen.as_ref()
     }
 
+    /// Manually configured serving networks, replaces interface detection
+    pub fn networks(&self) -> DhcpResult<Option<Vec<Ipv4Network>>> {
+        self.networks.as_ref().map(|n| {
+            n.iter().map(|n| n.parse::<Ipv4Network>().map_err(Into::into)).collect::<DhcpResult<Vec<_>>>()
+        }).transpose()
+    }
+
+    /// Subnets answered, relayed packets by giaddr and direct ones by the receiving interface, all if unset
+    pub fn serve_subnets(&self) -> DhcpResult<Option<Vec<Ipv4Network>>> {
+        self.serve_subnets.as_ref().map(|n| {
+            n.iter().map(|n| n.parse::<Ipv4Network>().map_err(Into::into)).collect::<DhcpResult<Vec<_>>>()
+        }).transpose()
+    }
+
+    /// Options for every reply, below the options of the sources
+    pub fn global_result(&self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        if self.global_options.is_empty() {
+            return Ok(None);
+        }
+        self.global_options.to_result(&packet_context(p), self.strict_mappings).map(|r| Some(r.label("global")))
+    }
+
+    /// Pinned mapping for the client key, checked before any source
+    pub fn override_result(&self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        let key = self.client_key(p);
+
+        match self.overrides.iter().find(|(k, _)| k.eq_ignore_ascii_case(&key)) {
+            Some((_, mapping)) => mapping.to_result(&packet_context(p), self.strict_mappings).map(Some),
+            None => Ok(None),
+        }
+    }
+
     pub fn from_file<P: AsRef<Path>>(path: P) -> DhcpResult<Self> {
         let file = File::open(path)?;
-        serde_yaml::from_reader(file).map_err(Into::into)
+        Self::from_reader(file)
+    }
+
+    pub fn from_reader<R: Read>(reader: R) -> DhcpResult<Self> {
+        serde_yaml::from_reader::<_, Self>(reader)?.validate()
+    }
+
+    /// Every `*.yml`/`*.yaml` file of the directory, `sources` are concatenated (`main.yml` first, then by file name),
+    /// other settings are taken from `main.yml` or the first file setting them, differing `port` values are an error
+    pub fn from_dir<P: AsRef<Path>>(path: P) -> DhcpResult<Self> {
+        let mut files = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        files.retain(|f| f.is_file() && matches!(f.extension().and_then(|e| e.to_str()), Some("yml") | Some("yaml")));
+        files.sort_by_key(|f| (f.file_name() != Some(std::ffi::OsStr::new(CONFIG_DIR_MAIN)), f.clone()));
+
+        if files.is_empty() {
+            return Err(DhcpError::ConfigFileNotFound);
+        }
+
+        let mut merged = serde_yaml::Mapping::new();
+        let mut sources = Vec::new();
+        let mut port: Option<(serde_yaml::Value, String)> = None;
+
+        for file in files {
+            let name = file.display().to_string();
+            let config = match serde_yaml::from_reader::<_, serde_yaml::Value>(File::open(&file)?)? {
+                serde_yaml::Value::Mapping(config) => config,
+                serde_yaml::Value::Null => continue, // empty file
+                _ => return Err(DhcpError::ConfigInvalid(format!("{} is no map of settings", name))),
+            };
+
+            for (key, value) in config {
+                match key.as_str() {
+                    Some("sources") => match value {
+                        serde_yaml::Value::Sequence(s) => sources.extend(s),
+                        serde_yaml::Value::Null => {}
+                        _ => return Err(DhcpError::ConfigInvalid(format!("sources of {} are no list", name))),
+                    },
+                    Some("port") => match &port {
+                        Some((first, first_name)) if *first != value => {
+                            return Err(DhcpError::ConfigInvalid(format!("port {:?} of {} conflicts with port {:?} of {}", value, name, first, first_name)));
+                        }
+                        Some(_) => {}
+                        None => {
+                            port = Some((value.clone(), name.clone()));
+                            merged.insert(key, value);
+                        }
+                    },
+                    _ => {
+                        if !merged.contains_key(&key) {
+                            merged.insert(key, value);
+                        }
+                    }
+                }
+            }
+        }
+
+        merged.insert("sources".into(), serde_yaml::Value::Sequence(sources));
+        serde_yaml::from_value::<Self>(serde_yaml::Value::Mapping(merged))?.validate()
+    }
+
+    /// Effective config as yaml, secrets (keys, tokens, passwords, authorization headers) replaced unless `redact` is false
+    pub fn dump(&self, redact: bool) -> DhcpResult<String> {
+        let mut value = serde_yaml::to_value(self)?;
+        if redact {
+            Self::redact(&mut value);
+        }
+        Ok(serde_yaml::to_string(&value)?)
     }
 
-    pub fn init_sources(&mut self) -> DhcpResult<Vec<impl DhcpHostSource>> {
-        let mut sources = vec![];
+    fn secret(key: &str) -> bool {
+        let key = key.to_lowercase();
+        key == "key" || key.ends_with("_key")
+            || ["token", "password", "secret", "authorization"].iter().any(|s| key.contains(s))
+    }
 
-        for source in self.sources.drain(..) { // clone sources?
-            match source.kind.as_str() {
-                DhcpRestSource::NAME => sources.push(DhcpRestSource::from_config(source.config)?),
-                _ => return Err(DhcpError::SourceKindUnknown)
+    fn redact(value: &mut serde_yaml::Value) {
+        match value {
+            serde_yaml::Value::Mapping(m) => {
+                for (k, v) in m.iter_mut() {
+                    match (k.as_str(), &v) {
+                        (Some(k), serde_yaml::Value::String(_)) | (Some(k), serde_yaml::Value::Number(_)) if Self::secret(k) => {
+                            *v = serde_yaml::Value::String(REDACTED.to_string());
+                        }
+                        _ => Self::redact(v),
+                    }
+                }
             }
+            serde_yaml::Value::Sequence(s) => s.iter_mut().for_each(Self::redact),
+            _ => {}
+        }
+    }
+
+    /// Checks settings excluding each other
+    fn validate(self) -> DhcpResult<Self> {
+        if self.force_broadcast && self.force_unicast {
+            return Err(DhcpError::ConfigInvalid("force_broadcast and force_unicast are mutually exclusive".to_string()));
+        }
+        self.reply_header.validate(self.reply_delivery())?;
+        self.serve_subnets()?;
+        if let Some(filter) = &self.mac_filter {
+            filter.validate()?;
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.validate()?;
+        }
+        Ok(self)
+    }
+
+    pub async fn from_url(url: &str) -> DhcpResult<Self> {
+        let fetch_error = |source| DhcpError::ConfigFetchError { url: url.to_string(), source };
+
+        let response = reqwest::Client::builder()
+            .timeout(CONFIG_FETCH_TIMEOUT)
+            .build()?
+            .get(url)
+            .send().await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(fetch_error)?;
+
+        serde_yaml::from_str::<Self>(&response.text().await.map_err(fetch_error)?)?.validate()
+    }
+
+    /// Config from stdin (`-`), a http(s) url or a file path
+    pub async fn load(source: &str) -> DhcpResult<Self> {
+        if source == "-" {
+            Self::from_reader(std::io::stdin())
+        } else if source.starts_with("http://") || source.starts_with("https://") {
+            Self::from_url(source).await
+        } else {
+            Self::from_file(source)
         }
+    }
+
+    pub(crate) fn sources(&self) -> &[Sources] { &self.sources }
+
+    /// Initialized sources, in the order of `sources()`
+    pub fn init_sources(&self) -> DhcpResult<Vec<BoxedDhcpHostSource>> {
+        // fail before any source is initialized
+        self.sources.iter().try_for_each(Sources::check_kind)?;
 
-        Ok(sources)
+        let script_limiter = self.max_concurrent_scripts
+            .map(|n| ConcurrencyLimiter::new(n, Duration::from_secs(self.script_permit_timeout)));
+
+        // one client per profile, shared by every source using it
+        let http_clients = self.http_clients.iter()
+            .map(|(name, profile)| Ok((name.clone(), profile.build()?)))
+            .collect::<DhcpResult<HashMap<String, reqwest::Client>>>()?;
+
+        self.sources.iter().map(|s| -> DhcpResult<BoxedDhcpHostSource> {
+            let mut source = s.init()?;
+            source.http_clients(&http_clients)?;
+            source.strict_mappings(self.strict_mappings);
+            source.limits(&self.limits);
+            if let Some(limiter) = &script_limiter {
+                source.script_limiter(limiter.clone());
+            }
+            Ok(source)
+        }).collect()
+    }
+
+    /// Initializes every source and compiles all templates, the server is not started
+    pub fn check(&self) -> DhcpResult<()> {
+        self.global_options.check_templates()?;
+        self.overrides.values().try_for_each(DhcpMapping::check_templates)?;
+        self.init_sources()?.iter().try_for_each(|source| source.check_templates())
     }
 }
 
 #[derive(Debug, StructOpt)]
 pub struct DhcpConfigOptions {
-    #[structopt(short, long, env = "DHCP_CONFIG", help = "default path: ./config.y[a]ml")]
+    #[structopt(short, long, env = "DHCP_CONFIG", help = "path, http(s) url or - for stdin, default: first of ./, $XDG_CONFIG_HOME/dhcpserver/, /etc/dhcpserver/ config.y[a]ml or $DHCP_CONFIG_SEARCH_PATH")]
     config: Option<String>,
 
+    #[structopt(long, conflicts_with = "config", help = "directory of *.y[a]ml files merged into one config, sources concatenated and other settings from main.yml or the first file setting them")]
+    config_dir: Option<String>,
+
     #[structopt(short, long, default_value="info", env = "DHCP_VERBOSITY", help = "off, error, warn, info, debug trace")]
-    verbosity: LevelFilter
+    verbosity: LevelFilter,
+
+    #[structopt(long, env = "DHCP_SYSLOG", help = "log to the local syslog daemon (daemon facility) instead of the terminal")]
+    syslog: bool,
+
+    #[structopt(long, env = "DHCP_LOG_FILE", help = "also log to this file, missing directories are created")]
+    log_file: Option<PathBuf>,
+
+    #[structopt(long, default_value = "10", env = "DHCP_LOG_MAX_SIZE", help = "megabytes of --log-file before it is rotated, 0 never rotates")]
+    log_max_size: u64,
+
+    #[structopt(long, env = "DHCP_UNPRIVILEGED", help = "bind `unprivileged_port` if binding `port` is not permitted")]
+    unprivileged: bool,
+
+    #[structopt(long, help = "render a mapping file with the variables of --context, print the options and exit")]
+    validate_template: Option<String>,
+
+    #[structopt(long, requires = "validate-template", help = "json file of template variables for --validate-template")]
+    context: Option<String>,
+
+    #[structopt(long, help = "print the effective config with secrets redacted and exit")]
+    dump_config: bool,
+
+    #[structopt(long, requires = "dump-config", help = "do not redact secrets in --dump-config")]
+    no_redact: bool,
+
+    #[structopt(long, help = "initialize every source and compile all templates, then exit without binding the socket")]
+    check: bool,
 }
 
 impl DhcpConfigOptions {
-    pub fn config(&self) -> Option<&str> {
+    pub fn config(&self) -> Option<String> {
         if let Some(s) = &self.config {
-            return Some(&s);
-        } else {
-            for s in ["./config.yml", "./config.yaml"] {
-                let p = Path::new(s);
-                if p.exists() {
-                    return Some(s);
-                }
-            }
+            return Some(s.clone());
+        }
+
+        let search_path = Self::search_path(std::env::var(CONFIG_SEARCH_PATH_ENV).ok(), std::env::var("XDG_CONFIG_HOME").ok());
+        log::debug!("searching config file in {}", search_path.join(", "));
+        search_path.into_iter().find(|s| Path::new(s).exists())
+    }
+
+    /// Config file candidates by precedence, `DHCP_CONFIG_SEARCH_PATH` (`:` separated) replaces the defaults
+    fn search_path(configured: Option<String>, xdg_config_home: Option<String>) -> Vec<String> {
+        if let Some(configured) = configured.filter(|c| !c.is_empty()) {
+            return configured.split(':').filter(|s| !s.is_empty()).map(String::from).collect();
         }
-        None
+
+        let mut dirs = vec![".".to_string()];
+        if let Some(xdg) = xdg_config_home.filter(|x| !x.is_empty()) {
+            dirs.push(format!("{}/dhcpserver", xdg));
+        }
+        dirs.push("/etc/dhcpserver".to_string());
+
+        dirs.iter()
+            .flat_map(|d| vec![format!("{}/config.yml", d), format!("{}/config.yaml", d)])
+            .collect()
     }
 
+    pub fn config_dir(&self) -> Option<&str> { self.config_dir.as_deref() }
+
     pub fn verbosity(&self) -> LevelFilter { self.verbosity }
+
+    pub fn syslog(&self) -> bool { self.syslog }
+
+    pub fn log_file(&self) -> Option<&Path> { self.log_file.as_deref() }
+
+    /// Bytes of the log file before it is rotated
+    pub fn log_max_size(&self) -> u64 { self.log_max_size.saturating_mul(1024 * 1024) }
+
+    pub fn unprivileged(&self) -> bool { self.unprivileged }
+
+    pub fn validate_template(&self) -> Option<&str> { self.validate_template.as_deref() }
+
+    pub fn context(&self) -> Option<&str> { self.context.as_deref() }
+
+    pub fn dump_config(&self) -> bool { self.dump_config }
+
+    pub fn no_redact(&self) -> bool { self.no_redact }
+
+    pub fn check(&self) -> bool { self.check }
+}
+
+#[test]
+fn test_override_result() {
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+overrides:
+  "01:02:03:05:06:07":
+    client_ip_address: 10.0.0.5
+    subnet_mask:
+      data: 255.255.255.0
+sources: []
+"#).unwrap();
+
+    let result = config.override_result(&crate::sources::test_packet(0, vec![])).unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 5)));
+    assert_eq!(result.options().try_ipv4_option(dhcplib::option::SUBNET_MASK).unwrap(), Ipv4Addr::new(255, 255, 255, 0));
+
+    let config: DhcpConfig = serde_yaml::from_str("sources: []").unwrap();
+    assert!(config.override_result(&crate::sources::test_packet(0, vec![])).unwrap().is_none());
+}
+
+#[test]
+fn test_unknown_source_kind() {
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+sources:
+  - kind: bogus
+    config: {}
+"#).unwrap();
+
+    match config.init_sources() {
+        Err(e @ DhcpError::SourceKindUnknown { .. }) => {
+            let message = e.to_string();
+            assert!(message.contains("bogus"));
+            assert!(message.contains(DhcpRestSource::NAME));
+        }
+        _ => panic!("unknown source kind accepted"),
+    }
+
+    // children of a chain are checked before anything is initialized
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+sources:
+  - kind: chain
+    config:
+      sources:
+        - kind: static
+          config: {}
+        - kind: bogus
+          config: {}
+"#).unwrap();
+    assert!(matches!(config.init_sources(), Err(DhcpError::SourceKindUnknown { kind, .. }) if kind == "bogus"));
+}
+
+#[test]
+fn test_hostname_policy() {
+    let hostname = "My_Host Name..Office-";
+
+    assert_eq!(HostnamePolicy::Passthrough.apply(hostname), hostname);
+    assert_eq!(HostnamePolicy::Sanitize.apply(hostname), "my-host-name.office");
+    assert_eq!(HostnamePolicy::ForceSuffix("lan.example".to_string()).apply(hostname), "my-host-name.office.lan.example");
+    assert_eq!(HostnamePolicy::ForceSuffix(".lan.example".to_string()).apply("printer.LAN.example"), "printer.lan.example");
+
+    let policy: HostnamePolicy = serde_yaml::from_str("force-suffix: lan").unwrap();
+    assert_eq!(policy, HostnamePolicy::ForceSuffix("lan".to_string()));
+}
+
+#[test]
+fn test_source_interfaces() {
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+sources:
+  - kind: chain
+    config:
+      sources: []
+    interfaces: [eth0, 10.1.0.0/16]
+  - kind: chain
+    config:
+      sources: []
+    interfaces: [eth1]
+"#).unwrap();
+    let ingress = |interface: Option<&str>, gateway: Ipv4Addr| Ingress {
+        interface: interface.map(ToString::to_string),
+        gateway,
+    };
+    let serving = |ingress: Ingress| config.sources().iter().map(|s| s.serves(&ingress)).collect::<Vec<bool>>();
+
+    assert_eq!(serving(ingress(Some("eth0"), Ipv4Addr::UNSPECIFIED)), vec![true, false]);
+    assert_eq!(serving(ingress(Some("eth1"), Ipv4Addr::UNSPECIFIED)), vec![false, true]);
+    assert_eq!(serving(ingress(None, Ipv4Addr::UNSPECIFIED)), vec![false, false]);
+    assert_eq!(serving(ingress(Some("eth1"), Ipv4Addr::new(10, 1, 2, 1))), vec![true, false]);
+    assert_eq!(serving(ingress(Some("eth0"), Ipv4Addr::new(10, 2, 0, 1))), vec![false, false]);
+}
+
+#[tokio::test]
+async fn test_http_client_profile() {
+    let config = |profile: &str| -> DhcpConfig { serde_yaml::from_str(&format!(r#"
+http_clients:
+  backend:
+    timeout: 5
+    headers:
+      x-profile: shared
+sources:
+  - kind: rest
+    config: &rest
+      offer: &schema
+        scripts: []
+        queries:
+          - url: "{}/profile"
+            name: host
+            method: GET
+            http_client: {}
+        mapping:
+          client_ip_address: "{{{{ results.host.ip }}}}"
+      reserve: *schema
+      release: *schema
+      decline: *schema
+      inform: *schema
+  - kind: chain
+    config:
+      sources:
+        - kind: rest
+          config: *rest
+"#, mockito::server_url(), profile)).unwrap() };
+
+    let _m = mockito::mock("GET", "/profile")
+        .match_header("x-profile", "shared")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(serde_json::json!({"ip": "10.0.0.8"}).to_string())
+        .expect(2)
+        .create();
+
+    let p = crate::sources::test_packet(0, vec![]);
+    for source in config("backend").init_sources().unwrap().iter_mut() {
+        let result = source.offer(&p).await.unwrap().unwrap();
+        assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 8)));
+    }
+    _m.assert();
+
+    assert!(matches!(config("missing").init_sources(), Err(DhcpError::ConfigInvalid(_))));
+}
+
+#[test]
+fn test_override_by_client_identifier() {
+    let config = |prefer: bool| -> DhcpConfig { serde_yaml::from_str(&format!(r#"
+prefer_client_identifier: {}
+overrides:
+  "ff:00:00:00:01":
+    client_ip_address: 10.0.0.6
+  "01:02:03:05:06:07":
+    client_ip_address: 10.0.0.5
+sources: []
+"#, prefer)).unwrap() };
+    let p = crate::sources::test_packet(0, vec![dhcplib::option::DhcpOption::ClientIdentifier(vec![0xff, 0, 0, 0, 1])]);
+
+    let result = config(true).override_result(&p).unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 6)));
+
+    let result = config(false).override_result(&p).unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 5)));
+
+    let result = config(true).override_result(&crate::sources::test_packet(0, vec![])).unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 5)));
+}
+
+#[test]
+fn test_pxe() {
+    use dhcplib::option::DhcpOption;
+    use crate::sources::mapping::CLIENT_SYSTEM_ARCHITECTURE;
+
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+pxe:
+  - arch: 7
+    next_server: 10.0.0.3
+    boot_file_name: ipxe.efi
+  - arch: 0
+    vendor_class: PXEClient
+    boot_file_name: undionly.kpxe
+sources: []
+"#).unwrap();
+    let client = |arch: u16, vendor_class: &str| crate::sources::test_packet(0, vec![
+        DhcpOption::Unknown(CLIENT_SYSTEM_ARCHITECTURE, arch.to_be_bytes().to_vec()),
+        DhcpOption::VendorClassIdentifier(vendor_class.as_bytes().to_vec()),
+    ]);
+
+    let uefi = config.pxe(&client(7, "PXEClient:Arch:00007:UNDI:003016")).unwrap();
+    assert_eq!(uefi.boot_file_name(), "ipxe.efi");
+    assert_eq!(uefi.next_server(), Some(Ipv4Addr::new(10, 0, 0, 3)));
+
+    let bios = config.pxe(&client(0, "PXEClient:Arch:00000:UNDI:002001")).unwrap();
+    assert_eq!(bios.boot_file_name(), "undionly.kpxe");
+    assert_eq!(bios.next_server(), None);
+
+    assert!(config.pxe(&client(0, "MSFT 5.0")).is_none());
+    assert!(config.pxe(&crate::sources::test_packet(0, vec![])).is_none());
+}
+
+#[test]
+fn test_config_from_reader() {
+    let config = DhcpConfig::from_reader("port: 6767\nsources: []".as_bytes()).unwrap();
+    assert_eq!(config.port(), 6767);
+
+    assert!(DhcpConfig::from_reader("port: [".as_bytes()).is_err());
+}
+
+#[test]
+fn test_reply_delivery() {
+    let config = |yaml: &str| DhcpConfig::from_reader(format!("{}\nsources: []", yaml).as_bytes());
+
+    assert_eq!(config("port: 67").unwrap().reply_delivery(), ReplyDelivery::Flags);
+    assert_eq!(config("force_broadcast: true").unwrap().reply_delivery(), ReplyDelivery::Broadcast);
+    assert_eq!(config("force_unicast: true").unwrap().reply_delivery(), ReplyDelivery::Unicast);
+    assert!(matches!(config("force_broadcast: true\nforce_unicast: true"), Err(DhcpError::ConfigInvalid(_))));
+}
+
+#[test]
+fn test_reply_header() {
+    let config = |yaml: &str| DhcpConfig::from_reader(format!("{}\nsources: []", yaml).as_bytes());
+    let mut request = vec![0u8; packet::OPTIONS_OFFSET];
+    packet::set_flags(&mut request, packet::BROADCAST_FLAG);
+    packet::set_secs(&mut request, 7);
+
+    assert_eq!(config("port: 67").unwrap().reply_header().fields(&request), (None, None));
+    assert_eq!(config("reply_header:\n  flags: copy\n  secs: copy").unwrap().reply_header().fields(&request), (Some(packet::BROADCAST_FLAG), Some(7)));
+    assert_eq!(config("reply_header:\n  flags: unicast\n  secs: 0").unwrap().reply_header().fields(&request), (Some(0), Some(0)));
+
+    assert!(matches!(config("force_unicast: true\nreply_header:\n  flags: broadcast"), Err(DhcpError::ConfigInvalid(_))));
+    assert!(matches!(config("force_broadcast: true\nreply_header:\n  flags: unicast"), Err(DhcpError::ConfigInvalid(_))));
+    assert!(config("force_broadcast: true\nreply_header:\n  flags: broadcast").is_ok());
+}
+
+#[test]
+fn test_relay_agent() {
+    let config = |yaml: &str| DhcpConfig::from_reader(format!("{}\nsources: []", yaml).as_bytes()).unwrap();
+
+    assert_eq!(config("port: 67").forward_to(), None);
+    assert_eq!(config("forward_to: 10.0.0.1").forward_to(), Some("10.0.0.1:67".parse().unwrap()));
+    assert_eq!(config("forward_to: 10.0.0.1\nforward_port: 6767").forward_to(), Some("10.0.0.1:6767".parse().unwrap()));
+
+    assert_eq!(config("port: 67").relay_agent().information(Some("eth0")), b"\x01\x04eth0".to_vec());
+    assert_eq!(config("port: 67").relay_agent().information(None), vec![]);
+    let agent = config("relay_agent:\n  circuit_id: vlan10\n  remote_id: lab");
+    assert_eq!(agent.relay_agent().information(Some("eth0")), b"\x01\x06vlan10\x02\x03lab".to_vec());
+}
+
+#[tokio::test]
+async fn test_config_from_url() {
+    let _m = mockito::mock("GET", "/config.yml")
+        .with_status(200)
+        .with_body("port: 6767\nsources: []")
+        .create();
+    let _missing = mockito::mock("GET", "/missing.yml")
+        .with_status(404)
+        .create();
+
+    let config = DhcpConfig::load(&format!("{}/config.yml", mockito::server_url())).await.unwrap();
+    assert_eq!(config.port(), 6767);
+
+    match DhcpConfig::load(&format!("{}/missing.yml", mockito::server_url())).await {
+        Err(DhcpError::ConfigFetchError { url, .. }) => assert!(url.ends_with("/missing.yml")),
+        _ => panic!("failed fetch accepted"),
+    }
+}
+
+#[test]
+fn test_dump() {
+    let config = DhcpConfig::from_reader(r#"
+port: 6767
+auth:
+  key_id: 1
+  key: secret-key
+management:
+  listen: 127.0.0.1:8067
+  token: secret-token
+http_clients:
+  backend:
+    headers:
+      Authorization: Bearer secret-bearer
+sources:
+  - kind: rest
+    config:
+      password: secret-password
+"#.as_bytes()).unwrap();
+
+    let dump = config.dump(false).unwrap();
+    let reloaded = DhcpConfig::from_reader(dump.as_bytes()).unwrap();
+    assert_eq!(reloaded.dump(false).unwrap(), dump);
+    assert_eq!(reloaded.port(), 6767);
+    assert!(dump.contains("secret-key"));
+
+    let redacted = config.dump(true).unwrap();
+    assert!(!redacted.contains("secret"));
+    assert!(redacted.contains("key_id: 1"));
+    assert!(DhcpConfig::from_reader(redacted.as_bytes()).is_ok());
+}
+
+#[test]
+fn test_limits() {
+    let config = DhcpConfig::from_reader("stats_capacity: 10\nsources: []".as_bytes()).unwrap();
+    assert_eq!(config.stats_capacity(), 10);
+    assert_eq!(config.limits().cache_entries(), None);
+
+    let config = DhcpConfig::from_reader("stats_capacity: 10\nlimits:\n  stats_entries: 5\n  cache_entries: 100\nsources: []".as_bytes()).unwrap();
+    assert_eq!(config.stats_capacity(), 5);
+    assert_eq!(config.limits().cache_entries(), Some(100));
+}
+
+#[test]
+fn test_scheduled() {
+    use crate::schedule::Weekday;
+
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+sources:
+  - kind: chain
+    config:
+      sources: []
+    schedule:
+      - days: [sat, sun]
+        from: "10:00"
+        to: "20:00"
+  - kind: chain
+    config:
+      sources: []
+"#).unwrap();
+    let guest = &config.sources()[0];
+
+    assert!(guest.scheduled(&LocalTime::new(Weekday::Sat, 10, 0)));
+    assert!(!guest.scheduled(&LocalTime::new(Weekday::Sat, 20, 0)));
+    assert!(!guest.scheduled(&LocalTime::new(Weekday::Mon, 12, 0)));
+    assert!(config.sources()[1].scheduled(&LocalTime::new(Weekday::Mon, 3, 0)));
+}
+
+#[test]
+fn test_config_search_path() {
+    assert_eq!(DhcpConfigOptions::search_path(None, Some("/home/u/.config".to_string())), vec![
+        "./config.yml", "./config.yaml",
+        "/home/u/.config/dhcpserver/config.yml", "/home/u/.config/dhcpserver/config.yaml",
+        "/etc/dhcpserver/config.yml", "/etc/dhcpserver/config.yaml",
+    ]);
+    assert_eq!(DhcpConfigOptions::search_path(None, None), vec![
+        "./config.yml", "./config.yaml", "/etc/dhcpserver/config.yml", "/etc/dhcpserver/config.yaml",
+    ]);
+    assert_eq!(DhcpConfigOptions::search_path(Some("/srv/dhcp.yml::/etc/dhcp.yml".to_string()), None), vec!["/srv/dhcp.yml", "/etc/dhcp.yml"]);
+
+    // first existing candidate wins
+    let dir = std::env::temp_dir().join("dhcpserver-test-search-path");
+    std::fs::create_dir_all(dir.join("dhcpserver")).unwrap();
+    std::fs::write(dir.join("dhcpserver/config.yaml"), "sources: []").unwrap();
+    let search_path = DhcpConfigOptions::search_path(None, dir.to_str().map(String::from));
+    let found = search_path.into_iter().filter(|s| !s.starts_with("./")).find(|s| Path::new(s).exists());
+    assert_eq!(found, Some(format!("{}/dhcpserver/config.yaml", dir.to_str().unwrap())));
+
+    std::fs::write(dir.join("dhcpserver/config.yml"), "sources: []").unwrap(); // .yml before .yaml
+    let search_path = DhcpConfigOptions::search_path(None, dir.to_str().map(String::from));
+    let found = search_path.into_iter().filter(|s| !s.starts_with("./")).find(|s| Path::new(s).exists());
+    assert_eq!(found, Some(format!("{}/dhcpserver/config.yml", dir.to_str().unwrap())));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_config_dir() {
+    let dir = std::env::temp_dir().join("dhcpserver-test-config-dir");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    std::fs::write(dir.join("b-subnet.yaml"), "max_hops: 4\nsources:\n  - kind: pool\n    config: { start: 10.1.0.10, end: 10.1.0.20 }").unwrap();
+    std::fs::write(dir.join("a-subnet.yml"), "port: 6767\nmax_hops: 2\nsources:\n  - kind: pool\n    config: { start: 10.0.0.10, end: 10.0.0.20 }").unwrap();
+    std::fs::write(dir.join("main.yml"), "port: 6767\nsources:\n  - kind: static\n    config: {}").unwrap();
+    std::fs::write(dir.join("notes.txt"), "not a config").unwrap();
+
+    let config = DhcpConfig::from_dir(&dir).unwrap();
+    assert_eq!(config.port(), 6767);
+    assert_eq!(config.max_hops(), 2); // first file setting it
+    assert_eq!(config.sources().iter().map(Sources::kind).collect::<Vec<_>>(), vec!["static", "pool", "pool"]);
+
+    std::fs::write(dir.join("b-subnet.yaml"), "port: 67").unwrap();
+    match DhcpConfig::from_dir(&dir) {
+        Err(DhcpError::ConfigInvalid(e)) => assert!(e.contains("b-subnet.yaml")),
+        _ => panic!("conflicting ports accepted"),
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(DhcpConfig::from_dir(&dir).is_err());
+}
+
+#[test]
+fn test_check() {
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+global_options:
+  domain_name:
+    data: "{{ client_hardware }}.example.com"
+sources:
+  - kind: static
+    config:
+      hosts:
+        "01:02:03:05:06:07":
+          client_ip_address: "{{ 10.0.0.5 }"
+"#).unwrap();
+    assert!(matches!(config.check(), Err(DhcpError::TemplateError { .. })));
+
+    let config: DhcpConfig = serde_yaml::from_str("sources:\n  - kind: static\n    config:\n      hosts:\n        \"01:02:03:05:06:07\":\n          client_ip_address: 10.0.0.5").unwrap();
+    assert!(config.check().is_ok());
+
+    let config: DhcpConfig = serde_yaml::from_str("sources:\n  - kind: unknown\n    config: {}").unwrap();
+    assert!(matches!(config.check(), Err(DhcpError::SourceKindUnknown { .. })));
+}
+
+#[test]
+fn test_mac_filter() {
+    let filter: MacFilter = serde_yaml::from_str("allow: [\"AA:BB:CC:*\", 01:02:03:05:06:07]\ndeny: [aa:bb:cc:00:00:01]").unwrap();
+    assert!(filter.validate().is_ok());
+    assert!(filter.permits("aa:bb:cc:12:34:56"));
+    assert!(filter.permits("01:02:03:05:06:07"));
+    assert!(!filter.permits("aa:bb:cc:00:00:01")); // deny wins
+    assert!(!filter.permits("aa:bb:cd:12:34:56"));
+
+    let filter: MacFilter = serde_yaml::from_str("deny: [\"de:ad:*\"]").unwrap();
+    assert!(filter.permits("01:02:03:05:06:07"));
+    assert!(!filter.permits("DE:AD:be:ef:00:01"));
+
+    for invalid in &["allow: [aa:bb:cc]", "deny: [\"aa:bb:cc:dd:ee:ff:*\"]", "deny: [\"zz:*\"]", "deny: [\"aa::bb:cc:dd:ee:ff\"]", "deny: [\"aa::*\"]"] {
+        assert!(serde_yaml::from_str::<MacFilter>(invalid).unwrap().validate().is_err(), "{}", invalid);
+    }
+    assert!(DhcpConfig::from_reader("mac_filter:\n  allow: [aa:bb]\nsources: []".as_bytes()).is_err());
 }
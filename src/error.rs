@@ -5,6 +5,8 @@ use reqwest::header::{InvalidHeaderValue, InvalidHeaderName};
 use dhcplib::MacAddress;
 use tokio::task::JoinError;
 use log::SetLoggerError;
+use pnet::ipnetwork::IpNetworkError;
+use std::net::Ipv4Addr;
 
 pub type DhcpResult<T> = Result<T, DhcpError>;
 
@@ -13,7 +15,7 @@ pub enum DhcpError {
     IoError(std::io::Error),
     SerdeYamlError(serde_yaml::Error),
     SerdeJsonError(serde_json::Error),
-    SourceKindUnknown,
+    SourceKindUnknown { kind: String, available: String },
     SerdeErrorString(String),
     TeraError(tera::Error),
     ParseIntError(ParseIntError),
@@ -24,9 +26,27 @@ pub enum DhcpError {
     ClientIpAddressMissing(MacAddress),
     DhcpLibError(dhcplib::error::DhcpError),
     CustomRestTypeError,
+    CustomFloatKindMissing(u8),
     JoinError(JoinError),
     ConfigFileNotFound,
-    SetLoggerError(SetLoggerError)
+    SetLoggerError(SetLoggerError),
+    IpNetworkError(IpNetworkError),
+    HyperError(hyper::Error),
+    SqliteError(rusqlite::Error),
+    SyslogError(String),
+    RateLimited,
+    InvalidClientIpAddress(Ipv4Addr),
+    InvalidLeaseTimes(String),
+    BindPermissionDenied(u16),
+    ConfigFetchError { url: String, source: reqwest::Error },
+    ConfigInvalid(String),
+    Rejected(String),
+    QueryTimeout(String),
+    ReleaseUnconfirmed(String),
+    ChaosInjected,
+    AddressConflict { ip: Ipv4Addr, client: String, holder: String },
+    TemplateError { option: String, template: String, field: Option<String>, source: tera::Error },
+    MappingFieldError { option: String, field: String, source: Box<DhcpError> },
 }
 
 impl Display for DhcpError {
@@ -34,7 +54,7 @@ impl Display for DhcpError {
         let s = match self {
             DhcpError::IoError(e) => e.to_string(),
             DhcpError::SerdeYamlError(e) => e.to_string(),
-            DhcpError::SourceKindUnknown => "Source unknown".to_string(),
+            DhcpError::SourceKindUnknown { kind, available } => format!("Source kind '{}' unknown, available kinds: {}", kind, available),
             DhcpError::SerdeErrorString(e) => e.to_string(),
             DhcpError::TeraError(e) => e.to_string(),
             DhcpError::ParseIntError(e) => e.to_string(),
@@ -45,10 +65,41 @@ impl Display for DhcpError {
             DhcpError::ClientIpAddressMissing(e) => e.to_string(),
             DhcpError::DhcpLibError(e) => e.to_string(),
             DhcpError::CustomRestTypeError => "Custom option type error".to_string(),
+            DhcpError::CustomFloatKindMissing(tag) => format!("Custom option {}: floats are no valid option payload without kind float32 or float64", tag),
             DhcpError::SerdeJsonError(e) => e.to_string(),
             DhcpError::JoinError(e) => e.to_string(),
             DhcpError::ConfigFileNotFound => "no config file found".to_string(),
             DhcpError::SetLoggerError(e) => e.to_string(),
+            DhcpError::IpNetworkError(e) => e.to_string(),
+            DhcpError::HyperError(e) => e.to_string(),
+            DhcpError::SqliteError(e) => format!("sqlite: {}", e),
+            DhcpError::SyslogError(e) => format!("connecting to syslog failed: {}", e),
+            DhcpError::RateLimited => "rate limit exceeded".to_string(),
+            DhcpError::InvalidClientIpAddress(ip) => format!("client ip address {} can not be assigned", ip),
+            DhcpError::InvalidLeaseTimes(e) => format!("invalid lease times: {}", e),
+            DhcpError::BindPermissionDenied(port) => format!("binding port {} not permitted: run as root, grant CAP_NET_BIND_SERVICE \
+                (setcap cap_net_bind_service=+ep <binary>), set `port` to a high value for testing or pass --unprivileged", port),
+            DhcpError::ConfigFetchError { url, source } => format!("fetching config {} failed: {}", url, source),
+            DhcpError::ConfigInvalid(e) => format!("invalid config: {}", e),
+            DhcpError::Rejected(reason) => format!("client rejected by backend: {}", reason),
+            DhcpError::QueryTimeout(name) => format!("query {} timed out", name),
+            DhcpError::ReleaseUnconfirmed(reason) => format!("release not confirmed by backend: {}", reason),
+            DhcpError::ChaosInjected => "failure injected by chaos config".to_string(),
+            DhcpError::AddressConflict { ip, client, holder } => format!("address {} handed to {} is held by {}", ip, client, holder),
+            DhcpError::MappingFieldError { option, field, source } => format!("option {}: {} invalid: {}", option, field, source),
+            DhcpError::TemplateError { option, template, field, source } => {
+                // tera keeps the actual cause (e.g. undefined variable) in the source chain
+                let mut message = format!("templating {} failed for '{}': {}", option, template, source);
+                if let Some(field) = field {
+                    message = format!("{} - {}", field, message);
+                }
+                let mut cause = source.source();
+                while let Some(c) = cause {
+                    message.push_str(&format!(": {}", c));
+                    cause = c.source();
+                }
+                message
+            }
         };
 
         write!(f, "{}", s)
@@ -127,3 +178,21 @@ impl From<SetLoggerError> for DhcpError {
         Self::SetLoggerError(e)
     }
 }
+
+impl From<IpNetworkError> for DhcpError {
+    fn from(e: IpNetworkError) -> Self {
+        Self::IpNetworkError(e)
+    }
+}
+
+impl From<hyper::Error> for DhcpError {
+    fn from(e: hyper::Error) -> Self {
+        Self::HyperError(e)
+    }
+}
+
+impl From<rusqlite::Error> for DhcpError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::SqliteError(e)
+    }
+}
@@ -0,0 +1,268 @@
+//! Addresses acked by the server until their lease expires, guards against handing an address to several clients
+
+use crate::error::DhcpResult;
+use crate::sources::mapping::INFINITE_LEASE_TIME;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::File;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+/// Seconds between sweeps of expired leases
+pub const LEASE_SWEEP_INTERVAL: u64 = 60;
+
+/// Client of a leased address, infinite leases never expire
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Lease {
+    mac: String,
+    /// source answering the request, as labelled in the option provenance
+    #[serde(default)]
+    source: String,
+    expires: Option<SystemTime>,
+}
+
+impl Lease {
+    pub fn mac(&self) -> &str { &self.mac }
+
+    pub fn source(&self) -> &str { &self.source }
+
+    pub fn expires(&self) -> Option<SystemTime> { self.expires }
+
+    fn valid(&self, now: SystemTime) -> bool {
+        self.expires.map_or(true, |expires| expires > now)
+    }
+
+    /// Seconds until the lease expires, the infinite lease time for infinite leases
+    pub fn remaining(&self, now: SystemTime) -> u32 {
+        match self.expires {
+            Some(expires) => expires.duration_since(now).map_or(0, |left| left.as_secs().min(u32::MAX as u64 - 1) as u32),
+            None => INFINITE_LEASE_TIME,
+        }
+    }
+}
+
+/// Durable copy of the lease table, loaded at startup and saved on each change
+pub trait LeaseStore: Send + Sync + Debug {
+    fn load(&self) -> DhcpResult<HashMap<Ipv4Addr, Lease>>;
+
+    fn save(&self, leases: &HashMap<Ipv4Addr, Lease>) -> DhcpResult<()>;
+}
+
+/// Leases as a json file, written to a temporary file first so a crash never leaves it truncated
+#[derive(Debug)]
+pub struct JsonLeaseStore {
+    path: PathBuf,
+}
+
+impl JsonLeaseStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl LeaseStore for JsonLeaseStore {
+    fn load(&self) -> DhcpResult<HashMap<Ipv4Addr, Lease>> {
+        Ok(serde_json::from_reader(File::open(&self.path)?)?)
+    }
+
+    fn save(&self, leases: &HashMap<Ipv4Addr, Lease>) -> DhcpResult<()> {
+        let temporary = self.path.with_extension("tmp");
+        serde_json::to_writer(File::create(&temporary)?, leases)?;
+        std::fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Saves snapshots of the lease table on its own thread, the table lock is never held while writing
+#[derive(Debug)]
+struct LeaseWriter {
+    snapshots: Option<Sender<HashMap<Ipv4Addr, Lease>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LeaseWriter {
+    fn new(store: Box<dyn LeaseStore>) -> Self {
+        let (snapshots, receiver) = channel::<HashMap<Ipv4Addr, Lease>>();
+        let thread = std::thread::spawn(move || {
+            while let Ok(mut leases) = receiver.recv() {
+                // snapshots queued meanwhile supersede it, only the latest is written
+                while let Ok(newer) = receiver.try_recv() {
+                    leases = newer;
+                }
+                if let Err(e) = store.save(&leases) {
+                    log::error!("leases not saved to {:?}: {}", store, e);
+                }
+            }
+        });
+
+        Self { snapshots: Some(snapshots), thread: Some(thread) }
+    }
+
+    fn save(&self, leases: &HashMap<Ipv4Addr, Lease>) {
+        if let Some(snapshots) = &self.snapshots {
+            let _ = snapshots.send(leases.clone());
+        }
+    }
+}
+
+impl Drop for LeaseWriter {
+    /// Pending snapshots are written before the table goes away
+    fn drop(&mut self) {
+        self.snapshots.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Leases by address, a client holds a single address, macs are kept lowercase
+#[derive(Serialize, Default, Debug)]
+pub struct LeaseTable {
+    leases: HashMap<Ipv4Addr, Lease>,
+    #[serde(skip)]
+    writer: Option<LeaseWriter>,
+}
+
+impl LeaseTable {
+    /// Valid leases of the store, a missing or corrupt store starts empty
+    pub fn with_store(store: Box<dyn LeaseStore>) -> Self {
+        let mut leases = match store.load() {
+            Ok(leases) => leases,
+            Err(e) => {
+                log::warn!("leases not loaded from {:?}, starting empty: {}", store, e);
+                HashMap::new()
+            }
+        };
+        leases.values_mut().for_each(|lease| lease.mac.make_ascii_lowercase());
+
+        let mut table = Self { leases, writer: Some(LeaseWriter::new(store)) };
+        table.sweep(SystemTime::now());
+        log::info!("{} leases loaded", table.len());
+        table
+    }
+
+    /// Hands a snapshot to the writer, the file is written in the background
+    fn flush(&self) {
+        if let Some(writer) = &self.writer {
+            writer.save(&self.leases);
+        }
+    }
+
+    /// Mac of another client holding a valid lease of the address
+    pub fn holder(&self, ip: Ipv4Addr, mac: &str, now: SystemTime) -> Option<&str> {
+        self.leases.get(&ip)
+            .filter(|lease| !lease.mac.eq_ignore_ascii_case(mac) && lease.valid(now))
+            .map(|lease| lease.mac.as_str())
+    }
+
+    /// Valid lease of the address
+    pub fn get(&self, ip: Ipv4Addr, now: SystemTime) -> Option<&Lease> {
+        self.leases.get(&ip).filter(|lease| lease.valid(now))
+    }
+
+    /// Address and valid lease of the client
+    pub fn lease_of(&self, mac: &str, now: SystemTime) -> Option<(Ipv4Addr, &Lease)> {
+        self.leases.iter()
+            .find(|(_, lease)| lease.mac.eq_ignore_ascii_case(mac) && lease.valid(now))
+            .map(|(ip, lease)| (*ip, lease))
+    }
+
+    /// Records the acked address, a previous address of the client is freed
+    pub fn commit(&mut self, ip: Ipv4Addr, mac: &str, source: &str, lease_time: u32, now: SystemTime) {
+        self.release(mac);
+
+        let expires = Some(lease_time).filter(|t| *t != INFINITE_LEASE_TIME).map(|t| now + Duration::from_secs(t as u64));
+        self.leases.insert(ip, Lease { mac: mac.to_ascii_lowercase(), source: source.to_string(), expires });
+        self.flush();
+    }
+
+    /// Frees every address of the client
+    pub fn release(&mut self, mac: &str) {
+        let before = self.leases.len();
+        self.leases.retain(|_, lease| !lease.mac.eq_ignore_ascii_case(mac));
+        if self.leases.len() != before {
+            self.flush();
+        }
+    }
+
+    /// Drops expired leases, returns how many
+    pub fn sweep(&mut self, now: SystemTime) -> usize {
+        let before = self.leases.len();
+        self.leases.retain(|_, lease| lease.valid(now));
+        before - self.leases.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.leases.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Ipv4Addr, &Lease)> {
+        self.leases.iter()
+    }
+}
+
+#[test]
+fn test_lease_table() {
+    let now = SystemTime::now();
+    let ip = Ipv4Addr::new(10, 0, 0, 5);
+    let mut table = LeaseTable::default();
+
+    table.commit(ip, "aa", "pool#0", 60, now);
+    assert_eq!(table.holder(ip, "bb", now), Some("aa"));
+    assert_eq!(table.holder(ip, "aa", now), None);
+    assert_eq!(table.holder(ip, "bb", now + Duration::from_secs(60)), None); // expired
+
+    // renewing with another address frees the old one
+    table.commit(Ipv4Addr::new(10, 0, 0, 6), "aa", "pool#0", 60, now);
+    assert_eq!(table.holder(ip, "bb", now), None);
+    assert_eq!(table.len(), 1);
+
+    table.commit(ip, "bb", "static#1", INFINITE_LEASE_TIME, now);
+    assert_eq!(table.sweep(now + Duration::from_secs(3600)), 1);
+    assert_eq!(table.holder(ip, "aa", now + Duration::from_secs(3600)), Some("bb"));
+
+    table.release("bb");
+    assert_eq!(table.len(), 0);
+
+    // macs match regardless of their case
+    table.commit(ip, "AA:BB:CC:DD:EE:FF", "pool#0", 60, now);
+    assert_eq!(table.holder(ip, "aa:bb:cc:dd:ee:ff", now), None);
+    assert_eq!(table.holder(ip, "01:02:03:05:06:07", now), Some("aa:bb:cc:dd:ee:ff"));
+    table.release("aa:bb:cc:dd:ee:FF");
+    assert_eq!(table.len(), 0);
+}
+
+#[test]
+fn test_json_lease_store() {
+    let nanos = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().subsec_nanos();
+    let path = std::env::temp_dir().join(format!("dhcpserver-test-leases-{}-{}.json", std::process::id(), nanos));
+    let now = SystemTime::now();
+
+    // missing file
+    let mut table = LeaseTable::with_store(Box::new(JsonLeaseStore::new(&path)));
+    assert_eq!(table.len(), 0);
+    table.commit(Ipv4Addr::new(10, 0, 0, 5), "aa", "pool#0", 3600, now);
+    table.commit(Ipv4Addr::new(10, 0, 0, 6), "bb", "static#1", INFINITE_LEASE_TIME, now);
+    drop(table); // waits for the writer
+
+    let table = LeaseTable::with_store(Box::new(JsonLeaseStore::new(&path)));
+    assert_eq!(table.len(), 2);
+    assert_eq!(table.holder(Ipv4Addr::new(10, 0, 0, 5), "bb", now), Some("aa"));
+
+    // a store written with uppercase macs is matched like any other
+    std::fs::write(&path, r#"{"10.0.0.7": {"mac": "AA:BB:CC:DD:EE:FF", "expires": null}}"#).unwrap();
+    let mut table = LeaseTable::with_store(Box::new(JsonLeaseStore::new(&path)));
+    assert_eq!(table.holder(Ipv4Addr::new(10, 0, 0, 7), "aa:bb:cc:dd:ee:ff", now), None);
+    table.release("aa:bb:cc:dd:ee:ff");
+    assert_eq!(table.len(), 0);
+    drop(table);
+
+    std::fs::write(&path, "{ corrupt").unwrap();
+    assert_eq!(LeaseTable::with_store(Box::new(JsonLeaseStore::new(&path))).len(), 0);
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,152 @@
+//! Log targets besides the terminal
+
+use crate::error::{DhcpResult, DhcpError};
+use log::{Log, Metadata, Record, LevelFilter};
+use simplelog::{SharedLogger, Config};
+use syslog::{BasicLogger, Facility, Formatter3164};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Rotated log files kept besides the current one, `<file>.1` is the newest
+pub const LOG_FILES_KEPT: usize = 5;
+
+/// Local syslog daemon (unix socket), daemon facility
+pub struct SyslogLogger {
+    level: LevelFilter,
+    logger: BasicLogger,
+}
+
+impl SyslogLogger {
+    pub fn new(level: LevelFilter) -> DhcpResult<Box<Self>> {
+        let formatter = Formatter3164 {
+            facility: Facility::LOG_DAEMON,
+            hostname: None,
+            process: env!("CARGO_PKG_NAME").to_string(),
+            pid: std::process::id(),
+        };
+        let logger = syslog::unix(formatter).map_err(|e| DhcpError::SyslogError(e.to_string()))?;
+
+        Ok(Box::new(Self { level, logger: BasicLogger::new(logger) }))
+    }
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.logger.flush();
+    }
+}
+
+impl SharedLogger for SyslogLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+/// Log file renamed to `<file>.1` once it exceeds the maximum size, older files are shifted up to `<file>.5`
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    size: u64,
+    /// rotated only between lines
+    line_start: bool,
+    file: File,
+}
+
+impl RotatingFile {
+    /// Creates missing directories, a max size of 0 never rotates
+    pub fn open<P: Into<PathBuf>>(path: P, max_size: u64) -> DhcpResult<Self> {
+        let path = path.into();
+        let with_path = |e: std::io::Error| DhcpError::IoError(std::io::Error::new(e.kind(), format!("log file {}: {}", path.display(), e)));
+
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(with_path)?;
+        }
+        let file = Self::append(&path).map_err(with_path)?;
+        let size = file.metadata().map_err(with_path)?.len();
+
+        Ok(Self { path, max_size, size, line_start: true, file })
+    }
+
+    fn append(path: &Path) -> std::io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for n in (1..LOG_FILES_KEPT).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated(n + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated(1))?;
+
+        self.file = Self::append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.max_size > 0 && self.line_start && self.size >= self.max_size {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        if written > 0 {
+            self.line_start = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[test]
+fn test_rotating_file() {
+    let dir = std::env::temp_dir().join("dhcpserver-test-log");
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("nested").join("dhcpserver.log");
+
+    let mut file = RotatingFile::open(&path, 10).unwrap(); // missing directories are created
+    let lines = 2 * (LOG_FILES_KEPT + 2); // two lines per file, the first two are dropped
+    for line in 0..lines {
+        write!(file, "line ").unwrap();
+        writeln!(file, "{}", line).unwrap(); // lines are not split over files
+    }
+
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("line {}\nline {}\n", lines - 2, lines - 1));
+    assert_eq!(std::fs::read_to_string(file.rotated(1)).unwrap(), format!("line {}\nline {}\n", lines - 4, lines - 3));
+    assert_eq!(std::fs::read_to_string(file.rotated(LOG_FILES_KEPT)).unwrap(), "line 2\nline 3\n");
+    assert!(!file.rotated(LOG_FILES_KEPT + 1).exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,11 +1,20 @@
+mod auth;
 mod config;
 mod error;
+mod lease;
+mod logging;
+mod management;
+mod packet;
+mod rate_limit;
+mod schedule;
 mod server;
 mod sources;
+mod stats;
 
 use crate::server::Server;
 use crate::config::{DhcpConfig, DhcpConfigOptions};
-use simplelog::{CombinedLogger, TermLogger, Config, TerminalMode, ColorChoice};
+use crate::logging::{SyslogLogger, RotatingFile};
+use simplelog::{CombinedLogger, SharedLogger, TermLogger, WriteLogger, Config, TerminalMode, ColorChoice};
 use structopt::StructOpt;
 use crate::error::{DhcpResult, DhcpError};
 
@@ -13,19 +22,54 @@ use crate::error::{DhcpResult, DhcpError};
 async fn main() -> DhcpResult<()>{
     let options:DhcpConfigOptions = DhcpConfigOptions::from_args();
 
-    CombinedLogger::init(
-        vec![
-            TermLogger::new(options.verbosity(),
-                            Config::default(),
-                            TerminalMode::Mixed,
-                            ColorChoice::Auto),
-        ]
-    )?;
+    let logger: Box<dyn SharedLogger> = if options.syslog() {
+        SyslogLogger::new(options.verbosity())?
+    } else {
+        TermLogger::new(options.verbosity(),
+                        Config::default(),
+                        TerminalMode::Mixed,
+                        ColorChoice::Auto)
+    };
+    let mut loggers = vec![logger];
+    if let Some(path) = options.log_file() {
+        loggers.push(WriteLogger::new(options.verbosity(), Config::default(), RotatingFile::open(path, options.log_max_size())?));
+    }
+    CombinedLogger::init(loggers)?;
 
-    let config_path = options.config().ok_or(DhcpError::ConfigFileNotFound)?;
+    if let Some(mapping) = options.validate_template() {
+        match sources::mapping::validate_template(mapping, options.context()) {
+            Ok(result) => println!("{}", serde_json::to_string_pretty(&result)?),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
 
-    log::info!("using config file {}", config_path);
+    let config = match options.config_dir() {
+        Some(dir) => {
+            log::info!("using config directory {}", dir);
+            DhcpConfig::from_dir(dir)?
+        }
+        None => {
+            let config_path = options.config().ok_or(DhcpError::ConfigFileNotFound)?;
+            log::info!("using config file {}", config_path);
+            DhcpConfig::load(&config_path).await?
+        }
+    }.with_unprivileged(options.unprivileged());
+    if options.dump_config() {
+        print!("{}", config.dump(!options.no_redact())?);
+        return Ok(());
+    }
+    if options.check() {
+        if let Err(e) = config.check() {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        println!("config ok");
+        return Ok(());
+    }
 
-    let config = DhcpConfig::from_file(config_path)?;
     Server::listen(config).await
 }
\ No newline at end of file
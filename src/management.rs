@@ -0,0 +1,268 @@
+use serde::{Serialize, Deserialize};
+use crate::config::DhcpConfig;
+use crate::error::DhcpResult;
+use crate::lease::LeaseTable;
+use crate::server::{Server, Operation, Ingress};
+use crate::sources::{SharedDhcpHostSources, SourceEntries};
+use crate::stats::{DhcpStats, ServerStats, LiveStats, LiveCounters, SourceHits};
+use dhcplib::DhcpPacket;
+use dhcplib::option::{DhcpOption, DhcpOptions};
+use hyper::{Body, Request, Response, StatusCode, Method};
+use hyper::header::AUTHORIZATION;
+use hyper::service::{make_service_fn, service_fn};
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::sync::Mutex;
+
+#[derive(Serialize, Deserialize)]
+pub struct DhcpManagementConfig {
+    listen: SocketAddr,
+    token: String,
+}
+
+/// Server counters with the entries held in memory
+#[derive(Serialize)]
+struct DhcpServerStats<'a> {
+    #[serde(flatten)]
+    server: &'a ServerStats,
+    entries: DhcpEntries,
+    live: DhcpLiveStats,
+}
+
+#[derive(Serialize)]
+struct DhcpLiveStats {
+    #[serde(flatten)]
+    counters: LiveCounters,
+    /// leases held by the sources
+    active_leases: usize,
+    sources: Vec<SourceHits>,
+}
+
+#[derive(Serialize)]
+struct DhcpEntries {
+    stats_entries: usize,
+    #[serde(flatten)]
+    sources: SourceEntries,
+}
+
+/// Lease held by the server, expiry in unix seconds and none for infinite leases
+#[derive(Serialize, Debug, PartialEq)]
+struct DhcpLease<'a> {
+    ip: Ipv4Addr,
+    mac: &'a str,
+    expires: Option<u64>,
+    source: &'a str,
+}
+
+/// Synthetic client for resolving a mapping without sending dhcp packets
+#[derive(Deserialize)]
+struct DhcpPreview {
+    client_hardware_address: macaddr::MacAddr6,
+    client_ip_address: Option<Ipv4Addr>,
+    gateway_ip_address: Option<Ipv4Addr>,
+    interface: Option<String>,
+    #[serde(default)]
+    options: Vec<DhcpOption>,
+}
+
+impl From<DhcpPreview> for DhcpPacket {
+    fn from(p: DhcpPreview) -> Self {
+        DhcpPacket::new(
+            dhcplib::MessageOperation::BootRequest,
+            dhcplib::HardwareAddressType::Ethernet,
+            0,
+            0,
+            0,
+            dhcplib::Flags::Broadcast,
+            p.client_ip_address.unwrap_or(Ipv4Addr::UNSPECIFIED),
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::UNSPECIFIED,
+            p.gateway_ip_address.unwrap_or(Ipv4Addr::UNSPECIFIED),
+            p.client_hardware_address,
+            ascii::AsciiString::new(),
+            ascii::AsciiString::new(),
+            DhcpOptions::new_with_options(p.options),
+        )
+    }
+}
+
+pub struct Management {}
+
+impl Management {
+    pub async fn listen(config: Arc<DhcpConfig>,
+                        sources: SharedDhcpHostSources,
+                        stats: Arc<Mutex<DhcpStats>>,
+                        live: Arc<LiveStats>,
+                        leases: Arc<Mutex<LeaseTable>>,
+    ) -> DhcpResult<()> {
+        let listen = match config.management() {
+            Some(m) => m.listen,
+            None => return Ok(()),
+        };
+
+        let service = make_service_fn(move |_| {
+            let config = config.clone();
+            let sources = sources.clone();
+            let stats = stats.clone();
+            let live = live.clone();
+            let leases = leases.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |r| Self::handle(r, config.clone(), sources.clone(), stats.clone(), live.clone(), leases.clone())))
+            }
+        });
+
+        log::info!("management api listening on {}", listen);
+        hyper::Server::try_bind(&listen)?.serve(service).await.map_err(Into::into)
+    }
+
+    fn authorized(request: &Request<Body>, token: &str) -> bool {
+        request.headers().get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map_or(false, |t| Self::constant_time_eq(t.as_bytes(), token.as_bytes()))
+    }
+
+    /// Compares every byte regardless of where the first mismatch is, the time taken tells nothing about the token
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+    }
+
+    fn response(status: StatusCode, body: String) -> Response<Body> {
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = status;
+        response
+    }
+
+    async fn handle(request: Request<Body>,
+                    config: Arc<DhcpConfig>,
+                    sources: SharedDhcpHostSources,
+                    stats: Arc<Mutex<DhcpStats>>,
+                    live: Arc<LiveStats>,
+                    leases: Arc<Mutex<LeaseTable>>,
+    ) -> Result<Response<Body>, Infallible> {
+        // liveness probes carry no token
+        if request.method() == Method::GET && request.uri().path() == "/health" {
+            return Ok(Self::response(StatusCode::OK, "ok".to_string()));
+        }
+
+        let token = config.management().map(|m| m.token.as_str()).unwrap_or_default();
+        if !Self::authorized(&request, token) {
+            return Ok(Self::response(StatusCode::UNAUTHORIZED, "unauthorized".to_string()));
+        }
+
+        let result = match (request.method(), request.uri().path()) {
+            (&Method::POST, "/preview") => Self::preview(request, &config, sources).await,
+            (&Method::GET, "/stats") => Self::server_stats(sources, stats, &live).await,
+            (&Method::GET, "/leases") => Self::leases(leases).await,
+            (&Method::POST, path) if path.starts_with("/force-release/") => Self::force_release(&path["/force-release/".len()..], stats).await,
+            (&Method::GET, path) if path.starts_with("/stats/") => Self::stats(&path["/stats/".len()..], stats).await,
+            _ => return Ok(Self::response(StatusCode::NOT_FOUND, "not found".to_string())),
+        };
+
+        Ok(result.unwrap_or_else(|e| {
+            log::debug!("management request failed: {}", e);
+            Self::response(StatusCode::BAD_REQUEST, e.to_string())
+        }))
+    }
+
+    /// Resolves the offer for a synthetic client as a dry run, no scripts run and nothing is allocated or cached for it
+    async fn preview(request: Request<Body>,
+                     config: &DhcpConfig,
+                     sources: SharedDhcpHostSources,
+    ) -> DhcpResult<Response<Body>> {
+        let body = hyper::body::to_bytes(request.into_body()).await?;
+        let preview: DhcpPreview = serde_json::from_slice(&body)?;
+        let ingress = Ingress {
+            interface: preview.interface.clone(),
+            gateway: preview.gateway_ip_address.unwrap_or(Ipv4Addr::UNSPECIFIED),
+        };
+        let p: DhcpPacket = preview.into();
+
+        let result = Server::lookup(config, &sources, Operation::Preview, &p, &ingress).await?
+            .map(|(result, _)| result);
+
+        Ok(Self::response(StatusCode::OK, serde_json::to_string(&result)?))
+    }
+
+    /// Naks the next request of a client by mac or ip address, the client has to start over with a discover
+    async fn force_release(target: &str, stats: Arc<Mutex<DhcpStats>>) -> DhcpResult<Response<Body>> {
+        match stats.lock().await.force_release(target) {
+            Some(mac) => Ok(Self::response(StatusCode::ACCEPTED, mac)),
+            None => Ok(Self::response(StatusCode::NOT_FOUND, "no client with this address".to_string())),
+        }
+    }
+
+    /// Server counters, entries of all sources are summed up
+    async fn server_stats(sources: SharedDhcpHostSources, stats: Arc<Mutex<DhcpStats>>, live: &LiveStats) -> DhcpResult<Response<Body>> {
+        let mut entries = SourceEntries::default();
+        for source in sources.iter() {
+            entries.add(source.lock().await.entries().await);
+        }
+
+        let stats = stats.lock().await;
+        let body = DhcpServerStats {
+            server: stats.server(),
+            entries: DhcpEntries { stats_entries: stats.clients(), sources: entries },
+            live: DhcpLiveStats { counters: live.counters(), active_leases: entries.lease_entries, sources: live.sources() },
+        };
+        Ok(Self::response(StatusCode::OK, serde_json::to_string(&body)?))
+    }
+
+    /// Leases held by the server ordered by address
+    async fn leases(leases: Arc<Mutex<LeaseTable>>) -> DhcpResult<Response<Body>> {
+        let leases = leases.lock().await;
+        Ok(Self::response(StatusCode::OK, serde_json::to_string(&Self::lease_list(&leases))?))
+    }
+
+    fn lease_list(leases: &LeaseTable) -> Vec<DhcpLease> {
+        let mut list = leases.iter().map(|(ip, lease)| DhcpLease {
+            ip: *ip,
+            mac: lease.mac(),
+            expires: lease.expires().map(|e| e.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()),
+            source: lease.source(),
+        }).collect::<Vec<DhcpLease>>();
+        list.sort_by_key(|l| l.ip);
+        list
+    }
+
+    /// Stats of a single client by mac address
+    async fn stats(mac: &str, stats: Arc<Mutex<DhcpStats>>) -> DhcpResult<Response<Body>> {
+        match stats.lock().await.get(mac) {
+            Some(s) => Ok(Self::response(StatusCode::OK, serde_json::to_string(s)?)),
+            None => Ok(Self::response(StatusCode::NOT_FOUND, "client not seen".to_string())),
+        }
+    }
+}
+
+#[test]
+fn test_authorized() {
+    let request = |token: &str| Request::builder()
+        .header(AUTHORIZATION, token)
+        .body(Body::empty())
+        .unwrap();
+
+    assert!(Management::authorized(&request("Bearer secret"), "secret"));
+    assert!(!Management::authorized(&request("Bearer wrong"), "secret"));
+    assert!(!Management::authorized(&request("secret"), "secret"));
+    assert!(!Management::authorized(&Request::new(Body::empty()), "secret"));
+    assert!(!Management::authorized(&request("Bearer secre"), "secret"));
+    assert!(!Management::authorized(&request("Bearer secrets"), "secret"));
+}
+
+#[test]
+fn test_lease_list() {
+    use std::time::Duration;
+
+    let now = UNIX_EPOCH + Duration::from_secs(1000);
+    let mut leases = LeaseTable::default();
+    leases.commit(Ipv4Addr::new(10, 0, 0, 9), "aa", "pool#0", 60, now);
+    leases.commit(Ipv4Addr::new(10, 0, 0, 5), "bb", "static#1", crate::sources::mapping::INFINITE_LEASE_TIME, now);
+
+    assert_eq!(Management::lease_list(&leases), vec![
+        DhcpLease { ip: Ipv4Addr::new(10, 0, 0, 5), mac: "bb", expires: None, source: "static#1" },
+        DhcpLease { ip: Ipv4Addr::new(10, 0, 0, 9), mac: "aa", expires: Some(1060), source: "pool#0" },
+    ]);
+}
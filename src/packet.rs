@@ -0,0 +1,381 @@
+//! Helpers working on serialized bootp/dhcp packets
+
+use std::net::Ipv4Addr;
+
+pub const OP_OFFSET: usize = 0;
+pub const HOPS_OFFSET: usize = 3;
+pub const XID_OFFSET: usize = 4;
+pub const SECS_OFFSET: usize = 8;
+pub const FLAGS_OFFSET: usize = 10;
+pub const CLIENT_OFFSET: usize = 12;
+pub const YOUR_OFFSET: usize = 16;
+pub const GATEWAY_OFFSET: usize = 24;
+pub const CLIENT_HARDWARE_OFFSET: usize = 28;
+pub const SERVER_NAME_OFFSET: usize = 44;
+pub const SERVER_NAME_LENGTH: usize = 64;
+pub const MAGIC_COOKIE_OFFSET: usize = 236;
+pub const OPTIONS_OFFSET: usize = 240; // fixed bootp header + magic cookie
+
+pub const OP_BOOT_REQUEST: u8 = 1;
+pub const OP_BOOT_REPLY: u8 = 2;
+pub const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+pub const BROADCAST_FLAG: u16 = 0x8000;
+
+pub const PAD: u8 = 0;
+pub const END: u8 = 255;
+pub const IP_ADDRESS_LEASE_TIME: u8 = 51;
+pub const MESSAGE_TYPE: u8 = 53;
+pub const SERVER_IDENTIFIER: u8 = 54;
+pub const RAPID_COMMIT: u8 = 80;
+pub const RELAY_AGENT_INFORMATION: u8 = 82;
+pub const SUBNET_SELECTION: u8 = 118;
+
+pub const RELAY_CIRCUIT_ID: u8 = 1;
+pub const RELAY_REMOTE_ID: u8 = 2;
+
+pub const DHCP_DISCOVER: u8 = 1;
+pub const DHCP_REQUEST: u8 = 3;
+pub const DHCP_ACK: u8 = 5;
+pub const DHCP_LEASE_QUERY: u8 = 10;
+pub const DHCP_LEASE_UNASSIGNED: u8 = 11;
+pub const DHCP_LEASE_UNKNOWN: u8 = 12;
+pub const DHCP_LEASE_ACTIVE: u8 = 13;
+
+/// Position and length of the option data
+pub fn find_option(bytes: &[u8], tag: u8) -> Option<(usize, usize)> {
+    let mut i = OPTIONS_OFFSET;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            PAD => i += 1,
+            END => return None,
+            t => {
+                let length = *bytes.get(i + 1)? as usize;
+                if t == tag {
+                    return Some((i + 2, length)).filter(|(start, length)| start + length <= bytes.len());
+                }
+                i += 2 + length;
+            }
+        }
+    }
+
+    None
+}
+
+/// Position of the end option
+pub fn find_end(bytes: &[u8]) -> Option<usize> {
+    let mut i = OPTIONS_OFFSET;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            PAD => i += 1,
+            END => return Some(i),
+            _ => i += 2 + *bytes.get(i + 1)? as usize,
+        }
+    }
+
+    None
+}
+
+/// Inserts the option before the end option, false if there is none or the data is too long
+pub fn insert_option(bytes: &mut Vec<u8>, tag: u8, data: &[u8]) -> bool {
+    let end = match find_end(bytes) {
+        Some(end) if data.len() <= u8::MAX as usize => end,
+        _ => return false,
+    };
+
+    let mut option = vec![tag, data.len() as u8];
+    option.extend_from_slice(data);
+    bytes.splice(end..end, option);
+    true
+}
+
+/// Removes the first occurrence of the option, false if there is none
+pub fn remove_option(bytes: &mut Vec<u8>, tag: u8) -> bool {
+    match find_option(bytes, tag) {
+        Some((start, length)) => {
+            bytes.drain(start - 2..start + length);
+            true
+        }
+        None => false,
+    }
+}
+
+fn ipv4_at(bytes: &[u8], offset: usize) -> Option<Ipv4Addr> {
+    let octets = bytes.get(offset..offset + 4)?;
+    Some(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+}
+
+/// Transaction id, shared by all packets of a client's exchange
+pub fn xid(bytes: &[u8]) -> Option<u32> {
+    let octets = bytes.get(XID_OFFSET..XID_OFFSET + 4)?;
+    Some(u32::from_be_bytes([octets[0], octets[1], octets[2], octets[3]]))
+}
+
+/// Client address (ciaddr)
+pub fn client(bytes: &[u8]) -> Option<Ipv4Addr> {
+    ipv4_at(bytes, CLIENT_OFFSET)
+}
+
+/// Zeroes the client address (ciaddr)
+pub fn clear_client(bytes: &mut [u8]) {
+    if let Some(ciaddr) = bytes.get_mut(CLIENT_OFFSET..CLIENT_OFFSET + 4) {
+        ciaddr.iter_mut().for_each(|b| *b = 0);
+    }
+}
+
+/// Address assigned to the client (yiaddr)
+pub fn your(bytes: &[u8]) -> Option<Ipv4Addr> {
+    ipv4_at(bytes, YOUR_OFFSET)
+}
+
+/// Bootp broadcast flag, the client can not receive unicast before it is configured
+pub fn broadcast_flag(bytes: &[u8]) -> bool {
+    bytes.get(FLAGS_OFFSET).map_or(false, |flags| flags & 0x80 != 0)
+}
+
+/// Seconds since the client began its exchange
+pub fn secs(bytes: &[u8]) -> Option<u16> {
+    bytes.get(SECS_OFFSET..SECS_OFFSET + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+pub fn set_secs(bytes: &mut [u8], secs: u16) {
+    if let Some(field) = bytes.get_mut(SECS_OFFSET..SECS_OFFSET + 2) {
+        field.copy_from_slice(&secs.to_be_bytes());
+    }
+}
+
+/// Bootp flags, only the broadcast flag is defined
+pub fn flags(bytes: &[u8]) -> Option<u16> {
+    bytes.get(FLAGS_OFFSET..FLAGS_OFFSET + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+pub fn set_flags(bytes: &mut [u8], flags: u16) {
+    if let Some(field) = bytes.get_mut(FLAGS_OFFSET..FLAGS_OFFSET + 2) {
+        field.copy_from_slice(&flags.to_be_bytes());
+    }
+}
+
+/// Relay agent address (giaddr)
+pub fn gateway(bytes: &[u8]) -> Option<Ipv4Addr> {
+    ipv4_at(bytes, GATEWAY_OFFSET)
+}
+
+/// Replaces the relay agent address (giaddr)
+pub fn set_gateway(bytes: &mut [u8], ip: Ipv4Addr) {
+    if let Some(giaddr) = bytes.get_mut(GATEWAY_OFFSET..GATEWAY_OFFSET + 4) {
+        giaddr.copy_from_slice(&ip.octets());
+    }
+}
+
+/// Bootp op code and dhcp magic cookie present, anything else (e.g. DHCPv6 through a shared relay) is no DHCPv4
+pub fn is_dhcpv4(bytes: &[u8]) -> bool {
+    matches!(bytes.get(OP_OFFSET), Some(&OP_BOOT_REQUEST) | Some(&OP_BOOT_REPLY))
+        && bytes.get(MAGIC_COOKIE_OFFSET..OPTIONS_OFFSET) == Some(&MAGIC_COOKIE[..])
+}
+
+/// Ethernet client hardware address (chaddr)
+pub fn client_hardware(bytes: &[u8]) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(bytes.get(CLIENT_HARDWARE_OFFSET..CLIENT_HARDWARE_OFFSET + 6)?);
+    Some(mac)
+}
+
+pub fn server_identifier(bytes: &[u8]) -> Option<Ipv4Addr> {
+    find_option(bytes, SERVER_IDENTIFIER)
+        .filter(|(_, length)| *length == 4)
+        .and_then(|(start, _)| ipv4_at(bytes, start))
+}
+
+/// Subnet selection (option 118, RFC 3011), the subnet to assign from chosen by client or relay
+pub fn subnet_selection(bytes: &[u8]) -> Option<Ipv4Addr> {
+    find_option(bytes, SUBNET_SELECTION)
+        .filter(|(_, length)| *length == 4)
+        .and_then(|(start, _)| ipv4_at(bytes, start))
+}
+
+/// Relay agent information (option 82, RFC 3046), echoed unchanged in replies
+pub fn relay_agent_information(bytes: &[u8]) -> Option<Vec<u8>> {
+    find_option(bytes, RELAY_AGENT_INFORMATION).map(|(start, length)| bytes[start..start + length].to_vec())
+}
+
+/// Data of a relay agent information sub-option, none if it is missing or truncated
+pub fn relay_sub_option(information: &[u8], code: u8) -> Option<&[u8]> {
+    let mut i = 0;
+
+    while i + 1 < information.len() {
+        let length = information[i + 1] as usize;
+        let data = information.get(i + 2..i + 2 + length)?;
+        if information[i] == code {
+            return Some(data);
+        }
+        i += 2 + length;
+    }
+
+    None
+}
+
+pub fn message_type(bytes: &[u8]) -> Option<u8> {
+    find_option(bytes, MESSAGE_TYPE)
+        .filter(|(_, length)| *length == 1)
+        .map(|(start, _)| bytes[start])
+}
+
+/// Replaces the server host name (sname), cut to leave room for the terminating nul
+pub fn set_server_name(bytes: &mut [u8], name: &[u8]) {
+    if let Some(field) = bytes.get_mut(SERVER_NAME_OFFSET..SERVER_NAME_OFFSET + SERVER_NAME_LENGTH) {
+        let length = name.len().min(SERVER_NAME_LENGTH - 1);
+        field.iter_mut().for_each(|b| *b = 0);
+        field[..length].copy_from_slice(&name[..length]);
+    }
+}
+
+/// Turns an offer into the ack of a rapid commit (RFC 4039), carrying an empty rapid commit option
+pub fn rapid_commit(bytes: &mut Vec<u8>) -> bool {
+    let start = match find_option(bytes, MESSAGE_TYPE) {
+        Some((start, 1)) => start,
+        _ => return false,
+    };
+
+    bytes[start] = DHCP_ACK;
+    find_option(bytes, RAPID_COMMIT).is_some() || insert_option(bytes, RAPID_COMMIT, &[])
+}
+
+/// Offset prefixed lines of 16 hex bytes
+pub fn hex_dump(bytes: &[u8]) -> String {
+    bytes.chunks(16).enumerate()
+        .map(|(i, line)| format!("{:04x}: {}", i * 16, line.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(" ")))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// RFC 4388 reply of the message type (unassigned, unknown or active) to a lease query, the query fields are kept
+pub fn lease_query_reply(bytes: &[u8], reply_type: u8) -> Option<Vec<u8>> {
+    if message_type(bytes)? != DHCP_LEASE_QUERY {
+        return None;
+    }
+
+    let (start, _) = find_option(bytes, MESSAGE_TYPE)?;
+    let mut reply = bytes.to_vec();
+    reply[OP_OFFSET] = OP_BOOT_REPLY;
+    reply[start] = reply_type;
+    Some(reply)
+}
+
+/// Lease query answered with the leased address (ciaddr), its client (chaddr) and the seconds left (option 51)
+pub fn lease_active(bytes: &[u8], ip: Ipv4Addr, mac: [u8; 6], remaining: u32) -> Option<Vec<u8>> {
+    let mut reply = lease_query_reply(bytes, DHCP_LEASE_ACTIVE)?;
+    reply.get_mut(CLIENT_OFFSET..CLIENT_OFFSET + 4)?.copy_from_slice(&ip.octets());
+    reply.get_mut(CLIENT_HARDWARE_OFFSET..CLIENT_HARDWARE_OFFSET + 6)?.copy_from_slice(&mac);
+    if !insert_option(&mut reply, IP_ADDRESS_LEASE_TIME, &remaining.to_be_bytes()) {
+        return None;
+    }
+    Some(reply)
+}
+
+#[test]
+fn test_lease_query_reply() {
+    let mut bytes = vec![0u8; OPTIONS_OFFSET];
+    bytes[OP_OFFSET] = 1;
+    bytes.extend_from_slice(&[MESSAGE_TYPE, 1, DHCP_LEASE_QUERY, END]);
+
+    let reply = lease_query_reply(&bytes, DHCP_LEASE_UNKNOWN).unwrap();
+    assert_eq!(reply[OP_OFFSET], OP_BOOT_REPLY);
+    assert_eq!(message_type(&reply), Some(DHCP_LEASE_UNKNOWN));
+
+    let reply = lease_active(&bytes, Ipv4Addr::new(10, 0, 0, 5), [1, 2, 3, 4, 5, 6], 600).unwrap();
+    assert_eq!(message_type(&reply), Some(DHCP_LEASE_ACTIVE));
+    assert_eq!(client(&reply), Some(Ipv4Addr::new(10, 0, 0, 5)));
+    assert_eq!(client_hardware(&reply), Some([1, 2, 3, 4, 5, 6]));
+    let (start, length) = find_option(&reply, IP_ADDRESS_LEASE_TIME).unwrap();
+    assert_eq!(&reply[start..start + length], &600u32.to_be_bytes());
+
+    bytes[OPTIONS_OFFSET + 2] = 1; // discover
+    assert_eq!(lease_query_reply(&bytes, DHCP_LEASE_UNKNOWN), None);
+}
+
+#[test]
+fn test_is_dhcpv4() {
+    let mut bytes = vec![0u8; OPTIONS_OFFSET];
+    bytes[OP_OFFSET] = OP_BOOT_REQUEST;
+    bytes[MAGIC_COOKIE_OFFSET..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+    bytes.extend_from_slice(&[MESSAGE_TYPE, 1, 1, END]);
+    assert!(is_dhcpv4(&bytes));
+
+    // DHCPv6 solicit: msg-type, transaction id, client identifier and elapsed time options
+    let solicit = [1, 0x12, 0x34, 0x56, 0, 1, 0, 10, 0, 3, 0, 1, 1, 2, 3, 4, 5, 6, 0, 8, 0, 2, 0, 0];
+    assert!(!is_dhcpv4(&solicit));
+
+    bytes[MAGIC_COOKIE_OFFSET] = 0; // plain bootp
+    assert!(!is_dhcpv4(&bytes));
+    assert!(!is_dhcpv4(&[]));
+}
+
+#[test]
+fn test_hex_dump() {
+    let bytes = (0u8..18).collect::<Vec<u8>>();
+    assert_eq!(hex_dump(&bytes), "0000: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n0010: 10 11");
+    assert_eq!(hex_dump(&[]), "");
+}
+
+#[test]
+fn test_set_server_name() {
+    let mut bytes = vec![0xffu8; OPTIONS_OFFSET];
+
+    set_server_name(&mut bytes, b"tftp.example");
+    assert_eq!(&bytes[SERVER_NAME_OFFSET..SERVER_NAME_OFFSET + 13], b"tftp.example\0");
+    assert!(bytes[SERVER_NAME_OFFSET..SERVER_NAME_OFFSET + SERVER_NAME_LENGTH].iter().skip(12).all(|b| *b == 0));
+    assert_eq!(bytes[SERVER_NAME_OFFSET - 1], 0xff);
+    assert_eq!(bytes[SERVER_NAME_OFFSET + SERVER_NAME_LENGTH], 0xff);
+
+    set_server_name(&mut bytes, &[b'a'; 80]); // nul terminated
+    assert_eq!(&bytes[SERVER_NAME_OFFSET..SERVER_NAME_OFFSET + 63], &[b'a'; 63][..]);
+    assert_eq!(bytes[SERVER_NAME_OFFSET + 63], 0);
+
+    set_server_name(&mut [0u8; 10], b"short packet"); // ignored
+}
+
+#[test]
+fn test_insert_remove_option() {
+    let mut bytes = vec![0u8; OPTIONS_OFFSET];
+    bytes.extend_from_slice(&[MESSAGE_TYPE, 1, DHCP_DISCOVER, END, PAD, PAD]);
+
+    assert!(insert_option(&mut bytes, RELAY_AGENT_INFORMATION, &[1, 2, b'a', b'b']));
+    assert_eq!(&bytes[OPTIONS_OFFSET..], &[MESSAGE_TYPE, 1, DHCP_DISCOVER, RELAY_AGENT_INFORMATION, 4, 1, 2, b'a', b'b', END, PAD, PAD]);
+    assert_eq!(find_option(&bytes, RELAY_AGENT_INFORMATION), Some((OPTIONS_OFFSET + 5, 4)));
+    assert!(!insert_option(&mut bytes, RELAY_AGENT_INFORMATION, &[0u8; 256]));
+
+    assert!(remove_option(&mut bytes, RELAY_AGENT_INFORMATION));
+    assert_eq!(&bytes[OPTIONS_OFFSET..], &[MESSAGE_TYPE, 1, DHCP_DISCOVER, END, PAD, PAD]);
+    assert!(!remove_option(&mut bytes, RELAY_AGENT_INFORMATION));
+
+    let mut truncated = vec![0u8; OPTIONS_OFFSET];
+    assert!(!insert_option(&mut truncated, RELAY_AGENT_INFORMATION, &[]));
+}
+
+#[test]
+fn test_rapid_commit() {
+    let mut bytes = vec![0u8; OPTIONS_OFFSET];
+    bytes.extend_from_slice(&[MESSAGE_TYPE, 1, 2, END]);
+
+    assert!(rapid_commit(&mut bytes));
+    assert_eq!(&bytes[OPTIONS_OFFSET..], &[MESSAGE_TYPE, 1, DHCP_ACK, RAPID_COMMIT, 0, END]);
+    assert!(rapid_commit(&mut bytes)); // not added twice
+    assert_eq!(bytes.len(), OPTIONS_OFFSET + 6);
+
+    assert!(!rapid_commit(&mut vec![0u8; OPTIONS_OFFSET]));
+}
+
+#[test]
+fn test_relay_agent_information() {
+    let mut bytes = vec![0u8; OPTIONS_OFFSET];
+    bytes.extend_from_slice(&[MESSAGE_TYPE, 1, DHCP_DISCOVER, END]);
+    assert_eq!(relay_agent_information(&bytes), None);
+
+    assert!(insert_option(&mut bytes, RELAY_AGENT_INFORMATION, &[RELAY_CIRCUIT_ID, 4, b'e', b't', b'h', b'0', RELAY_REMOTE_ID, 2, 0xab, 0xcd]));
+    let information = relay_agent_information(&bytes).unwrap();
+    assert_eq!(relay_sub_option(&information, RELAY_CIRCUIT_ID), Some(&b"eth0"[..]));
+    assert_eq!(relay_sub_option(&information, RELAY_REMOTE_ID), Some(&[0xab, 0xcd][..]));
+    assert_eq!(relay_sub_option(&information, 9), None);
+    assert_eq!(relay_sub_option(&[RELAY_CIRCUIT_ID, 8, b'e'], RELAY_CIRCUIT_ID), None); // truncated
+}
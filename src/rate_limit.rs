@@ -0,0 +1,229 @@
+use serde::{Serialize, Deserialize};
+use crate::error::{DhcpResult, DhcpError};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Semaphore, OwnedSemaphorePermit};
+use tokio::time::Instant;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RateLimitConfig {
+    rate: f64, // tokens per second
+    burst: Option<f64>, // defaults to rate
+    #[serde(default)]
+    shed: bool, // drop instead of waiting
+}
+
+impl RateLimitConfig {
+    pub fn validate(&self) -> DhcpResult<()> {
+        if self.rate.is_nan() || self.rate <= 0.0 {
+            return Err(DhcpError::ConfigInvalid(format!("rate_limit rate {} not positive", self.rate)));
+        }
+        Ok(())
+    }
+}
+
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + (now - self.updated).as_secs_f64() * self.rate).min(self.burst);
+        self.updated = now;
+    }
+
+    pub fn try_acquire(&mut self) -> bool {
+        self.refill();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Refilled to the burst, as good as a new bucket
+    fn full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.burst
+    }
+
+    /// Takes a token in advance, returns the time to wait until it is available
+    pub fn reserve(&mut self) -> Duration {
+        self.refill();
+        self.tokens -= 1.0;
+
+        if self.tokens >= 0.0 {
+            Duration::default()
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<TokenBucket>>,
+    shed: bool,
+}
+
+impl From<&RateLimitConfig> for RateLimiter {
+    fn from(c: &RateLimitConfig) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(TokenBucket::new(c.rate, c.burst.unwrap_or(c.rate).max(1.0)))),
+            shed: c.shed,
+        }
+    }
+}
+
+impl RateLimiter {
+    pub async fn acquire(&self) -> DhcpResult<()> {
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+
+            if self.shed {
+                return if bucket.try_acquire() { Ok(()) } else { Err(DhcpError::RateLimited) };
+            }
+            bucket.reserve()
+        };
+
+        if !wait.is_zero() {
+            log::debug!("rate limited, waiting {:?}", wait);
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+}
+
+/// Packets per second and burst of each client
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ClientRateLimitConfig {
+    rate: f64,
+    burst: Option<f64>, // defaults to rate
+}
+
+impl ClientRateLimitConfig {
+    pub fn validate(&self) -> DhcpResult<()> {
+        if self.rate.is_nan() || self.rate <= 0.0 {
+            return Err(DhcpError::ConfigInvalid(format!("rate_limit rate {} not positive", self.rate)));
+        }
+        Ok(())
+    }
+}
+
+/// A token bucket per client mac, packets of clients without a token are dropped, unlimited without config
+#[derive(Default)]
+pub struct ClientRateLimiter {
+    config: Option<ClientRateLimitConfig>,
+    buckets: HashMap<String, TokenBucket>,
+}
+
+impl ClientRateLimiter {
+    pub fn new(config: Option<ClientRateLimitConfig>) -> Self {
+        Self { config, buckets: HashMap::new() }
+    }
+
+    pub fn try_acquire(&mut self, mac: &str) -> bool {
+        let config = match self.config {
+            Some(config) => config,
+            None => return true,
+        };
+
+        self.buckets.entry(mac.to_string())
+            .or_insert_with(|| TokenBucket::new(config.rate, config.burst.unwrap_or(config.rate).max(1.0)))
+            .try_acquire()
+    }
+
+    /// Drops the buckets of clients idle long enough to be full again, returns how many
+    pub fn cleanup(&mut self) -> usize {
+        let before = self.buckets.len();
+        self.buckets.retain(|_, bucket| !bucket.full());
+        before - self.buckets.len()
+    }
+}
+
+/// Bounds concurrent work (scripts) over all sources
+#[derive(Clone)]
+pub struct ConcurrencyLimiter {
+    semaphore: Arc<Semaphore>,
+    timeout: Duration,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(permits: usize, timeout: Duration) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(permits)),
+            timeout,
+        }
+    }
+
+    /// `None` if no permit became available within the timeout
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        tokio::time::timeout(self.timeout, self.semaphore.clone().acquire_owned()).await.ok()?.ok()
+    }
+}
+
+#[tokio::test]
+async fn test_rate_limiter() {
+    let limiter = RateLimiter::from(&RateLimitConfig { rate: 20.0, burst: Some(1.0), shed: false });
+    let start = Instant::now();
+    for _ in 0..3 {
+        limiter.acquire().await.unwrap();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(100)); // 2 paced requests at 20/s
+
+    let limiter = RateLimiter::from(&RateLimitConfig { rate: 1.0, burst: Some(1.0), shed: true });
+    assert!(limiter.acquire().await.is_ok());
+    assert!(matches!(limiter.acquire().await, Err(DhcpError::RateLimited)));
+
+    for invalid in &["rate: 0", "rate: -1", "rate: .nan"] {
+        assert!(serde_yaml::from_str::<RateLimitConfig>(invalid).unwrap().validate().is_err(), "{}", invalid);
+    }
+}
+
+#[tokio::test]
+async fn test_concurrency_limiter() {
+    let limiter = ConcurrencyLimiter::new(2, Duration::from_millis(50));
+
+    let first = limiter.acquire().await.unwrap();
+    let _second = limiter.acquire().await.unwrap();
+    assert!(limiter.acquire().await.is_none());
+
+    drop(first);
+    assert!(limiter.acquire().await.is_some());
+}
+
+#[tokio::test]
+async fn test_client_rate_limiter() {
+    let config: ClientRateLimitConfig = serde_yaml::from_str("rate: 20\nburst: 2").unwrap();
+    let mut limiter = ClientRateLimiter::new(Some(config));
+
+    assert!(limiter.try_acquire("aa"));
+    assert!(limiter.try_acquire("aa"));
+    assert!(!limiter.try_acquire("aa"));
+    assert!(limiter.try_acquire("bb")); // buckets per client
+    assert_eq!(limiter.cleanup(), 0);
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(limiter.cleanup(), 2);
+    assert!(limiter.try_acquire("aa"));
+
+    let mut unlimited = ClientRateLimiter::default();
+    assert!((0..100).all(|_| unlimited.try_acquire("aa")));
+    assert!(serde_yaml::from_str::<ClientRateLimitConfig>("rate: 0").unwrap().validate().is_err());
+}
@@ -0,0 +1,138 @@
+//! Time windows gating sources, evaluated against the local server clock
+
+use serde::{Serialize, Deserialize};
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Weekday {
+    Sun,
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+}
+
+impl Weekday {
+    const ALL: [Weekday; 7] = [Weekday::Sun, Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri, Weekday::Sat];
+
+    /// Days since sunday, as `tm_wday`
+    fn from_index(i: usize) -> Self {
+        Self::ALL[i % 7]
+    }
+
+    fn previous(self) -> Self {
+        Self::from_index(self as usize + 6)
+    }
+}
+
+/// Wall clock time, the weekday and minute of the day
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+pub(crate) struct LocalTime {
+    pub(crate) weekday: Weekday,
+    pub(crate) hour: u16,
+    pub(crate) minute: u16,
+}
+
+impl LocalTime {
+    pub(crate) fn new(weekday: Weekday, hour: u16, minute: u16) -> Self {
+        Self { weekday, hour, minute }
+    }
+
+    /// Local time of the server by the system timezone
+    pub(crate) fn now() -> Self {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as libc::time_t)
+            .unwrap_or_default();
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        unsafe { libc::localtime_r(&now, &mut tm) };
+
+        Self::new(Weekday::from_index(tm.tm_wday as usize), tm.tm_hour as u16, tm.tm_min as u16)
+    }
+
+    fn minute_of_day(&self) -> u16 {
+        self.hour * 60 + self.minute
+    }
+}
+
+/// Time of the day as `HH:MM`, stored as minute of the day
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) struct TimeOfDay(u16);
+
+impl Serialize for TimeOfDay {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:02}:{:02}", self.0 / 60, self.0 % 60))
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let invalid = || serde::de::Error::custom(format!("invalid time of day '{}', expected HH:MM", s));
+
+        let (hour, minute) = s.split_once(':').ok_or_else(invalid)?;
+        let hour: u16 = hour.parse().map_err(|_| invalid())?;
+        let minute: u16 = minute.parse().map_err(|_| invalid())?;
+
+        // 24:00 ends a window at midnight
+        match hour.checked_mul(60).and_then(|m| m.checked_add(minute)) {
+            Some(m) if minute < 60 && m <= MINUTES_PER_DAY => Ok(Self(m)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// `from` inclusive to `to` exclusive on the given days (every day if empty), windows ending before they start span midnight
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct TimeWindow {
+    #[serde(default)]
+    days: Vec<Weekday>,
+    from: TimeOfDay,
+    to: TimeOfDay,
+}
+
+impl TimeWindow {
+    fn on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    pub(crate) fn contains(&self, time: &LocalTime) -> bool {
+        let (from, to, minute) = (self.from.0, self.to.0, time.minute_of_day());
+
+        if from <= to {
+            self.on(time.weekday) && from <= minute && minute < to
+        } else { // the part after midnight belongs to the window started the day before
+            (self.on(time.weekday) && minute >= from) || (self.on(time.weekday.previous()) && minute < to)
+        }
+    }
+}
+
+#[test]
+fn test_time_window() {
+    let window: TimeWindow = serde_yaml::from_str("days: [mon, tue, wed, thu, fri]\nfrom: \"08:00\"\nto: \"18:00\"").unwrap();
+
+    assert!(window.contains(&LocalTime::new(Weekday::Mon, 8, 0)));
+    assert!(window.contains(&LocalTime::new(Weekday::Fri, 17, 59)));
+    assert!(!window.contains(&LocalTime::new(Weekday::Fri, 18, 0)));
+    assert!(!window.contains(&LocalTime::new(Weekday::Tue, 7, 59)));
+    assert!(!window.contains(&LocalTime::new(Weekday::Sat, 12, 0)));
+
+    // friday night until saturday morning
+    let window: TimeWindow = serde_yaml::from_str("days: [fri]\nfrom: \"22:00\"\nto: \"06:00\"").unwrap();
+    assert!(window.contains(&LocalTime::new(Weekday::Fri, 23, 0)));
+    assert!(window.contains(&LocalTime::new(Weekday::Sat, 5, 59)));
+    assert!(!window.contains(&LocalTime::new(Weekday::Fri, 5, 0)));
+    assert!(!window.contains(&LocalTime::new(Weekday::Sun, 1, 0)));
+
+    let window: TimeWindow = serde_yaml::from_str("from: \"00:00\"\nto: \"24:00\"").unwrap();
+    assert!(window.contains(&LocalTime::new(Weekday::Sun, 23, 59)));
+
+    assert!(serde_yaml::from_str::<TimeWindow>("from: \"8\"\nto: \"18:00\"").is_err());
+    assert!(serde_yaml::from_str::<TimeWindow>("from: \"08:60\"\nto: \"18:00\"").is_err());
+    assert!(serde_yaml::from_str::<TimeWindow>("from: \"08:00\"\nto: \"24:01\"").is_err());
+    assert!(serde_yaml::from_str::<TimeWindow>("from: \"1093:00\"\nto: \"18:00\"").is_err()); // not wrapped to 00:44
+    assert!(serde_yaml::from_str::<TimeWindow>("from: \"1100:00\"\nto: \"18:00\"").is_err());
+}
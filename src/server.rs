@@ -1,31 +1,415 @@
-use std::net::{UdpSocket, Ipv4Addr, SocketAddr, SocketAddrV4};
-use crate::config::DhcpConfig;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::net::UdpSocket;
+use tokio::io::Interest;
+use crate::config::{DhcpConfig, HostnamePolicy, PxeBoot, ReplyDelivery};
 use crate::error::{DhcpResult, DhcpError};
-use dhcplib::option::{DhcpOption, DhcpOptions, BOOT_FILE_NAME, MESSAGE, IP_ADDRESS_LEASE_TIME, VENDOR_CLASS_IDENTIFIER, SERVER_IDENTIFIER};
+use dhcplib::option::{DhcpOption, DhcpOptions, BOOT_FILE_NAME, MESSAGE, IP_ADDRESS_LEASE_TIME, VENDOR_CLASS_IDENTIFIER, SERVER_IDENTIFIER, PARAMETER_REQUEST_LIST, HOST_NAME, MESSAGE_TYPE, RENEWAL_TIME_VALUE, REBINDING_TIME_VALUE, TFTP_SERVER};
 use dhcplib::messaging::DhcpMessaging;
 use dhcplib::DhcpPacket;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use pnet::ipnetwork::{IpNetwork, Ipv4Network};
-use crate::sources::DhcpHostSource;
+use crate::sources::{DhcpHostSource, DhcpSourceResult, BoxedDhcpHostSource, SharedDhcpHostSources};
 use std::convert::TryFrom;
+use std::future::Future;
+use crate::management::Management;
+use crate::packet;
+use crate::stats::{DhcpStats, LiveStats};
+use crate::lease::{LeaseTable, JsonLeaseStore, LEASE_SWEEP_INTERVAL};
+use crate::rate_limit::ClientRateLimiter;
+use crate::schedule::LocalTime;
+use crate::sources::mapping::{packet_context, captive_portal, CAPTIVE_PORTAL, CLIENT_MACHINE_IDENTIFIER};
+use ascii::AsciiString;
+use std::collections::HashMap;
+use std::os::unix::io::AsRawFd;
+use nix::sys::socket::{recvmsg, setsockopt, sockopt, ControlMessageOwned, MsgFlags, SockAddr};
+use nix::sys::uio::IoVec;
 
 const UDP_PACKET_BUFFER_SIZE: usize = 512;
+const SEND_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+const RELEASE_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const RECV_ERROR_BACKOFF: Duration = Duration::from_millis(10);
+const RECV_ERROR_BACKOFF_MAX: Duration = Duration::from_secs(1);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const RATE_LIMIT_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_SERVER_PORT: u16 = 67;
+const FORCED_RELEASE: &str = "address released by administrator";
+const ADDRESS_CONFLICT: &str = "address in use by another client";
+
+tokio::task_local! {
+    /// Correlation id of the packet being processed, shared with logs and backend requests
+    pub(crate) static CORRELATION_ID: String;
+}
+
+static PACKET_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+tokio::task_local! {
+    /// Transaction id of the packet being processed, shared by all packets of a client's exchange
+    pub(crate) static XID: u32;
+}
+
+/// Correlation id of the current packet, `-` outside of packet processing
+pub(crate) fn correlation_id() -> String {
+    CORRELATION_ID.try_with(Clone::clone).unwrap_or_else(|_| "-".to_string())
+}
+
+/// Log line prefix of the current packet, correlation id and `xid=` field if known
+pub(crate) fn log_prefix() -> String {
+    match XID.try_with(|xid| *xid) {
+        Ok(xid) => format!("{} xid={:#010x}", correlation_id(), xid),
+        Err(_) => correlation_id(),
+    }
+}
+
+// kept in the reply even if the client did not request them
+const MANDATORY_OPTIONS: [u8; 5] = [MESSAGE_TYPE, SERVER_IDENTIFIER, IP_ADDRESS_LEASE_TIME, RENEWAL_TIME_VALUE, REBINDING_TIME_VALUE];
+
+pub(crate) enum Operation {
+    Offer,
+    /// offer of the management preview, sources answer without side effects
+    Preview,
+    Reserve,
+    Inform,
+}
+
+/// Where a packet entered the server
+pub(crate) struct Ingress {
+    pub(crate) interface: Option<String>,
+    /// address selecting the client's subnet, unspecified for direct packets
+    pub(crate) gateway: Ipv4Addr,
+}
+
+impl Ingress {
+    /// Subnet selection (option 118) overrides the relay address (giaddr) and the receiving interface
+    pub(crate) fn new(interface: Option<String>, bytes: &[u8]) -> Self {
+        let gateway = packet::subnet_selection(bytes)
+            .or_else(|| packet::gateway(bytes))
+            .unwrap_or(Ipv4Addr::UNSPECIFIED);
+
+        Self { interface, gateway }
+    }
+}
+
+/// Set on each serialized reply, after the packet is built
+#[derive(Default)]
+struct ReplyFields {
+    server_name: Option<AsciiString>,
+    rapid_commit: bool,
+    flags: Option<u16>,
+    secs: Option<u16>,
+    /// option 82 of the request, relay agents expect it back
+    relay_agent_information: Option<Vec<u8>>,
+}
 
 pub struct Server {}
 
 impl Server {
-    pub async fn listen(mut config: DhcpConfig) -> DhcpResult<()> {
-        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.port()))?;
+    pub async fn listen(config: DhcpConfig) -> DhcpResult<()> {
+        let socket = Self::bind(config.port(), config.unprivileged_port())?;
         socket.set_broadcast(true)?;
+        setsockopt(socket.as_raw_fd(), sockopt::Ipv4PacketInfo, &true).map_err(std::io::Error::from)?;
 
-        log::info!("UDP Socket bound on port {}", config.port());
+        log::info!("UDP Socket bound on port {}", socket.local_addr()?.port());
+        let socket = Arc::new(socket);
 
         let mut buf = vec![0u8; UDP_PACKET_BUFFER_SIZE];
         let sources = config.init_sources()?;
-        let shared_source = Arc::new(Mutex::new(sources));
+        let live = Arc::new(LiveStats::new(sources.iter().map(|s| s.name()).collect()));
+        let shared_source = crate::sources::shared(sources);
+        let stats = Arc::new(Mutex::new(DhcpStats::new(config.stats_capacity())));
+        let leases = Arc::new(Mutex::new(match config.lease_file() {
+            Some(path) => LeaseTable::with_store(Box::new(JsonLeaseStore::new(path))),
+            None => LeaseTable::default(),
+        }));
+        let limiter = Arc::new(Mutex::new(ClientRateLimiter::new(config.rate_limit())));
+        let config = Arc::new(config);
+
+        if config.rate_limit().is_some() {
+            let cleanup_limiter = limiter.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(RATE_LIMIT_CLEANUP_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    let dropped = cleanup_limiter.lock().await.cleanup();
+                    if dropped > 0 {
+                        log::debug!("rate limits of {} idle clients dropped", dropped);
+                    }
+                }
+            });
+        }
+
+        let sweep_leases = leases.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(LEASE_SWEEP_INTERVAL));
+            loop {
+                interval.tick().await;
+                let mut leases = sweep_leases.lock().await;
+                let expired = leases.sweep(SystemTime::now());
+                if expired > 0 {
+                    log::debug!("{} expired leases dropped, {} leases held", expired, leases.len());
+                }
+            }
+        });
+
+        // held shared by every packet in flight, taken exclusively on shutdown
+        let in_flight = Arc::new(RwLock::new(()));
+        let shutdown = Self::shutdown_signal();
+        tokio::pin!(shutdown);
+
+        if config.management().is_some() {
+            let management_config = config.clone();
+            let management_sources = shared_source.clone();
+            let management_stats = stats.clone();
+            let management_live = live.clone();
+            let management_leases = leases.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Management::listen(management_config, management_sources, management_stats, management_live, management_leases).await {
+                    log::error!("management api stopped: {}", e);
+                }
+            });
+        }
+
+        let local_networks = Self::local_networks(&config)?;
+
+        let interface_names = Self::interface_names();
+        let local_hardware = Self::local_hardware();
+
+        log::debug!("Outbound ip addresses: {:?}", local_networks.iter().map(|i| i.ip()).collect::<Vec<Ipv4Addr>>());
+
+        let mut recv_errors = 0;
+
+        loop {
+            let received = tokio::select! {
+                received = Self::next_packet(&socket, &mut buf, config.recv_timeout(), &shared_source) => received,
+                _ = &mut shutdown => break,
+            };
+
+            let (size, sender, interface) = match received {
+                Ok(received) => {
+                    recv_errors = 0;
+                    received
+                }
+                Err(e) if Self::recv_fatal(&e) => return Err(e.into()),
+                Err(e) => {
+                    recv_errors += 1;
+                    log::warn!("receiving failed ({}), continuing", e);
+                    tokio::time::sleep(Self::recv_backoff(recv_errors)).await;
+                    continue;
+                }
+            };
+
+            log::trace!("UDP packet received");
+
+            let bytes = buf[..size].to_vec();
+            if Self::own_packet(&bytes, &local_networks, &local_hardware) {
+                let dropped = stats.lock().await.own_packet();
+                log::debug!("own packet received from {}, dropped ({} dropped)", sender, dropped);
+                continue;
+            }
+
+            let permit = in_flight.clone().read_owned().await;
+
+            let ingress = Ingress::new(interface.and_then(|i| interface_names.get(&i).cloned()), &bytes);
+            let cloned_source = shared_source.clone();
+            let cloned_socket = socket.clone();
+            let cloned_local_networks = local_networks.clone();
+            let cloned_config = config.clone();
+            let cloned_stats = stats.clone();
+            let cloned_live = live.clone();
+            let cloned_leases = leases.clone();
+            let cloned_limiter = limiter.clone();
+
+            let id = Self::new_correlation_id();
+            let xid = packet::xid(&bytes).unwrap_or_default();
+
+            // packets are processed concurrently, a slow source only holds up packets waiting for it
+            tokio::spawn(CORRELATION_ID.scope(id, XID.scope(xid, async move {
+                log::trace!("[{}] spawning new thread", log_prefix());
+                if let Err(e) = Self::process(bytes, ingress, cloned_source, sender, cloned_socket, cloned_local_networks, cloned_config, cloned_stats, cloned_live, cloned_leases, cloned_limiter).await {
+                    log::error!("[{}] {}", log_prefix(), e);
+                }
+                drop(permit);
+            })));
+        }
+
+        Self::shutdown(&shared_source, &in_flight).await;
+        Ok(())
+    }
+
+    /// Completes on ctrl-c or SIGTERM
+    async fn shutdown_signal() {
+        let interrupt = async {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                log::warn!("ctrl-c not handled: {}", e);
+                std::future::pending::<()>().await
+            }
+        };
+        let terminate = async {
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(mut terminate) => terminate.recv().await,
+                Err(e) => {
+                    log::warn!("SIGTERM not handled: {}", e);
+                    std::future::pending().await
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = interrupt => {}
+            _ = terminate => {}
+        }
+    }
+
+    /// No more packets are received, packets in flight (delayed naks included) are awaited up to `SHUTDOWN_TIMEOUT` and the sources shut down
+    async fn shutdown(sources: &[Mutex<BoxedDhcpHostSource>], in_flight: &RwLock<()>) {
+        log::info!("shutting down, waiting for packets in flight");
+        let _idle = match tokio::time::timeout(SHUTDOWN_TIMEOUT, in_flight.write()).await {
+            Ok(idle) => Some(idle),
+            Err(_) => {
+                log::warn!("packets still in flight after {:?}, shutting down anyway", SHUTDOWN_TIMEOUT);
+                None
+            }
+        };
+
+        for source in sources.iter() {
+            let mut source = source.lock().await;
+            if let Err(e) = source.shutdown().await {
+                log::error!("{} shutdown failed: {}", source.name(), e);
+            }
+        }
+    }
+
+    /// Binds `port`, or `fallback` if that is not permitted
+    fn bind(port: u16, fallback: Option<u16>) -> DhcpResult<UdpSocket> {
+        let error = match std::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)) {
+            Ok(socket) => return Self::nonblocking(socket),
+            Err(e) => Self::bind_error(e, port),
+        };
+
+        match (error, fallback) {
+            (DhcpError::BindPermissionDenied(_), Some(fallback)) => {
+                log::warn!("binding port {} not permitted, using unprivileged port {}", port, fallback);
+                std::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, fallback)).map_err(|e| Self::bind_error(e, fallback)).and_then(Self::nonblocking)
+            }
+            (error, _) => Err(error),
+        }
+    }
+
+    fn nonblocking(socket: std::net::UdpSocket) -> DhcpResult<UdpSocket> {
+        socket.set_nonblocking(true)?;
+        Ok(UdpSocket::from_std(socket)?)
+    }
+
+    /// Missing privileges (EACCES, EPERM) get a hint how to run the server
+    fn bind_error(e: std::io::Error, port: u16) -> DhcpError {
+        match e.raw_os_error() {
+            Some(libc::EACCES) | Some(libc::EPERM) => DhcpError::BindPermissionDenied(port),
+            _ => e.into(),
+        }
+    }
+
+    /// Unique per packet and server start
+    fn new_correlation_id() -> String {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        format!("{:x}-{:x}", started, PACKET_COUNTER.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Receives a packet with the index of the receiving interface (IP_PKTINFO)
+    fn recv(socket: &UdpSocket, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr, Option<u32>)> {
+        let iov = [IoVec::from_mut_slice(buf)];
+        let mut cmsg = nix::cmsg_space!(libc::in_pktinfo);
+        let message = recvmsg(socket.as_raw_fd(), &iov, Some(&mut cmsg), MsgFlags::empty()).map_err(std::io::Error::from)?;
+
+        let interface = message.cmsgs().find_map(|c| match c {
+            ControlMessageOwned::Ipv4PacketInfo(info) => Some(info.ipi_ifindex as u32),
+            _ => None,
+        });
+
+        match message.address {
+            Some(SockAddr::Inet(address)) => Ok((message.bytes, address.to_std(), interface)),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "sender address missing")),
+        }
+    }
+
+    /// Waits until a packet is received, readiness without a packet is waited for again
+    async fn receive(socket: &UdpSocket, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr, Option<u32>)> {
+        loop {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || Self::recv(socket, buf)) {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                received => return received,
+            }
+        }
+    }
+
+    /// Receives the next packet, the maintenance of the sources runs whenever the receive timeout passes
+    async fn next_packet(socket: &UdpSocket, buf: &mut [u8], timeout: Option<Duration>, sources: &[Mutex<BoxedDhcpHostSource>]) -> std::io::Result<(usize, SocketAddr, Option<u32>)> {
+        loop {
+            match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, Self::receive(socket, buf)).await {
+                    Ok(received) => return received,
+                    Err(_) => Self::maintenance(sources).await,
+                },
+                None => return Self::receive(socket, buf).await,
+            }
+        }
+    }
+
+    async fn maintenance(sources: &[Mutex<BoxedDhcpHostSource>]) {
+        log::trace!("receive timed out, running maintenance");
+        for source in sources {
+            let mut source = source.lock().await;
+            if let Err(e) = source.maintenance().await {
+                log::error!("{} maintenance failed: {}", source.name(), e);
+            }
+        }
+    }
+
+    /// Socket is unusable (closed), any other receive error is transient (EINTR, icmp errors, ...)
+    fn recv_fatal(e: &std::io::Error) -> bool {
+        matches!(e.raw_os_error(), Some(libc::EBADF) | Some(libc::ENOTSOCK))
+    }
+
+    /// No delay for a single error, doubling for repeated errors
+    fn recv_backoff(errors: u32) -> Duration {
+        match errors {
+            0 | 1 => Duration::default(),
+            n => (RECV_ERROR_BACKOFF * 2u32.pow((n - 2).min(10))).min(RECV_ERROR_BACKOFF_MAX),
+        }
+    }
+
+    fn interface_names() -> HashMap<u32, String> {
+        pnet::datalink::interfaces().into_iter().map(|i| (i.index, i.name)).collect()
+    }
+
+    /// Hardware addresses of the local interfaces
+    fn local_hardware() -> Vec<[u8; 6]> {
+        pnet::datalink::interfaces().into_iter()
+            .filter_map(|i| i.mac)
+            .map(|m| [m.0, m.1, m.2, m.3, m.4, m.5])
+            .filter(|m| m != &[0u8; 6])
+            .collect()
+    }
+
+    /// Reply carrying our server identifier or a packet of our own hardware address, looped back (broadcast, local client)
+    fn own_packet(bytes: &[u8], local_networks: &[Ipv4Network], local_hardware: &[[u8; 6]]) -> bool {
+        let own_server = bytes.get(packet::OP_OFFSET) == Some(&packet::OP_BOOT_REPLY)
+            && packet::server_identifier(bytes).map_or(false, |server| local_networks.iter().any(|n| n.ip() == server));
+        let own_hardware = packet::client_hardware(bytes).map_or(false, |mac| local_hardware.contains(&mac));
+
+        own_server || own_hardware
+    }
+
+    /// Prepares available networks, configured or detected from the interfaces
+    fn local_networks(config: &DhcpConfig) -> DhcpResult<Vec<Ipv4Network>> {
+        if let Some(networks) = config.networks()? {
+            log::info!("using configured networks {:?}", networks);
+            return Ok(networks);
+        }
 
-        // prepare available networks
         let local_networks = pnet::datalink::interfaces().iter().map(|i| {
             i.ips.iter().filter_map(|ip| {
                 if let IpNetwork::V4(i) = ip {
@@ -40,161 +424,2146 @@ impl Server {
             }).collect::<Vec<Ipv4Network>>()
         }).flatten().collect::<Vec<Ipv4Network>>();
 
-        log::debug!("Outbound ip addresses: {:?}", local_networks.iter().map(|i| i.ip()).collect::<Vec<Ipv4Addr>>());
+        if local_networks.is_empty() {
+            log::warn!("no usable network detected, replies can not be sent - configure `networks` manually");
+        }
 
-        loop {
-            let (_, sender) = socket.recv_from(&mut buf)?;
+        Ok(local_networks)
+    }
 
-            log::trace!("UDP packet received");
+    fn hops_exceeded(p: &DhcpPacket, max_hops: u8) -> bool {
+        p.hops() > max_hops
+    }
 
-            let bytes = buf.clone();
-            let cloned_source = shared_source.clone();
-            let cloned_socket = socket.try_clone()?;
-            let cloned_local_networks = local_networks.clone();
+    /// Request selecting another server (option 54), the client declined our offer
+    fn foreign_server(p: &DhcpPacket, local_networks: &[Ipv4Network]) -> Option<Ipv4Addr> {
+        p.options().try_ipv4_option(SERVER_IDENTIFIER).ok()
+            .filter(|server| !local_networks.iter().any(|n| n.ip() == *server))
+    }
+
+    /// Options requested by the client (option 55) missing in the reply, mandatory options are the server's business
+    fn unprovided(options: &DhcpOptions, p: &DhcpPacket) -> Vec<u8> {
+        p.options().try_vec_u8_option(PARAMETER_REQUEST_LIST).unwrap_or_default().into_iter()
+            .filter(|tag| !MANDATORY_OPTIONS.contains(tag) && options.option(*tag).is_none())
+            .collect()
+    }
+
+    async fn count_unprovided(options: &DhcpOptions, p: &DhcpPacket, stats: &Mutex<DhcpStats>) {
+        let unprovided = Self::unprovided(options, p);
+        if !unprovided.is_empty() {
+            log::debug!("[{}] requested options {:?} not provided", log_prefix(), unprovided);
+            stats.lock().await.unprovided(&unprovided);
+        }
+    }
+
+    /// Restricts (and orders) the reply options to the parameter request list (option 55)
+    fn apply_prl(options: DhcpOptions, p: &DhcpPacket, honor_prl: bool, order_by_prl: bool) -> DhcpOptions {
+        let prl = match p.options().try_vec_u8_option(PARAMETER_REQUEST_LIST) {
+            Ok(prl) if honor_prl => prl,
+            _ => return options,
+        };
+        let position = |tag: u8| prl.iter().position(|t| *t == tag);
+
+        let mut filtered = options.iter()
+            .filter(|o| MANDATORY_OPTIONS.contains(&o.tag()) || position(o.tag()).is_some())
+            .cloned()
+            .collect::<Vec<DhcpOption>>();
+
+        if order_by_prl { // unrequested mandatory options first
+            filtered.sort_by_key(|o| position(o.tag()).map_or(0, |i| i + 1));
+        }
+
+        DhcpOptions::new_with_options(filtered)
+    }
+
+    /// bootp siaddr of the source result, the pxe entry or the configured default
+    fn next_server(result: &DhcpSourceResult, pxe: Option<&PxeBoot>, config: &DhcpConfig) -> Ipv4Addr {
+        result.next_server()
+            .or_else(|| pxe.and_then(PxeBoot::next_server))
+            .or_else(|| config.next_server())
+            .unwrap_or(Ipv4Addr::UNSPECIFIED)
+    }
+
+    /// Boot file (option 67) and tftp server (option 66) of the pxe entry unless a source provided them,
+    /// the client machine identifier (option 97) is echoed as pxe clients expect
+    fn apply_pxe(options: &mut DhcpOptions, pxe: Option<&PxeBoot>, next_server: Ipv4Addr, p: &DhcpPacket) -> DhcpResult<()> {
+        let pxe = match pxe {
+            Some(pxe) => pxe,
+            None => return Ok(()),
+        };
+        let ascii = |s: String| AsciiString::from_ascii(s).map_err(|e| DhcpError::SerdeErrorString(e.to_string()));
+
+        if options.option(BOOT_FILE_NAME).is_none() {
+            options.upsert(DhcpOption::BootFileName(ascii(pxe.boot_file_name().to_string())?));
+        }
+        if options.option(TFTP_SERVER).is_none() && !next_server.is_unspecified() {
+            options.upsert(DhcpOption::TftpServer(ascii(next_server.to_string())?));
+        }
+        if let Some(uuid) = p.options().option(CLIENT_MACHINE_IDENTIFIER) {
+            options.upsert(uuid.clone());
+        }
+        Ok(())
+    }
 
-            match tokio::spawn(async move {
-                log::trace!("spawning new thread");
-                Self::process(bytes, cloned_source, sender, cloned_socket, cloned_local_networks).await
-            }).await {
-                Ok(_) => {}
-                Err(e) => log::error!("{:?}", e)
+    /// Random delay up to `max`, spreads the retries of nak'd clients
+    fn nak_delay(max: Duration) -> Duration {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or_default();
+        Duration::from_millis(u64::from(nanos) % (max.as_millis() as u64 + 1))
+    }
+
+    /// Bootp server host name (sname) of a reply, at most 63 ascii characters
+    fn server_name(p: &DhcpPacket, config: &DhcpConfig) -> Option<AsciiString> {
+        let name = tera::Tera::one_off(config.server_name()?, &packet_context(p), false).map_err(DhcpError::from)
+            .and_then(|n| AsciiString::from_ascii(n).map_err(|e| DhcpError::SerdeErrorString(e.to_string())));
+
+        match name {
+            Ok(mut name) => {
+                if name.len() >= packet::SERVER_NAME_LENGTH {
+                    log::warn!("server name {} exceeds {} characters, truncated", name, packet::SERVER_NAME_LENGTH - 1);
+                    name.truncate(packet::SERVER_NAME_LENGTH - 1);
+                }
+                Some(name)
+            }
+            Err(e) => {
+                log::warn!("server name not rendered: {}", e);
+                None
             }
         }
     }
 
-    fn send(p: DhcpPacket, socket: UdpSocket, mut sender: SocketAddr, local_networks: Vec<Ipv4Network>) -> DhcpResult<()> {
-        let mut bytes = p.into_bytes_with_server_ips(local_networks.iter().map(|s| s.ip()).collect());
+    /// Rejection reason of the backend or the configured nak message (option 56) rendered for the client
+    fn nak_message(p: &DhcpPacket, config: &DhcpConfig, rejection: Option<&str>) -> Option<AsciiString> {
+        let message = match rejection {
+            Some(reason) => Ok(reason.to_string()),
+            None => tera::Tera::one_off(config.nak_message()?, &packet_context(p), false).map_err(DhcpError::from),
+        };
+
+        match message
+            .and_then(|m| AsciiString::from_ascii(m).map_err(|e| DhcpError::SerdeErrorString(e.to_string()))) {
+            Ok(message) => Some(message),
+            Err(e) => {
+                log::warn!("nak message not rendered: {}", e);
+                None
+            }
+        }
+    }
 
-        for a in local_networks {
-            sender.set_ip(a.broadcast().into());
-            if let Some(b) = bytes.remove(&a.ip()) {
-                socket.send_to(b.as_slice(), sender)?;
+    /// Global options below the source result, options of the source win
+    fn with_global_options(result: DhcpSourceResult, p: &DhcpPacket, config: &DhcpConfig) -> DhcpResult<DhcpSourceResult> {
+        match config.global_result(p)? {
+            Some(mut global) => {
+                global.merge(result);
+                Ok(global)
             }
+            None => Ok(result),
         }
+    }
+
+    /// Inform answered by the global options alone, the client keeps its address
+    fn inform_fallback(p: &DhcpPacket, ciaddr: Ipv4Addr, config: &DhcpConfig) -> DhcpResult<Option<DhcpSourceResult>> {
+        Ok(config.global_result(p)?
+            .filter(|global| global.options().iter().next().is_some())
+            .map(|global| {
+                log::debug!("[{}] inform from {} answered by global options", log_prefix(), p.client_hardware());
+                let next_server = *global.next_server();
+                let provenance = global.provenance().clone();
+                DhcpSourceResult::new(Some(ciaddr), global.into()).with_next_server(next_server).with_provenance(provenance)
+            }))
+    }
 
+    /// Server wide captive portal (option 114) unless a source provided one
+    fn apply_captive_portal(options: &mut DhcpOptions, config: &DhcpConfig) -> DhcpResult<()> {
+        match config.captive_portal_url() {
+            Some(url) if options.option(CAPTIVE_PORTAL).is_none() => options.upsert(captive_portal(url)?),
+            _ => {}
+        }
         Ok(())
     }
 
-    async fn process(bytes: Vec<u8>,
-                     sources: Arc<Mutex<Vec<impl DhcpHostSource + Send>>>,
-                     sender: SocketAddr,
-                     socket: UdpSocket,
-                     local_networks: Vec<Ipv4Network>,
-    ) -> DhcpResult<()> {
-        let message = DhcpMessaging::try_from(bytes.as_slice())?;
-        if let Some(DhcpOption::MessageType(t)) = message.packet().message_type() {
-            log::debug!("{:?} packet received", t);
+    /// Debug log of the origin of each reply option, options added by the server have none
+    fn log_provenance(options: &DhcpOptions, provenance: &HashMap<u8, String>) {
+        if !log::log_enabled!(log::Level::Debug) {
+            return;
         }
 
-        match message {
-            DhcpMessaging::Discover(p) => {
-                for source in sources.lock().await.iter_mut() {
-                    source.packet_received(p.packet()).await?;
+        for option in options.iter() {
+            log::debug!("[{}] option {} set by {}", log_prefix(), option.tag(),
+                        provenance.get(&option.tag()).map_or("server", String::as_str));
+        }
+    }
 
-                    match source.offer(&p.packet()).await {
-                        Ok(Some(result)) => {
-                            let mac = (*p.packet().client_hardware()).into();
-                            let client_ip_address = result.client_ip_address().ok_or(DhcpError::ClientIpAddressMissing(mac))?;
-                            let options: DhcpOptions = result.into();
-                            let send_packet = p.into_offer(options.try_u32_option(IP_ADDRESS_LEASE_TIME)?,
-                                                           client_ip_address,
-                                                           Ipv4Addr::UNSPECIFIED,
-                                                           options.try_ascii_option(BOOT_FILE_NAME).ok(),
-                                                           options.try_ascii_option(MESSAGE).ok(),
-                                                           options).into();
-
-                            source.packet_sending(&send_packet).await?;
-                            Self::send(send_packet, socket, sender, local_networks)?;
-                            source.packet_sent().await?;
-                            break;
-                        }
-                        Ok(None) => log::debug!("{} not found in source {}", p.packet().client_hardware(), source.name()),
-                        Err(e) => log::error!("{}", e),
-                    }
-                }
+    /// Echoes the client hostname (option 12) unless a source provided one
+    fn apply_hostname_policy(options: &mut DhcpOptions, p: &DhcpPacket, policy: Option<&HostnamePolicy>) {
+        let policy = match policy {
+            Some(policy) if options.option(HOST_NAME).is_none() => policy,
+            _ => return,
+        };
+
+        if let Ok(hostname) = p.options().try_ascii_option(HOST_NAME) {
+            match AsciiString::from_ascii(policy.apply(hostname.as_str())) {
+                Ok(h) if !h.is_empty() => options.upsert(DhcpOption::HostName(h)),
+                _ => log::debug!("[{}] hostname {} dropped by policy", log_prefix(), hostname),
             }
-            DhcpMessaging::Offer(_) => log::trace!("offer packet discarded"),
-            DhcpMessaging::Request(p) => {
-                for source in sources.lock().await.iter_mut() {
-                    source.packet_received(p.packet()).await?;
+        }
+    }
 
-                    match source.reserve(&p.packet()).await {
-                        Ok(Some(result)) => {
-                            let mac = (*p.packet().client_hardware()).into();
-                            let client_ip_address = result.client_ip_address().ok_or(DhcpError::ClientIpAddressMissing(mac))?;
-                            let options: DhcpOptions = result.into();
-                            let send_packet = p.into_ack(options.try_u32_option(IP_ADDRESS_LEASE_TIME)?,
-                                                         client_ip_address,
-                                                         Ipv4Addr::UNSPECIFIED,
-                                                         options.try_ascii_option(BOOT_FILE_NAME).ok(),
-                                                         options.try_ascii_option(SERVER_IDENTIFIER).ok(),
-                                                         options.try_ascii_option(MESSAGE).ok(),
-                                                         options.try_vec_u8_option(VENDOR_CLASS_IDENTIFIER).ok(),
-                                                         options).into();
-
-                            log::debug!("sending ack");
-                            source.packet_sending(&send_packet).await?;
-                            Self::send(send_packet, socket, sender, local_networks)?;
-                            source.packet_sent().await?;
-                            return Ok(());
-                        }
-                        Ok(None) => log::debug!("{} not found in source {}", p.packet().client_hardware(), source.name()),
-                        Err(e) => log::error!("{}", e),
-                    }
+    /// Broadcast of the network unless the reply may be unicast to the client
+    fn destination(reply: &[u8], sender: SocketAddr, network: &Ipv4Network, delivery: ReplyDelivery) -> SocketAddr {
+        let client = packet::client(reply).filter(|ip| !ip.is_unspecified());
+        let relayed = packet::gateway(reply).map_or(false, |ip| !ip.is_unspecified());
+
+        let unicast = match delivery {
+            ReplyDelivery::Broadcast => None,
+            ReplyDelivery::Unicast => client.or_else(|| packet::your(reply)),
+            ReplyDelivery::Flags => client.filter(|_| !relayed && !packet::broadcast_flag(reply)),
+        };
+
+        let mut destination = sender;
+        destination.set_ip(unicast.unwrap_or_else(|| network.broadcast()).into());
+        destination
+    }
+
+    /// Relay agent (giaddr) of a relayed reply and the network reaching it, the one containing it or the first,
+    /// unless broadcast is forced
+    fn relay_route(reply: &[u8], local_networks: &[Ipv4Network], delivery: ReplyDelivery) -> Option<(Ipv4Network, SocketAddr)> {
+        let relay = packet::gateway(reply).filter(|ip| !ip.is_unspecified() && delivery != ReplyDelivery::Broadcast)?;
+        let network = local_networks.iter().find(|n| n.contains(relay)).or_else(|| local_networks.first())?;
+        Some((*network, SocketAddr::new(relay.into(), DHCP_SERVER_PORT)))
+    }
+
+    async fn send(p: DhcpPacket, fields: &ReplyFields, socket: Arc<UdpSocket>, sender: SocketAddr, local_networks: Vec<Ipv4Network>, config: &DhcpConfig) -> DhcpResult<()> {
+        let mut bytes = p.into_bytes_with_server_ips(local_networks.iter().map(|s| s.ip()).collect());
+
+        // relayed replies are unicast once to the relay instead of broadcast on every network
+        let route = bytes.values().next().and_then(|b| Self::relay_route(b, &local_networks, config.reply_delivery()));
+        let networks = match route {
+            Some((network, _)) => vec![network],
+            None => local_networks,
+        };
+
+        let pending = networks.iter().filter_map(|a| {
+            let mut b = bytes.remove(&a.ip())?;
+            if fields.rapid_commit && !packet::rapid_commit(&mut b) {
+                log::warn!("[{}] rapid commit option not added to the reply via {}", log_prefix(), a);
+            }
+            if let Some(name) = &fields.server_name {
+                packet::set_server_name(&mut b, name.as_bytes());
+            }
+            if let Some(flags) = fields.flags {
+                packet::set_flags(&mut b, flags);
+            }
+            if let Some(secs) = fields.secs {
+                packet::set_secs(&mut b, secs);
+            }
+            if let Some(information) = &fields.relay_agent_information {
+                if packet::find_option(&b, packet::RELAY_AGENT_INFORMATION).is_none()
+                    && !packet::insert_option(&mut b, packet::RELAY_AGENT_INFORMATION, information) {
+                    log::warn!("[{}] relay agent information not echoed in the reply via {}", log_prefix(), a);
                 }
+            }
+            if let Some(auth) = config.auth() {
+                b = auth.sign(b);
+            }
+            let destination = match route {
+                Some((_, relay)) => relay,
+                None => Self::destination(&b, sender, a, config.reply_delivery()),
+            };
+            if log::log_enabled!(log::Level::Trace) {
+                log::trace!("[{}] sending {} bytes to {} via {}:\n{}", log_prefix(), b.len(), destination, a, packet::hex_dump(&b));
+            }
+            Some((destination, b))
+        }).collect::<Vec<(SocketAddr, Vec<u8>)>>();
 
-                log::debug!("sending nak");
-                let send_packet: DhcpPacket = p.into_nak(
-                    Ipv4Addr::UNSPECIFIED,
-                    None,
-                    None,
-                    None,
-                ).into();
-                Self::send(send_packet, socket, sender, local_networks)?;
+        // networks already sent to are not retried
+        let pending = &Mutex::new(pending);
+        let socket = &socket;
+        Self::retry_send(move || async move {
+            let mut pending = pending.lock().await;
+            let mut error = None;
+            let mut failed = Vec::new();
+            for (destination, b) in pending.drain(..) {
+                if let Err(e) = socket.send_to(b.as_slice(), destination).await {
+                    error = Some(e);
+                    failed.push((destination, b));
+                }
             }
-            DhcpMessaging::Inform(p) => {
-                for source in sources.lock().await.iter_mut() {
-                    source.packet_received(p.packet()).await?;
+            *pending = failed;
+            error.map_or(Ok(()), |e| Err(e.into()))
+        }, config.send_retries()).await
+    }
 
-                    match source.inform(&p.packet()).await {
-                        Ok(Some(result)) => {
-                            let mac = (*p.packet().client_hardware()).into();
-                            let client_ip_address = result.client_ip_address().ok_or(DhcpError::ClientIpAddressMissing(mac))?;
-                            let options: DhcpOptions = result.into();
-                            let send_packet = p.into_ack(client_ip_address,
-                                                         Ipv4Addr::UNSPECIFIED,
-                                                         options.try_ascii_option(BOOT_FILE_NAME).ok(),
-                                                         options.try_ascii_option(SERVER_IDENTIFIER).ok(),
-                                                         options.try_ascii_option(MESSAGE).ok(),
-                                                         options.try_vec_u8_option(VENDOR_CLASS_IDENTIFIER).ok(),
-                                                         options).into();
-
-                            log::debug!("sending ack");
-                            source.packet_sending(&send_packet).await?;
-                            Self::send(send_packet, socket, sender, local_networks)?;
-                            source.packet_sent().await?;
-                            return Ok(());
-                        }
-                        Ok(None) => log::debug!("{} not found in source {}", p.packet().client_hardware(), source.name()),
-                        Err(e) => log::error!("{}", e),
-                    }
+    /// Retries a failed send with a linear backoff, the reply is not computed again
+    async fn retry_send<F: FnMut() -> S, S: Future<Output = DhcpResult<()>>>(mut send: F, retries: u32) -> DhcpResult<()> {
+        let mut attempt = 0;
+
+        loop {
+            match send().await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < retries => {
+                    attempt += 1;
+                    log::warn!("[{}] send failed ({}), retry {}/{}", log_prefix(), e, attempt, retries);
+                    tokio::time::sleep(SEND_RETRY_BACKOFF * attempt).await;
                 }
+                Err(e) => return Err(e),
             }
-            DhcpMessaging::Release(p) => {
-                for source in sources.lock().await.iter_mut() {
-                    source.packet_received(p.packet()).await?;
-                    source.release(&p.packet()).await?;
+        }
+    }
+
+    /// Releases at the source, retried with a linear backoff as the client never retransmits a release
+    async fn release(source: &mut dyn DhcpHostSource, p: &DhcpPacket, retries: u32) -> DhcpResult<()> {
+        let mut attempt = 0;
+
+        loop {
+            match source.release(p).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < retries => {
+                    attempt += 1;
+                    log::warn!("[{}] release at {} failed ({}), retry {}/{}", log_prefix(), source.name(), e, attempt, retries);
+                    tokio::time::sleep(RELEASE_RETRY_BACKOFF * attempt).await;
                 }
+                Err(e) => return Err(e),
             }
-            DhcpMessaging::Decline(p) => {
-                for source in sources.lock().await.iter_mut() {
-                    source.packet_received(p.packet()).await?;
-                    source.decline(&p.packet()).await?;
+        }
+    }
+
+    /// Rejects assigned addresses no client can use
+    fn validate_client_ip_address(result: &DhcpSourceResult) -> DhcpResult<()> {
+        match *result.client_ip_address() {
+            Some(ip) if ip.is_unspecified() || ip.is_multicast() || ip.is_broadcast() => Err(DhcpError::InvalidClientIpAddress(ip)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Looks up the client in the configured overrides and sources.
+    /// An offer with an invalid address falls through to the next source.
+    /// The index refers to the answering source, `None` for an override
+    pub(crate) async fn lookup(config: &DhcpConfig,
+                    sources: &[Mutex<BoxedDhcpHostSource>],
+                    operation: Operation,
+                    p: &DhcpPacket,
+                    ingress: &Ingress,
+    ) -> DhcpResult<Option<(DhcpSourceResult, Option<usize>)>> {
+        if let Some(result) = config.override_result(p)? {
+            Self::validate_client_ip_address(&result)?;
+            log::debug!("[{}] {} answered by override", log_prefix(), p.client_hardware());
+            return Ok(Some((result.label("override"), None)));
+        }
+
+        let mut rejection = None;
+        let mut merged: Option<(DhcpSourceResult, Option<usize>)> = None;
+        let now = LocalTime::now();
+
+        for (index, (source, settings)) in sources.iter().zip(config.sources()).enumerate() {
+            if !settings.serves(ingress) {
+                log::trace!("[{}] source {} skipped for interface {:?}", log_prefix(), settings.kind(), ingress.interface);
+                continue;
+            }
+
+            if !settings.matches_user_class(p) {
+                log::trace!("[{}] source {} skipped for user class", log_prefix(), settings.kind());
+                continue;
+            }
+
+            if !settings.scheduled(&now) {
+                log::trace!("[{}] source {} skipped outside of its schedule", log_prefix(), settings.kind());
+                continue;
+            }
+
+            let mut source = source.lock().await;
+            if !matches!(operation, Operation::Preview) {
+                source.packet_received(p).await?;
+            }
+
+            // merging, only the source of the winning address offers or reserves it, later ones contribute options from a dry run
+            let contributing = merged.as_ref().map_or(false, |(m, _)| m.client_ip_address().is_some());
+            let result = match operation {
+                Operation::Offer | Operation::Reserve if contributing => source.preview(p).await,
+                Operation::Offer => source.offer(p).await,
+                Operation::Preview => source.preview(p).await,
+                Operation::Reserve => source.reserve(p).await,
+                Operation::Inform => source.inform(p).await,
+            };
+
+            match result {
+                Ok(Some(result)) => match Self::validate_client_ip_address(&result) {
+                    Ok(()) => {
+                        let result = result.label(&format!("{}#{}", source.name(), index));
+                        if !config.merge_sources() {
+                            return Ok(Some((result, Some(index))));
+                        }
+                        // later sources upsert options, the first client ip address wins
+                        match merged.as_mut() {
+                            Some((m, _)) => m.merge(result),
+                            None => merged = Some((result, Some(index))),
+                        }
+                    }
+                    Err(e) if matches!(operation, Operation::Offer | Operation::Preview) => {
+                        log::error!("[{}] source {} skipped: {}", log_prefix(), source.name(), e);
+                    }
+                    Err(e) => return Err(e),
+                },
+                Ok(None) => log::debug!("[{}] {} not found in source {}", log_prefix(), p.client_hardware(), source.name()),
+                Err(DhcpError::Rejected(reason)) => {
+                    log::debug!("[{}] {} rejected by source {}: {}", log_prefix(), p.client_hardware(), source.name(), reason);
+                    rejection = Some(reason);
                 }
+                Err(e) => log::error!("[{}] {}", log_prefix(), e),
             }
-            DhcpMessaging::Ack(_) => log::trace!("ack packet discarded"),
-            DhcpMessaging::Nak(_) => log::trace!("nak packet discarded"),
+        }
+
+        if merged.is_some() {
+            return Ok(merged);
+        }
+
+        // no source answered, a rejection is kept as nak reason
+        match rejection {
+            Some(reason) => Err(DhcpError::Rejected(reason)),
+            None => Ok(None),
+        }
+    }
+
+    /// Source of an answer as labelled in the option provenance, overrides have none
+    fn source_label(config: &DhcpConfig, index: Option<usize>) -> String {
+        match index.and_then(|i| config.sources().get(i).map(|s| format!("{}#{}", s.kind(), i))) {
+            Some(label) => label,
+            None => "override".to_string(),
+        }
+    }
+
+    /// Rejected clients are not answered, only requests are nak'd with the reason
+    fn unless_rejected<T>(found: DhcpResult<Option<T>>) -> DhcpResult<Option<T>> {
+        match found {
+            Err(DhcpError::Rejected(_)) => Ok(None),
+            found => found,
+        }
+    }
+
+    async fn reply(sources: &[Mutex<BoxedDhcpHostSource>],
+                   index: Option<usize>,
+                   send_packet: DhcpPacket,
+                   fields: ReplyFields,
+                   socket: Arc<UdpSocket>,
+                   sender: SocketAddr,
+                   local_networks: Vec<Ipv4Network>,
+                   config: &DhcpConfig,
+    ) -> DhcpResult<()> {
+        if let Some(i) = index {
+            sources[i].lock().await.packet_sending(&send_packet).await?;
+        }
+        Self::send(send_packet, &fields, socket, sender, local_networks, config).await?;
+        if let Some(i) = index {
+            sources[i].lock().await.packet_sent().await?;
         }
         Ok(())
     }
+
+    /// Commits the lease of a discover carrying the rapid commit option (RFC 4039) by reserving it,
+    /// without a reservation the client gets a regular offer
+    async fn rapid_commit(requested: bool,
+                          config: &DhcpConfig,
+                          sources: &[Mutex<BoxedDhcpHostSource>],
+                          p: &DhcpPacket,
+                          ingress: &Ingress,
+    ) -> Option<(DhcpSourceResult, Option<usize>)> {
+        if !requested {
+            return None;
+        }
+
+        match Self::unless_rejected(Self::lookup(config, sources, Operation::Reserve, p, ingress).await) {
+            Ok(found) => found,
+            Err(e) => {
+                log::warn!("[{}] rapid commit of {} failed, offering instead: {}", log_prefix(), p.client_hardware(), e);
+                None
+            }
+        }
+    }
+
+    /// Checks the address against the other clients if conflict detection is configured, true if the reply is skipped
+    async fn conflicting(config: &DhcpConfig, stats: &Mutex<DhcpStats>, mac: &str, ip: Option<Ipv4Addr>) -> bool {
+        let (detection, ip) = match (config.conflict_detection(), ip) {
+            (Some(detection), Some(ip)) => (detection, ip),
+            _ => return false,
+        };
+
+        let mut stats = stats.lock().await;
+        match stats.assign(mac, ip, detection.window()) {
+            Some(holder) => {
+                let conflicts = stats.address_conflict();
+                let e = DhcpError::AddressConflict { ip, client: mac.to_string(), holder };
+                log::error!("[{}] {} ({} conflicts)", log_prefix(), e, conflicts);
+                detection.skip()
+            }
+            None => false,
+        }
+    }
+
+    /// Another client holds a valid lease of the address, the offer is skipped and the request naked
+    async fn leased_elsewhere(leases: &Mutex<LeaseTable>, mac: &str, ip: Option<Ipv4Addr>) -> bool {
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return false,
+        };
+
+        match leases.lock().await.holder(ip, mac, SystemTime::now()) {
+            Some(holder) => {
+                log::warn!("[{}] {} is leased to {}, not handed to {}", log_prefix(), ip, holder, mac);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Relay mode: discovers and requests are stamped with relay agent information (option 82) and sent upstream,
+    /// replies of the upstream server are sent on to the client without it
+    async fn forward(mut bytes: Vec<u8>, upstream: SocketAddrV4, ingress: &Ingress, socket: Arc<UdpSocket>, sender: SocketAddr, local_networks: &[Ipv4Network], config: &DhcpConfig) -> DhcpResult<()> {
+        if bytes[packet::OP_OFFSET] == packet::OP_BOOT_REPLY {
+            if sender.ip() != *upstream.ip() {
+                log::trace!("[{}] reply from {} not forwarded, not the upstream server", log_prefix(), sender);
+                return Ok(());
+            }
+
+            let destination = Self::relayed_reply(&mut bytes, local_networks, config.reply_delivery());
+            log::debug!("[{}] forwarding reply of {} to {}", log_prefix(), sender, destination);
+            socket.send_to(bytes.as_slice(), destination).await?;
+            return Ok(());
+        }
+
+        if !matches!(packet::message_type(&bytes), Some(packet::DHCP_DISCOVER) | Some(packet::DHCP_REQUEST)) {
+            log::trace!("[{}] packet from {} not forwarded", log_prefix(), sender);
+            return Ok(());
+        }
+
+        if bytes[packet::HOPS_OFFSET] >= config.max_hops() {
+            log::warn!("[{}] packet from {} not forwarded, hop count {} reaches {}", log_prefix(), sender, bytes[packet::HOPS_OFFSET], config.max_hops());
+            return Ok(());
+        }
+        bytes[packet::HOPS_OFFSET] += 1;
+
+        // the upstream server replies to the first relay agent
+        if packet::gateway(&bytes).map_or(false, |ip| ip.is_unspecified()) {
+            if let Some(network) = local_networks.first() {
+                packet::set_gateway(&mut bytes, network.ip());
+            }
+        }
+
+        let information = config.relay_agent().information(ingress.interface.as_deref());
+        if !information.is_empty() && packet::find_option(&bytes, packet::RELAY_AGENT_INFORMATION).is_none()
+            && !packet::insert_option(&mut bytes, packet::RELAY_AGENT_INFORMATION, &information) {
+            log::warn!("[{}] relay agent information not added to the packet from {}", log_prefix(), sender);
+        }
+
+        log::debug!("[{}] forwarding packet from {} to {}", log_prefix(), sender, upstream);
+        socket.send_to(bytes.as_slice(), upstream).await?;
+        Ok(())
+    }
+
+    /// Strips the relay agent information of a reply, the client is reached on the network of the relay address
+    fn relayed_reply(bytes: &mut Vec<u8>, local_networks: &[Ipv4Network], delivery: ReplyDelivery) -> SocketAddr {
+        packet::remove_option(bytes, packet::RELAY_AGENT_INFORMATION);
+
+        let gateway = packet::gateway(bytes).unwrap_or(Ipv4Addr::UNSPECIFIED);
+        let network = local_networks.iter().find(|n| n.ip() == gateway).or_else(|| local_networks.first());
+        let client = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), DHCP_CLIENT_PORT);
+
+        match network {
+            Some(network) => Self::destination(bytes, client, network, delivery),
+            None => SocketAddr::new(Ipv4Addr::BROADCAST.into(), DHCP_CLIENT_PORT),
+        }
+    }
+
+    /// Relayed packets are served by their relay address (giaddr), direct ones by the networks of the receiving interface
+    fn served(bytes: &[u8], local_networks: &[Ipv4Network], subnets: Option<&[Ipv4Network]>) -> bool {
+        let subnets = match subnets {
+            Some(subnets) => subnets,
+            None => return true,
+        };
+
+        match packet::gateway(bytes).filter(|ip| !ip.is_unspecified()) {
+            Some(giaddr) => subnets.iter().any(|s| s.contains(giaddr)),
+            None => local_networks.iter().any(|n| subnets.iter().any(|s| s.contains(n.ip()))),
+        }
+    }
+
+    /// RFC 4388 lease query by address (ciaddr) or else by client (chaddr), answered from the lease table.
+    /// Free addresses of the served subnets are unassigned, anything else is unknown
+    async fn lease_query(bytes: &[u8],
+                         socket: Arc<UdpSocket>,
+                         sender: SocketAddr,
+                         config: &DhcpConfig,
+                         local_networks: &[Ipv4Network],
+                         leases: &Mutex<LeaseTable>,
+    ) -> DhcpResult<()> {
+        if !config.leasequery_enabled() {
+            log::trace!("[{}] lease query discarded", log_prefix());
+            return Ok(());
+        }
+
+        let ciaddr = packet::client(bytes).filter(|ip| !ip.is_unspecified());
+        let now = SystemTime::now();
+        let lease = {
+            let leases = leases.lock().await;
+            let lease = match ciaddr {
+                Some(ip) => leases.get(ip, now).map(|lease| (ip, lease)),
+                None => packet::client_hardware(bytes).and_then(|mac| leases.lease_of(&macaddr::MacAddr6::from(mac).to_string(), now)),
+            };
+            lease.and_then(|(ip, lease)| Some((ip, lease.mac().parse::<macaddr::MacAddr6>().ok()?, lease.remaining(now))))
+        };
+        let subnets = config.serve_subnets()?;
+
+        let reply = match lease {
+            Some((ip, mac, remaining)) => {
+                log::debug!("[{}] sending lease active of {} to {}", log_prefix(), ip, sender);
+                packet::lease_active(bytes, ip, mac.into_array(), remaining)
+            }
+            None if ciaddr.map_or(false, |ip| Self::served_address(ip, local_networks, subnets.as_deref())) => {
+                log::debug!("[{}] sending lease unassigned to {}", log_prefix(), sender);
+                packet::lease_query_reply(bytes, packet::DHCP_LEASE_UNASSIGNED)
+            }
+            None => {
+                log::debug!("[{}] sending lease unknown to {}", log_prefix(), sender);
+                packet::lease_query_reply(bytes, packet::DHCP_LEASE_UNKNOWN)
+            }
+        };
+
+        if let Some(reply) = reply {
+            socket.send_to(reply.as_slice(), sender).await?;
+        }
+        Ok(())
+    }
+
+    /// Address of a subnet the server answers for, `serve_subnets` or else the local networks
+    fn served_address(ip: Ipv4Addr, local_networks: &[Ipv4Network], subnets: Option<&[Ipv4Network]>) -> bool {
+        match subnets {
+            Some(subnets) => subnets.iter().any(|s| s.contains(ip)),
+            None => local_networks.iter().any(|n| n.contains(ip)),
+        }
+    }
+
+    async fn process(bytes: Vec<u8>,
+                     ingress: Ingress,
+                     sources: SharedDhcpHostSources,
+                     sender: SocketAddr,
+                     socket: Arc<UdpSocket>,
+                     local_networks: Vec<Ipv4Network>,
+                     config: Arc<DhcpConfig>,
+                     stats: Arc<Mutex<DhcpStats>>,
+                     live: Arc<LiveStats>,
+                     leases: Arc<Mutex<LeaseTable>>,
+                     limiter: Arc<Mutex<ClientRateLimiter>>,
+    ) -> DhcpResult<()> {
+        if !packet::is_dhcpv4(&bytes) {
+            let ignored = stats.lock().await.non_dhcpv4();
+            log::debug!("[{}] non-IPv4 DHCP payload from {}, ignoring ({} ignored)", log_prefix(), sender, ignored);
+            return Ok(());
+        }
+
+        // before any parsing or source query, a flooding client costs as little as possible
+        if let Some(mac) = packet::client_hardware(&bytes).map(|mac| macaddr::MacAddr6::from(mac).to_string()) {
+            if !limiter.lock().await.try_acquire(&mac) {
+                log::debug!("[{}] packet from {} dropped, rate limit exceeded", log_prefix(), mac);
+                return Ok(());
+            }
+        }
+
+        if let Some(upstream) = config.forward_to() {
+            return Self::forward(bytes, upstream, &ingress, socket, sender, &local_networks, &config).await;
+        }
+
+        if packet::message_type(&bytes) == Some(packet::DHCP_LEASE_QUERY) {
+            return Self::lease_query(&bytes, socket, sender, &config, &local_networks, &leases).await;
+        }
+
+        if !Self::served(&bytes, &local_networks, config.serve_subnets()?.as_deref()) {
+            let dropped = stats.lock().await.unserved();
+            log::debug!("[{}] packet from {} of a subnet not served, dropped ({} dropped)", log_prefix(), sender, dropped);
+            return Ok(());
+        }
+
+        let ciaddr = packet::client(&bytes).unwrap_or(Ipv4Addr::UNSPECIFIED);
+        let message = if packet::message_type(&bytes) == Some(packet::DHCP_DISCOVER) && !ciaddr.is_unspecified() {
+            // protocol violation (RFC 2131 4.4.1) of buggy clients, the offer must not be built from it
+            if !config.lenient_ciaddr() {
+                log::debug!("[{}] discover with client address {} from {}, dropped", log_prefix(), ciaddr, sender);
+                return Ok(());
+            }
+            log::debug!("[{}] discover with client address {} from {}, ignoring the address", log_prefix(), ciaddr, sender);
+            let mut cleared = bytes.clone();
+            packet::clear_client(&mut cleared);
+            DhcpMessaging::try_from(cleared.as_slice())?
+        } else {
+            DhcpMessaging::try_from(bytes.as_slice())?
+        };
+        if let Some(DhcpOption::MessageType(t)) = message.packet().message_type() {
+            log::debug!("[{}] {:?} packet received from {}", log_prefix(), t, message.packet().client_hardware());
+        }
+
+        if !config.auth().map_or(true, |auth| auth.verify(&bytes)) {
+            log::warn!("[{}] packet from {} dropped, authentication failed", log_prefix(), message.packet().client_hardware());
+            return Ok(());
+        }
+
+        if Self::hops_exceeded(message.packet(), config.max_hops()) {
+            log::warn!("[{}] packet from {} dropped, hop count {} exceeds {} (relay loop?)",
+                       log_prefix(), message.packet().client_hardware(), message.packet().hops(), config.max_hops());
+            return Ok(());
+        }
+
+        let mac = message.packet().client_hardware().to_string();
+        if !config.mac_filter().map_or(true, |filter| filter.permits(&mac)) {
+            log::debug!("[{}] packet from {} dropped by mac filter", log_prefix(), mac);
+            return Ok(());
+        }
+
+        let (flags, secs) = config.reply_header().fields(&bytes);
+        let relay_agent_information = packet::relay_agent_information(&bytes);
+
+        match message {
+            DhcpMessaging::Discover(p) => {
+                stats.lock().await.discover(&mac);
+                // a rapid commit reserves right away, nothing is offered (and held) first
+                let rapid_commit = config.rapid_commit() && packet::find_option(&bytes, packet::RAPID_COMMIT).is_some();
+                let (found, rapid_commit) = match Self::rapid_commit(rapid_commit, &config, &sources, p.packet(), &ingress).await {
+                    Some(committed) => (Some(committed), true),
+                    None => (Self::unless_rejected(Self::lookup(&config, &sources, Operation::Offer, p.packet(), &ingress).await)?, false),
+                };
+                live.lookup(found.as_ref().and_then(|(_, index)| *index));
+                if let Some((result, index)) = found {
+                    let ip = *result.client_ip_address();
+                    if Self::conflicting(&config, &stats, &mac, ip).await || Self::leased_elsewhere(&leases, &mac, ip).await {
+                        log::warn!("[{}] offer of {} by {} to {} skipped, the address belongs to another client",
+                                   log_prefix(), ip.map_or_else(String::new, |ip| ip.to_string()), Self::source_label(&config, index), mac);
+                        return Ok(());
+                    }
+                    let result = Self::with_global_options(result, p.packet(), &config)?;
+                    let mac = (*p.packet().client_hardware()).into();
+                    let client_ip_address = result.client_ip_address().ok_or(DhcpError::ClientIpAddressMissing(mac))?;
+                    let pxe = config.pxe(p.packet());
+                    let next_server = Self::next_server(&result, pxe, &config);
+                    let provenance = result.provenance().clone();
+                    let mut options: DhcpOptions = result.into();
+                    Self::apply_pxe(&mut options, pxe, next_server, p.packet())?;
+                    Self::apply_captive_portal(&mut options, &config)?;
+                    let mut options = Self::apply_prl(options, p.packet(), config.honor_prl(), config.order_by_prl());
+                    Self::apply_hostname_policy(&mut options, p.packet(), config.hostname_policy());
+                    Self::log_provenance(&options, &provenance);
+                    Self::count_unprovided(&options, p.packet(), &stats).await;
+                    let lease_time = options.try_u32_option(IP_ADDRESS_LEASE_TIME)?;
+                    let send_packet = p.into_offer(lease_time,
+                                                   client_ip_address,
+                                                   next_server,
+                                                   options.try_ascii_option(BOOT_FILE_NAME).ok(),
+                                                   options.try_ascii_option(MESSAGE).ok(),
+                                                   options).into();
+
+                    if rapid_commit {
+                        log::debug!("[{}] sending rapid commit ack", log_prefix());
+                    }
+                    Self::reply(&sources, index, send_packet, ReplyFields { server_name: Self::server_name(p.packet(), &config), rapid_commit, flags, secs, relay_agent_information }, socket, sender, local_networks, &config).await?;
+                    if rapid_commit {
+                        live.ack();
+                        leases.lock().await.commit(client_ip_address, &mac, &Self::source_label(&config, index), lease_time, SystemTime::now());
+                        stats.lock().await.ack(&mac, client_ip_address);
+                    } else {
+                        live.offer();
+                        stats.lock().await.offer(&mac, client_ip_address);
+                    }
+                }
+            }
+            DhcpMessaging::Offer(_) => log::trace!("[{}] offer packet discarded", log_prefix()),
+            DhcpMessaging::Request(p) => {
+                if let Some(server) = Self::foreign_server(p.packet(), &local_networks) {
+                    log::debug!("[{}] request from {} selects server {}, offer withdrawn", log_prefix(), p.packet().client_hardware(), server);
+                    for source in sources.iter() {
+                        let mut source = source.lock().await;
+                        if let Err(e) = source.withdraw(p.packet()).await {
+                            log::error!("[{}] withdrawing the offer to {} failed at {}: {}", log_prefix(), mac, source.name(), e);
+                        }
+                    }
+                    return Ok(());
+                }
+
+                let (found, rejection) = if stats.lock().await.take_force_release(&mac) {
+                    log::info!("[{}] release of {} forced, sending nak", log_prefix(), mac);
+                    (None, Some(FORCED_RELEASE.to_string()))
+                } else {
+                    let found = Self::lookup(&config, &sources, Operation::Reserve, p.packet(), &ingress).await;
+                    live.lookup(found.as_ref().ok().and_then(Option::as_ref).and_then(|(_, index)| *index));
+                    match found {
+                        Err(DhcpError::Rejected(reason)) => (None, Some(reason)),
+                        found => (found?, None),
+                    }
+                };
+                let conflicting = match &found {
+                    Some((result, _)) => Self::conflicting(&config, &stats, &mac, *result.client_ip_address()).await
+                        || Self::leased_elsewhere(&leases, &mac, *result.client_ip_address()).await,
+                    None => false,
+                };
+                let (found, rejection) = if conflicting { (None, Some(ADDRESS_CONFLICT.to_string())) } else { (found, rejection) };
+
+                if let Some((result, index)) = found {
+                    let result = Self::with_global_options(result, p.packet(), &config)?;
+                    let mac = (*p.packet().client_hardware()).into();
+                    let client_ip_address = result.client_ip_address().ok_or(DhcpError::ClientIpAddressMissing(mac))?;
+                    let pxe = config.pxe(p.packet());
+                    let next_server = Self::next_server(&result, pxe, &config);
+                    let provenance = result.provenance().clone();
+                    let mut options: DhcpOptions = result.into();
+                    Self::apply_pxe(&mut options, pxe, next_server, p.packet())?;
+                    Self::apply_captive_portal(&mut options, &config)?;
+                    let mut options = Self::apply_prl(options, p.packet(), config.honor_prl(), config.order_by_prl());
+                    Self::apply_hostname_policy(&mut options, p.packet(), config.hostname_policy());
+                    Self::log_provenance(&options, &provenance);
+                    Self::count_unprovided(&options, p.packet(), &stats).await;
+                    let lease_time = options.try_u32_option(IP_ADDRESS_LEASE_TIME)?;
+                    let send_packet = p.into_ack(lease_time,
+                                                 client_ip_address,
+                                                 next_server,
+                                                 options.try_ascii_option(BOOT_FILE_NAME).ok(),
+                                                 options.try_ascii_option(SERVER_IDENTIFIER).ok(),
+                                                 options.try_ascii_option(MESSAGE).ok(),
+                                                 options.try_vec_u8_option(VENDOR_CLASS_IDENTIFIER).ok(),
+                                                 options).into();
+
+                    log::debug!("[{}] sending ack", log_prefix());
+                    Self::reply(&sources, index, send_packet, ReplyFields { server_name: Self::server_name(p.packet(), &config), rapid_commit: false, flags, secs, relay_agent_information }, socket, sender, local_networks, &config).await?;
+                    live.ack();
+                    leases.lock().await.commit(client_ip_address, &mac, &Self::source_label(&config, index), lease_time, SystemTime::now());
+                    stats.lock().await.ack(&mac, client_ip_address);
+                    return Ok(());
+                }
+
+                if stats.lock().await.nak_suppressed(&mac, config.nak_suppress_window()) {
+                    log::debug!("[{}] nak to {} suppressed", log_prefix(), mac);
+                    return Ok(());
+                }
+
+                log::debug!("[{}] sending nak", log_prefix());
+                live.nak();
+                stats.lock().await.nak(&mac, rejection.as_deref().unwrap_or("no source answered"));
+                let message = Self::nak_message(p.packet(), &config, rejection.as_deref());
+                let send_packet: DhcpPacket = p.into_nak(
+                    Ipv4Addr::UNSPECIFIED,
+                    message,
+                    None,
+                    None,
+                ).into();
+
+                let fields = ReplyFields { flags, secs, relay_agent_information, ..Default::default() };
+                // every packet has its own task, other packets keep being processed and a shutdown waits for the nak
+                tokio::time::sleep(Self::nak_delay(config.nak_delay())).await;
+                Self::send(send_packet, &fields, socket, sender, local_networks, &config).await?;
+            }
+            DhcpMessaging::Inform(p) => {
+                let found = Self::unless_rejected(Self::lookup(&config, &sources, Operation::Inform, p.packet(), &ingress).await)?;
+                live.lookup(found.as_ref().and_then(|(_, index)| *index));
+                let found = match found {
+                    Some(found) => Some(found),
+                    None => {
+                        let ciaddr = packet::client(&bytes).unwrap_or(Ipv4Addr::UNSPECIFIED);
+                        Self::inform_fallback(p.packet(), ciaddr, &config)?.map(|result| (result, None))
+                    }
+                };
+
+                if let Some((result, index)) = found {
+                    let result = Self::with_global_options(result, p.packet(), &config)?;
+                    let mac = (*p.packet().client_hardware()).into();
+                    let client_ip_address = result.client_ip_address().ok_or(DhcpError::ClientIpAddressMissing(mac))?;
+                    let pxe = config.pxe(p.packet());
+                    let next_server = Self::next_server(&result, pxe, &config);
+                    let provenance = result.provenance().clone();
+                    let mut options: DhcpOptions = result.into();
+                    Self::apply_pxe(&mut options, pxe, next_server, p.packet())?;
+                    Self::apply_captive_portal(&mut options, &config)?;
+                    let mut options = Self::apply_prl(options, p.packet(), config.honor_prl(), config.order_by_prl());
+                    Self::apply_hostname_policy(&mut options, p.packet(), config.hostname_policy());
+                    Self::log_provenance(&options, &provenance);
+                    Self::count_unprovided(&options, p.packet(), &stats).await;
+                    let send_packet = p.into_ack(client_ip_address,
+                                                 next_server,
+                                                 options.try_ascii_option(BOOT_FILE_NAME).ok(),
+                                                 options.try_ascii_option(SERVER_IDENTIFIER).ok(),
+                                                 options.try_ascii_option(MESSAGE).ok(),
+                                                 options.try_vec_u8_option(VENDOR_CLASS_IDENTIFIER).ok(),
+                                                 options).into();
+
+                    log::debug!("[{}] sending ack", log_prefix());
+                    Self::reply(&sources, index, send_packet, ReplyFields { server_name: Self::server_name(p.packet(), &config), rapid_commit: false, flags, secs, relay_agent_information }, socket, sender, local_networks, &config).await?;
+                }
+            }
+            DhcpMessaging::Release(p) => {
+                stats.lock().await.unassign(&mac);
+                leases.lock().await.release(&mac);
+                // a source failing does not keep the others from releasing
+                for source in sources.iter() {
+                    let mut source = source.lock().await;
+                    source.packet_received(p.packet()).await?;
+                    match Self::release(&mut **source, p.packet(), config.release_retries()).await {
+                        Ok(()) => log::info!("[{}] release of {} confirmed by {}", log_prefix(), mac, source.name()),
+                        Err(e) => {
+                            let failed = stats.lock().await.release_failed();
+                            log::error!("[{}] release of {} failed at {}: {} ({} failed)", log_prefix(), mac, source.name(), e, failed);
+                        }
+                    }
+                }
+            }
+            DhcpMessaging::Decline(p) => {
+                stats.lock().await.unassign(&mac);
+                leases.lock().await.release(&mac);
+                for source in sources.iter() {
+                    let mut source = source.lock().await;
+                    source.packet_received(p.packet()).await?;
+                    source.decline(&p.packet()).await?;
+                }
+            }
+            DhcpMessaging::Ack(_) => log::trace!("[{}] ack packet discarded", log_prefix()),
+            DhcpMessaging::Nak(_) => log::trace!("[{}] nak packet discarded", log_prefix()),
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_hops_exceeded() {
+    assert!(!Server::hops_exceeded(&crate::sources::test_packet(0, vec![]), 16));
+    assert!(!Server::hops_exceeded(&crate::sources::test_packet(16, vec![]), 16));
+    assert!(Server::hops_exceeded(&crate::sources::test_packet(17, vec![]), 16));
+}
+
+#[test]
+fn test_foreign_server() {
+    let local_networks = vec![Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap()];
+
+    let p = crate::sources::test_packet(0, vec![DhcpOption::ServerIdentifier(Ipv4Addr::new(10, 0, 0, 254))]);
+    assert_eq!(Server::foreign_server(&p, &local_networks), Some(Ipv4Addr::new(10, 0, 0, 254)));
+
+    let p = crate::sources::test_packet(0, vec![DhcpOption::ServerIdentifier(Ipv4Addr::new(10, 0, 0, 1))]);
+    assert_eq!(Server::foreign_server(&p, &local_networks), None);
+
+    let p = crate::sources::test_packet(0, vec![]);
+    assert_eq!(Server::foreign_server(&p, &local_networks), None);
+}
+
+#[test]
+fn test_apply_prl() {
+    use dhcplib::option::{SUBNET_MASK, ROUTER, DOMAIN_NAME_SERVER, DOMAIN_NAME};
+
+    let options = || DhcpOptions::new_with_options(vec![
+        DhcpOption::SubnetMask(Ipv4Addr::new(255, 255, 255, 0)),
+        DhcpOption::Router(vec![Ipv4Addr::new(10, 0, 0, 1)]),
+        DhcpOption::DomainNameServer(vec![Ipv4Addr::new(10, 0, 0, 2)]),
+        DhcpOption::IpAddressLeaseTime(3600),
+    ]);
+    let p = crate::sources::test_packet(0, vec![DhcpOption::ParameterRequestList(vec![DOMAIN_NAME_SERVER, DOMAIN_NAME, SUBNET_MASK, ROUTER])]);
+    let tags = |o: DhcpOptions| o.iter().map(|o| o.tag()).collect::<Vec<u8>>();
+
+    assert_eq!(tags(Server::apply_prl(options(), &p, false, true)), vec![SUBNET_MASK, ROUTER, DOMAIN_NAME_SERVER, IP_ADDRESS_LEASE_TIME]);
+    assert_eq!(tags(Server::apply_prl(options(), &p, true, true)), vec![IP_ADDRESS_LEASE_TIME, DOMAIN_NAME_SERVER, SUBNET_MASK, ROUTER]);
+}
+
+#[test]
+fn test_server_name() {
+    let config = |name: &str| -> DhcpConfig { serde_yaml::from_str(&format!("server_name: \"{}\"\nsources: []", name)).unwrap() };
+    let p = crate::sources::test_packet(0, vec![]);
+
+    let name = Server::server_name(&p, &config("boot-{{ hops }}.example")).unwrap();
+    assert_eq!(name.as_str(), "boot-0.example");
+
+    let name = Server::server_name(&p, &config(&"a".repeat(80))).unwrap();
+    assert_eq!(name.len(), 63); // field keeps the terminating nul
+
+    assert!(Server::server_name(&p, &config("bööt")).is_none());
+    assert!(Server::server_name(&p, &config("{{ missing }}")).is_none());
+    assert!(Server::server_name(&p, &serde_yaml::from_str("sources: []").unwrap()).is_none());
+}
+
+#[tokio::test]
+async fn test_count_unprovided() {
+    use dhcplib::option::{SUBNET_MASK, DOMAIN_NAME};
+    const NTP_SERVERS: u8 = 42;
+
+    let options = DhcpOptions::new_with_options(vec![
+        DhcpOption::SubnetMask(Ipv4Addr::new(255, 255, 255, 0)),
+        DhcpOption::IpAddressLeaseTime(3600),
+    ]);
+    let prl = vec![SUBNET_MASK, DOMAIN_NAME, NTP_SERVERS, RENEWAL_TIME_VALUE];
+    let p = crate::sources::test_packet(0, vec![DhcpOption::ParameterRequestList(prl)]);
+    assert_eq!(Server::unprovided(&options, &p), vec![DOMAIN_NAME, NTP_SERVERS]);
+    assert!(Server::unprovided(&options, &crate::sources::test_packet(0, vec![])).is_empty());
+
+    let stats = Mutex::new(DhcpStats::new(8));
+    Server::count_unprovided(&options, &p, &stats).await;
+    Server::count_unprovided(&options, &p, &stats).await;
+    let unprovided = serde_json::to_value(stats.lock().await.server()).unwrap()["unprovided_options"].clone();
+    assert_eq!(unprovided, serde_json::json!({"15": 2, "42": 2}));
+}
+
+#[test]
+fn test_local_networks_configured() {
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+networks:
+  - 10.0.0.1/24
+sources: []
+"#).unwrap();
+
+    assert_eq!(Server::local_networks(&config).unwrap(), vec![Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap()]);
+}
+
+#[test]
+fn test_next_server() {
+    let config: DhcpConfig = serde_yaml::from_str("next_server: 10.0.0.2\nsources: []").unwrap();
+    let mapping: crate::sources::mapping::DhcpMapping = serde_yaml::from_str("next_server: 10.0.0.3").unwrap();
+
+    let result = mapping.to_result(&tera::Context::new(), false).unwrap();
+    assert_eq!(Server::next_server(&result, None, &config), Ipv4Addr::new(10, 0, 0, 3));
+    assert_eq!(Server::next_server(&DhcpSourceResult::new(None, DhcpOptions::new()), None, &config), Ipv4Addr::new(10, 0, 0, 2));
+
+    let config: DhcpConfig = serde_yaml::from_str("sources: []").unwrap();
+    assert_eq!(Server::next_server(&DhcpSourceResult::new(None, DhcpOptions::new()), None, &config), Ipv4Addr::UNSPECIFIED);
+}
+
+#[test]
+fn test_apply_pxe() {
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+pxe:
+  - arch: 7
+    boot_file_name: ipxe.efi
+sources: []
+"#).unwrap();
+    let p = crate::sources::test_packet(0, vec![
+        DhcpOption::Unknown(crate::sources::mapping::CLIENT_SYSTEM_ARCHITECTURE, vec![0, 7]),
+        DhcpOption::Unknown(CLIENT_MACHINE_IDENTIFIER, vec![0; 17]),
+    ]);
+
+    let mut options = DhcpOptions::new();
+    Server::apply_pxe(&mut options, config.pxe(&p), Ipv4Addr::new(10, 0, 0, 2), &p).unwrap();
+    assert_eq!(options.try_ascii_option(BOOT_FILE_NAME).unwrap().as_str(), "ipxe.efi");
+    assert!(matches!(options.option(CLIENT_MACHINE_IDENTIFIER), Some(DhcpOption::Unknown(_, data)) if data.len() == 17));
+
+    let mut options = DhcpOptions::new(); // no pxe client
+    Server::apply_pxe(&mut options, None, Ipv4Addr::new(10, 0, 0, 2), &p).unwrap();
+    assert!(options.option(CLIENT_MACHINE_IDENTIFIER).is_none());
+}
+
+#[test]
+fn test_nak_message() {
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+nak_message: "device {{ client_hardware_address }} not registered"
+sources: []
+"#).unwrap();
+    let p = crate::sources::test_packet(0, vec![]);
+    let expected = format!("device {} not registered", p.client_hardware());
+
+    assert_eq!(Server::nak_message(&p, &config, None).unwrap().as_str(), expected);
+    assert_eq!(Server::nak_message(&p, &config, Some("not registered")).unwrap().as_str(), "not registered");
+
+    let config: DhcpConfig = serde_yaml::from_str("sources: []").unwrap();
+    assert!(Server::nak_message(&p, &config, None).is_none());
+}
+
+#[cfg(test)]
+struct TestSource(Ipv4Addr);
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl DhcpHostSource for TestSource {
+    fn name(&self) -> &'static str { "test" }
+
+    async fn offer(&mut self, _: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        Ok(Some(DhcpSourceResult::new(Some(self.0), DhcpOptions::new())))
+    }
+
+    async fn reserve(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> { self.offer(p).await }
+
+    async fn release(&mut self, _: &DhcpPacket) -> DhcpResult<()> { Ok(()) }
+
+    async fn decline(&mut self, _: &DhcpPacket) -> DhcpResult<()> { Ok(()) }
+
+    async fn inform(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> { self.offer(p).await }
+
+    fn from_config<'a, T: serde::Deserializer<'a> + Send>(_: T) -> DhcpResult<Self> where Self: Sized {
+        Ok(Self(Ipv4Addr::UNSPECIFIED))
+    }
+}
+
+#[tokio::test]
+async fn test_invalid_client_ip_address() {
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+sources:
+  - kind: static
+    config: {}
+  - kind: static
+    config: {}
+"#).unwrap();
+    let sources = crate::sources::shared(vec![
+        Box::new(TestSource(Ipv4Addr::UNSPECIFIED)),
+        Box::new(TestSource(Ipv4Addr::new(10, 0, 0, 5))),
+    ]);
+    let p = crate::sources::test_packet(0, vec![]);
+    let ingress = Ingress { interface: None, gateway: Ipv4Addr::UNSPECIFIED };
+
+    let (result, index) = Server::lookup(&config, &sources, Operation::Offer, &p, &ingress).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 5)));
+    assert_eq!(index, Some(1));
+
+    match Server::lookup(&config, &sources, Operation::Reserve, &p, &ingress).await {
+        Err(DhcpError::InvalidClientIpAddress(ip)) => assert_eq!(ip, Ipv4Addr::UNSPECIFIED),
+        _ => panic!("invalid client ip address accepted"),
+    }
+}
+
+#[tokio::test]
+async fn test_merge_sources() {
+    use dhcplib::option::{SUBNET_MASK, DOMAIN_NAME_SERVER};
+
+    let yaml = |merge: bool| format!(r#"
+merge_sources: {}
+sources:
+  - kind: static
+    config:
+      hosts:
+        "01:02:03:05:06:07":
+          client_ip_address: 10.0.0.5
+          subnet_mask:
+            data: 255.255.0.0
+  - kind: static
+    config:
+      hosts:
+        "01:02:03:05:06:07":
+          client_ip_address: 10.0.0.9
+          subnet_mask:
+            data: 255.255.255.0
+          domain_name_server:
+            data: 10.0.0.2
+"#, merge);
+    let p = crate::sources::test_packet(0, vec![]);
+    let ingress = Ingress { interface: None, gateway: Ipv4Addr::UNSPECIFIED };
+
+    let config: DhcpConfig = serde_yaml::from_str(&yaml(true)).unwrap();
+    let sources = crate::sources::shared(config.init_sources().unwrap());
+    let (result, index) = Server::lookup(&config, &sources, Operation::Offer, &p, &ingress).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 5)));
+    assert_eq!(result.options().try_ipv4_option(SUBNET_MASK).unwrap(), Ipv4Addr::new(255, 255, 255, 0));
+    assert_eq!(result.options().try_ipv4vec_option(DOMAIN_NAME_SERVER).unwrap(), vec![Ipv4Addr::new(10, 0, 0, 2)]);
+    assert_eq!(result.provenance().get(&DOMAIN_NAME_SERVER).unwrap(), "static#1");
+    assert_eq!(index, Some(0));
+
+    // first answer wins by default
+    let config: DhcpConfig = serde_yaml::from_str(&yaml(false)).unwrap();
+    let sources = crate::sources::shared(config.init_sources().unwrap());
+    let (result, _) = Server::lookup(&config, &sources, Operation::Offer, &p, &ingress).await.unwrap().unwrap();
+    assert_eq!(result.options().try_ipv4_option(SUBNET_MASK).unwrap(), Ipv4Addr::new(255, 255, 0, 0));
+    assert!(result.options().try_ipv4vec_option(DOMAIN_NAME_SERVER).is_err());
+
+    // only the source of the winning address reserves it
+    let config: DhcpConfig = serde_yaml::from_str("merge_sources: true\nsources:\n  - kind: static\n    config: {}\n  - kind: static\n    config: {}").unwrap();
+    let (first, second): (Arc<std::sync::Mutex<Vec<&'static str>>>, Arc<std::sync::Mutex<Vec<&'static str>>>) = Default::default();
+    let sources = crate::sources::shared(vec![
+        Box::new(MockSource { answer: Some(Ipv4Addr::new(10, 0, 0, 5)), calls: first.clone() }),
+        Box::new(MockSource { answer: Some(Ipv4Addr::new(10, 0, 0, 9)), calls: second.clone() }),
+    ]);
+    let (result, index) = Server::lookup(&config, &sources, Operation::Reserve, &p, &ingress).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 5)));
+    assert_eq!(index, Some(0));
+    assert_eq!(*first.lock().unwrap(), vec!["reserve"]);
+    assert_eq!(*second.lock().unwrap(), vec!["preview"]);
+}
+
+#[tokio::test]
+async fn test_retry_send() {
+    let mut attempts = 0;
+    let result = Server::retry_send(|| {
+        attempts += 1;
+        let result = if attempts == 1 {
+            Err(std::io::Error::new(std::io::ErrorKind::Other, "transient").into())
+        } else {
+            Ok(())
+        };
+        async { result }
+    }, 2).await;
+    assert!(result.is_ok());
+    assert_eq!(attempts, 2);
+
+    let mut attempts = 0;
+    let result = Server::retry_send(|| {
+        attempts += 1;
+        async { Err(std::io::Error::new(std::io::ErrorKind::Other, "down").into()) }
+    }, 2).await;
+    assert!(result.is_err());
+    assert_eq!(attempts, 3);
+}
+
+#[tokio::test]
+async fn test_recv_errors() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut buf = [0u8; 16];
+
+    let e = Server::recv(&socket, &mut buf).unwrap_err(); // nothing to receive
+    assert!(!Server::recv_fatal(&e));
+    assert!(!Server::recv_fatal(&std::io::Error::from_raw_os_error(libc::EINTR)));
+    assert!(!Server::recv_fatal(&std::io::Error::from_raw_os_error(libc::ECONNREFUSED)));
+
+    assert!(Server::recv_fatal(&std::io::Error::from_raw_os_error(libc::EBADF)));
+
+    assert_eq!(Server::recv_backoff(1), Duration::default());
+    assert_eq!(Server::recv_backoff(2), Duration::from_millis(10));
+    assert_eq!(Server::recv_backoff(3), Duration::from_millis(20));
+    assert_eq!(Server::recv_backoff(100), Duration::from_secs(1));
+}
+
+#[test]
+fn test_inform_fallback() {
+    use dhcplib::option::DOMAIN_NAME_SERVER;
+
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+global_options:
+  domain_name_server:
+    data: 10.0.0.2
+sources: []
+"#).unwrap();
+    let p = crate::sources::test_packet(0, vec![]);
+
+    let result = Server::inform_fallback(&p, Ipv4Addr::new(10, 0, 0, 9), &config).unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 9)));
+    assert_eq!(result.options().try_ipv4vec_option(DOMAIN_NAME_SERVER).unwrap(), vec![Ipv4Addr::new(10, 0, 0, 2)]);
+    assert_eq!(result.provenance().get(&DOMAIN_NAME_SERVER).unwrap(), "global");
+
+    let config: DhcpConfig = serde_yaml::from_str("sources: []").unwrap();
+    assert!(Server::inform_fallback(&p, Ipv4Addr::new(10, 0, 0, 9), &config).unwrap().is_none());
+}
+
+#[test]
+fn test_destination() {
+    let network = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap();
+    let sender: SocketAddr = "0.0.0.0:68".parse().unwrap();
+    let broadcast: SocketAddr = "10.0.0.255:68".parse().unwrap();
+
+    let mut reply = vec![0u8; packet::OPTIONS_OFFSET];
+    reply[packet::YOUR_OFFSET..packet::YOUR_OFFSET + 4].copy_from_slice(&[10, 0, 0, 5]);
+
+    // client without address
+    assert_eq!(Server::destination(&reply, sender, &network, ReplyDelivery::Flags), broadcast);
+    assert_eq!(Server::destination(&reply, sender, &network, ReplyDelivery::Broadcast), broadcast);
+    assert_eq!(Server::destination(&reply, sender, &network, ReplyDelivery::Unicast), "10.0.0.5:68".parse().unwrap());
+
+    // renewing client
+    reply[packet::CLIENT_OFFSET..packet::CLIENT_OFFSET + 4].copy_from_slice(&[10, 0, 0, 5]);
+    assert_eq!(Server::destination(&reply, sender, &network, ReplyDelivery::Flags), "10.0.0.5:68".parse().unwrap());
+    assert_eq!(Server::destination(&reply, sender, &network, ReplyDelivery::Broadcast), broadcast);
+    assert_eq!(Server::destination(&reply, sender, &network, ReplyDelivery::Unicast), "10.0.0.5:68".parse().unwrap());
+
+    reply[packet::FLAGS_OFFSET] = 0x80; // client asks for broadcast
+    assert_eq!(Server::destination(&reply, sender, &network, ReplyDelivery::Flags), broadcast);
+    assert_eq!(Server::destination(&reply, sender, &network, ReplyDelivery::Unicast), "10.0.0.5:68".parse().unwrap());
+}
+
+#[test]
+fn test_relay_route() {
+    let local_networks = vec![Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap(), Ipv4Network::new(Ipv4Addr::new(10, 1, 0, 1), 24).unwrap()];
+    let mut reply = test_raw_packet(2);
+    assert_eq!(Server::relay_route(&reply, &local_networks, ReplyDelivery::Flags), None);
+
+    packet::set_gateway(&mut reply, Ipv4Addr::new(10, 1, 0, 2));
+    assert_eq!(Server::relay_route(&reply, &local_networks, ReplyDelivery::Flags), Some((local_networks[1], "10.1.0.2:67".parse().unwrap())));
+    assert_eq!(Server::relay_route(&reply, &local_networks, ReplyDelivery::Unicast), Some((local_networks[1], "10.1.0.2:67".parse().unwrap())));
+    assert_eq!(Server::relay_route(&reply, &local_networks, ReplyDelivery::Broadcast), None);
+
+    // relays more than one hop away are reached through the routing table
+    packet::set_gateway(&mut reply, Ipv4Addr::new(192, 168, 7, 1));
+    assert_eq!(Server::relay_route(&reply, &local_networks, ReplyDelivery::Flags), Some((local_networks[0], "192.168.7.1:67".parse().unwrap())));
+    assert_eq!(Server::relay_route(&reply, &[], ReplyDelivery::Flags), None);
+}
+
+/// Records the source methods called by the server
+#[cfg(test)]
+struct MockSource {
+    answer: Option<Ipv4Addr>,
+    calls: Arc<std::sync::Mutex<Vec<&'static str>>>,
+}
+
+#[cfg(test)]
+impl MockSource {
+    fn answer(&self, call: &'static str) -> DhcpResult<Option<DhcpSourceResult>> {
+        self.calls.lock().unwrap().push(call);
+        Ok(self.answer.map(|ip| DhcpSourceResult::new(Some(ip), DhcpOptions::new_with_options(vec![
+            DhcpOption::IpAddressLeaseTime(3600),
+        ]))))
+    }
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl DhcpHostSource for MockSource {
+    fn name(&self) -> &'static str { "mock" }
+
+    async fn offer(&mut self, _: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> { self.answer("offer") }
+
+    async fn reserve(&mut self, _: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> { self.answer("reserve") }
+
+    async fn release(&mut self, _: &DhcpPacket) -> DhcpResult<()> { self.answer("release").map(|_| ()) }
+
+    async fn decline(&mut self, _: &DhcpPacket) -> DhcpResult<()> { self.answer("decline").map(|_| ()) }
+
+    async fn inform(&mut self, _: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> { self.answer("inform") }
+
+    async fn preview(&mut self, _: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> { self.answer("preview") }
+
+    async fn withdraw(&mut self, _: &DhcpPacket) -> DhcpResult<()> { self.answer("withdraw").map(|_| ()) }
+
+    fn from_config<'a, T: serde::Deserializer<'a> + Send>(_: T) -> DhcpResult<Self> where Self: Sized {
+        Ok(Self { answer: None, calls: Default::default() })
+    }
+
+    async fn maintenance(&mut self) -> DhcpResult<()> {
+        self.calls.lock().unwrap().push("maintenance");
+        Ok(())
+    }
+}
+
+/// Serialized client packet of the message type
+#[cfg(test)]
+fn test_raw_packet(message_type: u8) -> Vec<u8> {
+    let mut bytes = vec![0u8; packet::OPTIONS_OFFSET];
+    bytes[packet::OP_OFFSET] = if matches!(message_type, 2 | 5 | 6) { packet::OP_BOOT_REPLY } else { packet::OP_BOOT_REQUEST };
+    bytes[1] = 1; // ethernet
+    bytes[2] = 6; // hardware address length
+    bytes[4..8].copy_from_slice(&[0, 0, 0, 123]); // xid
+    bytes[28..34].copy_from_slice(&[1, 2, 3, 5, 6, 7]); // chaddr
+    bytes[packet::MAGIC_COOKIE_OFFSET..packet::OPTIONS_OFFSET].copy_from_slice(&packet::MAGIC_COOKIE);
+    bytes.extend_from_slice(&[packet::MESSAGE_TYPE, 1, message_type, packet::END]);
+    bytes
+}
+
+/// Processes a packet with a single mock source, the shared state can be handed in to look at it afterwards
+#[cfg(test)]
+struct TestProcess {
+    /// top level config lines
+    config: String,
+    /// source list replacing the mock source
+    sources: Option<String>,
+    answer: Option<Ipv4Addr>,
+    stats: Arc<Mutex<DhcpStats>>,
+    leases: Arc<Mutex<LeaseTable>>,
+    limiter: Arc<Mutex<ClientRateLimiter>>,
+}
+
+#[cfg(test)]
+impl TestProcess {
+    fn new(answer: Option<Ipv4Addr>) -> Self {
+        Self {
+            config: String::new(),
+            sources: None,
+            answer,
+            stats: Arc::new(Mutex::new(DhcpStats::new(8))),
+            leases: Default::default(),
+            limiter: Default::default(),
+        }
+    }
+
+    fn with_config(mut self, config: &str) -> Self {
+        self.config = config.to_string();
+        self
+    }
+
+    fn with_sources(mut self, sources: &str) -> Self {
+        self.sources = Some(sources.to_string());
+        self
+    }
+
+    fn with_stats(mut self, stats: Arc<Mutex<DhcpStats>>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    fn with_leases(mut self, leases: Arc<Mutex<LeaseTable>>) -> Self {
+        self.leases = leases;
+        self
+    }
+
+    fn with_limiter(mut self, limiter: Arc<Mutex<ClientRateLimiter>>) -> Self {
+        self.limiter = limiter;
+        self
+    }
+
+    /// Source calls and the reply
+    async fn run(self, bytes: Vec<u8>) -> (Vec<&'static str>, Option<Vec<u8>>) {
+        let config: DhcpConfig = serde_yaml::from_str(&format!(r#"
+force_broadcast: true
+{}
+sources:
+{}
+"#, self.config, self.sources.as_deref().unwrap_or("  - kind: static\n    config: {}"))).unwrap();
+        let calls: Arc<std::sync::Mutex<Vec<&'static str>>> = Default::default();
+        let sources = match self.sources {
+            Some(_) => crate::sources::shared(config.init_sources().unwrap()),
+            None => crate::sources::shared(vec![Box::new(MockSource { answer: self.answer, calls: calls.clone() })]),
+        };
+
+        // the broadcast address of a /32 is the host itself
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let local_networks = vec![Ipv4Network::new(Ipv4Addr::LOCALHOST, 32).unwrap()];
+
+        Server::process(bytes,
+                        Ingress { interface: None, gateway: Ipv4Addr::UNSPECIFIED },
+                        sources,
+                        client.local_addr().unwrap(),
+                        socket,
+                        local_networks,
+                        Arc::new(config),
+                        self.stats,
+                        Arc::new(LiveStats::new(vec!["mock"])),
+                        self.leases,
+                        self.limiter,
+        ).await.unwrap();
+
+        let mut buf = vec![0u8; UDP_PACKET_BUFFER_SIZE];
+        let reply = client.recv(&mut buf).ok().map(|size| buf[..size].to_vec());
+        let calls = calls.lock().unwrap().clone();
+        (calls, reply)
+    }
+}
+
+#[tokio::test]
+async fn test_process_message_types() {
+    const DISCOVER: u8 = 1;
+    const OFFER: u8 = 2;
+    const REQUEST: u8 = 3;
+    const DECLINE: u8 = 4;
+    const ACK: u8 = 5;
+    const NAK: u8 = 6;
+    const RELEASE: u8 = 7;
+    const INFORM: u8 = 8;
+    let ip = Some(Ipv4Addr::new(10, 0, 0, 5));
+    let reply_type = |reply: Option<Vec<u8>>| reply.and_then(|r| packet::message_type(&r));
+
+    let (calls, reply) = TestProcess::new(ip).run(test_raw_packet(DISCOVER)).await;
+    assert_eq!(calls, vec!["offer"]);
+    assert_eq!(reply_type(reply.clone()), Some(OFFER));
+    assert_eq!(packet::your(&reply.unwrap()), ip);
+
+    let (calls, reply) = TestProcess::new(ip).run(test_raw_packet(REQUEST)).await;
+    assert_eq!(calls, vec!["reserve"]);
+    assert_eq!(reply_type(reply), Some(ACK));
+
+    let (calls, reply) = TestProcess::new(None).run(test_raw_packet(REQUEST)).await;
+    assert_eq!(calls, vec!["reserve"]);
+    assert_eq!(reply_type(reply), Some(NAK));
+
+    let (calls, reply) = TestProcess::new(ip).run(test_raw_packet(INFORM)).await;
+    assert_eq!(calls, vec!["inform"]);
+    assert_eq!(reply_type(reply), Some(ACK));
+
+    let (calls, reply) = TestProcess::new(ip).run(test_raw_packet(RELEASE)).await;
+    assert_eq!(calls, vec!["release"]);
+    assert!(reply.is_none());
+
+    let (calls, reply) = TestProcess::new(ip).run(test_raw_packet(DECLINE)).await;
+    assert_eq!(calls, vec!["decline"]);
+    assert!(reply.is_none());
+
+    for discarded in [OFFER, ACK, NAK] {
+        let (calls, reply) = TestProcess::new(ip).run(test_raw_packet(discarded)).await;
+        assert!(calls.is_empty());
+        assert!(reply.is_none());
+    }
+}
+
+#[tokio::test]
+async fn test_match_user_class() {
+    use crate::sources::mapping::USER_CLASS;
+
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+sources:
+  - kind: static
+    config: {}
+    match_user_class: "^voip$"
+  - kind: static
+    config: {}
+"#).unwrap();
+    let sources = crate::sources::shared(vec![
+        Box::new(TestSource(Ipv4Addr::new(10, 1, 0, 5))),
+        Box::new(TestSource(Ipv4Addr::new(10, 0, 0, 5))),
+    ]);
+    let ingress = Ingress { interface: None, gateway: Ipv4Addr::UNSPECIFIED };
+
+    let voip = crate::sources::test_packet(0, vec![DhcpOption::Unknown(USER_CLASS, b"\x04voip".to_vec())]);
+    let (_, index) = Server::lookup(&config, &sources, Operation::Offer, &voip, &ingress).await.unwrap().unwrap();
+    assert_eq!(index, Some(0));
+
+    let kiosk = crate::sources::test_packet(0, vec![DhcpOption::Unknown(USER_CLASS, b"\x05kiosk".to_vec())]);
+    let (_, index) = Server::lookup(&config, &sources, Operation::Offer, &kiosk, &ingress).await.unwrap().unwrap();
+    assert_eq!(index, Some(1));
+
+    let (_, index) = Server::lookup(&config, &sources, Operation::Offer, &crate::sources::test_packet(0, vec![]), &ingress).await.unwrap().unwrap();
+    assert_eq!(index, Some(1));
+}
+
+#[test]
+fn test_own_packet() {
+    let local_networks = vec![Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap()];
+    let local_hardware = vec![[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]];
+    let with_server = |op: u8, server: [u8; 4]| {
+        let mut bytes = test_raw_packet(5);
+        bytes[packet::OP_OFFSET] = op;
+        bytes.pop(); // end
+        bytes.extend_from_slice(&[packet::SERVER_IDENTIFIER, 4]);
+        bytes.extend_from_slice(&server);
+        bytes.push(packet::END);
+        bytes
+    };
+
+    assert!(Server::own_packet(&with_server(packet::OP_BOOT_REPLY, [10, 0, 0, 1]), &local_networks, &local_hardware));
+    assert!(!Server::own_packet(&with_server(packet::OP_BOOT_REPLY, [10, 0, 0, 2]), &local_networks, &local_hardware));
+    // requests selecting us carry our server identifier
+    assert!(!Server::own_packet(&with_server(packet::OP_BOOT_REQUEST, [10, 0, 0, 1]), &local_networks, &local_hardware));
+
+    let mut bytes = test_raw_packet(1);
+    assert!(!Server::own_packet(&bytes, &local_networks, &local_hardware));
+    bytes[packet::CLIENT_HARDWARE_OFFSET..packet::CLIENT_HARDWARE_OFFSET + 6].copy_from_slice(&local_hardware[0]);
+    assert!(Server::own_packet(&bytes, &local_networks, &local_hardware));
+}
+
+#[tokio::test]
+async fn test_xid() {
+    assert_eq!(packet::xid(&test_raw_packet(1)), Some(123));
+    assert_eq!(log_prefix(), "-");
+
+    let prefix = CORRELATION_ID.scope("abc-1".to_string(), XID.scope(123, async { log_prefix() })).await;
+    assert_eq!(prefix, "abc-1 xid=0x0000007b");
+
+    let context = crate::sources::mapping::packet_context(&crate::sources::test_packet(0, vec![]));
+    assert_eq!(context.get("xid"), Some(&serde_json::json!(123)));
+}
+
+#[tokio::test]
+async fn test_bind_error() {
+    let error = Server::bind_error(std::io::Error::from_raw_os_error(libc::EACCES), 67);
+    assert!(matches!(error, DhcpError::BindPermissionDenied(67)));
+    assert!(error.to_string().contains("CAP_NET_BIND_SERVICE"));
+    assert!(matches!(Server::bind_error(std::io::Error::from_raw_os_error(libc::EPERM), 67), DhcpError::BindPermissionDenied(67)));
+    assert!(matches!(Server::bind_error(std::io::Error::from_raw_os_error(libc::EADDRINUSE), 67), DhcpError::IoError(_)));
+
+    let socket = Server::bind(0, Some(6767)).unwrap(); // no fallback needed
+    assert_ne!(socket.local_addr().unwrap().port(), 6767);
+}
+
+#[tokio::test]
+async fn test_discover_with_ciaddr() {
+    let mut bytes = test_raw_packet(packet::DHCP_DISCOVER);
+    bytes[packet::CLIENT_OFFSET..packet::CLIENT_OFFSET + 4].copy_from_slice(&[10, 0, 0, 9]);
+    let ip = Some(Ipv4Addr::new(10, 0, 0, 5));
+
+    let (calls, reply) = TestProcess::new(ip).run(bytes.clone()).await;
+    assert_eq!(calls, vec!["offer"]);
+    let reply = reply.unwrap();
+    assert_eq!(packet::client(&reply), Some(Ipv4Addr::UNSPECIFIED));
+    assert_eq!(packet::your(&reply), ip);
+
+    let (calls, reply) = TestProcess::new(ip).with_config("lenient_ciaddr: false").run(bytes).await;
+    assert!(calls.is_empty());
+    assert!(reply.is_none());
+}
+
+#[tokio::test]
+async fn test_force_release() {
+    const REQUEST: u8 = 3;
+    const ACK: u8 = 5;
+    const NAK: u8 = 6;
+    let ip = Some(Ipv4Addr::new(10, 0, 0, 5));
+    let stats = Arc::new(Mutex::new(DhcpStats::new(8)));
+    let reply_type = |reply: Option<Vec<u8>>| reply.and_then(|r| packet::message_type(&r));
+
+    stats.lock().await.force_release("01:02:03:05:06:07").unwrap();
+    let (calls, reply) = TestProcess::new(ip).with_stats(stats.clone()).run(test_raw_packet(REQUEST)).await;
+    assert!(calls.is_empty()); // no source asked
+    assert_eq!(reply_type(reply), Some(NAK));
+    let client = serde_json::to_value(stats.lock().await.get("01:02:03:05:06:07").unwrap()).unwrap();
+    assert_eq!(client["last_nak_reason"], FORCED_RELEASE);
+
+    let (calls, reply) = TestProcess::new(ip).with_stats(stats.clone()).run(test_raw_packet(REQUEST)).await;
+    assert_eq!(calls, vec!["reserve"]);
+    assert_eq!(reply_type(reply), Some(ACK));
+}
+
+/// Answers after a delay, counting the calls running at the same time
+#[cfg(test)]
+struct SlowSource {
+    active: Arc<std::sync::atomic::AtomicUsize>,
+    overlapping: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl DhcpHostSource for SlowSource {
+    fn name(&self) -> &'static str { "slow" }
+
+    async fn offer(&mut self, _: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+        self.overlapping.fetch_max(active, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        Ok(Some(DhcpSourceResult::new(Some(Ipv4Addr::new(10, 0, 0, 5)), DhcpOptions::new_with_options(vec![
+            DhcpOption::IpAddressLeaseTime(3600),
+        ]))))
+    }
+
+    async fn reserve(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> { self.offer(p).await }
+
+    async fn release(&mut self, _: &DhcpPacket) -> DhcpResult<()> { Ok(()) }
+
+    async fn decline(&mut self, _: &DhcpPacket) -> DhcpResult<()> { Ok(()) }
+
+    async fn inform(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> { self.offer(p).await }
+
+    fn from_config<'a, T: serde::Deserializer<'a> + Send>(_: T) -> DhcpResult<Self> where Self: Sized {
+        Ok(Self { active: Default::default(), overlapping: Default::default() })
+    }
+}
+
+/// Time is paused, sleeps of the sources advance it exactly by their duration once every task waits
+#[tokio::test(start_paused = true)]
+async fn test_source_locking() {
+    use crate::sources::mapping::USER_CLASS;
+
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+sources:
+  - kind: static
+    config: {}
+    match_user_class: "^voip$"
+  - kind: static
+    config: {}
+"#).unwrap();
+    let overlapping: Arc<std::sync::atomic::AtomicUsize> = Default::default();
+    let slow = || SlowSource { active: Default::default(), overlapping: overlapping.clone() };
+    let sources = crate::sources::shared(vec![Box::new(slow()), Box::new(slow())]);
+    let ingress = Ingress { interface: None, gateway: Ipv4Addr::UNSPECIFIED };
+    let voip = crate::sources::test_packet(0, vec![DhcpOption::Unknown(USER_CLASS, b"\x04voip".to_vec())]);
+    let other = crate::sources::test_packet(0, vec![]);
+
+    // different sources run concurrently
+    let started = tokio::time::Instant::now();
+    let (a, b) = tokio::join!(
+        Server::lookup(&config, &sources, Operation::Offer, &voip, &ingress),
+        Server::lookup(&config, &sources, Operation::Offer, &other, &ingress),
+    );
+    assert_eq!(a.unwrap().unwrap().1, Some(0));
+    assert_eq!(b.unwrap().unwrap().1, Some(1));
+    assert_eq!(started.elapsed(), Duration::from_millis(100));
+
+    // calls of the same source don't overlap
+    let started = tokio::time::Instant::now();
+    let (a, b) = tokio::join!(
+        Server::lookup(&config, &sources, Operation::Offer, &voip, &ingress),
+        Server::lookup(&config, &sources, Operation::Offer, &voip, &ingress),
+    );
+    assert!(a.unwrap().is_some() && b.unwrap().is_some());
+    assert_eq!(started.elapsed(), Duration::from_millis(200));
+    assert_eq!(overlapping.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_concurrent_processing() {
+    use crate::sources::mapping::USER_CLASS;
+
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+force_broadcast: true
+sources:
+  - kind: static
+    config: {}
+    match_user_class: "^voip$"
+  - kind: static
+    config: {}
+"#).unwrap();
+    let config = Arc::new(config);
+    let overlapping: Arc<std::sync::atomic::AtomicUsize> = Default::default();
+    let slow = || SlowSource { active: Default::default(), overlapping: overlapping.clone() };
+    let sources = crate::sources::shared(vec![Box::new(slow()), Box::new(slow())]);
+    let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let mut voip = test_raw_packet(packet::DHCP_DISCOVER);
+    assert!(packet::insert_option(&mut voip, USER_CLASS, b"\x04voip"));
+
+    // each discover is spawned like in the receive loop, one slow backend answer does not wait for the other
+    let started = tokio::time::Instant::now();
+    let handles = vec![voip, test_raw_packet(packet::DHCP_DISCOVER)].into_iter().map(|bytes| {
+        tokio::spawn(Server::process(bytes,
+                                     Ingress { interface: None, gateway: Ipv4Addr::UNSPECIFIED },
+                                     sources.clone(),
+                                     client.local_addr().unwrap(),
+                                     socket.clone(),
+                                     vec![Ipv4Network::new(Ipv4Addr::LOCALHOST, 32).unwrap()],
+                                     config.clone(),
+                                     Arc::new(Mutex::new(DhcpStats::new(8))),
+                                     Arc::new(LiveStats::new(vec!["slow", "slow"])),
+                                     Default::default(),
+                                     Default::default()))
+    }).collect::<Vec<_>>();
+    for handle in handles {
+        handle.await.unwrap().unwrap();
+    }
+    assert_eq!(started.elapsed(), Duration::from_millis(100));
+
+    client.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let mut buf = vec![0u8; UDP_PACKET_BUFFER_SIZE];
+    assert!(client.recv(&mut buf).is_ok() && client.recv(&mut buf).is_ok());
+}
+
+#[tokio::test]
+async fn test_forward() {
+    let upstream = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    upstream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let config: DhcpConfig = serde_yaml::from_str(&format!(r#"
+forward_to: 127.0.0.1
+forward_port: {}
+relay_agent:
+  remote_id: lab
+sources: []
+"#, upstream.local_addr().unwrap().port())).unwrap();
+    let config = Arc::new(config);
+    let client: SocketAddr = "127.0.0.1:68".parse().unwrap();
+    let local_networks = vec![Ipv4Network::new(Ipv4Addr::LOCALHOST, 32).unwrap()];
+
+    let forward = |bytes: Vec<u8>| {
+        let config = config.clone();
+        let local_networks = local_networks.clone();
+        async move {
+            Server::process(bytes,
+                            Ingress { interface: Some("eth0".to_string()), gateway: Ipv4Addr::UNSPECIFIED },
+                            crate::sources::shared(vec![]),
+                            client,
+                            Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+                            local_networks,
+                            config,
+                            Arc::new(Mutex::new(DhcpStats::new(8))),
+                            Arc::new(LiveStats::new(vec![])),
+                            Default::default(),
+                            Default::default(),
+            ).await.unwrap();
+        }
+    };
+    let mut buf = vec![0u8; UDP_PACKET_BUFFER_SIZE];
+
+    forward(test_raw_packet(packet::DHCP_DISCOVER)).await;
+    let size = upstream.recv(&mut buf).unwrap();
+    let forwarded = &buf[..size];
+    assert_eq!(forwarded[packet::HOPS_OFFSET], 1);
+    assert_eq!(packet::gateway(forwarded), Some(Ipv4Addr::LOCALHOST));
+    let (start, length) = packet::find_option(forwarded, packet::RELAY_AGENT_INFORMATION).unwrap();
+    assert_eq!(&forwarded[start..start + length], b"\x01\x04eth0\x02\x03lab");
+
+    forward(test_raw_packet(8)).await; // inform is answered by nobody
+    assert!(upstream.recv(&mut buf).is_err());
+}
+
+#[test]
+fn test_relayed_reply() {
+    let local_networks = vec![Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap(), Ipv4Network::new(Ipv4Addr::new(10, 1, 0, 1), 24).unwrap()];
+    let mut reply = test_raw_packet(2);
+    packet::set_gateway(&mut reply, Ipv4Addr::new(10, 1, 0, 1));
+    assert!(packet::insert_option(&mut reply, packet::RELAY_AGENT_INFORMATION, b"\x01\x04eth0"));
+
+    let destination = Server::relayed_reply(&mut reply, &local_networks, ReplyDelivery::Flags);
+    assert_eq!(destination, "10.1.0.255:68".parse().unwrap());
+    assert_eq!(packet::find_option(&reply, packet::RELAY_AGENT_INFORMATION), None);
+    assert_eq!(packet::message_type(&reply), Some(2));
+}
+
+/// Fails the first releases, counting every attempt
+#[cfg(test)]
+struct FlakySource {
+    failures: u32,
+    releases: Arc<std::sync::atomic::AtomicU32>,
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl DhcpHostSource for FlakySource {
+    fn name(&self) -> &'static str { "flaky" }
+
+    async fn offer(&mut self, _: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> { Ok(None) }
+
+    async fn reserve(&mut self, _: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> { Ok(None) }
+
+    async fn release(&mut self, _: &DhcpPacket) -> DhcpResult<()> {
+        if self.releases.fetch_add(1, Ordering::SeqCst) < self.failures {
+            return Err(DhcpError::ReleaseUnconfirmed("backend down".to_string()));
+        }
+        Ok(())
+    }
+
+    async fn decline(&mut self, _: &DhcpPacket) -> DhcpResult<()> { Ok(()) }
+
+    async fn inform(&mut self, _: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> { Ok(None) }
+
+    fn from_config<'a, T: serde::Deserializer<'a> + Send>(_: T) -> DhcpResult<Self> where Self: Sized {
+        Ok(Self { failures: 0, releases: Default::default() })
+    }
+}
+
+#[tokio::test]
+async fn test_release_retried() {
+    let config: DhcpConfig = serde_yaml::from_str("release_retries: 2\nsources: []").unwrap();
+    let config = Arc::new(config);
+    let release = |failures: u32| {
+        let config = config.clone();
+        async move {
+            let releases: Arc<std::sync::atomic::AtomicU32> = Default::default();
+            let other: Arc<std::sync::atomic::AtomicU32> = Default::default();
+            let stats = Arc::new(Mutex::new(DhcpStats::new(8)));
+            let sources = crate::sources::shared(vec![
+                Box::new(FlakySource { failures, releases: releases.clone() }),
+                Box::new(FlakySource { failures: 0, releases: other.clone() }),
+            ]);
+
+            Server::process(test_raw_packet(7),
+                            Ingress { interface: None, gateway: Ipv4Addr::UNSPECIFIED },
+                            sources,
+                            "127.0.0.1:68".parse().unwrap(),
+                            Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+                            vec![Ipv4Network::new(Ipv4Addr::LOCALHOST, 32).unwrap()],
+                            config,
+                            stats.clone(),
+                            Arc::new(LiveStats::new(vec!["flaky", "flaky"])),
+                            Default::default(),
+                            Default::default(),
+            ).await.unwrap();
+
+            let failed = serde_json::to_value(stats.lock().await.server()).unwrap()["releases_failed"].as_u64().unwrap();
+            (releases.load(Ordering::SeqCst), other.load(Ordering::SeqCst), failed)
+        }
+    };
+
+    assert_eq!(release(0).await, (1, 1, 0));
+    assert_eq!(release(2).await, (3, 1, 0)); // confirmed by the last retry
+    assert_eq!(release(5).await, (3, 1, 1)); // given up, the other source still released
+}
+
+#[tokio::test]
+async fn test_recv_timeout_maintenance() {
+    let calls: Arc<std::sync::Mutex<Vec<&'static str>>> = Default::default();
+    let sources = crate::sources::shared(vec![Box::new(MockSource { answer: None, calls: calls.clone() })]);
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap();
+    let sending = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(180));
+        client.send_to(b"packet", address).unwrap();
+    });
+
+    let mut buf = vec![0u8; UDP_PACKET_BUFFER_SIZE];
+    let (size, _, _) = Server::next_packet(&socket, &mut buf, Some(Duration::from_millis(50)), &sources).await.unwrap();
+    sending.join().unwrap();
+
+    assert_eq!(&buf[..size], b"packet");
+    assert!(calls.lock().unwrap().iter().filter(|c| **c == "maintenance").count() >= 2);
+}
+
+#[test]
+fn test_subnet_selection() {
+    let config: DhcpConfig = serde_yaml::from_str(r#"
+sources:
+  - kind: static
+    config: {}
+    interfaces: [10.1.0.0/16]
+  - kind: static
+    config: {}
+    interfaces: [eth0, 10.2.0.0/16]
+"#).unwrap();
+    let serving = |ingress: Ingress| config.sources().iter().map(|s| s.serves(&ingress)).collect::<Vec<bool>>();
+
+    let mut bytes = test_raw_packet(packet::DHCP_DISCOVER);
+    assert_eq!(serving(Ingress::new(Some("eth0".to_string()), &bytes)), vec![false, true]);
+    packet::set_gateway(&mut bytes, Ipv4Addr::new(10, 2, 0, 1));
+    assert_eq!(serving(Ingress::new(Some("eth0".to_string()), &bytes)), vec![false, true]);
+
+    // option 118 wins over giaddr and the receiving interface
+    assert!(packet::insert_option(&mut bytes, packet::SUBNET_SELECTION, &[10, 1, 4, 0]));
+    let ingress = Ingress::new(Some("eth0".to_string()), &bytes);
+    assert_eq!(ingress.gateway, Ipv4Addr::new(10, 1, 4, 0));
+    assert_eq!(serving(ingress), vec![true, false]);
+
+    let p = DhcpMessaging::try_from(bytes.as_slice()).unwrap();
+    assert_eq!(packet_context(p.packet()).into_json()["request"]["subnet_selection"], "10.1.4.0");
+}
+
+#[tokio::test]
+async fn test_rapid_commit() {
+    let ip = Some(Ipv4Addr::new(10, 0, 0, 5));
+    let mut discover = test_raw_packet(packet::DHCP_DISCOVER);
+    assert!(packet::insert_option(&mut discover, packet::RAPID_COMMIT, &[]));
+
+    // two message exchange, the lease is committed right away
+    let stats = Arc::new(Mutex::new(DhcpStats::new(8)));
+    let (calls, reply) = TestProcess::new(ip).with_config("rapid_commit: true").with_stats(stats.clone()).run(discover.clone()).await;
+    let reply = reply.unwrap();
+    assert_eq!(calls, vec!["reserve"]);
+    assert_eq!(packet::message_type(&reply), Some(packet::DHCP_ACK));
+    assert!(packet::find_option(&reply, packet::RAPID_COMMIT).is_some());
+    assert_eq!(packet::your(&reply), ip);
+    let client = serde_json::to_value(stats.lock().await.get("01:02:03:05:06:07").unwrap()).unwrap();
+    assert_eq!(client["acks_sent"], 1);
+    assert_eq!(client["offers_sent"], 0);
+
+    // nothing reserved, the sources are asked for a regular offer
+    let (calls, reply) = TestProcess::new(None).with_config("rapid_commit: true").run(discover.clone()).await;
+    assert_eq!(calls, vec!["reserve", "offer"]);
+    assert!(reply.is_none());
+
+    // four message exchange without the option or with rapid commit disabled
+    let (calls, reply) = TestProcess::new(ip).with_config("rapid_commit: true").run(test_raw_packet(packet::DHCP_DISCOVER)).await;
+    assert_eq!(calls, vec!["offer"]);
+    assert_eq!(reply.and_then(|r| packet::message_type(&r)), Some(2));
+
+    let (calls, reply) = TestProcess::new(ip).run(discover).await;
+    let reply = reply.unwrap();
+    assert_eq!(calls, vec!["offer"]);
+    assert_eq!(packet::message_type(&reply), Some(2));
+    assert!(packet::find_option(&reply, packet::RAPID_COMMIT).is_none());
+}
+
+#[tokio::test]
+async fn test_lease_query() {
+    let leases = Arc::new(Mutex::new(LeaseTable::default()));
+    leases.lock().await.commit(Ipv4Addr::new(10, 0, 0, 5), "01:02:03:05:06:07", "mock#0", 600, SystemTime::now());
+    let query = |ciaddr: Ipv4Addr, chaddr: [u8; 6]| {
+        let mut bytes = test_raw_packet(packet::DHCP_LEASE_QUERY);
+        bytes[packet::CLIENT_OFFSET..packet::CLIENT_OFFSET + 4].copy_from_slice(&ciaddr.octets());
+        bytes[packet::CLIENT_HARDWARE_OFFSET..packet::CLIENT_HARDWARE_OFFSET + 6].copy_from_slice(&chaddr);
+        bytes
+    };
+    let ask = |bytes: Vec<u8>| TestProcess::new(None).with_config("leasequery_enabled: true").with_leases(leases.clone()).run(bytes);
+
+    // by address, the reply names the client and the seconds left
+    let (calls, reply) = ask(query(Ipv4Addr::new(10, 0, 0, 5), [0; 6])).await;
+    let reply = reply.unwrap();
+    assert!(calls.is_empty());
+    assert_eq!(packet::message_type(&reply), Some(packet::DHCP_LEASE_ACTIVE));
+    assert_eq!(packet::client_hardware(&reply), Some([1, 2, 3, 5, 6, 7]));
+    let (start, _) = packet::find_option(&reply, packet::IP_ADDRESS_LEASE_TIME).unwrap();
+    assert!((590..=600).contains(&u32::from_be_bytes([reply[start], reply[start + 1], reply[start + 2], reply[start + 3]])));
+
+    // by client, the reply names the address
+    let (_, reply) = ask(query(Ipv4Addr::UNSPECIFIED, [1, 2, 3, 5, 6, 7])).await;
+    let reply = reply.unwrap();
+    assert_eq!(packet::message_type(&reply), Some(packet::DHCP_LEASE_ACTIVE));
+    assert_eq!(packet::client(&reply), Some(Ipv4Addr::new(10, 0, 0, 5)));
+
+    // a free address of a served subnet, an address or client the server knows nothing about
+    let (_, reply) = ask(query(Ipv4Addr::LOCALHOST, [0; 6])).await;
+    assert_eq!(reply.and_then(|r| packet::message_type(&r)), Some(packet::DHCP_LEASE_UNASSIGNED));
+    let (_, reply) = ask(query(Ipv4Addr::new(10, 0, 0, 9), [0; 6])).await;
+    assert_eq!(reply.and_then(|r| packet::message_type(&r)), Some(packet::DHCP_LEASE_UNKNOWN));
+    let (_, reply) = ask(query(Ipv4Addr::UNSPECIFIED, [9; 6])).await;
+    assert_eq!(reply.and_then(|r| packet::message_type(&r)), Some(packet::DHCP_LEASE_UNKNOWN));
+}
+
+#[tokio::test]
+async fn test_infinite_lease_time() {
+    use crate::sources::mapping::INFINITE_LEASE_TIME;
+
+    let pool = r#"
+  - kind: pool
+    config:
+      start: 10.0.0.10
+      end: 10.0.0.10
+      mapping:
+        ip_address_lease_time:
+          data: infinite
+"#;
+    let lease_time = |reply: &[u8]| {
+        let (start, _) = packet::find_option(reply, packet::IP_ADDRESS_LEASE_TIME).unwrap();
+        u32::from_be_bytes([reply[start], reply[start + 1], reply[start + 2], reply[start + 3]])
+    };
+    let leases: Arc<Mutex<LeaseTable>> = Default::default();
+
+    let (_, reply) = TestProcess::new(None).with_sources(pool).run(test_raw_packet(packet::DHCP_DISCOVER)).await;
+    let reply = reply.unwrap();
+    assert_eq!(packet::message_type(&reply), Some(2));
+    assert_eq!(lease_time(&reply), INFINITE_LEASE_TIME);
+
+    // sent unclamped and recorded without expiry
+    let mut request = test_raw_packet(packet::DHCP_REQUEST);
+    assert!(packet::insert_option(&mut request, dhcplib::option::REQUESTED_IP_ADDRESS, &[10, 0, 0, 10]));
+    let (_, reply) = TestProcess::new(None).with_sources(pool).with_leases(leases.clone()).run(request).await;
+    let reply = reply.unwrap();
+    assert_eq!(packet::message_type(&reply), Some(packet::DHCP_ACK));
+    assert_eq!(lease_time(&reply), INFINITE_LEASE_TIME);
+    assert_eq!(leases.lock().await.get(Ipv4Addr::new(10, 0, 0, 10), SystemTime::now()).unwrap().expires(), None);
+}
+
+#[tokio::test]
+async fn test_request_other_server() {
+    let mut request = test_raw_packet(packet::DHCP_REQUEST);
+    assert!(packet::insert_option(&mut request, packet::SERVER_IDENTIFIER, &[10, 0, 0, 254]));
+
+    // the offer held for the client is withdrawn, nothing is reserved or sent
+    let (calls, reply) = TestProcess::new(Some(Ipv4Addr::new(10, 0, 0, 5))).run(request).await;
+    assert_eq!(calls, vec!["withdraw"]);
+    assert!(reply.is_none());
+}
+
+#[tokio::test]
+async fn test_reply_header() {
+    let ip = Some(Ipv4Addr::new(10, 0, 0, 5));
+    let mut discover = test_raw_packet(packet::DHCP_DISCOVER);
+    packet::set_secs(&mut discover, 7);
+
+    let (_, reply) = TestProcess::new(ip).with_config("reply_header:\n  flags: broadcast\n  secs: 9").run(discover.clone()).await;
+    let reply = reply.unwrap();
+    assert_eq!(packet::flags(&reply), Some(packet::BROADCAST_FLAG));
+    assert_eq!(packet::secs(&reply), Some(9));
+
+    let (_, reply) = TestProcess::new(ip).with_config("reply_header:\n  flags: copy\n  secs: copy").run(discover).await;
+    let reply = reply.unwrap();
+    assert_eq!(packet::flags(&reply), Some(0));
+    assert_eq!(packet::secs(&reply), Some(7));
+
+    // naks carry the configured header too
+    let (_, reply) = TestProcess::new(None).with_config("reply_header:\n  secs: 3").run(test_raw_packet(packet::DHCP_REQUEST)).await;
+    assert_eq!(reply.and_then(|r| packet::secs(&r)), Some(3));
+}
+
+#[tokio::test]
+async fn test_relay_agent_information_echo() {
+    let ip = Some(Ipv4Addr::new(10, 0, 0, 5));
+    let information = [packet::RELAY_CIRCUIT_ID, 4, b'e', b't', b'h', b'0'];
+
+    for message_type in [packet::DHCP_DISCOVER, packet::DHCP_REQUEST].iter() {
+        let mut bytes = test_raw_packet(*message_type);
+        assert!(packet::insert_option(&mut bytes, packet::RELAY_AGENT_INFORMATION, &information));
+        let (_, reply) = TestProcess::new(ip).run(bytes).await;
+        assert_eq!(reply.and_then(|r| packet::relay_agent_information(&r)), Some(information.to_vec()));
+    }
+
+    // naks are echoed too, replies to packets without the option have none
+    let mut bytes = test_raw_packet(packet::DHCP_REQUEST);
+    assert!(packet::insert_option(&mut bytes, packet::RELAY_AGENT_INFORMATION, &information));
+    let (_, reply) = TestProcess::new(None).run(bytes).await;
+    assert_eq!(reply.and_then(|r| packet::relay_agent_information(&r)), Some(information.to_vec()));
+    let (_, reply) = TestProcess::new(ip).run(test_raw_packet(packet::DHCP_DISCOVER)).await;
+    assert_eq!(reply.and_then(|r| packet::relay_agent_information(&r)), None);
+}
+
+#[tokio::test]
+async fn test_mac_filter_drop() {
+    let ip = Some(Ipv4Addr::new(10, 0, 0, 5));
+
+    let (calls, reply) = TestProcess::new(ip).with_config("mac_filter:\n  deny: [\"01:02:03:*\"]").run(test_raw_packet(packet::DHCP_DISCOVER)).await;
+    assert!(calls.is_empty());
+    assert!(reply.is_none());
+
+    let (calls, reply) = TestProcess::new(ip).with_config("mac_filter:\n  allow: [\"01:02:03:*\"]").run(test_raw_packet(packet::DHCP_DISCOVER)).await;
+    assert_eq!(calls, vec!["offer"]);
+    assert!(reply.is_some());
+}
+
+#[tokio::test]
+async fn test_client_rate_limit() {
+    let ip = Some(Ipv4Addr::new(10, 0, 0, 5));
+    let config: DhcpConfig = serde_yaml::from_str("rate_limit: { rate: 0.1, burst: 2 }\nsources: []").unwrap();
+    let limiter = Arc::new(Mutex::new(ClientRateLimiter::new(config.rate_limit())));
+    let discover = |mac: u8| {
+        let mut bytes = test_raw_packet(packet::DHCP_DISCOVER);
+        bytes[packet::CLIENT_HARDWARE_OFFSET + 5] = mac;
+        bytes
+    };
+
+    for answered in [true, true, false].iter() {
+        let (calls, reply) = TestProcess::new(ip).with_limiter(limiter.clone()).run(discover(7)).await;
+        assert_eq!(!calls.is_empty(), *answered);
+        assert_eq!(reply.is_some(), *answered);
+    }
+
+    // other clients are not held up by the flood
+    let (calls, _) = TestProcess::new(ip).with_limiter(limiter).run(discover(8)).await;
+    assert_eq!(calls, vec!["offer"]);
+}
+
+#[tokio::test]
+async fn test_lease_table_conflict() {
+    let ip = Some(Ipv4Addr::new(10, 0, 0, 5));
+    let leases: Arc<Mutex<LeaseTable>> = Default::default();
+    leases.lock().await.commit(Ipv4Addr::new(10, 0, 0, 5), "aa:bb:cc:dd:ee:ff", "mock#0", 3600, SystemTime::now());
+
+    // held by another client, no offer and a nak for the request
+    let (_, reply) = TestProcess::new(ip).with_leases(leases.clone()).run(test_raw_packet(packet::DHCP_DISCOVER)).await;
+    assert!(reply.is_none());
+    let (_, reply) = TestProcess::new(ip).with_leases(leases.clone()).run(test_raw_packet(packet::DHCP_REQUEST)).await;
+    assert_eq!(reply.and_then(|r| packet::message_type(&r)), Some(6));
+
+    // freed by the release of the holder, the ack commits the lease
+    leases.lock().await.release("aa:bb:cc:dd:ee:ff");
+    let (_, reply) = TestProcess::new(ip).with_leases(leases.clone()).run(test_raw_packet(packet::DHCP_REQUEST)).await;
+    assert_eq!(reply.and_then(|r| packet::message_type(&r)), Some(packet::DHCP_ACK));
+    assert!(leases.lock().await.holder(Ipv4Addr::new(10, 0, 0, 5), "aa:bb:cc:dd:ee:ff", SystemTime::now()).is_some());
+}
+
+#[tokio::test]
+async fn test_serve_subnets() {
+    let ip = Some(Ipv4Addr::new(10, 1, 0, 5));
+    let relayed = |giaddr: Ipv4Addr| {
+        let mut bytes = test_raw_packet(packet::DHCP_DISCOVER);
+        packet::set_gateway(&mut bytes, giaddr);
+        bytes
+    };
+    let unserved = |stats: &DhcpStats| serde_json::to_value(stats.server()).unwrap()["unserved_dropped"].as_u64().unwrap();
+
+    let stats = Arc::new(Mutex::new(DhcpStats::new(8)));
+    let (calls, _) = TestProcess::new(ip).with_config("serve_subnets: [10.1.0.0/16, 10.3.0.0/16]").with_stats(stats.clone()).run(relayed(Ipv4Addr::new(10, 1, 4, 1))).await;
+    assert_eq!(calls, vec!["offer"]);
+    assert_eq!(unserved(&*stats.lock().await), 0);
+
+    let (calls, reply) = TestProcess::new(ip).with_config("serve_subnets: [10.1.0.0/16, 10.3.0.0/16]").with_stats(stats.clone()).run(relayed(Ipv4Addr::new(10, 2, 4, 1))).await;
+    assert!(calls.is_empty());
+    assert!(reply.is_none());
+    assert_eq!(unserved(&*stats.lock().await), 1);
+
+    // direct packets by the receiving interface, 127.0.0.1/32 in the tests
+    let (calls, _) = TestProcess::new(ip).with_config("serve_subnets: [127.0.0.0/8]").with_stats(stats.clone()).run(test_raw_packet(packet::DHCP_DISCOVER)).await;
+    assert_eq!(calls, vec!["offer"]);
+    let (calls, _) = TestProcess::new(ip).with_config("serve_subnets: [10.1.0.0/16]").with_stats(stats.clone()).run(test_raw_packet(packet::DHCP_DISCOVER)).await;
+    assert!(calls.is_empty());
+    assert_eq!(unserved(&*stats.lock().await), 2);
+
+    assert!(DhcpConfig::from_reader("serve_subnets: [10.1.0.0/33]\nsources: []".as_bytes()).is_err());
+}
+
+#[tokio::test]
+async fn test_address_conflict() {
+    let ip = Some(Ipv4Addr::new(10, 0, 0, 5));
+    let other = |message_type: u8| {
+        let mut bytes = test_raw_packet(message_type);
+        bytes[packet::CLIENT_HARDWARE_OFFSET + 5] = 8;
+        bytes
+    };
+    let conflicts = |stats: &DhcpStats| serde_json::to_value(stats.server()).unwrap()["address_conflicts"].as_u64().unwrap();
+    let config = "conflict_detection:\n  skip: true";
+
+    let stats = Arc::new(Mutex::new(DhcpStats::new(8)));
+    let (_, reply) = TestProcess::new(ip).with_config(config).with_stats(stats.clone()).run(test_raw_packet(packet::DHCP_DISCOVER)).await;
+    assert!(reply.is_some());
+    let (_, reply) = TestProcess::new(ip).with_config(config).with_stats(stats.clone()).run(test_raw_packet(packet::DHCP_DISCOVER)).await;
+    assert!(reply.is_some()); // same client again
+
+    // the same address for another client is skipped, its request nak'd
+    let (calls, reply) = TestProcess::new(ip).with_config(config).with_stats(stats.clone()).run(other(packet::DHCP_DISCOVER)).await;
+    assert_eq!(calls, vec!["offer"]);
+    assert!(reply.is_none());
+    let (_, reply) = TestProcess::new(ip).with_config(config).with_stats(stats.clone()).run(other(packet::DHCP_REQUEST)).await;
+    assert_eq!(reply.and_then(|r| packet::message_type(&r)), Some(6));
+    assert_eq!(conflicts(&*stats.lock().await), 2);
+
+    // free again once released
+    TestProcess::new(ip).with_config(config).with_stats(stats.clone()).run(test_raw_packet(7)).await;
+    let (_, reply) = TestProcess::new(ip).with_config(config).with_stats(stats.clone()).run(other(packet::DHCP_DISCOVER)).await;
+    assert!(reply.is_some());
+
+    // only counted without skip
+    let stats = Arc::new(Mutex::new(DhcpStats::new(8)));
+    TestProcess::new(ip).with_config("conflict_detection: {}").with_stats(stats.clone()).run(test_raw_packet(packet::DHCP_DISCOVER)).await;
+    let (_, reply) = TestProcess::new(ip).with_config("conflict_detection: {}").with_stats(stats.clone()).run(other(packet::DHCP_DISCOVER)).await;
+    assert!(reply.is_some());
+    assert_eq!(conflicts(&*stats.lock().await), 1);
 }
@@ -0,0 +1,167 @@
+use crate::sources::{DhcpHostSource, DhcpSourceResult, SourceEntries};
+use crate::config::Limits;
+use crate::sources::mapping::{DhcpMapping, packet_context};
+use crate::error::{DhcpResult, DhcpError};
+use serde::{Deserializer, Deserialize};
+use serde::de::DeserializeOwned;
+use dhcplib::DhcpPacket;
+use dhcplib::option::{IP_ADDRESS_LEASE_TIME, REQUESTED_IP_ADDRESS};
+use std::net::Ipv4Addr;
+
+/// Address allocation without option handling, wrapped into a source by `DhcpAllocatorSource`
+#[async_trait::async_trait]
+pub trait AddressAllocator: Send {
+    fn name(&self) -> &'static str;
+
+    /// Address to offer, `None` if exhausted
+    async fn allocate(&mut self, p: &DhcpPacket) -> DhcpResult<Option<Ipv4Addr>>;
+
+    /// Address `allocate` would offer, nothing is held for the client
+    async fn peek(&mut self, p: &DhcpPacket) -> DhcpResult<Option<Ipv4Addr>>;
+
+    /// Address requested by the client (option 50) if it may keep it, `None` naks the request
+    async fn confirm(&mut self, p: &DhcpPacket) -> DhcpResult<Option<Ipv4Addr>>;
+
+    /// Records the confirmed address for `lease_time` seconds (the allocator's default if `None`), `false` naks the request
+    async fn commit(&mut self, _: &DhcpPacket, _: Ipv4Addr, _: Option<u32>) -> DhcpResult<bool> { Ok(true) }
+
+    async fn release(&mut self, p: &DhcpPacket) -> DhcpResult<()>;
+
+    /// Drops the address held for an offer the client turned down, its leases stay
+    async fn withdraw(&mut self, _: &DhcpPacket) -> DhcpResult<()> { Ok(()) }
+
+    /// Address declined by a client (in use by someone else), never allocated again
+    async fn blacklist(&mut self, ip: Ipv4Addr) -> DhcpResult<()>;
+
+    /// Cap on leases held, beyond it expired leases and then the least recently used offer are evicted
+    fn max_leases(&mut self, _: usize) {}
+
+    /// Drops expired leases and offers, returns how many were dropped
+    fn expire(&mut self) -> usize { 0 }
+
+    /// Leases currently held
+    fn leases(&self) -> usize { 0 }
+}
+
+#[derive(Deserialize)]
+struct DhcpAllocatorSourceConfig<A> {
+    #[serde(flatten)]
+    allocator: A,
+    #[serde(default)]
+    mapping: DhcpMapping,
+}
+
+/// Source answering with the allocated address and the options of a static mapping
+pub(crate) struct DhcpAllocatorSource<A> {
+    allocator: A,
+    mapping: DhcpMapping,
+    strict_mappings: bool,
+}
+
+impl<A: AddressAllocator> DhcpAllocatorSource<A> {
+    pub fn new(allocator: A, mapping: DhcpMapping) -> Self {
+        Self {
+            allocator,
+            mapping,
+            strict_mappings: false,
+        }
+    }
+
+    fn result(&self, p: &DhcpPacket, ip: Option<Ipv4Addr>) -> DhcpResult<Option<DhcpSourceResult>> {
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return Ok(None),
+        };
+
+        let mut context = packet_context(p);
+        context.insert("allocated_ip_address", &ip);
+
+        let mapped = self.mapping.to_result(&context, self.strict_mappings)?;
+        let next_server = *mapped.next_server();
+        Ok(Some(DhcpSourceResult::new(Some(ip), mapped.into()).with_next_server(next_server)))
+    }
+}
+
+#[async_trait::async_trait]
+impl<A: AddressAllocator + DeserializeOwned> DhcpHostSource for DhcpAllocatorSource<A> {
+    fn name(&self) -> &'static str {
+        self.allocator.name()
+    }
+
+    async fn offer(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        let ip = self.allocator.allocate(p).await?;
+        self.result(p, ip)
+    }
+
+    async fn reserve(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        let ip = match self.allocator.confirm(p).await? {
+            Some(ip) => ip,
+            None => return Ok(None),
+        };
+
+        let result = self.result(p, Some(ip))?;
+        let lease_time = result.as_ref().and_then(|r| r.options().try_u32_option(IP_ADDRESS_LEASE_TIME).ok());
+        if !self.allocator.commit(p, ip, lease_time).await? {
+            return Ok(None);
+        }
+        Ok(result)
+    }
+
+    async fn preview(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        let ip = self.allocator.peek(p).await?;
+        self.result(p, ip)
+    }
+
+    async fn release(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        self.allocator.release(p).await
+    }
+
+    async fn withdraw(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        self.allocator.withdraw(p).await
+    }
+
+    async fn decline(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        match p.options().try_ipv4_option(REQUESTED_IP_ADDRESS) {
+            Ok(ip) => self.allocator.blacklist(ip).await,
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// The client keeps its address (ciaddr), nothing is allocated
+    async fn inform(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        let ip = Some(*p.client()).filter(|ip| !ip.is_unspecified());
+        self.result(p, ip)
+    }
+
+    fn from_config<'a, T: Deserializer<'a> + Send>(config: T) -> DhcpResult<Self> where Self: Sized {
+        let config: DhcpAllocatorSourceConfig<A> = Deserialize::deserialize(config).map_err(|e| DhcpError::SerdeErrorString(e.to_string()))?;
+        Ok(Self::new(config.allocator, config.mapping))
+    }
+
+    fn strict_mappings(&mut self, strict: bool) {
+        self.strict_mappings = strict;
+    }
+
+    fn limits(&mut self, limits: &Limits) {
+        if let Some(max) = limits.lease_entries() {
+            self.allocator.max_leases(max);
+        }
+    }
+
+    async fn entries(&mut self) -> SourceEntries {
+        SourceEntries { cache_entries: 0, lease_entries: self.allocator.leases() }
+    }
+
+    /// Drops expired leases and offers of the allocator
+    async fn maintenance(&mut self) -> DhcpResult<()> {
+        let expired = self.allocator.expire();
+        if expired > 0 {
+            log::debug!("{} dropped {} expired leases", self.allocator.name(), expired);
+        }
+        Ok(())
+    }
+
+    fn check_templates(&self) -> DhcpResult<()> {
+        self.mapping.check_templates()
+    }
+}
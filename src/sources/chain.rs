@@ -0,0 +1,244 @@
+use crate::sources::{DhcpHostSource, DhcpSourceResult, BoxedDhcpHostSource, SourceEntries};
+use crate::config::{Limits, Sources};
+use crate::error::{DhcpResult, DhcpError};
+use serde::{Deserializer, Deserialize};
+use dhcplib::DhcpPacket;
+use crate::rate_limit::ConcurrencyLimiter;
+use std::collections::HashMap;
+
+#[derive(Deserialize)]
+struct DhcpChainSourceConfig {
+    sources: Vec<Sources>,
+}
+
+/// Merges the results of all child sources, later children override options of earlier ones
+pub(crate) struct DhcpChainSource {
+    sources: Vec<BoxedDhcpHostSource>,
+}
+
+enum DhcpChainOperation {
+    Offer,
+    Preview,
+    Reserve,
+    Inform,
+}
+
+impl DhcpChainSource {
+    pub const NAME: &'static str = "chain";
+
+    async fn merge(&mut self, operation: DhcpChainOperation, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        let mut merged: Option<DhcpSourceResult> = None;
+
+        for (index, source) in self.sources.iter_mut().enumerate() {
+            let result = match operation {
+                DhcpChainOperation::Offer => source.offer(p).await,
+                DhcpChainOperation::Preview => source.preview(p).await,
+                DhcpChainOperation::Reserve => source.reserve(p).await,
+                DhcpChainOperation::Inform => source.inform(p).await,
+            };
+
+            match result {
+                Ok(Some(result)) => {
+                    let result = result.label(&format!("{}#{}", source.name(), index));
+                    match merged.as_mut() {
+                        Some(m) => m.merge(result),
+                        None => merged = Some(result),
+                    }
+                }
+                Ok(None) => log::debug!("{} not found in chained source {}", p.client_hardware(), source.name()),
+                // the chain answers as one source, a backend rejecting the client rejects it
+                Err(DhcpError::Rejected(reason)) => return Err(DhcpError::Rejected(reason)),
+                Err(e) => log::error!("{}", e),
+            }
+        }
+
+        Ok(merged)
+    }
+}
+
+#[async_trait::async_trait]
+impl DhcpHostSource for DhcpChainSource {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    async fn offer(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        self.merge(DhcpChainOperation::Offer, p).await
+    }
+
+    async fn preview(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        self.merge(DhcpChainOperation::Preview, p).await
+    }
+
+    async fn reserve(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        self.merge(DhcpChainOperation::Reserve, p).await
+    }
+
+    async fn release(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        for source in self.sources.iter_mut() {
+            source.release(p).await?;
+        }
+        Ok(())
+    }
+
+    async fn decline(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        for source in self.sources.iter_mut() {
+            source.decline(p).await?;
+        }
+        Ok(())
+    }
+
+    async fn inform(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        self.merge(DhcpChainOperation::Inform, p).await
+    }
+
+    async fn withdraw(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        for source in self.sources.iter_mut() {
+            source.withdraw(p).await?;
+        }
+        Ok(())
+    }
+
+    fn from_config<'a, T: Deserializer<'a> + Send>(config: T) -> DhcpResult<Self> where Self: Sized {
+        let config: DhcpChainSourceConfig = Deserialize::deserialize(config).map_err(|e| DhcpError::SerdeErrorString(e.to_string()))?;
+
+        Ok(Self {
+            sources: config.sources.iter().map(Sources::init).collect::<DhcpResult<_>>()?
+        })
+    }
+
+    async fn packet_received(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        for source in self.sources.iter_mut() {
+            source.packet_received(p).await?;
+        }
+        Ok(())
+    }
+
+    async fn packet_sending(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        for source in self.sources.iter_mut() {
+            source.packet_sending(p).await?;
+        }
+        Ok(())
+    }
+
+    async fn packet_sent(&mut self) -> DhcpResult<()> {
+        for source in self.sources.iter_mut() {
+            source.packet_sent().await?;
+        }
+        Ok(())
+    }
+
+    fn strict_mappings(&mut self, strict: bool) {
+        for source in self.sources.iter_mut() {
+            source.strict_mappings(strict);
+        }
+    }
+
+    fn limits(&mut self, limits: &Limits) {
+        for source in self.sources.iter_mut() {
+            source.limits(limits);
+        }
+    }
+
+    async fn entries(&mut self) -> SourceEntries {
+        let mut entries = SourceEntries::default();
+        for source in self.sources.iter_mut() {
+            entries.add(source.entries().await);
+        }
+        entries
+    }
+
+    fn script_limiter(&mut self, limiter: ConcurrencyLimiter) {
+        for source in self.sources.iter_mut() {
+            source.script_limiter(limiter.clone());
+        }
+    }
+
+    fn http_clients(&mut self, clients: &HashMap<String, reqwest::Client>) -> DhcpResult<()> {
+        for source in self.sources.iter_mut() {
+            source.http_clients(clients)?;
+        }
+        Ok(())
+    }
+
+    async fn maintenance(&mut self) -> DhcpResult<()> {
+        for source in self.sources.iter_mut() {
+            source.maintenance().await?;
+        }
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> DhcpResult<()> {
+        for source in self.sources.iter_mut() {
+            source.shutdown().await?;
+        }
+        Ok(())
+    }
+
+    fn check_templates(&self) -> DhcpResult<()> {
+        self.sources.iter().try_for_each(|source| source.check_templates())
+    }
+}
+
+/// Rejects every client, or fails without a rejection
+#[cfg(test)]
+struct FailingSource(fn() -> DhcpError);
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl DhcpHostSource for FailingSource {
+    fn name(&self) -> &'static str { "failing" }
+
+    async fn offer(&mut self, _: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> { Err((self.0)()) }
+
+    async fn reserve(&mut self, _: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> { Err((self.0)()) }
+
+    async fn release(&mut self, _: &DhcpPacket) -> DhcpResult<()> { Ok(()) }
+
+    async fn decline(&mut self, _: &DhcpPacket) -> DhcpResult<()> { Ok(()) }
+
+    async fn inform(&mut self, _: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> { Err((self.0)()) }
+
+    fn from_config<'a, T: Deserializer<'a> + Send>(_: T) -> DhcpResult<Self> where Self: Sized {
+        Ok(Self(|| DhcpError::ChaosInjected))
+    }
+}
+
+#[tokio::test]
+async fn test_chain() {
+    use dhcplib::option::{SUBNET_MASK, DOMAIN_NAME_SERVER};
+    use std::net::Ipv4Addr;
+
+    let config: serde_yaml::Value = serde_yaml::from_str(r#"
+sources:
+  - kind: static
+    config:
+      hosts:
+        "01:02:03:05:06:07":
+          subnet_mask:
+            data: 255.255.0.0
+  - kind: static
+    config:
+      hosts:
+        "01:02:03:05:06:07":
+          client_ip_address: 10.0.0.5
+          subnet_mask:
+            data: 255.255.255.0
+          domain_name_server:
+            data: 10.0.0.2
+"#).unwrap();
+    let mut chain = DhcpChainSource::from_config(config).unwrap();
+    let p = crate::sources::test_packet(0, vec![]);
+
+    let result = chain.offer(&p).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 5)));
+    assert_eq!(result.options().try_ipv4_option(SUBNET_MASK).unwrap(), Ipv4Addr::new(255, 255, 255, 0));
+    assert_eq!(result.provenance().get(&DOMAIN_NAME_SERVER).unwrap(), "static#1");
+    assert!(chain.offer(&crate::sources::test_packet_from_mac([9, 9, 9, 9, 9, 9])).await.unwrap().is_none());
+
+    // a failing child is skipped, a rejecting child rejects the client
+    chain.sources.insert(0, Box::new(FailingSource(|| DhcpError::ChaosInjected)));
+    assert!(chain.reserve(&p).await.unwrap().is_some());
+    chain.sources.push(Box::new(FailingSource(|| DhcpError::Rejected("blocked".to_string()))));
+    assert!(matches!(chain.reserve(&p).await, Err(DhcpError::Rejected(reason)) if reason == "blocked"));
+}
@@ -0,0 +1,189 @@
+//! Fault injection around a source, exercises timeouts and retries without a broken backend
+
+use crate::sources::{DhcpHostSource, DhcpSourceResult, BoxedDhcpHostSource, SourceEntries};
+use crate::config::Limits;
+use crate::error::{DhcpResult, DhcpError};
+use crate::rate_limit::ConcurrencyLimiter;
+use serde::{Serialize, Deserialize, Deserializer};
+use dhcplib::DhcpPacket;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Faults injected into the operations of a source, only if configured
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ChaosConfig {
+    /// upper bound of the random delay before each operation
+    #[serde(default)]
+    latency_ms: u64,
+    /// share of failing operations from 0.0 to 1.0
+    #[serde(default)]
+    error_rate: f64,
+    /// fixed seed for reproducible runs
+    seed: Option<u64>,
+}
+
+impl ChaosConfig {
+    pub(crate) fn validate(&self) -> DhcpResult<()> {
+        if !(0.0..=1.0).contains(&self.error_rate) {
+            return Err(DhcpError::ConfigInvalid(format!("chaos error_rate {} not between 0 and 1", self.error_rate)));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct DhcpChaosSource {
+    source: BoxedDhcpHostSource,
+    chaos: ChaosConfig,
+    state: u64,
+}
+
+impl DhcpChaosSource {
+    pub(crate) fn new(source: BoxedDhcpHostSource, chaos: ChaosConfig) -> Self {
+        let seed = chaos.seed.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or_default());
+
+        Self {
+            source,
+            chaos,
+            state: seed | 1, // xorshift never leaves zero
+        }
+    }
+
+    /// xorshift64, uniform in [0, 1)
+    fn random(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    async fn inject(&mut self, operation: &str) -> DhcpResult<()> {
+        if self.chaos.latency_ms > 0 {
+            let latency = (self.random() * (self.chaos.latency_ms + 1) as f64) as u64;
+            tokio::time::sleep(Duration::from_millis(latency)).await;
+        }
+
+        if self.random() < self.chaos.error_rate {
+            log::debug!("{} {} failed by chaos", self.source.name(), operation);
+            return Err(DhcpError::ChaosInjected);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl DhcpHostSource for DhcpChaosSource {
+    fn name(&self) -> &'static str {
+        self.source.name()
+    }
+
+    async fn offer(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        self.inject("offer").await?;
+        self.source.offer(p).await
+    }
+
+    /// The wrapped source answers without injected faults
+    async fn preview(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        self.source.preview(p).await
+    }
+
+    async fn reserve(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        self.inject("reserve").await?;
+        self.source.reserve(p).await
+    }
+
+    async fn release(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        self.inject("release").await?;
+        self.source.release(p).await
+    }
+
+    async fn decline(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        self.inject("decline").await?;
+        self.source.decline(p).await
+    }
+
+    async fn inform(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        self.inject("inform").await?;
+        self.source.inform(p).await
+    }
+
+    async fn withdraw(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        self.source.withdraw(p).await
+    }
+
+    /// Only built around a configured source
+    fn from_config<'a, T: Deserializer<'a> + Send>(_: T) -> DhcpResult<Self> where Self: Sized {
+        Err(DhcpError::ConfigInvalid("chaos is configured on a source, not as one".to_string()))
+    }
+
+    async fn packet_received(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        self.source.packet_received(p).await
+    }
+
+    async fn packet_sending(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        self.source.packet_sending(p).await
+    }
+
+    async fn packet_sent(&mut self) -> DhcpResult<()> {
+        self.source.packet_sent().await
+    }
+
+    fn strict_mappings(&mut self, strict: bool) {
+        self.source.strict_mappings(strict)
+    }
+
+    fn limits(&mut self, limits: &Limits) {
+        self.source.limits(limits)
+    }
+
+    async fn entries(&mut self) -> SourceEntries {
+        self.source.entries().await
+    }
+
+    fn script_limiter(&mut self, limiter: ConcurrencyLimiter) {
+        self.source.script_limiter(limiter)
+    }
+
+    fn http_clients(&mut self, clients: &HashMap<String, reqwest::Client>) -> DhcpResult<()> {
+        self.source.http_clients(clients)
+    }
+
+    async fn maintenance(&mut self) -> DhcpResult<()> {
+        self.source.maintenance().await
+    }
+
+    async fn shutdown(&mut self) -> DhcpResult<()> {
+        self.source.shutdown().await
+    }
+
+    fn check_templates(&self) -> DhcpResult<()> {
+        self.source.check_templates()
+    }
+}
+
+#[tokio::test]
+async fn test_chaos_error_rate() {
+    use crate::sources::static_file::DhcpStaticSource;
+
+    let chaos = |yaml: &str| -> DhcpChaosSource {
+        let source = DhcpStaticSource::from_config(serde_yaml::from_str::<serde_yaml::Value>("{}").unwrap()).unwrap();
+        DhcpChaosSource::new(Box::new(source), serde_yaml::from_str(yaml).unwrap())
+    };
+    let p = crate::sources::test_packet(0, vec![]);
+
+    let mut s = chaos("error_rate: 0.3\nseed: 42");
+    let mut failed = 0;
+    for _ in 0..10000 {
+        match s.offer(&p).await {
+            Err(DhcpError::ChaosInjected) => failed += 1,
+            result => assert!(result.unwrap().is_none()),
+        }
+    }
+    assert!((2700..3300).contains(&failed), "{} of 10000 failed", failed);
+
+    let mut s = chaos("error_rate: 0.0");
+    assert!(s.offer(&p).await.is_ok());
+    let mut s = chaos("error_rate: 1.0");
+    assert!(matches!(s.offer(&p).await, Err(DhcpError::ChaosInjected)));
+
+    assert!(serde_yaml::from_str::<ChaosConfig>("error_rate: 1.5").unwrap().validate().is_err());
+}
@@ -0,0 +1,994 @@
+use crate::sources::DhcpSourceResult;
+use serde::{Serialize, Deserialize};
+use crate::error::{DhcpResult, DhcpError};
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use tera::Context;
+use dhcplib::option::{DhcpOption, DhcpOptions, HOST_NAME, CLIENT_IDENTIFIER, SERVER_IDENTIFIER, REQUESTED_IP_ADDRESS, RENEWAL_TIME_VALUE, REBINDING_TIME_VALUE, IP_ADDRESS_LEASE_TIME};
+use crate::config::HostnamePolicy;
+use serde_yaml::Value;
+use serde_yaml::from_value as serde_from_value;
+use dhcplib::DhcpPacket;
+use std::net::Ipv4Addr;
+use pnet::ipnetwork::Ipv4Network;
+use std::fs::File;
+
+macro_rules! to_value {
+    ($t:ident, $v:tt) => {
+        $v.try_into().and_then(|s: DhcpMappingItem| serde_from_value(s.data)
+                     .map(DhcpOption::$t)
+                     .map_err(Into::into))
+    };
+    ($t:ident, $v:tt, list) => {
+        $v.try_into().and_then(|s: DhcpMappingItem| serde_from_value(as_list(s.data))
+                     .map(DhcpOption::$t)
+                     .map_err(Into::into))
+    };
+}
+
+/// Wraps a single value into a one element list
+fn as_list(value: Value) -> Value {
+    match value {
+        Value::Sequence(_) | Value::Null => value,
+        v => Value::Sequence(vec![v]),
+    }
+}
+
+pub(crate) const CLIENT_SYSTEM_ARCHITECTURE: u8 = 93;
+pub(crate) const CLIENT_NETWORK_INTERFACE_IDENTIFIER: u8 = 94;
+pub(crate) const CLIENT_MACHINE_IDENTIFIER: u8 = 97;
+pub(crate) const STATIC_ROUTE: u8 = 33;
+pub(crate) const CLASSLESS_STATIC_ROUTE: u8 = 121;
+pub(crate) const CAPTIVE_PORTAL: u8 = 114;
+pub(crate) const USER_CLASS: u8 = 77;
+
+const RESULTS_PREFIX: &str = "results.";
+
+/// Lease time sentinel for a lease that never expires
+pub(crate) const INFINITE_LEASE_TIME: u32 = 0xFFFFFFFF;
+
+/// Lease time in seconds or `infinite`/`permanent`
+fn lease_time(value: Value) -> DhcpResult<DhcpOption> {
+    let item: DhcpMappingItem = value.try_into()?;
+
+    let seconds = match &item.data {
+        Value::String(s) if s.eq_ignore_ascii_case("infinite") || s.eq_ignore_ascii_case("permanent") => INFINITE_LEASE_TIME,
+        _ => serde_from_value(item.data)?,
+    };
+
+    Ok(DhcpOption::IpAddressLeaseTime(seconds))
+}
+
+/// Renewal (T1) before rebinding (T2) before lease expiry, as far as set (RFC 2131 4.4.5)
+fn lease_times(options: &DhcpOptions) -> DhcpResult<()> {
+    let times = [
+        ("renewal_time_value", options.try_u32_option(RENEWAL_TIME_VALUE).ok()),
+        ("rebinding_time_value", options.try_u32_option(REBINDING_TIME_VALUE).ok()),
+        ("ip_address_lease_time", options.try_u32_option(IP_ADDRESS_LEASE_TIME).ok()),
+    ];
+    let set = times.iter().filter_map(|(name, time)| time.map(|t| (*name, t))).collect::<Vec<(&str, u32)>>();
+
+    match set.windows(2).find(|w| w[0].1 >= w[1].1) {
+        Some(w) => Err(DhcpError::InvalidLeaseTimes(format!("{} ({}) not below {} ({})", w[0].0, w[0].1, w[1].0, w[1].1))),
+        None => Ok(()),
+    }
+}
+
+#[derive(Deserialize)]
+struct DhcpRoute {
+    dest: String,
+    gateway: Ipv4Addr,
+}
+
+/// Prefix length implied by the address class, `None` for class d/e
+fn classful_prefix(ip: Ipv4Addr) -> Option<u8> {
+    match ip.octets()[0] {
+        0..=127 => Some(8),
+        128..=191 => Some(16),
+        192..=223 => Some(24),
+        _ => None,
+    }
+}
+
+/// Routes as classless static routes (option 121, RFC 3442) and, where expressible, static routes (option 33).
+/// Option 33 only carries classful non default routes, clients supporting option 121 ignore it
+fn routes(value: Value) -> DhcpResult<Vec<DhcpOption>> {
+    let item: DhcpMappingItem = value.try_into()?;
+    let routes: Vec<DhcpRoute> = serde_from_value(as_list(item.data))?;
+
+    let mut classless = vec![];
+    let mut classful = vec![];
+
+    for route in routes {
+        let dest = route.dest.parse::<Ipv4Network>()?;
+        let network = dest.network().octets();
+        let significant = (dest.prefix() as usize + 7) / 8;
+
+        classless.push(dest.prefix());
+        classless.extend_from_slice(&network[..significant]);
+        classless.extend_from_slice(&route.gateway.octets());
+
+        if dest.prefix() > 0 && classful_prefix(dest.network()) == Some(dest.prefix()) {
+            classful.extend_from_slice(&network);
+            classful.extend_from_slice(&route.gateway.octets());
+        }
+    }
+
+    let mut options = vec![DhcpOption::Unknown(CLASSLESS_STATIC_ROUTE, classless)];
+    if !classful.is_empty() {
+        options.push(DhcpOption::Unknown(STATIC_ROUTE, classful));
+    }
+    Ok(options)
+}
+
+/// Captive portal uri (option 114, RFC 8910)
+pub(crate) fn captive_portal(url: &str) -> DhcpResult<DhcpOption> {
+    let url = url::Url::parse(url)?;
+    Ok(DhcpOption::Unknown(CAPTIVE_PORTAL, url.as_str().as_bytes().to_vec()))
+}
+
+/// Renders all strings of the value, `name` identifies the value in errors
+pub(crate) fn template_values<'a>(value: &'a mut serde_yaml::Value, context: &'a Context, name: &str) -> DhcpResult<&'a mut serde_yaml::Value> {
+    match value {
+        Value::String(s) => {
+            let t = tera::Tera::one_off(s, context, false).map_err(|e| {
+                if log::log_enabled!(log::Level::Debug) {
+                    let keys = context.clone().into_json().as_object()
+                        .map(|o| o.keys().cloned().collect::<Vec<String>>())
+                        .unwrap_or_default();
+                    log::debug!("templating {} failed, available variables: {}", name, keys.join(", "));
+                }
+                DhcpError::TemplateError { option: name.to_string(), template: s.clone(), field: None, source: e }
+            })?;
+            *value = serde_yaml::from_str(&t)?;
+        }
+        Value::Sequence(v) => {
+            for i in v {
+                template_values(i, context, name)?;
+            }
+        }
+        Value::Mapping(v) => {
+            for (_, v) in v {
+                template_values(v, context, name)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(value)
+}
+
+/// Compiles all strings of the value without rendering, catches template syntax errors
+pub(crate) fn check_templates(value: &Value, name: &str) -> DhcpResult<()> {
+    match value {
+        Value::String(s) => {
+            tera::Tera::default().add_raw_template(name, s)
+                .map_err(|e| DhcpError::TemplateError { option: name.to_string(), template: s.clone(), field: None, source: e })?;
+        }
+        Value::Sequence(v) => v.iter().try_for_each(|v| check_templates(v, name))?,
+        Value::Mapping(v) => v.iter().try_for_each(|(_, v)| check_templates(v, name))?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// First template string of the value referencing a query result
+fn result_template(value: &Value) -> Option<&str> {
+    match value {
+        Value::String(s) if s.contains(RESULTS_PREFIX) => Some(s),
+        Value::Sequence(v) => v.iter().find_map(result_template),
+        Value::Mapping(m) => m.iter().find_map(|(_, v)| result_template(v)),
+        _ => None,
+    }
+}
+
+/// Query result field (`results.<query>.<field>`) the template of a mapping reads, as `query '<query>' field '<field>'`
+/// followed by `missing` if the results lack it or by its json pointer into the query result otherwise
+pub(crate) fn result_field(template: &Value, context: &Context) -> Option<String> {
+    let template = result_template(template)?;
+    let start = template.find(RESULTS_PREFIX)? + RESULTS_PREFIX.len();
+    let path = template[start..].chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '_' || *c == '.')
+        .collect::<String>();
+    let (query, field) = path.trim_end_matches('.').split_once('.')?;
+
+    let pointer = format!("/{}", field.replace('.', "/"));
+    let found = context.get("results")
+        .and_then(|r| r.get(query))
+        .map_or(false, |r| r.pointer(&pointer).is_some());
+
+    if found {
+        Some(format!("query '{}' field '{}' ({})", query, field, pointer))
+    } else {
+        Some(format!("query '{}' field '{}' missing", query, field))
+    }
+}
+
+/// Names the query result field behind a failed mapping, errors of mappings not reading a result are kept
+fn with_result_field(e: DhcpError, option: &str, template: &Value, context: &Context) -> DhcpError {
+    let field = match result_field(template, context) {
+        Some(field) => field,
+        None => return e,
+    };
+
+    match e {
+        DhcpError::TemplateError { option, template, source, .. } => DhcpError::TemplateError { option, template, field: Some(field), source },
+        e => DhcpError::MappingFieldError { option: option.to_string(), field, source: Box::new(e) },
+    }
+}
+
+/// Client identifier (option 61) as lowercase colon separated hex, including the type byte
+pub(crate) fn client_identifier(p: &DhcpPacket) -> Option<String> {
+    p.options().try_vec_u8_option(CLIENT_IDENTIFIER).ok()
+        .filter(|id| !id.is_empty())
+        .map(|id| id.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(":"))
+}
+
+/// Client system architecture (option 93), e.g. 0 bios or 7 uefi x64
+pub(crate) fn client_architecture(p: &DhcpPacket) -> Option<u16> {
+    match p.options().option(CLIENT_SYSTEM_ARCHITECTURE) {
+        Some(DhcpOption::Unknown(_, data)) if data.len() >= 2 => Some(u16::from_be_bytes([data[0], data[1]])),
+        _ => None,
+    }
+}
+
+/// Client network interface identifier (option 94, RFC 4578), e.g. undi 2.1
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct ClientNdi {
+    #[serde(rename = "type")]
+    kind: u8,
+    major: u8,
+    minor: u8,
+}
+
+pub(crate) fn client_ndi(p: &DhcpPacket) -> Option<ClientNdi> {
+    match p.options().option(CLIENT_NETWORK_INTERFACE_IDENTIFIER) {
+        Some(DhcpOption::Unknown(_, data)) if data.len() == 3 => Some(ClientNdi { kind: data[0], major: data[1], minor: data[2] }),
+        _ => None,
+    }
+}
+
+/// Client machine identifier (option 97, RFC 4578) as lowercase uuid, in the byte order sent
+pub(crate) fn client_uuid(p: &DhcpPacket) -> Option<String> {
+    let data = match p.options().option(CLIENT_MACHINE_IDENTIFIER) {
+        Some(DhcpOption::Unknown(_, data)) if data.len() == 17 && data[0] == 0 => &data[1..],
+        _ => return None,
+    };
+
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    Some(format!("{}-{}-{}-{}-{}", hex(&data[..4]), hex(&data[4..6]), hex(&data[6..8]), hex(&data[8..10]), hex(&data[10..])))
+}
+
+/// Subnet selection (option 118, RFC 3011)
+pub(crate) fn subnet_selection(p: &DhcpPacket) -> Option<Ipv4Addr> {
+    match p.options().option(crate::packet::SUBNET_SELECTION) {
+        Some(DhcpOption::Unknown(_, data)) if data.len() == 4 => Some(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+        _ => None,
+    }
+}
+
+/// Relay agent sub-option (option 82, RFC 3046), printable ids as text and others as colon separated hex
+pub(crate) fn relay_agent_sub_option(p: &DhcpPacket, code: u8) -> Option<String> {
+    let data = match p.options().option(crate::packet::RELAY_AGENT_INFORMATION) {
+        Some(DhcpOption::Unknown(_, data)) => crate::packet::relay_sub_option(data, code).filter(|d| !d.is_empty())?,
+        _ => return None,
+    };
+
+    if data.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+        Some(String::from_utf8_lossy(data).into_owned())
+    } else {
+        Some(data.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(":"))
+    }
+}
+
+/// User classes (option 77) separated by `,`, RFC 3004 length prefixed or a plain string as sent by some clients
+pub(crate) fn user_class(p: &DhcpPacket) -> Option<String> {
+    let data = match p.options().option(USER_CLASS) {
+        Some(DhcpOption::Unknown(_, data)) if !data.is_empty() => data,
+        _ => return None,
+    };
+
+    let mut classes = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        let length = data[i] as usize;
+        match data.get(i + 1..i + 1 + length) {
+            Some(class) if length > 0 => classes.push(String::from_utf8_lossy(class).into_owned()),
+            _ => return Some(String::from_utf8_lossy(data).into_owned()),
+        }
+        i += 1 + length;
+    }
+
+    Some(classes.join(","))
+}
+
+/// Client state of a request (RFC 2131 4.3.2), only meaningful for requests
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RequestState {
+    Selecting,
+    InitReboot,
+    /// renewing or rebinding, unicast and broadcast requests are not told apart
+    Renewing,
+}
+
+pub(crate) fn request_state(p: &DhcpPacket) -> Option<RequestState> {
+    if p.options().option(SERVER_IDENTIFIER).is_some() {
+        Some(RequestState::Selecting)
+    } else if !p.client().is_unspecified() {
+        Some(RequestState::Renewing)
+    } else if p.options().option(REQUESTED_IP_ADDRESS).is_some() {
+        Some(RequestState::InitReboot)
+    } else {
+        None
+    }
+}
+
+/// Template variables describing the received packet
+pub(crate) fn packet_context(p: &DhcpPacket) -> Context {
+    let mut context = Context::new();
+
+    context.insert("client_hardware_address", &p.client_hardware().to_string());
+    context.insert("client_ip_address", &p.client());
+    context.insert("server_ip_address", &p.server());
+    context.insert("client_hostname", &p.hostname());
+    context.insert("client_hostname_sanitized", &p.options().try_ascii_option(HOST_NAME).ok()
+        .map(|h| HostnamePolicy::sanitize(h.as_str())));
+    context.insert("hops", &p.hops());
+    context.insert("client_identifier", &client_identifier(p));
+    context.insert("client_architecture", &client_architecture(p));
+    context.insert("relay_circuit_id", &relay_agent_sub_option(p, crate::packet::RELAY_CIRCUIT_ID));
+    context.insert("relay_remote_id", &relay_agent_sub_option(p, crate::packet::RELAY_REMOTE_ID));
+    context.insert("correlation_id", &crate::server::correlation_id());
+    context.insert("xid", &p.xid());
+    context.insert("time", &crate::schedule::LocalTime::now());
+    context.insert("request", &serde_json::json!({
+        "state": request_state(p),
+        "user_class": user_class(p),
+        "arch": client_architecture(p),
+        "ndi": client_ndi(p),
+        "uuid": client_uuid(p),
+        "subnet_selection": subnet_selection(p),
+    }));
+
+    context
+}
+
+/// Renders a mapping file against the variables of a json context file, for trying templates offline
+pub(crate) fn validate_template(mapping: &str, context: Option<&str>) -> DhcpResult<DhcpSourceResult> {
+    let mapping: DhcpMapping = serde_yaml::from_reader(File::open(mapping)?)?;
+    let context = match context {
+        Some(path) => Context::from_value(serde_json::from_reader(File::open(path)?)?)?,
+        None => Context::new(),
+    };
+
+    mapping.to_result(&context, true) // every broken template is reported
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DhcpMappingItemCustomKind {
+    #[serde(alias = "str")]
+    String,
+    Bool,
+    Integer,
+    /// 4 byte IEEE 754, big endian
+    Float32,
+    /// 8 byte IEEE 754, big endian
+    Float64,
+    None,
+}
+
+impl Default for DhcpMappingItemCustomKind {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct DhcpMappingItem {
+    pub(crate) data: Value,
+    #[serde(default)]
+    pub(crate) required: bool,
+}
+
+impl TryFrom<serde_yaml::Value> for DhcpMappingItem {
+    type Error = DhcpError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        serde_from_value(value).map_err(Into::into)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct DhcpMappingItemCustom {
+    pub(crate) tag: u8,
+    #[serde(default)]
+    pub(crate) kind: DhcpMappingItemCustomKind,
+    #[serde(flatten)]
+    pub(crate) item: DhcpMappingItem,
+}
+
+impl TryFrom<serde_yaml::Value> for DhcpMappingItemCustom {
+    type Error = DhcpError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        serde_from_value(value).map_err(Into::into)
+    }
+}
+
+impl TryInto<DhcpOption> for DhcpMappingItemCustom {
+    type Error = DhcpError;
+
+    fn try_into(self) -> Result<DhcpOption, Self::Error> {
+        let d = match self.item.data {
+            Value::Null => vec![],
+            Value::Bool(v) => {
+                if v { vec![1] } else { vec![0] }
+            }
+            // floats are no usual option payload, their encoding has to be asked for
+            Value::Number(v) => match self.kind {
+                DhcpMappingItemCustomKind::Float32 => (v.as_f64().ok_or(DhcpError::CustomRestTypeError)? as f32).to_be_bytes().to_vec(),
+                DhcpMappingItemCustomKind::Float64 => v.as_f64().ok_or(DhcpError::CustomRestTypeError)?.to_be_bytes().to_vec(),
+                _ => if let Some(i) = v.as_i64() {
+                    i.to_be_bytes().to_vec()
+                } else if let Some(i) = v.as_u64() {
+                    i.to_be_bytes().to_vec()
+                } else {
+                    return Err(DhcpError::CustomFloatKindMissing(self.tag));
+                }
+            }
+            Value::String(v) => v.as_bytes().to_vec(),
+            _ => return Err(DhcpError::CustomRestTypeError),
+        };
+        Ok(DhcpOption::Unknown(self.tag, d))
+    }
+}
+
+/// Key of an entry in the list form of a mapping
+const MAPPING_ENTRY_KEY: &str = "key";
+/// Plain (non item) value of an entry in the list form of a mapping
+const MAPPING_ENTRY_VALUE: &str = "value";
+
+/// Option name to templated option data, shared by all sources.
+/// Configured as map or as list of `{ key: <name>, ... }` entries, which keeps the order and allows repeated names
+#[derive(Default, Debug)]
+pub(crate) struct DhcpMapping(Vec<(String, Value)>);
+
+impl From<HashMap<String, Value>> for DhcpMapping {
+    fn from(m: HashMap<String, Value>) -> Self {
+        Self(m.into_iter().collect())
+    }
+}
+
+impl<'de> Deserialize<'de> for DhcpMapping {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = match Value::deserialize(deserializer)? {
+            Value::Null => vec![],
+            Value::Mapping(m) => m.into_iter()
+                .map(|(k, v)| match k {
+                    Value::String(k) => Ok((k, v)),
+                    k => Err(serde::de::Error::custom(format!("option name {:?} is no string", k))),
+                })
+                .collect::<Result<_, _>>()?,
+            Value::Sequence(list) => list.into_iter().map(Self::entry).collect::<Result<_, _>>()?,
+            _ => return Err(serde::de::Error::custom("mapping is neither a map nor a list of entries")),
+        };
+        Ok(Self(entries))
+    }
+}
+
+impl Serialize for DhcpMapping {
+    /// Map form unless names repeat
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let names = self.0.iter().map(|(k, _)| k).collect::<std::collections::HashSet<_>>();
+
+        if names.len() == self.0.len() {
+            serializer.collect_map(self.0.iter().map(|(k, v)| (k, v)))
+        } else {
+            serializer.collect_seq(self.0.iter().map(|(k, v)| {
+                let mut entry = match v {
+                    Value::Mapping(m) => m.clone(),
+                    v => std::iter::once((Value::from(MAPPING_ENTRY_VALUE), v.clone())).collect(),
+                };
+                entry.insert(Value::from(MAPPING_ENTRY_KEY), Value::from(k.as_str()));
+                entry
+            }))
+        }
+    }
+}
+
+impl DhcpMapping {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Template syntax errors of any option
+    pub fn check_templates(&self) -> DhcpResult<()> {
+        self.0.iter().try_for_each(|(key, template)| check_templates(template, key))
+    }
+
+    /// Name and value of a list entry, a sole `value` field is the plain value, other fields form an item
+    fn entry<E: serde::de::Error>(entry: Value) -> Result<(String, Value), E> {
+        let mut entry = match entry {
+            Value::Mapping(m) => m,
+            _ => return Err(E::custom("mapping entry is no map")),
+        };
+
+        let key = match entry.remove(&Value::from(MAPPING_ENTRY_KEY)) {
+            Some(Value::String(key)) => key,
+            _ => return Err(E::custom(format!("mapping entry without string `{}`", MAPPING_ENTRY_KEY))),
+        };
+
+        let value = match entry.get(&Value::from(MAPPING_ENTRY_VALUE)) {
+            Some(v) if entry.len() == 1 => v.clone(),
+            _ => Value::Mapping(entry),
+        };
+        Ok((key, value))
+    }
+
+    fn is_required(value: &Value) -> bool {
+        if let serde_yaml::Value::Mapping(m) = value {
+            m.get(&serde_yaml::Value::String("required".to_string()))
+                .unwrap_or(&serde_yaml::Value::Bool(false))
+                .as_bool()
+                .unwrap_or(false)
+        } else {
+            false
+        }
+    }
+
+    /// `strict` fails on every broken option instead of only on required ones
+    pub fn to_result(&self, context: &Context, strict: bool) -> DhcpResult<DhcpSourceResult> {
+        let mut client_ip_address = None;
+        let mut next_server = None;
+        let mut options = DhcpOptions::new();
+
+        for (key, template) in &self.0 {
+            let required = Self::is_required(template) || strict;
+            let mut value = template.clone(); // keep the template for the next packet
+            let template_result = template_values(&mut value, context, key)
+                .map_err(|e| with_result_field(e, key, template, context));
+
+            // handle error if required
+            match template_result {
+                Ok(_) => {}
+                Err(e) if required => return Err(e),
+                Err(e) => {
+                    log::warn!("option templating error {} ({})", key, e);
+                    continue;
+                }
+            }
+
+            let v = value.clone();
+
+            // map + deserialize option
+            let option = match key.as_str() {
+                "client_ip_address" => {
+                    client_ip_address = Some(serde_from_value(v).map_err(|e| {
+                        log::error!("{}:{:?} - {}", key, value, e);
+                        with_result_field(e.into(), key, template, context)
+                    })?);
+                    continue;
+                }
+                "next_server" => {
+                    next_server = Some(serde_from_value(v).map_err(|e| {
+                        log::error!("{}:{:?} - {}", key, value, e);
+                        with_result_field(e.into(), key, template, context)
+                    })?);
+                    continue;
+                }
+                "captive_portal_url" => v.try_into()
+                    .and_then(|s: DhcpMappingItem| serde_from_value::<String>(s.data).map_err(Into::into))
+                    .and_then(|url| captive_portal(&url)),
+                "routes" => {
+                    match routes(v).map_err(|e| with_result_field(e, key, template, context)) {
+                        Ok(routes) => routes.into_iter().for_each(|r| options.upsert(r)),
+                        Err(e) if required => return Err(e),
+                        Err(e) => log::warn!("invalid option mapping: {}:{:?} ({})", key, value, e),
+                    }
+                    continue;
+                }
+                "subnet_mask" => to_value!(SubnetMask, v),
+                "time_offset" => to_value!(TimeOffset, v),
+                "router" => to_value!(Router, v, list),
+                "time_server" => to_value!(TimeServer, v, list),
+                "name_server" => to_value!(NameServer, v, list),
+                "domain_name_server" => to_value!(DomainNameServer, v, list),
+                "log_server" => to_value!(LogServer, v, list),
+                "cookie_server" => to_value!(CookieServer, v, list),
+                "lpr_server" => to_value!(LPRServer, v, list),
+                "impress_server" => to_value!(ImpressServer, v, list),
+                "resource_location_server" => to_value!(ResourceLocationServer, v, list),
+                "host_name" => to_value!(HostName,v),
+                "boot_file_size" => to_value!(BootFileSize,v),
+                "merit_dump_file" => to_value!(MeritDumpFile,v),
+                "domain_name" => to_value!(DomainName,v),
+                "swap_server" => to_value!(SwapServer,v),
+                "root_path" => to_value!(RootPath,v),
+                "extension_path" => to_value!(ExtensionPath,v),
+                "ip_forwarding" => to_value!(IpForwarding,v),
+                "non_local_source_routing" => to_value!(NonLocalSourceRouting,v),
+                "policy_filter" => to_value!(PolicyFilter,v),
+                "maximum_datagram_reassembly_size" => to_value!(MaximumDatagramReassemblySize,v),
+                "default_ip_ttl" => to_value!(DefaultIpTTL,v),
+                "path_mtu_aging_timeout" => to_value!(PathMtuAgingTimeout,v),
+                "path_mtu_plateau_table" => to_value!(PathMtuPlateauTable,v),
+                "interface_mtu" => to_value!(InterfaceMtu,v),
+                "all_subnets_local" => to_value!(AllSubnetsLocal,v),
+                "broadcast_address" => to_value!(BroadcastAddress,v),
+                "mask_supplier" => to_value!(MaskSupplier,v),
+                "perform_router_discovery" => to_value!(PerformRouterDiscovery,v),
+                "router_solicitation_address" => to_value!(RouterSolicitationAddress,v),
+                "static_route" => to_value!(StaticRoute,v),
+                "trailer_encapsulation" => to_value!(TrailerEncapsulation,v),
+                "arp_cache_timeout" => to_value!(ArpCacheTimeout,v),
+                "ethernet_encapsulation" => to_value!(EthernetEncapsulation,v),
+                "tcp_default_ttl" => to_value!(TcpDefaultTTL,v),
+                "tcp_keep_alive_interval" => to_value!(TcpKeepAliveInterval,v),
+                "tcp_keep_alive_garbage" => to_value!(TcpKeepAliveGarbage,v),
+                "network_information_service_domain" => to_value!(NetworkInformationServiceDomain,v),
+                "network_information_servers" => to_value!(NetworkInformationServers, v, list),
+                "network_time_protocol_servers" => to_value!(NetworkTimeProtocolServers, v, list),
+                "vendor_specific" => to_value!(VendorSpecific,v),
+                "net_bios_over_tcp_ip_name_server" => to_value!(NetBiosOverTcpIpNameServer, v, list),
+                "net_bios_over_tcp_ip_datagram_distribution_server" => to_value!(NetBiosOverTcpIpDatagramDistributionServer,v),
+                "net_bios_over_tcp_ip_node_type" => to_value!(NetBiosOverTcpIpNodeType,v),
+                "net_bios_over_tcp_ip_scope" => to_value!(NetBiosOverTcpIpScope,v),
+                "x_window_system_font_server" => to_value!(XWindowSystemFontServer,v),
+                "x_window_system_display_manager" => to_value!(XWindowSystemDisplayManager,v),
+                "requested_ip_address" => to_value!(RequestedIpAddress,v),
+                "ip_address_lease_time" => lease_time(v),
+                "option_overload" => to_value!(OptionOverload,v),
+                "message_type" => to_value!(MessageType,v),
+                "server_identifier" => to_value!(ServerIdentifier,v),
+                "parameter_request_list" => to_value!(ParameterRequestList,v),
+                "message" => to_value!(Message,v),
+                "maximum_dhcp_message_size" => to_value!(MaximumDhcpMessageSize,v),
+                "renewal_time_value" => to_value!(RenewalTimeValue,v),
+                "rebinding_time_value" => to_value!(RebindingTimeValue,v),
+                "vendor_class_identifier" => to_value!(VendorClassIdentifier,v),
+                "client_identifier" => to_value!(ClientIdentifier,v),
+                "network_information_service_plus_domain" => to_value!(NetworkInformationServicePlusDomain,v),
+                "network_information_service_plus_server" => to_value!(NetworkInformationServicePlusServer,v),
+                "tftp_server" => to_value!(TftpServer,v),
+                "boot_file_name" => to_value!(BootFileName,v),
+                "mobile_ip_home_agent" => to_value!(MobileIpHomeAgent,v),
+                "smtp_server" => to_value!(SmtpServer, v, list),
+                "pop3_server" => to_value!(Pop3Server, v, list),
+                "nntp_server" => to_value!(NntpServer, v, list),
+                "www_server" => to_value!(WwwServer, v, list),
+                "finger_server" => to_value!(FingerServer,v),
+                "irc_server" => to_value!(IrcServer,v),
+                "street_talk_server" => to_value!(StreetTalkServer,v),
+                "street_talk_directory_assistance_server" => to_value!(StreetTalkDirectoryAssistanceServer,v),
+                _ => DhcpMappingItemCustom::try_from(v).and_then(TryInto::try_into) // custom options
+            };
+
+            // handle errors if required
+            match option.map_err(|e| with_result_field(e, key, template, context)) {
+                Ok(v) => options.upsert(v),
+                Err(e) if required => return Err(e),
+                Err(e) => log::warn!("invalid option mapping: {}:{:?} ({})", key, value, e)
+            }
+        }
+
+        match lease_times(&options) {
+            Ok(_) => {}
+            Err(e) if strict => return Err(e),
+            Err(e) => log::warn!("{}", e),
+        }
+
+        Ok(DhcpSourceResult::new(client_ip_address, options).with_next_server(next_server))
+    }
+}
+
+#[test]
+fn test_infinite_lease_time() {
+    for data in ["infinite", "Permanent", "{{ 'infinite' }}", "4294967295"] {
+        let mapping: DhcpMapping = serde_yaml::from_str(&format!("ip_address_lease_time:\n  data: \"{}\"", data)).unwrap();
+        let result = mapping.to_result(&Context::new(), false).unwrap();
+        assert_eq!(result.options().try_u32_option(IP_ADDRESS_LEASE_TIME).unwrap(), INFINITE_LEASE_TIME);
+    }
+
+    let mapping: DhcpMapping = serde_yaml::from_str("ip_address_lease_time:\n  data: 7200").unwrap();
+    let result = mapping.to_result(&Context::new(), false).unwrap();
+    assert_eq!(result.options().try_u32_option(IP_ADDRESS_LEASE_TIME).unwrap(), 7200);
+}
+
+#[test]
+fn test_template_error() {
+    let mapping: DhcpMapping = serde_yaml::from_str(r#"
+router:
+  data: "{{ results.missing.router }}"
+  required: true
+"#).unwrap();
+
+    match mapping.to_result(&Context::new(), false) {
+        Err(DhcpError::TemplateError { option, template, .. }) => {
+            assert_eq!(option, "router");
+            assert_eq!(template, "{{ results.missing.router }}");
+        }
+        _ => panic!("template error expected"),
+    }
+}
+
+#[test]
+fn test_result_field_error() {
+    let mut context = Context::new();
+    context.insert("results", &serde_json::json!({"inventory": {"device": {"name": "printer"}}}));
+
+    let mapping: DhcpMapping = serde_yaml::from_str(r#"client_ip_address: "{{ results.inventory.device.ip }}""#).unwrap();
+    let e = mapping.to_result(&context, true).err().unwrap();
+    assert!(e.to_string().starts_with("query 'inventory' field 'device.ip' missing"), "{}", e);
+
+    let mapping: DhcpMapping = serde_yaml::from_str(r#"
+router:
+  data: "{{ results.inventory.device.name }}"
+  required: true
+"#).unwrap();
+    match mapping.to_result(&context, false) {
+        Err(e @ DhcpError::MappingFieldError { .. }) => {
+            assert!(e.to_string().starts_with("option router: query 'inventory' field 'device.name' (/device/name) invalid:"), "{}", e);
+        }
+        _ => panic!("mapping field error expected"),
+    }
+
+    // mappings not reading a query result keep their error
+    let mapping: DhcpMapping = serde_yaml::from_str("router:\n  data: not an address\n  required: true").unwrap();
+    assert!(!matches!(mapping.to_result(&context, false), Err(DhcpError::MappingFieldError { .. }) | Ok(_)));
+}
+
+#[test]
+fn test_strict_mapping() {
+    use dhcplib::option::SUBNET_MASK;
+
+    let mapping: DhcpMapping = serde_yaml::from_str(r#"
+subnet_mask:
+  data: 255.255.255.0
+router:
+  data: not an address
+domain_name:
+  data: "{{ results.missing.domain }}"
+"#).unwrap();
+
+    let result = mapping.to_result(&Context::new(), false).unwrap();
+    assert!(result.options().option(SUBNET_MASK).is_some());
+    assert!(result.options().option(dhcplib::option::ROUTER).is_none());
+
+    assert!(mapping.to_result(&Context::new(), true).is_err());
+}
+
+#[test]
+fn test_single_value_list_option() {
+    use dhcplib::option::ROUTER;
+
+    for data in ["10.0.0.1", "[10.0.0.1]", "\"{{ '10.0.0.1' }}\""] {
+        let mapping: DhcpMapping = serde_yaml::from_str(&format!("router:\n  data: {}", data)).unwrap();
+        let result = mapping.to_result(&Context::new(), true).unwrap();
+        assert_eq!(result.options().try_ipv4vec_option(ROUTER).unwrap(), vec![std::net::Ipv4Addr::new(10, 0, 0, 1)]);
+    }
+
+    let mapping: DhcpMapping = serde_yaml::from_str("router:\n  data: [10.0.0.1, 10.0.0.2]").unwrap();
+    let result = mapping.to_result(&Context::new(), true).unwrap();
+    assert_eq!(result.options().try_ipv4vec_option(ROUTER).unwrap(), vec![std::net::Ipv4Addr::new(10, 0, 0, 1), std::net::Ipv4Addr::new(10, 0, 0, 2)]);
+}
+
+#[test]
+fn test_routes() {
+    let mapping: DhcpMapping = serde_yaml::from_str(r#"
+routes:
+  data:
+    - dest: 0.0.0.0/0
+      gateway: 10.0.0.1
+    - dest: 10.0.0.0/8
+      gateway: 10.0.0.2
+    - dest: 172.16.32.0/20
+      gateway: 10.0.0.3
+"#).unwrap();
+    let result = mapping.to_result(&Context::new(), true).unwrap();
+
+    assert_eq!(result.options().option(CLASSLESS_STATIC_ROUTE).unwrap(), &DhcpOption::Unknown(CLASSLESS_STATIC_ROUTE, vec![
+        0, 10, 0, 0, 1,
+        8, 10, 10, 0, 0, 2,
+        20, 172, 16, 32, 10, 0, 0, 3,
+    ]));
+    assert_eq!(result.options().option(STATIC_ROUTE).unwrap(), &DhcpOption::Unknown(STATIC_ROUTE, vec![
+        10, 0, 0, 0, 10, 0, 0, 2,
+    ]));
+}
+
+#[test]
+fn test_captive_portal() {
+    let mapping: DhcpMapping = serde_yaml::from_str("captive_portal_url:\n  data: https://portal.example/api").unwrap();
+    let result = mapping.to_result(&Context::new(), true).unwrap();
+    assert_eq!(result.options().option(CAPTIVE_PORTAL).unwrap(), &DhcpOption::Unknown(CAPTIVE_PORTAL, b"https://portal.example/api".to_vec()));
+
+    let mapping: DhcpMapping = serde_yaml::from_str("captive_portal_url:\n  data: not a url").unwrap();
+    assert!(mapping.to_result(&Context::new(), true).is_err());
+}
+
+#[test]
+fn test_request_state() {
+    use crate::sources::{test_packet, test_packet_with_client};
+
+    let selecting = test_packet(0, vec![
+        DhcpOption::ServerIdentifier(Ipv4Addr::new(10, 0, 0, 1)),
+        DhcpOption::RequestedIpAddress(Ipv4Addr::new(10, 0, 0, 5)),
+    ]);
+    assert_eq!(request_state(&selecting), Some(RequestState::Selecting));
+
+    let init_reboot = test_packet(0, vec![DhcpOption::RequestedIpAddress(Ipv4Addr::new(10, 0, 0, 5))]);
+    assert_eq!(request_state(&init_reboot), Some(RequestState::InitReboot));
+
+    let renewing = test_packet_with_client(Ipv4Addr::new(10, 0, 0, 5), vec![]);
+    assert_eq!(request_state(&renewing), Some(RequestState::Renewing));
+    assert_eq!(request_state(&test_packet(0, vec![])), None);
+
+    let context = packet_context(&renewing);
+    assert_eq!(tera::Tera::one_off("{{ request.state }}", &context, false).unwrap(), "renewing");
+}
+
+#[test]
+fn test_user_class() {
+    use crate::sources::test_packet;
+
+    let p = test_packet(0, vec![DhcpOption::Unknown(USER_CLASS, b"\x04voip\x05kiosk".to_vec())]);
+    assert_eq!(user_class(&p).as_deref(), Some("voip,kiosk"));
+
+    let p = test_packet(0, vec![DhcpOption::Unknown(USER_CLASS, b"voip".to_vec())]); // length 118 exceeds the data
+    assert_eq!(user_class(&p).as_deref(), Some("voip"));
+    assert_eq!(tera::Tera::one_off("{{ request.user_class }}", &packet_context(&p), false).unwrap(), "voip");
+
+    assert_eq!(user_class(&test_packet(0, vec![])), None);
+}
+
+#[test]
+fn test_relay_agent_sub_option() {
+    use crate::sources::test_packet;
+    use crate::packet::{RELAY_AGENT_INFORMATION, RELAY_CIRCUIT_ID, RELAY_REMOTE_ID};
+
+    let p = test_packet(0, vec![DhcpOption::Unknown(RELAY_AGENT_INFORMATION, b"\x01\x04eth0\x02\x02\xab\xcd".to_vec())]);
+    assert_eq!(relay_agent_sub_option(&p, RELAY_CIRCUIT_ID).as_deref(), Some("eth0"));
+    assert_eq!(relay_agent_sub_option(&p, RELAY_REMOTE_ID).as_deref(), Some("ab:cd"));
+    assert_eq!(tera::Tera::one_off("{{ relay_circuit_id }}/{{ relay_remote_id }}", &packet_context(&p), false).unwrap(), "eth0/ab:cd");
+
+    assert_eq!(relay_agent_sub_option(&test_packet(0, vec![]), RELAY_CIRCUIT_ID), None);
+}
+
+#[test]
+fn test_lease_times() {
+    let mapping = |t1: u32, t2: u32, lease: u32| -> DhcpMapping { serde_yaml::from_str(&format!(r#"
+renewal_time_value:
+  data: {}
+rebinding_time_value:
+  data: {}
+ip_address_lease_time:
+  data: {}
+"#, t1, t2, lease)).unwrap() };
+
+    // dns/ntp refreshed every 5 minutes of a day long lease
+    let result = mapping(300, 600, 86400).to_result(&Context::new(), true).unwrap();
+    assert_eq!(result.options().try_u32_option(RENEWAL_TIME_VALUE).unwrap(), 300);
+    assert_eq!(result.options().try_u32_option(REBINDING_TIME_VALUE).unwrap(), 600);
+
+    for (t1, t2, lease) in [(600, 300, 86400), (300, 86400, 86400), (300, 300, 3600), (7200, 9000, 3600)] {
+        match mapping(t1, t2, lease).to_result(&Context::new(), true) {
+            Err(DhcpError::InvalidLeaseTimes(_)) => {}
+            _ => panic!("invalid lease times expected for {} {} {}", t1, t2, lease),
+        }
+        assert!(mapping(t1, t2, lease).to_result(&Context::new(), false).is_ok()); // warning only
+    }
+
+    // unset times are not compared
+    let mapping: DhcpMapping = serde_yaml::from_str("renewal_time_value:\n  data: 300\nip_address_lease_time:\n  data: infinite").unwrap();
+    assert!(mapping.to_result(&Context::new(), true).is_ok());
+}
+
+#[test]
+fn test_validate_template() {
+    use dhcplib::option::{DOMAIN_NAME, ROUTER};
+
+    let mapping = std::env::temp_dir().join("dhcpserver-test-validate-template.yml");
+    let context = std::env::temp_dir().join("dhcpserver-test-validate-template.json");
+    std::fs::write(&mapping, r#"
+client_ip_address: "10.0.0.{{ hops + 5 }}"
+router:
+  data: 10.0.0.1
+domain_name:
+  data: "{{ client_hardware_address | replace(from=':', to='') }}.example"
+"#).unwrap();
+    std::fs::write(&context, r#"{"hops": 0, "client_hardware_address": "aa:bb:cc:dd:ee:ff"}"#).unwrap();
+
+    let result = validate_template(mapping.to_str().unwrap(), context.to_str()).unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 5)));
+    assert_eq!(result.options().try_ipv4vec_option(ROUTER).unwrap(), vec![Ipv4Addr::new(10, 0, 0, 1)]);
+    assert_eq!(result.options().try_ascii_option(DOMAIN_NAME).unwrap().as_str(), "aabbccddeeff.example");
+
+    // variables missing without context
+    assert!(matches!(validate_template(mapping.to_str().unwrap(), None), Err(DhcpError::TemplateError { .. })));
+
+    std::fs::remove_file(mapping).unwrap();
+    std::fs::remove_file(context).unwrap();
+}
+
+#[test]
+fn test_pxe_client_options() {
+    let uuid = vec![0, 0x4c, 0x4c, 0x45, 0x44, 0x00, 0x57, 0x10, 0x80, 0x38, 0xb6, 0xc0, 0x4f, 0x4d, 0x59, 0x31, 0x32];
+    let p = crate::sources::test_packet(0, vec![
+        DhcpOption::Unknown(CLIENT_SYSTEM_ARCHITECTURE, vec![0, 7]),
+        DhcpOption::Unknown(CLIENT_NETWORK_INTERFACE_IDENTIFIER, vec![1, 3, 10]),
+        DhcpOption::Unknown(CLIENT_MACHINE_IDENTIFIER, uuid),
+    ]);
+
+    assert_eq!(client_architecture(&p), Some(7));
+    assert_eq!(client_ndi(&p), Some(ClientNdi { kind: 1, major: 3, minor: 10 }));
+    assert_eq!(client_uuid(&p).as_deref(), Some("4c4c4544-0057-1080-8038-b6c04f4d5931"));
+
+    let context = packet_context(&p).into_json();
+    assert_eq!(context["request"]["arch"], 7);
+    assert_eq!(context["request"]["ndi"], serde_json::json!({"type": 1, "major": 3, "minor": 10}));
+    assert_eq!(context["request"]["uuid"], "4c4c4544-0057-1080-8038-b6c04f4d5931");
+
+    // malformed payloads
+    let p = crate::sources::test_packet(0, vec![
+        DhcpOption::Unknown(CLIENT_NETWORK_INTERFACE_IDENTIFIER, vec![1, 2]),
+        DhcpOption::Unknown(CLIENT_MACHINE_IDENTIFIER, vec![1; 17]),
+    ]);
+    assert_eq!(client_ndi(&p), None);
+    assert_eq!(client_uuid(&p), None);
+}
+
+#[test]
+fn test_custom_float() {
+    let custom = |yaml: &str| -> DhcpResult<DhcpOption> { DhcpMappingItemCustom::try_from(serde_yaml::from_str::<Value>(yaml).unwrap())?.try_into() };
+
+    assert!(matches!(custom("tag: 200\ndata: 1.5"), Err(DhcpError::CustomFloatKindMissing(200))));
+    assert!(matches!(custom("tag: 200\nkind: integer\ndata: 1.5"), Err(DhcpError::CustomFloatKindMissing(200))));
+    assert!(matches!(custom("tag: 200\nkind: float32\ndata: 1.5"), Ok(DhcpOption::Unknown(200, d)) if d == 1.5f32.to_be_bytes()));
+    assert!(matches!(custom("tag: 200\nkind: float64\ndata: 1.5"), Ok(DhcpOption::Unknown(200, d)) if d == 1.5f64.to_be_bytes()));
+    assert!(matches!(custom("tag: 200\nkind: float32\ndata: 2"), Ok(DhcpOption::Unknown(200, d)) if d == 2f32.to_be_bytes()));
+    assert!(matches!(custom("tag: 200\ndata: 2"), Ok(DhcpOption::Unknown(200, d)) if d == 2i64.to_be_bytes()));
+
+    // rejected like any broken option, fatal only in strict mode
+    let mapping: DhcpMapping = serde_yaml::from_str("ratio:\n  tag: 200\n  data: \"{{ 3 / 2 }}\"").unwrap();
+    assert!(matches!(mapping.to_result(&Context::new(), true), Err(DhcpError::CustomFloatKindMissing(200))));
+    assert!(mapping.to_result(&Context::new(), false).unwrap().options().option(200).is_none());
+}
+
+#[test]
+fn test_mapping_list_form() {
+    use dhcplib::option::ROUTER;
+
+    let mapping: DhcpMapping = serde_yaml::from_str(r#"
+- key: client_ip_address
+  value: 10.0.0.5
+- key: router
+  data: 10.0.0.1
+- key: vendor
+  tag: 224
+  data: first
+- key: vendor
+  tag: 225
+  data: second
+"#).unwrap();
+    assert_eq!(mapping.0.iter().map(|(k, _)| k.as_str()).collect::<Vec<&str>>(), vec!["client_ip_address", "router", "vendor", "vendor"]);
+
+    let result = mapping.to_result(&Context::new(), true).unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 5)));
+    assert_eq!(result.options().try_ipv4vec_option(ROUTER).unwrap(), vec![Ipv4Addr::new(10, 0, 0, 1)]);
+    assert!(matches!(result.options().option(224), Some(DhcpOption::Unknown(224, d)) if d == b"first"));
+    assert!(matches!(result.options().option(225), Some(DhcpOption::Unknown(225, d)) if d == b"second"));
+
+    // repeated names serialize as list, the order is kept
+    let yaml = serde_yaml::to_string(&mapping).unwrap();
+    let reparsed: DhcpMapping = serde_yaml::from_str(&yaml).unwrap();
+    assert_eq!(reparsed.0, mapping.0);
+
+    let map: DhcpMapping = serde_yaml::from_str("router:\n  data: 10.0.0.1\nclient_ip_address: 10.0.0.5").unwrap();
+    assert_eq!(map.0[0].0, "router");
+    assert!(serde_yaml::to_value(&map).unwrap().is_mapping());
+
+    assert!(serde_yaml::from_str::<DhcpMapping>("- data: 10.0.0.1").is_err());
+    assert!(serde_yaml::from_str::<DhcpMapping>("router").is_err());
+}
@@ -1,28 +1,101 @@
-use serde::Deserializer;
+use serde::{Deserializer, Serialize};
 use crate::error::DhcpResult;
 use std::net::Ipv4Addr;
 use dhcplib::DhcpPacket;
 use dhcplib::option::DhcpOptions;
+use crate::rate_limit::ConcurrencyLimiter;
+use crate::config::Limits;
+use std::collections::HashMap;
 
+pub mod allocator;
+pub mod chain;
+pub mod chaos;
+pub mod mapping;
+pub mod pool;
 pub mod rest;
+pub mod static_file;
+pub mod sqlite;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct DhcpSourceResult {
     client_ip_address: Option<Ipv4Addr>,
-    options: DhcpOptions
+    next_server: Option<Ipv4Addr>,
+    options: DhcpOptions,
+    #[serde(skip)]
+    provenance: HashMap<u8, String>,
 }
 
 impl DhcpSourceResult {
     pub fn new(client_ip_address: Option<Ipv4Addr>, options: DhcpOptions) -> Self {
         Self {
             client_ip_address,
-            options
+            next_server: None,
+            options,
+            provenance: HashMap::new(),
         }
     }
 
+    pub fn with_next_server(mut self, next_server: Option<Ipv4Addr>) -> Self {
+        self.next_server = next_server;
+        self
+    }
+
     pub fn client_ip_address(&self) -> &Option<Ipv4Addr> { &self.client_ip_address }
 
+    /// bootp siaddr
+    pub fn next_server(&self) -> &Option<Ipv4Addr> { &self.next_server }
+
     pub fn options(&self) -> &DhcpOptions { &self.options }
+
+    /// Origin of each option by tag
+    pub fn provenance(&self) -> &HashMap<u8, String> { &self.provenance }
+
+    pub fn with_provenance(mut self, provenance: HashMap<u8, String>) -> Self {
+        self.provenance = provenance;
+        self
+    }
+
+    /// Records `label` as origin of the options, labels of nested sources are prefixed
+    pub fn label(mut self, label: &str) -> Self {
+        for option in self.options.iter() {
+            let origin = match self.provenance.remove(&option.tag()) {
+                Some(origin) => format!("{}/{}", label, origin),
+                None => label.to_string(),
+            };
+            self.provenance.insert(option.tag(), origin);
+        }
+        self
+    }
+
+    /// Upserts the options of a later result, the first client ip address wins
+    pub fn merge(&mut self, other: DhcpSourceResult) {
+        if self.client_ip_address.is_none() {
+            self.client_ip_address = other.client_ip_address;
+        }
+        if other.next_server.is_some() {
+            self.next_server = other.next_server;
+        }
+
+        for option in other.options.iter() {
+            self.options.upsert(option.clone());
+            match other.provenance.get(&option.tag()) {
+                Some(origin) => self.provenance.insert(option.tag(), origin.clone()),
+                None => self.provenance.remove(&option.tag()),
+            };
+        }
+    }
+}
+
+impl Clone for DhcpSourceResult {
+    /// Options are copied one by one
+    fn clone(&self) -> Self {
+        Self {
+            client_ip_address: self.client_ip_address,
+            next_server: self.next_server,
+            options: DhcpOptions::new_with_options(self.options.iter().cloned().collect()),
+            provenance: self.provenance.clone(),
+        }
+    }
 }
 
 impl From<DhcpSourceResult> for DhcpOptions {
@@ -31,13 +104,32 @@ impl From<DhcpSourceResult> for DhcpOptions {
     }
 }
 
-#[async_trait::async_trait]
-pub trait DhcpHostSource {
-    const NAME: &'static str;
+/// Entries a source keeps in memory
+#[derive(Debug, Serialize, Default, Clone, Copy, PartialEq)]
+pub struct SourceEntries {
+    pub cache_entries: usize,
+    pub lease_entries: usize,
+}
 
-    fn name(&self) -> &'static str {
-        Self::NAME
+impl SourceEntries {
+    pub fn add(&mut self, other: SourceEntries) {
+        self.cache_entries += other.cache_entries;
+        self.lease_entries += other.lease_entries;
     }
+}
+
+pub type BoxedDhcpHostSource = Box<dyn DhcpHostSource>;
+
+/// Sources locked one by one, calls of a single source never overlap while other sources stay available
+pub type SharedDhcpHostSources = std::sync::Arc<Vec<tokio::sync::Mutex<BoxedDhcpHostSource>>>;
+
+pub fn shared(sources: Vec<BoxedDhcpHostSource>) -> SharedDhcpHostSources {
+    std::sync::Arc::new(sources.into_iter().map(tokio::sync::Mutex::new).collect())
+}
+
+#[async_trait::async_trait]
+pub trait DhcpHostSource: Send {
+    fn name(&self) -> &'static str;
 
     async fn offer(&mut self, p: &DhcpPacket) ->  DhcpResult<Option<DhcpSourceResult>>; // from discover --> offer
 
@@ -49,6 +141,12 @@ pub trait DhcpHostSource {
 
     async fn inform(&mut self, p: &DhcpPacket) ->  DhcpResult<Option<DhcpSourceResult>>; // from release // release -> ack/nak
 
+    /// Offer a client would get, without running scripts or holding anything for it - sources with side effects on offer override it
+    async fn preview(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> { self.offer(p).await }
+
+    /// The client selected another server, whatever was held for its offer can go
+    async fn withdraw(&mut self, _: &DhcpPacket) -> DhcpResult<()> { Ok(()) }
+
     fn from_config<'a, T: Deserializer<'a> + Send>(config: T) -> DhcpResult<Self> where Self: Sized;
 
     async fn packet_received(&mut self, _: &DhcpPacket) -> DhcpResult<()> { Ok(()) }
@@ -56,4 +154,103 @@ pub trait DhcpHostSource {
     async fn packet_sending(&mut self, _: &DhcpPacket) -> DhcpResult<()> { Ok(()) }
 
     async fn packet_sent(&mut self) -> DhcpResult<()> { Ok(()) }
+
+    /// Promotes broken option mappings to errors
+    fn strict_mappings(&mut self, _: bool) {}
+
+    /// Caps on cached and leased entries
+    fn limits(&mut self, _: &Limits) {}
+
+    /// Entries currently held, for the management stats
+    async fn entries(&mut self) -> SourceEntries { SourceEntries::default() }
+
+    /// Limit shared by the scripts of all sources
+    fn script_limiter(&mut self, _: ConcurrencyLimiter) {}
+
+    /// Http clients shared by profile name
+    fn http_clients(&mut self, _: &HashMap<String, reqwest::Client>) -> DhcpResult<()> { Ok(()) }
+
+    /// Periodic housekeeping, run whenever `recv_timeout` passes without a packet
+    async fn maintenance(&mut self) -> DhcpResult<()> { Ok(()) }
+
+    /// Called once before the server exits
+    async fn shutdown(&mut self) -> DhcpResult<()> { Ok(()) }
+
+    /// Compiles the templates of the source without rendering them, for `--check`
+    fn check_templates(&self) -> DhcpResult<()> { Ok(()) }
+}
+
+#[test]
+fn test_merge() {
+    use dhcplib::option::{DhcpOption, SUBNET_MASK, ROUTER, DOMAIN_NAME_SERVER};
+
+    let mut result = DhcpSourceResult::new(None, DhcpOptions::new_with_options(vec![
+        DhcpOption::SubnetMask(Ipv4Addr::new(255, 255, 0, 0)),
+        DhcpOption::Router(vec![Ipv4Addr::new(10, 0, 0, 1)]),
+    ]));
+    result.merge(DhcpSourceResult::new(Some(Ipv4Addr::new(10, 0, 0, 5)), DhcpOptions::new_with_options(vec![
+        DhcpOption::SubnetMask(Ipv4Addr::new(255, 255, 255, 0)),
+    ])));
+    result.merge(DhcpSourceResult::new(Some(Ipv4Addr::new(10, 0, 0, 6)), DhcpOptions::new_with_options(vec![
+        DhcpOption::DomainNameServer(vec![Ipv4Addr::new(10, 0, 0, 2)]),
+    ])));
+
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 5)));
+    assert_eq!(result.options().try_ipv4_option(SUBNET_MASK).unwrap(), Ipv4Addr::new(255, 255, 255, 0));
+    assert_eq!(result.options().try_ipv4vec_option(ROUTER).unwrap(), vec![Ipv4Addr::new(10, 0, 0, 1)]);
+    assert_eq!(result.options().try_ipv4vec_option(DOMAIN_NAME_SERVER).unwrap(), vec![Ipv4Addr::new(10, 0, 0, 2)]);
+}
+
+#[test]
+fn test_provenance() {
+    use dhcplib::option::{DhcpOption, SUBNET_MASK, ROUTER};
+
+    let mut result = DhcpSourceResult::new(None, DhcpOptions::new_with_options(vec![
+        DhcpOption::SubnetMask(Ipv4Addr::new(255, 255, 0, 0)),
+        DhcpOption::Router(vec![Ipv4Addr::new(10, 0, 0, 1)]),
+    ])).label("rest#0");
+    result.merge(DhcpSourceResult::new(None, DhcpOptions::new_with_options(vec![
+        DhcpOption::SubnetMask(Ipv4Addr::new(255, 255, 255, 0)),
+    ])).label("rest#1"));
+    let result = result.label("chain#2");
+
+    assert_eq!(result.provenance().get(&SUBNET_MASK).unwrap(), "chain#2/rest#1");
+    assert_eq!(result.provenance().get(&ROUTER).unwrap(), "chain#2/rest#0");
+}
+
+#[cfg(test)]
+pub(crate) fn test_packet(hops: u8, options: Vec<dhcplib::option::DhcpOption>) -> DhcpPacket {
+    test_packet_from(hops, Ipv4Addr::UNSPECIFIED, [1, 2, 3, 5, 6, 7], options)
+}
+
+/// Packet of a client already having an address (ciaddr)
+#[cfg(test)]
+pub(crate) fn test_packet_with_client(ciaddr: Ipv4Addr, options: Vec<dhcplib::option::DhcpOption>) -> DhcpPacket {
+    test_packet_from(0, ciaddr, [1, 2, 3, 5, 6, 7], options)
+}
+
+/// Packet of another client
+#[cfg(test)]
+pub(crate) fn test_packet_from_mac(mac: [u8; 6]) -> DhcpPacket {
+    test_packet_from(0, Ipv4Addr::UNSPECIFIED, mac, vec![])
+}
+
+#[cfg(test)]
+fn test_packet_from(hops: u8, ciaddr: Ipv4Addr, mac: [u8; 6], options: Vec<dhcplib::option::DhcpOption>) -> DhcpPacket {
+    DhcpPacket::new(
+        dhcplib::MessageOperation::BootRequest,
+        dhcplib::HardwareAddressType::Ethernet,
+        hops,
+        123,
+        0,
+        dhcplib::Flags::Broadcast,
+        ciaddr,
+        std::net::Ipv4Addr::UNSPECIFIED,
+        std::net::Ipv4Addr::UNSPECIFIED,
+        std::net::Ipv4Addr::UNSPECIFIED,
+        macaddr::MacAddr6::from(mac),
+        ascii::AsciiString::new(),
+        ascii::AsciiString::new(),
+        DhcpOptions::new_with_options(options),
+    )
 }
@@ -0,0 +1,311 @@
+use crate::sources::allocator::AddressAllocator;
+use crate::sources::mapping::INFINITE_LEASE_TIME;
+use crate::error::DhcpResult;
+use serde::Deserialize;
+use dhcplib::DhcpPacket;
+use dhcplib::option::REQUESTED_IP_ADDRESS;
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::time::{Duration, SystemTime};
+
+/// Address of a client until it expires, infinite leases never expire
+struct PoolLease {
+    ip: Ipv4Addr,
+    /// tick of the last use
+    tick: u64,
+    expires: Option<SystemTime>,
+    /// offered but not yet requested by the client
+    offered: bool,
+}
+
+impl PoolLease {
+    fn valid(&self, now: SystemTime) -> bool {
+        self.expires.map_or(true, |expires| expires > now)
+    }
+}
+
+/// In memory address pool, one address per client mac
+#[derive(Deserialize)]
+pub(crate) struct DhcpPool {
+    start: Ipv4Addr,
+    end: Ipv4Addr,
+    /// seconds an offered address is held for the request of the client
+    #[serde(default = "DhcpPool::default_offer_timeout")]
+    offer_timeout: u64,
+    /// lease expiry of results without lease time
+    #[serde(default = "DhcpPool::default_lease_time")]
+    lease_time: u64,
+    #[serde(skip)]
+    leases: HashMap<String, PoolLease>,
+    #[serde(skip)]
+    tick: u64,
+    #[serde(skip)]
+    max_leases: Option<usize>,
+    #[serde(skip)]
+    blacklist: HashSet<Ipv4Addr>,
+}
+
+impl DhcpPool {
+    pub const NAME: &'static str = "pool";
+
+    fn default_offer_timeout() -> u64 { 60 }
+    fn default_lease_time() -> u64 { 3600 }
+
+    fn contains(&self, ip: Ipv4Addr) -> bool {
+        (u32::from(self.start)..=u32::from(self.end)).contains(&u32::from(ip))
+    }
+
+    /// In range, not declined and not held by another client
+    fn available(&self, ip: Ipv4Addr, mac: &str, now: SystemTime) -> bool {
+        self.contains(ip)
+            && !self.blacklist.contains(&ip)
+            && self.leases.iter().all(|(m, lease)| lease.ip != ip || m == mac || !lease.valid(now))
+    }
+
+    /// Records the lease, at the cap expired leases and then the least recently used offer of another client are evicted,
+    /// `false` if the table only holds live leases
+    fn lease(&mut self, mac: String, ip: Ipv4Addr, expires: Option<SystemTime>, offered: bool) -> bool {
+        self.tick += 1;
+
+        if let Some(max) = self.max_leases {
+            if !self.leases.contains_key(&mac) && self.leases.len() >= max {
+                self.expire();
+            }
+
+            if !self.leases.contains_key(&mac) && self.leases.len() >= max {
+                let oldest = self.leases.iter()
+                    .filter(|(_, lease)| lease.offered)
+                    .min_by_key(|(_, lease)| lease.tick)
+                    .map(|(m, _)| m.clone());
+
+                match oldest {
+                    Some(oldest) => {
+                        log::warn!("pool lease table full ({} entries), evicting the offer of {}", max, oldest);
+                        self.leases.remove(&oldest);
+                    }
+                    None => {
+                        log::warn!("pool lease table full ({} live leases), no address for {}", max, mac);
+                        return false;
+                    }
+                }
+            }
+        }
+
+        self.leases.insert(mac, PoolLease { ip, tick: self.tick, expires, offered });
+        true
+    }
+
+    fn requested(p: &DhcpPacket) -> Option<Ipv4Addr> {
+        p.options().try_ipv4_option(REQUESTED_IP_ADDRESS).ok()
+    }
+
+    /// Address of the client's lease, the requested one or the first available
+    fn candidate(&self, p: &DhcpPacket, mac: &str, now: SystemTime) -> Option<Ipv4Addr> {
+        self.leases.get(mac).map(|lease| lease.ip).filter(|ip| self.available(*ip, mac, now))
+            .or_else(|| Self::requested(p).filter(|ip| self.available(*ip, mac, now)))
+            .or_else(|| (u32::from(self.start)..=u32::from(self.end))
+                .map(Ipv4Addr::from)
+                .find(|ip| self.available(*ip, mac, now)))
+    }
+}
+
+#[async_trait::async_trait]
+impl AddressAllocator for DhcpPool {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    /// The offered address is held for `offer_timeout` seconds, a live lease of the client is kept as is
+    async fn allocate(&mut self, p: &DhcpPacket) -> DhcpResult<Option<Ipv4Addr>> {
+        let mac = p.client_hardware().to_string();
+        let now = SystemTime::now();
+
+        let ip = match self.candidate(p, &mac, now) {
+            Some(ip) => ip,
+            None => {
+                log::warn!("pool {} - {} exhausted", self.start, self.end);
+                return Ok(None);
+            }
+        };
+
+        let (expires, offered) = match self.leases.get(&mac) {
+            Some(lease) if !lease.offered && lease.ip == ip && lease.valid(now) => (lease.expires, false),
+            _ => (Some(now + Duration::from_secs(self.offer_timeout)), true),
+        };
+        Ok(Some(ip).filter(|_| self.lease(mac, ip, expires, offered)))
+    }
+
+    async fn peek(&mut self, p: &DhcpPacket) -> DhcpResult<Option<Ipv4Addr>> {
+        Ok(self.candidate(p, &p.client_hardware().to_string(), SystemTime::now()))
+    }
+
+    async fn confirm(&mut self, p: &DhcpPacket) -> DhcpResult<Option<Ipv4Addr>> {
+        let mac = p.client_hardware().to_string();
+
+        match Self::requested(p).or_else(|| self.leases.get(&mac).map(|lease| lease.ip)) {
+            Some(ip) if self.available(ip, &mac, SystemTime::now()) => Ok(Some(ip)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn commit(&mut self, p: &DhcpPacket, ip: Ipv4Addr, lease_time: Option<u32>) -> DhcpResult<bool> {
+        let expires = match lease_time.unwrap_or(self.lease_time as u32) {
+            INFINITE_LEASE_TIME => None,
+            seconds => Some(SystemTime::now() + Duration::from_secs(seconds as u64)),
+        };
+        Ok(self.lease(p.client_hardware().to_string(), ip, expires, false))
+    }
+
+    async fn release(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        self.leases.remove(&p.client_hardware().to_string());
+        Ok(())
+    }
+
+    async fn withdraw(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        let mac = p.client_hardware().to_string();
+        if self.leases.get(&mac).map_or(false, |lease| lease.offered) {
+            self.leases.remove(&mac);
+        }
+        Ok(())
+    }
+
+    async fn blacklist(&mut self, ip: Ipv4Addr) -> DhcpResult<()> {
+        log::warn!("{} declined, removed from pool", ip);
+        self.leases.retain(|_, lease| lease.ip != ip);
+        self.blacklist.insert(ip);
+        Ok(())
+    }
+
+    fn max_leases(&mut self, max: usize) {
+        self.max_leases = Some(max);
+    }
+
+    fn expire(&mut self) -> usize {
+        let now = SystemTime::now();
+        let before = self.leases.len();
+        self.leases.retain(|_, lease| lease.valid(now));
+        before - self.leases.len()
+    }
+
+    fn leases(&self) -> usize {
+        self.leases.len()
+    }
+}
+
+#[tokio::test]
+async fn test_pool() {
+    use crate::sources::DhcpHostSource;
+    use crate::sources::allocator::DhcpAllocatorSource;
+    use dhcplib::option::{DhcpOption, SUBNET_MASK};
+
+    let config: serde_yaml::Value = serde_yaml::from_str(r#"
+start: 10.0.0.10
+end: 10.0.0.11
+mapping:
+  subnet_mask:
+    data: 255.255.255.0
+"#).unwrap();
+    let mut s = DhcpAllocatorSource::<DhcpPool>::from_config(config).unwrap();
+    let p = crate::sources::test_packet(0, vec![]);
+    let requesting = |ip: Ipv4Addr| crate::sources::test_packet(0, vec![DhcpOption::RequestedIpAddress(ip)]);
+
+    let result = s.offer(&p).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 10)));
+    assert_eq!(result.options().try_ipv4_option(SUBNET_MASK).unwrap(), Ipv4Addr::new(255, 255, 255, 0));
+
+    let result = s.reserve(&requesting(Ipv4Addr::new(10, 0, 0, 10))).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 10)));
+    assert!(s.reserve(&requesting(Ipv4Addr::new(10, 0, 0, 20))).await.unwrap().is_none()); // out of range
+
+    s.decline(&requesting(Ipv4Addr::new(10, 0, 0, 10))).await.unwrap();
+    let result = s.offer(&p).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 11)));
+
+    s.release(&p).await.unwrap();
+    assert!(s.reserve(&requesting(Ipv4Addr::new(10, 0, 0, 10))).await.unwrap().is_none()); // declined before
+}
+
+#[tokio::test]
+async fn test_pool_lease_entries() {
+    use crate::sources::DhcpHostSource;
+    use crate::sources::allocator::DhcpAllocatorSource;
+    use crate::config::Limits;
+
+    let config: serde_yaml::Value = serde_yaml::from_str("start: 10.0.0.10\nend: 10.0.0.20").unwrap();
+    let mut s = DhcpAllocatorSource::<DhcpPool>::from_config(config).unwrap();
+    s.limits(&serde_yaml::from_str::<Limits>("lease_entries: 2").unwrap());
+    let client = |mac: u8| crate::sources::test_packet_from_mac([0, 0, 0, 0, 0, mac]);
+
+    s.offer(&client(1)).await.unwrap();
+    s.offer(&client(2)).await.unwrap();
+    s.offer(&client(1)).await.unwrap(); // 2 is now the least recently used
+    s.offer(&client(3)).await.unwrap();
+    assert_eq!(s.entries().await.lease_entries, 2);
+
+    // the evicted client gets a new allocation, the kept one its address
+    assert_eq!(s.offer(&client(1)).await.unwrap().unwrap().client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 10)));
+    assert_eq!(s.offer(&client(2)).await.unwrap().unwrap().client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 11)));
+}
+
+#[tokio::test]
+async fn test_pool_expiry() {
+    use crate::sources::DhcpHostSource;
+    use crate::sources::allocator::DhcpAllocatorSource;
+    use crate::config::Limits;
+    use dhcplib::option::DhcpOption;
+
+    let client = |mac: u8| crate::sources::test_packet_from_mac([0, 0, 0, 0, 0, mac]);
+    let requesting = |mac: u8| crate::sources::test_packet_from(0, Ipv4Addr::UNSPECIFIED, [0, 0, 0, 0, 0, mac],
+                                                                   vec![DhcpOption::RequestedIpAddress(Ipv4Addr::new(10, 0, 0, 10))]);
+
+    // offers are held until the client requests them
+    let config: serde_yaml::Value = serde_yaml::from_str("start: 10.0.0.10\nend: 10.0.0.10").unwrap();
+    let mut s = DhcpAllocatorSource::<DhcpPool>::from_config(config).unwrap();
+    assert!(s.preview(&client(1)).await.unwrap().is_some()); // nothing held
+    assert_eq!(s.entries().await.lease_entries, 0);
+    assert!(s.offer(&client(1)).await.unwrap().is_some());
+    assert!(s.offer(&client(2)).await.unwrap().is_none());
+    assert!(s.preview(&client(2)).await.unwrap().is_none());
+    s.withdraw(&client(1)).await.unwrap(); // 1 selected another server
+    assert!(s.offer(&client(2)).await.unwrap().is_some());
+
+    // an expired offer frees the address, the expired lease is swept
+    let config: serde_yaml::Value = serde_yaml::from_str("start: 10.0.0.10\nend: 10.0.0.10\noffer_timeout: 0\nlease_time: 0").unwrap();
+    let mut s = DhcpAllocatorSource::<DhcpPool>::from_config(config).unwrap();
+    assert!(s.offer(&client(1)).await.unwrap().is_some());
+    assert_eq!(s.offer(&client(2)).await.unwrap().unwrap().client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 10)));
+    assert!(s.reserve(&requesting(2)).await.unwrap().is_some());
+    assert!(s.reserve(&requesting(3)).await.unwrap().is_some()); // lease of 2 expired
+    s.maintenance().await.unwrap();
+    assert_eq!(s.entries().await.lease_entries, 0);
+
+    // live leases are never evicted at the cap
+    let config: serde_yaml::Value = serde_yaml::from_str("start: 10.0.0.10\nend: 10.0.0.20").unwrap();
+    let mut s = DhcpAllocatorSource::<DhcpPool>::from_config(config).unwrap();
+    s.limits(&serde_yaml::from_str::<Limits>("lease_entries: 1").unwrap());
+    assert!(s.reserve(&requesting(1)).await.unwrap().is_some());
+    assert!(s.offer(&client(2)).await.unwrap().is_none());
+    assert!(s.reserve(&requesting(1)).await.unwrap().is_some()); // renewal
+
+    // an infinite lease time of the mapping outlasts the pool default
+    let config: serde_yaml::Value = serde_yaml::from_str("start: 10.0.0.10\nend: 10.0.0.10\nlease_time: 0\nmapping:\n  ip_address_lease_time:\n    data: infinite").unwrap();
+    let mut s = DhcpAllocatorSource::<DhcpPool>::from_config(config).unwrap();
+    assert!(s.reserve(&requesting(1)).await.unwrap().is_some());
+    s.maintenance().await.unwrap();
+    assert_eq!(s.entries().await.lease_entries, 1);
+    assert!(s.reserve(&requesting(2)).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_pool_inform() {
+    use crate::sources::DhcpHostSource;
+    use crate::sources::allocator::DhcpAllocatorSource;
+
+    let config: serde_yaml::Value = serde_yaml::from_str("start: 10.0.0.10\nend: 10.0.0.20").unwrap();
+    let mut s = DhcpAllocatorSource::<DhcpPool>::from_config(config).unwrap();
+
+    assert!(s.inform(&crate::sources::test_packet(0, vec![])).await.unwrap().is_none());
+    let result = s.inform(&crate::sources::test_packet_with_client(Ipv4Addr::new(10, 0, 0, 50), vec![])).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 50)));
+    assert_eq!(s.entries().await.lease_entries, 0);
+}
@@ -1,15 +1,14 @@
-use crate::sources::{DhcpHostSource, DhcpSourceResult};
+use crate::sources::{DhcpHostSource, DhcpSourceResult, SourceEntries};
+use crate::config::Limits;
+use crate::sources::mapping::{DhcpMapping, RequestState, template_values, packet_context, request_state, check_templates};
 use serde::{Serialize, Deserializer, Deserialize};
 use crate::error::{DhcpResult, DhcpError};
 use std::collections::HashMap;
-use std::convert::{TryFrom, TryInto};
 use tera::Context;
 use std::str::FromStr;
-use dhcplib::option::{DhcpOption, DhcpOptions};
 use serde_yaml::Value;
-use reqwest::header::{HeaderName, HeaderValue, HeaderMap};
-use reqwest::{Client, Method};
-use serde_yaml::from_value as serde_from_value;
+use reqwest::header::{HeaderName, HeaderValue, HeaderMap, CACHE_CONTROL, ACCEPT};
+use reqwest::{Client, Method, Request, Response};
 use dhcplib::DhcpPacket;
 use url::Url;
 use std::time::{Duration, SystemTime};
@@ -18,106 +17,58 @@ use std::fmt::{Display, Formatter};
 use tokio::task::JoinHandle;
 use tokio::process::Command;
 use std::process::Stdio;
+use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use tokio::sync::Mutex;
+use crate::rate_limit::{RateLimitConfig, RateLimiter, ConcurrencyLimiter};
 
-macro_rules! to_value {
-    ($t:ident, $v:tt) => {
-        $v.try_into().and_then(|s: DhcpRestMappingItem| serde_from_value(s.data)
-                     .map(DhcpOption::$t)
-                     .map_err(Into::into))
-    }
-}
-
-fn template_values<'a>(value: &'a mut serde_yaml::Value, context: &'a Context) -> DhcpResult<&'a mut serde_yaml::Value> {
-    match value {
-        Value::String(s) => {
-            let t = tera::Tera::one_off(s, context, false)?;
-            *value = serde_yaml::from_str(&t)?;
-        }
-        Value::Sequence(v) => {
-            for i in v {
-                template_values(i, context)?;
-            }
-        }
-        Value::Mapping(v) => {
-            for (_, v) in v {
-                template_values(v, context)?;
-            }
-        }
-        _ => {}
-    }
-
-    Ok(value)
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "lowercase")]
-enum DhcpRestMappingItemCustomKind {
-    #[serde(alias = "str")]
-    String,
-    Bool,
-    Integer,
-    None,
-}
-
-impl Default for DhcpRestMappingItemCustomKind {
-    fn default() -> Self {
-        Self::None
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct DhcpRestMappingItem {
-    data: Value,
-    #[serde(default)]
-    required: bool,
-}
-
-impl TryFrom<serde_yaml::Value> for DhcpRestMappingItem {
-    type Error = DhcpError;
-
-    fn try_from(value: Value) -> Result<Self, Self::Error> {
-        serde_from_value(value).map_err(Into::into)
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct DhcpRestMappingItemCustom {
-    tag: u8,
-    #[serde(default)]
-    kind: DhcpRestMappingItemCustomKind,
-    #[serde(flatten)]
-    item: DhcpRestMappingItem,
-}
+/// Share of the ttl after which a background refresh starts
+const REFRESH_AHEAD: f64 = 0.8;
 
-impl TryFrom<serde_yaml::Value> for DhcpRestMappingItemCustom {
-    type Error = DhcpError;
-
-    fn try_from(value: Value) -> Result<Self, Self::Error> {
-        serde_from_value(value).map_err(Into::into)
-    }
-}
+/// Longest ttl taken from a response, the delta-seconds cap of RFC 9111
+const MAX_TTL_SECS: u64 = 1 << 31;
 
 struct DhcpRestSourceHttpCacheItem<T> {
     data: T,
     time: SystemTime,
+    ttl: Duration,
+    refreshing: bool,
+    /// last hit, the least recently used entry is evicted at the cap
+    used: SystemTime,
 }
 
 impl<T> DhcpRestSourceHttpCacheItem<T> {
-    fn expired(&self, duration: Duration) -> bool {
-        SystemTime::now() > self.time + duration
-    }
-}
-
-impl From<serde_json::Value> for DhcpRestSourceHttpCacheItem<serde_json::Value> {
-    fn from(data: serde_json::Value) -> Self {
+    fn new(data: T, ttl: Duration) -> Self {
         Self {
             data,
             time: SystemTime::now(),
+            ttl,
+            refreshing: false,
+            used: SystemTime::now(),
         }
     }
+
+    fn expired(&self) -> bool {
+        self.time.checked_add(self.ttl).map_or(false, |expires| SystemTime::now() > expires)
+    }
+
+    fn near_expiry(&self) -> bool {
+        self.time.checked_add(self.ttl.mul_f64(REFRESH_AHEAD)).map_or(false, |refresh| SystemTime::now() > refresh)
+    }
+}
+
+/// Persisted cache entry
+#[derive(Serialize, Deserialize)]
+struct DhcpRestSourceHttpCacheRecord {
+    url: Url,
+    method: String,
+    data: serde_json::Value,
+    time: SystemTime,
+    ttl: Duration,
 }
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(PartialEq, Eq, Hash, Clone)]
 struct DhcpRestSourceHttpCacheKey {
     url: Url,
     method: Method,
@@ -129,10 +80,23 @@ impl Display for DhcpRestSourceHttpCacheKey {
     }
 }
 
+type DhcpRestSourceHttpCache = Arc<Mutex<HashMap<DhcpRestSourceHttpCacheKey, DhcpRestSourceHttpCacheItem<serde_json::Value>>>>;
+
+#[derive(Clone)]
 struct DhcpRestSourceHttp {
-    cache: HashMap<DhcpRestSourceHttpCacheKey, DhcpRestSourceHttpCacheItem<serde_json::Value>>,
+    cache: DhcpRestSourceHttpCache,
     expiration: Duration,
+    background_refresh: bool,
+    ttl_field: Option<String>,
+    rate_limit: Option<RateLimiter>,
+    correlation_header: Option<HeaderName>,
+    headers: HeaderMap,
     http: Client,
+    capacity: Option<usize>,
+    retries: u32,
+    retry_delay: Duration,
+    /// rendered for the current request
+    auth: Option<DhcpRestQueryAuth>,
 }
 
 impl DhcpRestSourceHttp {
@@ -145,31 +109,188 @@ impl DhcpRestSourceHttp {
         Ok(Self {
             cache: Default::default(),
             expiration: Duration::from_secs_f32(expiration),
+            background_refresh: false,
+            ttl_field: None,
+            rate_limit: None,
+            correlation_header: None,
+            headers: HeaderMap::new(),
             http: Default::default(),
+            capacity: None,
+            retries: 0,
+            retry_delay: Duration::from_millis(0),
+            auth: None,
         })
     }
 
-    async fn json<T: DeserializeOwned>(&mut self, method: Method, url: Url, body: &Value) -> DhcpResult<T> {
-        let key = DhcpRestSourceHttpCacheKey { url: url.clone(), method: method.clone() };
+    async fn records(&self) -> Vec<DhcpRestSourceHttpCacheRecord> {
+        self.cache.lock().await.iter()
+            .filter(|(_, item)| !item.expired())
+            .map(|(key, item)| DhcpRestSourceHttpCacheRecord {
+                url: key.url.clone(),
+                method: key.method.to_string(),
+                data: item.data.clone(),
+                time: item.time,
+                ttl: item.ttl,
+            })
+            .collect()
+    }
+
+    /// Drops expired entries, returns how many
+    async fn sweep(&self) -> usize {
+        let mut cache = self.cache.lock().await;
+        let before = cache.len();
+        cache.retain(|_, item| !item.expired());
+        before - cache.len()
+    }
+
+    /// Loads persisted entries which are not expired yet, used before the source is shared
+    fn restore(&self, records: Vec<DhcpRestSourceHttpCacheRecord>) {
+        let mut cache = match self.cache.try_lock() {
+            Ok(cache) => cache,
+            Err(_) => return,
+        };
+
+        for r in records {
+            let item = DhcpRestSourceHttpCacheItem { data: r.data, time: r.time, ttl: r.ttl, refreshing: false, used: r.time };
+            match Method::from_str(&r.method) {
+                Ok(method) if !item.expired() => {
+                    cache.insert(DhcpRestSourceHttpCacheKey { url: r.url, method }, item);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Cache-Control max-age of the response
+    fn max_age(headers: &HeaderMap) -> Option<Duration> {
+        headers.get(CACHE_CONTROL)?
+            .to_str().ok()?
+            .split(',')
+            .find_map(|d| d.trim().strip_prefix("max-age="))
+            .and_then(|s| s.parse::<u64>().ok())
+            .map(|secs| Duration::from_secs(secs.min(MAX_TTL_SECS)))
+    }
 
-        if let Some(j) = self.cache.get(&key) {
-            if !j.expired(self.expiration) { // use cached value
-                log::debug!("use cached item {}", key);
-                let value = j.data.clone();
-                return serde_json::from_value(value).map_err(DhcpError::SerdeJsonError);
+    /// Entry ttl by json field, max-age header or configured expiration
+    fn ttl(&self, value: &serde_json::Value, max_age: Option<Duration>) -> Duration {
+        self.ttl_field.as_ref()
+            .and_then(|f| value.pointer(f))
+            .and_then(serde_json::Value::as_f64)
+            .filter(|ttl| *ttl >= 0.0)
+            .map(|ttl| Duration::from_secs_f64(ttl.min(MAX_TTL_SECS as f64)))
+            .or(max_age)
+            .unwrap_or(self.expiration)
+    }
+
+    async fn json<T: DeserializeOwned>(&mut self, method: Method, url: Url, body: &Value) -> DhcpResult<T> {
+        let key = DhcpRestSourceHttpCacheKey { url, method };
+
+        {
+            let mut cache = self.cache.lock().await;
+            if let Some(j) = cache.get_mut(&key) {
+                if !j.expired() { // use cached value
+                    log::debug!("use cached item {}", key);
+                    j.used = SystemTime::now();
+                    if self.background_refresh && !j.refreshing && j.near_expiry() {
+                        j.refreshing = true;
+                        self.refresh(key, body.clone());
+                    }
+                    let value = j.data.clone();
+                    return serde_json::from_value(value).map_err(DhcpError::SerdeJsonError);
+                }
+                log::debug!("cached item {} expired", key);
+                cache.remove(&key); // invalidate expired data
             }
-            log::debug!("cached item {} expired", key);
-            self.cache.remove(&key); // invalidate expired data
+        }
+
+        let value = self.fetch(key, body).await?;
+        serde_json::from_value(value).map_err(DhcpError::SerdeJsonError)
+    }
+
+    /// Requests the backend and caches the response
+    async fn fetch(&self, key: DhcpRestSourceHttpCacheKey, body: &Value) -> DhcpResult<serde_json::Value> {
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.acquire().await?;
         }
 
         // new request/response
-        let request = self.http.request(method, url).json(body).build()?;
-        let response = self.http.execute(request).await?;
+        let mut request = self.http.request(key.method.clone(), key.url.clone()).headers(self.headers.clone()).json(body);
+        if let Some(header) = &self.correlation_header {
+            request = request.header(header.clone(), crate::server::correlation_id());
+        }
+        request = match &self.auth {
+            Some(DhcpRestQueryAuth::Bearer { token }) => request.bearer_auth(token),
+            Some(DhcpRestQueryAuth::Basic { username, password }) => request.basic_auth(username, password.as_ref()),
+            None => request,
+        };
+        let response = self.execute(request.build()?).await?;
+        let max_age = Self::max_age(response.headers());
         let value: serde_json::Value = response.json().await?;
-        if self.expiration.as_secs_f32() > 0.0 {
-            self.cache.insert(key, value.clone().into());
+        let ttl = self.ttl(&value, max_age);
+        if ttl.as_secs_f32() > 0.0 {
+            let mut cache = self.cache.lock().await;
+            self.evict(&mut cache, &key);
+            cache.insert(key, DhcpRestSourceHttpCacheItem::new(value.clone(), ttl));
+        }
+        Ok(value)
+    }
+
+    /// Retries connection errors and server errors with exponential backoff, other responses are final
+    async fn execute(&self, mut request: Request) -> DhcpResult<Response> {
+        let mut attempt = 0;
+
+        loop {
+            let retry = if attempt < self.retries { request.try_clone() } else { None };
+            let url = request.url().clone();
+            let result = self.http.execute(request).await;
+
+            let reason = match &result {
+                Ok(response) if response.status().is_server_error() => response.status().to_string(),
+                Err(e) if e.is_connect() || e.is_timeout() => e.to_string(),
+                _ => return Ok(result?),
+            };
+            request = match retry {
+                Some(retry) => retry,
+                None => return Ok(result?),
+            };
+
+            let delay = self.retry_delay.saturating_mul(2u32.saturating_pow(attempt));
+            attempt += 1;
+            log::warn!("{} failed ({}), retry {} of {} in {:?}", url, reason, attempt, self.retries, delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Makes room for a new key at the cap, dropping the least recently used entry
+    fn evict(&self, cache: &mut HashMap<DhcpRestSourceHttpCacheKey, DhcpRestSourceHttpCacheItem<serde_json::Value>>, key: &DhcpRestSourceHttpCacheKey) {
+        let max = match self.capacity {
+            Some(max) if !cache.contains_key(key) && cache.len() >= max => max,
+            _ => return,
+        };
+
+        if let Some(oldest) = cache.iter().min_by_key(|(_, item)| item.used).map(|(k, _)| k.clone()) {
+            log::warn!("rest cache full ({} entries), evicting {}", max, oldest);
+            cache.remove(&oldest);
         }
-        serde_json::from_value(value).map_err(DhcpError::SerdeJsonError)
+    }
+
+    async fn len(&self) -> usize {
+        self.cache.lock().await.len()
+    }
+
+    /// Refetches a cached entry near expiry in the background, the cached value answers meanwhile
+    fn refresh(&self, key: DhcpRestSourceHttpCacheKey, body: Value) {
+        let http = self.clone();
+
+        tokio::spawn(async move {
+            log::debug!("refreshing cached item {}", key);
+            if let Err(e) = http.fetch(key.clone(), &body).await {
+                log::warn!("refreshing cached item {} failed: {}", key, e);
+                if let Some(item) = http.cache.lock().await.get_mut(&key) {
+                    item.refreshing = false; // retried by the next hit
+                }
+            }
+        });
     }
 }
 
@@ -178,8 +299,72 @@ impl Default for DhcpRestSourceHttp {
         Self {
             cache: Default::default(),
             expiration: Default::default(),
+            background_refresh: false,
+            ttl_field: None,
+            rate_limit: None,
+            correlation_header: None,
+            headers: HeaderMap::new(),
             http: Default::default(),
+            capacity: None,
+            retries: 0,
+            retry_delay: Duration::from_millis(0),
+            auth: None,
+        }
+    }
+}
+
+/// Http client settings shared by the queries of all sources referencing the profile by name
+#[derive(Serialize, Deserialize)]
+pub(crate) struct HttpClientProfile {
+    #[serde(default = "HttpClientProfile::ssl_verify")]
+    ssl_verify: bool,
+    proxy: Option<String>,
+    timeout: Option<u64>,
+    connect_timeout: Option<u64>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+}
+
+impl HttpClientProfile {
+    fn ssl_verify() -> bool { true }
+
+    pub(crate) fn build(&self) -> DhcpResult<Client> {
+        let mut builder = Client::builder()
+            .danger_accept_invalid_certs(!self.ssl_verify)
+            .default_headers(DhcpRestConfigSchemaQuery::map_to_headers(&self.headers)?);
+
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy.as_str())?);
         }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(Duration::from_secs(timeout));
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(Duration::from_secs(timeout));
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Credentials of a query, templated like the url
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum DhcpRestQueryAuth {
+    Bearer { token: String },
+    Basic { username: String, password: Option<String> },
+}
+
+impl DhcpRestQueryAuth {
+    fn render(&self, context: &Context) -> DhcpResult<Self> {
+        let render = |s: &str| tera::Tera::one_off(s, context, false);
+
+        Ok(match self {
+            Self::Bearer { token } => Self::Bearer { token: render(token)? },
+            Self::Basic { username, password } => Self::Basic {
+                username: render(username)?,
+                password: password.as_deref().map(render).transpose()?,
+            },
+        })
     }
 }
 
@@ -187,28 +372,74 @@ impl Default for DhcpRestSourceHttp {
 struct DhcpRestConfigSchemaQuery {
     url: String,
     name: String,
-    #[serde(default = "DhcpRestConfigSchemaQuery::ssl_verify")]
-    ssl_verify: bool,
+    /// verified by default, set by the profile for queries using `http_client`
+    ssl_verify: Option<bool>,
     headers: Option<HashMap<String, String>>,
     #[serde(deserialize_with = "DhcpRestSourceHttp::deserialize_with", default)]
     cache: DhcpRestSourceHttp,
+    cache_ttl_field: Option<String>,
+    cache_pool: Option<String>,
+    error_field: Option<String>,
+    http_client: Option<String>,
     #[serde(deserialize_with = "DhcpRestConfigSchemaQuery::deserialize_with")]
     method: Method,
     #[serde(default)]
     body: Value,
+    #[serde(default = "DhcpRestConfigSchemaQuery::required")]
+    required: bool,
+    /// retries of connection and server errors
+    #[serde(default)]
+    retries: u32,
+    /// delay before the first retry, doubled for each further one
+    #[serde(default)]
+    retry_delay_ms: u64,
+    /// bearer token or basic auth, besides an `Authorization` header
+    auth: Option<DhcpRestQueryAuth>,
+    /// limit of the whole query including retries
+    #[serde(default = "DhcpRestConfigSchemaQuery::timeout_ms")]
+    timeout_ms: u64,
 }
 
 impl DhcpRestConfigSchemaQuery {
-    fn ssl_verify() -> bool { true }
+    fn required() -> bool { true }
+
+    fn timeout_ms() -> u64 { 5000 }
 
+    /// Own http client unless a shared client profile is used, headers are sent on top of the profile's
     fn init(&mut self) -> DhcpResult<()> {
-        self.cache.http = Client::builder()
-            .danger_accept_invalid_certs(self.ssl_verify)
-            .default_headers(Self::map_to_headers(self.headers.as_ref().unwrap_or(&HashMap::new()))?)
-            .build()?;
+        match (&self.http_client, self.ssl_verify) {
+            (Some(profile), Some(_)) => return Err(DhcpError::ConfigInvalid(
+                format!("query {} sets ssl_verify next to http_client {}, set it in the profile", self.name, profile))),
+            (Some(_), None) => {}
+            (None, ssl_verify) => {
+                self.cache.http = Client::builder()
+                    .danger_accept_invalid_certs(!ssl_verify.unwrap_or(true))
+                    .build()?;
+            }
+        }
+        self.cache.headers = Self::map_to_headers(self.headers.as_ref().unwrap_or(&HashMap::new()))?;
+        self.cache.ttl_field = self.cache_ttl_field.clone();
+        self.cache.retries = self.retries;
+        self.cache.retry_delay = Duration::from_millis(self.retry_delay_ms);
         Ok(())
     }
 
+    async fn run(&mut self, context: &Context) -> DhcpResult<serde_json::Value> {
+        let templated_query = tera::Tera::one_off(&self.url, context, false)?;
+        template_values(&mut self.body, context, &self.name)?;
+        self.cache.auth = self.auth.as_ref().map(|auth| auth.render(context)).transpose()?;
+        let request = self.cache.json(self.method.clone(), templated_query.parse()?, &self.body);
+        let result: serde_json::Value = tokio::time::timeout(Duration::from_millis(self.timeout_ms), request).await
+            .map_err(|_| DhcpError::QueryTimeout(self.name.clone()))??;
+
+        // backend policy, the client is not answered and nak'd with the error
+        match self.error_field.as_deref().and_then(|f| result.pointer(f)) {
+            None | Some(serde_json::Value::Null) => Ok(result),
+            Some(serde_json::Value::String(e)) => Err(DhcpError::Rejected(e.clone())),
+            Some(e) => Err(DhcpError::Rejected(e.to_string())),
+        }
+    }
+
     fn deserialize_with<'de, D>(deserializer: D) -> Result<Method, D::Error>
         where
             D: Deserializer<'de>,
@@ -232,7 +463,9 @@ impl DhcpRestConfigSchemaQuery {
             h.insert(HeaderName::from_str(k)?, HeaderValue::from_str(v)?);
         }
 
-        h.insert("Accept", "application/json".parse()?);
+        if !h.contains_key(ACCEPT) { // user headers win
+            h.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        }
 
         Ok(h)
     }
@@ -250,12 +483,23 @@ struct DhcpRestConfigSchemaScript {
 impl DhcpRestConfigSchemaScript {
     fn timeout() -> u64 { 60 }
 
-    async fn run(&self, context: &Context) -> DhcpResult<()> {
+    async fn run(&self, context: &Context, limiter: Option<&ConcurrencyLimiter>) -> DhcpResult<()> {
         let program = tera::Tera::one_off(&self.exec, context, false)?;
         let args = self.args.iter().map(|a| {
             tera::Tera::one_off(a, context, false).map_err(Into::into)
         }).collect::<DhcpResult<Vec<String>>>()?;
 
+        let permit = match limiter {
+            Some(limiter) => match limiter.acquire().await {
+                Some(permit) => Some(permit),
+                None => {
+                    log::warn!("script {} skipped, too many scripts running", program);
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
+
         log::debug!("running script: {} {}", program, args.join(" "));
 
         let mut c = Command::new(&program);
@@ -272,6 +516,7 @@ impl DhcpRestConfigSchemaScript {
 
         let timeout = self.timeout;
         let j: JoinHandle<DhcpResult<()>> = tokio::spawn(async move {
+            let _permit = permit; // released when the script is done
             match tokio::time::timeout(Duration::from_secs(timeout), child.wait_with_output()).await {
                 Ok(Ok(output)) => {
                     let stdout = String::from_utf8(output.stdout)
@@ -304,251 +549,469 @@ impl DhcpRestConfigSchemaScript {
 struct DhcpRestSourceConfigSchema {
     scripts: Vec<DhcpRestConfigSchemaScript>,
     queries: Vec<DhcpRestConfigSchemaQuery>,
-    mapping: HashMap<String, serde_yaml::Value>,
+    mapping: DhcpMapping,
 }
 
 impl DhcpRestSourceConfigSchema {
-    fn is_required(value: &Value) -> bool {
-        if let serde_yaml::Value::Mapping(m) = value {
-            m.get(&serde_yaml::Value::String("required".to_string()))
-                .unwrap_or(&serde_yaml::Value::Bool(false))
-                .as_bool()
-                .unwrap_or(false)
-        } else {
-            false
-        }
-    }
-
-    fn context_to_result(&mut self, context: &Context) -> DhcpResult<DhcpSourceResult> {
-        let mut client_ip_address = None;
-        let mut options = DhcpOptions::new();
-
-        for (key, value) in &mut self.mapping {
-            let required = Self::is_required(value);
-            let template_result = template_values(value, &context);
-
-            // handle error if required
-            match template_result {
-                Ok(_) => {}
-                Err(e) if required => return Err(e),
-                Err(e) => {
-                    log::warn!("option templating error {} ({})", key, e);
-                    continue;
-                }
-            }
+    fn context_to_result(&self, context: &Context, strict: bool) -> DhcpResult<DhcpSourceResult> {
+        self.mapping.to_result(context, strict)
+    }
+}
 
-            let v = value.clone();
+/// Context variable computed from the packet before any query runs
+#[derive(Deserialize)]
+struct DhcpRestPreQueryVariable {
+    name: String,
+    value: String,
+}
 
-            // map + deserialize option
-            let option = match key.as_str() {
-                "client_ip_address" => {
-                    client_ip_address = Some(serde_from_value(v).map_err(|e| {
-                        log::error!("{}:{:?} - {}", key, value, e);
-                        e
-                    })?);
-                    continue;
-                }
-                "subnet_mask" => to_value!(SubnetMask, v),
-                "time_offset" => to_value!(TimeOffset, v),
-                "router" => to_value!(Router,v),
-                "time_server" => to_value!(TimeServer,v),
-                "name_server" => to_value!(NameServer,v),
-                "domain_name_server" => to_value!(DomainNameServer,v),
-                "log_server" => to_value!(LogServer,v),
-                "cookie_server" => to_value!(CookieServer,v),
-                "lpr_server" => to_value!(LPRServer,v),
-                "impress_server" => to_value!(ImpressServer,v),
-                "resource_location_server" => to_value!(ResourceLocationServer,v),
-                "host_name" => to_value!(HostName,v),
-                "boot_file_size" => to_value!(BootFileSize,v),
-                "merit_dump_file" => to_value!(MeritDumpFile,v),
-                "domain_name" => to_value!(DomainName,v),
-                "swap_server" => to_value!(SwapServer,v),
-                "root_path" => to_value!(RootPath,v),
-                "extension_path" => to_value!(ExtensionPath,v),
-                "ip_forwarding" => to_value!(IpForwarding,v),
-                "non_local_source_routing" => to_value!(NonLocalSourceRouting,v),
-                "policy_filter" => to_value!(PolicyFilter,v),
-                "maximum_datagram_reassembly_size" => to_value!(MaximumDatagramReassemblySize,v),
-                "default_ip_ttl" => to_value!(DefaultIpTTL,v),
-                "path_mtu_aging_timeout" => to_value!(PathMtuAgingTimeout,v),
-                "path_mtu_plateau_table" => to_value!(PathMtuPlateauTable,v),
-                "interface_mtu" => to_value!(InterfaceMtu,v),
-                "all_subnets_local" => to_value!(AllSubnetsLocal,v),
-                "broadcast_address" => to_value!(BroadcastAddress,v),
-                "mask_supplier" => to_value!(MaskSupplier,v),
-                "perform_router_discovery" => to_value!(PerformRouterDiscovery,v),
-                "router_solicitation_address" => to_value!(RouterSolicitationAddress,v),
-                "static_route" => to_value!(StaticRoute,v),
-                "trailer_encapsulation" => to_value!(TrailerEncapsulation,v),
-                "arp_cache_timeout" => to_value!(ArpCacheTimeout,v),
-                "ethernet_encapsulation" => to_value!(EthernetEncapsulation,v),
-                "tcp_default_ttl" => to_value!(TcpDefaultTTL,v),
-                "tcp_keep_alive_interval" => to_value!(TcpKeepAliveInterval,v),
-                "tcp_keep_alive_garbage" => to_value!(TcpKeepAliveGarbage,v),
-                "network_information_service_domain" => to_value!(NetworkInformationServiceDomain,v),
-                "network_information_servers" => to_value!(NetworkInformationServers,v),
-                "network_time_protocol_servers" => to_value!(NetworkTimeProtocolServers,v),
-                "vendor_specific" => to_value!(VendorSpecific,v),
-                "net_bios_over_tcp_ip_name_server" => to_value!(NetBiosOverTcpIpNameServer,v),
-                "net_bios_over_tcp_ip_datagram_distribution_server" => to_value!(NetBiosOverTcpIpDatagramDistributionServer,v),
-                "net_bios_over_tcp_ip_node_type" => to_value!(NetBiosOverTcpIpNodeType,v),
-                "net_bios_over_tcp_ip_scope" => to_value!(NetBiosOverTcpIpScope,v),
-                "x_window_system_font_server" => to_value!(XWindowSystemFontServer,v),
-                "x_window_system_display_manager" => to_value!(XWindowSystemDisplayManager,v),
-                "requested_ip_address" => to_value!(RequestedIpAddress,v),
-                "ip_address_lease_time" => to_value!(IpAddressLeaseTime,v),
-                "option_overload" => to_value!(OptionOverload,v),
-                "message_type" => to_value!(MessageType,v),
-                "server_identifier" => to_value!(ServerIdentifier,v),
-                "parameter_request_list" => to_value!(ParameterRequestList,v),
-                "message" => to_value!(Message,v),
-                "maximum_dhcp_message_size" => to_value!(MaximumDhcpMessageSize,v),
-                "renewal_time_value" => to_value!(RenewalTimeValue,v),
-                "rebinding_time_value" => to_value!(RebindingTimeValue,v),
-                "vendor_class_identifier" => to_value!(VendorClassIdentifier,v),
-                "client_identifier" => to_value!(ClientIdentifier,v),
-                "network_information_service_plus_domain" => to_value!(NetworkInformationServicePlusDomain,v),
-                "network_information_service_plus_server" => to_value!(NetworkInformationServicePlusServer,v),
-                "tftp_server" => to_value!(TftpServer,v),
-                "boot_file_name" => to_value!(BootFileName,v),
-                "mobile_ip_home_agent" => to_value!(MobileIpHomeAgent,v),
-                "smtp_server" => to_value!(SmtpServer,v),
-                "pop3_server" => to_value!(Pop3Server,v),
-                "nntp_server" => to_value!(NntpServer,v),
-                "www_server" => to_value!(WwwServer,v),
-                "finger_server" => to_value!(FingerServer,v),
-                "irc_server" => to_value!(IrcServer,v),
-                "street_talk_server" => to_value!(StreetTalkServer,v),
-                "street_talk_directory_assistance_server" => to_value!(StreetTalkDirectoryAssistanceServer,v),
-                _ => DhcpRestMappingItemCustom::try_from(v).and_then(TryInto::try_into) // custom options
-            };
+/// Last known good query results by `<operation>.<client mac>`, answers while the backend is down
+struct DhcpRestSourceSnapshot {
+    path: PathBuf,
+    interval: Duration,
+    results: HashMap<String, HashMap<String, serde_json::Value>>,
+    persisted: SystemTime,
+}
 
-            // handle errors if required
-            match option {
-                Ok(v) => options.upsert(v),
-                Err(e) if required => return Err(e),
-                Err(e) => log::warn!("invalid option mapping: {}:{:?} ({})", key, value, e)
+impl DhcpRestSourceSnapshot {
+    fn load(path: PathBuf, interval: Duration) -> Self {
+        let results = match File::open(&path)
+            .map_err(DhcpError::from)
+            .and_then(|f| serde_json::from_reader(f).map_err(Into::into)) {
+            Ok(results) => results,
+            Err(e) => {
+                log::warn!("fallback snapshot {} not loaded: {}", path.display(), e);
+                HashMap::new()
             }
+        };
+
+        Self {
+            path,
+            interval,
+            results,
+            persisted: SystemTime::now(),
         }
+    }
+
+    fn persist(&mut self) -> DhcpResult<()> {
+        serde_json::to_writer(File::create(&self.path)?, &self.results)?;
+        self.persisted = SystemTime::now();
+        Ok(())
+    }
 
-        Ok(DhcpSourceResult::new(client_ip_address, options))
+    /// Persisted at most once per interval
+    fn update(&mut self, key: String, results: HashMap<String, serde_json::Value>) {
+        self.results.insert(key, results);
+
+        if self.persisted.elapsed().map_or(true, |e| e >= self.interval) {
+            if let Err(e) = self.persist() {
+                log::error!("fallback snapshot {} not persisted: {}", self.path.display(), e);
+            }
+        }
     }
 }
 
 #[derive(Deserialize)]
 struct DhcpRestSourceConfig {
+    rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    background_refresh: bool,
+    cache_persist: Option<PathBuf>,
+    sticky_ttl: Option<u64>,
+    fallback_snapshot: Option<PathBuf>,
+    #[serde(default = "DhcpRestSourceConfig::fallback_snapshot_interval")]
+    fallback_snapshot_interval: u64,
+    #[serde(default = "DhcpRestSourceConfig::correlation_header")]
+    correlation_header: Option<String>,
+    #[serde(default)]
+    pre_query: Vec<DhcpRestPreQueryVariable>,
     offer: DhcpRestSourceConfigSchema,
     reserve: DhcpRestSourceConfigSchema,
+    reserve_initial: Option<DhcpRestSourceConfigSchema>,
+    reserve_renew: Option<DhcpRestSourceConfigSchema>,
     release: DhcpRestSourceConfigSchema,
+    release_confirm: Option<DhcpRestSourceConfigSchema>,
     decline: DhcpRestSourceConfigSchema,
     inform: DhcpRestSourceConfigSchema,
 }
 
+impl DhcpRestSourceConfig {
+    fn fallback_snapshot_interval() -> u64 { 60 }
+
+    fn correlation_header() -> Option<String> { Some("X-Request-Id".to_string()) }
+
+    fn sticky_ttl(&self) -> Option<Duration> {
+        self.sticky_ttl.filter(|ttl| *ttl > 0).map(Duration::from_secs)
+    }
+}
+
 pub(crate) struct DhcpRestSource {
-    config: DhcpRestSourceConfig
+    config: DhcpRestSourceConfig,
+    snapshot: Option<DhcpRestSourceSnapshot>,
+    cache_pools: HashMap<String, DhcpRestSourceHttpCache>,
+    /// last result by client mac, answers retransmits and the request following an offer
+    sticky: HashMap<String, DhcpRestSourceHttpCacheItem<DhcpSourceResult>>,
+    strict_mappings: bool,
+    script_limiter: Option<ConcurrencyLimiter>,
 }
 
 impl DhcpRestSource {
-    async fn query(config: &mut DhcpRestSourceConfigSchema, p: &DhcpPacket) -> DhcpResult<Context> {
-        let mut context = Context::new();
+    pub const NAME: &'static str = "rest";
+
+    fn schemas(&self) -> Vec<(&'static str, &DhcpRestSourceConfigSchema)> {
+        let mut schemas = vec![
+            ("offer", &self.config.offer),
+            ("reserve", &self.config.reserve),
+            ("release", &self.config.release),
+            ("decline", &self.config.decline),
+            ("inform", &self.config.inform),
+        ];
+        schemas.extend(self.config.reserve_initial.as_ref().map(|s| ("reserve_initial", s)));
+        schemas.extend(self.config.reserve_renew.as_ref().map(|s| ("reserve_renew", s)));
+        schemas.extend(self.config.release_confirm.as_ref().map(|s| ("release_confirm", s)));
+        schemas
+    }
 
-        context.insert("client_hardware_address", &p.client_hardware().to_string());
-        context.insert("client_ip_address", &p.client());
-        context.insert("server_ip_address", &p.server());
-        context.insert("client_hostname", &p.hostname());
+    fn schemas_mut(config: &mut DhcpRestSourceConfig) -> Vec<&mut DhcpRestSourceConfigSchema> {
+        let mut schemas = vec![&mut config.offer, &mut config.reserve, &mut config.release, &mut config.decline, &mut config.inform];
+        schemas.extend(config.reserve_initial.as_mut());
+        schemas.extend(config.reserve_renew.as_mut());
+        schemas.extend(config.release_confirm.as_mut());
+        schemas
+    }
 
-        let mut queries: HashMap<String, serde_json::Value> = HashMap::new();
-        for q in &mut config.queries {
-            let templated_query = tera::Tera::one_off(&q.url, &context, false)?;
-            template_values(&mut q.body, &context)?;
-            let result: serde_json::Value = q.cache.json(q.method.clone(), templated_query.parse()?, &q.body).await?;
+    /// Schema answering a request in its state, `reserve` unless a state specific schema is configured
+    fn reserve_schema<'a>(config: &'a mut DhcpRestSourceConfig, p: &DhcpPacket) -> (&'a mut DhcpRestSourceConfigSchema, &'a [DhcpRestPreQueryVariable]) {
+        let schema = match request_state(p) {
+            Some(RequestState::Renewing) => config.reserve_renew.as_mut(),
+            _ => config.reserve_initial.as_mut(),
+        };
+        (schema.unwrap_or(&mut config.reserve), &config.pre_query)
+    }
 
-            queries.insert(q.name.clone(), result);
-            context.insert("results", &queries)
+    fn sticky_result(&mut self, p: &DhcpPacket) -> Option<DhcpSourceResult> {
+        let mac = p.client_hardware().to_string();
+        match self.sticky.get(&mac) {
+            Some(item) if !item.expired() => {
+                log::debug!("use sticky result of {}", mac);
+                Some(item.data.clone())
+            }
+            Some(_) => {
+                self.sticky.remove(&mac);
+                None
+            }
+            None => None,
         }
+    }
 
-        Ok(context)
+    fn stick(&mut self, p: &DhcpPacket, result: &DhcpSourceResult) {
+        if let Some(ttl) = self.config.sticky_ttl() {
+            self.sticky.insert(p.client_hardware().to_string(), DhcpRestSourceHttpCacheItem::new(result.clone(), ttl));
+        }
     }
-}
 
-impl TryInto<DhcpOption> for DhcpRestMappingItemCustom {
-    type Error = DhcpError;
+    /// Cache entries by `<operation>.<query name>`
+    async fn persist_cache(&self, path: &Path) -> DhcpResult<()> {
+        let mut records = HashMap::new();
 
-    fn try_into(self) -> Result<DhcpOption, Self::Error> {
-        let d = match self.item.data {
-            Value::Null => vec![],
-            Value::Bool(v) => {
-                if v { vec![1] } else { vec![0] }
+        for (operation, schema) in self.schemas() {
+            for q in &schema.queries {
+                records.insert(format!("{}.{}", operation, q.name), q.cache.records().await);
             }
-            Value::Number(v) => {
-                if let Some(i) = v.as_i64() {
-                    i.to_be_bytes().to_vec()
-                } else if let Some(i) = v.as_f64() {
-                    i.to_be_bytes().to_vec()
-                } else if let Some(i) = v.as_u64() {
-                    i.to_be_bytes().to_vec()
-                } else {
-                    return Err(DhcpError::CustomRestTypeError);
+        }
+
+        serde_json::to_writer(File::create(path)?, &records)?;
+        log::info!("rest cache persisted to {}", path.display());
+        Ok(())
+    }
+
+    fn restore_cache(&self, path: &Path) {
+        let mut records: HashMap<String, Vec<DhcpRestSourceHttpCacheRecord>> = match File::open(path)
+            .map_err(DhcpError::from)
+            .and_then(|f| serde_json::from_reader(f).map_err(Into::into)) {
+            Ok(records) => records,
+            Err(e) => {
+                log::warn!("rest cache {} not restored: {}", path.display(), e);
+                return;
+            }
+        };
+
+        for (operation, schema) in self.schemas() {
+            for q in &schema.queries {
+                if let Some(r) = records.remove(&format!("{}.{}", operation, q.name)) {
+                    q.cache.restore(r);
                 }
             }
-            Value::String(v) => v.as_bytes().to_vec(),
-            _ => return Err(DhcpError::CustomRestTypeError),
+        }
+        log::info!("rest cache restored from {}", path.display());
+    }
+
+    /// Packet context with the `pre_query` variables, later variables may use earlier ones
+    fn source_context(pre_query: &[DhcpRestPreQueryVariable], p: &DhcpPacket) -> DhcpResult<Context> {
+        let mut context = packet_context(p);
+
+        for v in pre_query {
+            let value = tera::Tera::one_off(&v.value, &context, false)
+                .map_err(|e| DhcpError::TemplateError { option: v.name.clone(), template: v.value.clone(), field: None, source: e })?;
+            context.insert(v.name.as_str(), &value);
+        }
+
+        Ok(context)
+    }
+
+    fn results_context(pre_query: &[DhcpRestPreQueryVariable], p: &DhcpPacket, results: &HashMap<String, serde_json::Value>) -> DhcpResult<Context> {
+        let mut context = Self::source_context(pre_query, p)?;
+        context.insert("results", results);
+        Ok(context)
+    }
+
+    /// Query results, the fallback snapshot answers if the backend failed
+    fn fallback(&mut self, operation: &str, p: &DhcpPacket, results: DhcpResult<HashMap<String, serde_json::Value>>) -> DhcpResult<Context> {
+        let snapshot = match self.snapshot.as_mut() {
+            Some(snapshot) => snapshot,
+            None => return results.and_then(|r| Self::results_context(&self.config.pre_query, p, &r)),
         };
-        Ok(DhcpOption::Unknown(self.tag, d))
+        let key = format!("{}.{}", operation, p.client_hardware());
+
+        match results {
+            Ok(results) => {
+                let context = Self::results_context(&self.config.pre_query, p, &results)?;
+                snapshot.update(key, results);
+                Ok(context)
+            }
+            Err(DhcpError::Rejected(reason)) => {
+                snapshot.results.remove(&key);
+                Err(DhcpError::Rejected(reason))
+            }
+            Err(e) => match snapshot.results.get(&key) {
+                Some(results) => {
+                    log::warn!("{} answered from stale snapshot, backend failed: {}", key, e);
+                    Self::results_context(&self.config.pre_query, p, results)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    async fn query(config: &mut DhcpRestSourceConfigSchema, pre_query: &[DhcpRestPreQueryVariable], p: &DhcpPacket) -> DhcpResult<HashMap<String, serde_json::Value>> {
+        let mut context = Self::source_context(pre_query, p)?;
+
+        let mut queries: HashMap<String, serde_json::Value> = HashMap::new();
+        for q in &mut config.queries {
+            let result = match q.run(&context).await {
+                Ok(result) => result,
+                Err(e) if !q.required && !matches!(e, DhcpError::Rejected(_)) => {
+                    log::warn!("optional query {} failed, continuing without: {}", q.name, e);
+                    serde_json::Value::Null
+                }
+                Err(e) => return Err(e),
+            };
+
+            queries.insert(q.name.clone(), result);
+            context.insert("results", &queries)
+        }
+
+        Ok(queries)
     }
 }
 
 #[async_trait::async_trait]
 impl DhcpHostSource for DhcpRestSource {
-    const NAME: &'static str = "rest";
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
 
     async fn offer(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
-        let c = Self::query(&mut self.config.offer, p).await?;
+        if let Some(result) = self.sticky_result(p) {
+            return Ok(Some(result));
+        }
+
+        let results = Self::query(&mut self.config.offer, &self.config.pre_query, p).await;
+        let c = self.fallback("offer", p, results)?;
 
         for script in &self.config.offer.scripts {
-            script.run(&c).await?;
+            script.run(&c, self.script_limiter.as_ref()).await?;
         }
 
-        self.config.offer.context_to_result(&c).map(Option::Some)
+        let result = self.config.offer.context_to_result(&c, self.strict_mappings)?;
+        self.stick(p, &result);
+        Ok(Some(result))
     }
 
+    /// Offer queries only, scripts are skipped and neither the sticky result nor the snapshot change
+    async fn preview(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        if let Some(result) = self.sticky.get(&p.client_hardware().to_string()).filter(|item| !item.expired()) {
+            return Ok(Some(result.data.clone()));
+        }
+
+        let c = Self::query(&mut self.config.offer, &self.config.pre_query, p).await
+            .and_then(|r| Self::results_context(&self.config.pre_query, p, &r))?;
+        self.config.offer.context_to_result(&c, self.strict_mappings).map(Option::Some)
+    }
+
+    /// A sticky result of the offer is reused without querying the reserve schema
     async fn reserve(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
-        let c = Self::query(&mut self.config.reserve, p).await?;
-        self.config.reserve.context_to_result(&c).map(Option::Some)
+        if let Some(result) = self.sticky_result(p) {
+            return Ok(Some(result));
+        }
+
+        let (schema, pre_query) = Self::reserve_schema(&mut self.config, p);
+        let results = Self::query(schema, pre_query, p).await;
+        let c = self.fallback("reserve", p, results)?;
+        let (schema, _) = Self::reserve_schema(&mut self.config, p);
+        let result = schema.context_to_result(&c, self.strict_mappings)?;
+        self.stick(p, &result);
+        Ok(Some(result))
     }
 
+    /// The release is confirmed by the `release_confirm` queries succeeding, if configured
     async fn release(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
-        Self::query(&mut self.config.release, p).await.map(|_| ())
+        self.sticky.remove(&p.client_hardware().to_string());
+        Self::query(&mut self.config.release, &self.config.pre_query, p).await?;
+
+        if let Some(confirm) = self.config.release_confirm.as_mut() {
+            Self::query(confirm, &self.config.pre_query, p).await
+                .map_err(|e| DhcpError::ReleaseUnconfirmed(e.to_string()))?;
+        }
+        Ok(())
     }
 
     async fn decline(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
-        Self::query(&mut self.config.decline, p).await.map(|_| ())
+        self.sticky.remove(&p.client_hardware().to_string());
+        Self::query(&mut self.config.decline, &self.config.pre_query, p).await.map(|_| ())
+    }
+
+    /// The sticky result of the offer is dropped, the next discover queries again
+    async fn withdraw(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        self.sticky.remove(&p.client_hardware().to_string());
+        Ok(())
     }
 
     async fn inform(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
-        let c = Self::query(&mut self.config.inform, p).await?;
-        self.config.inform.context_to_result(&c).map(Option::Some)
+        let c = Self::query(&mut self.config.inform, &self.config.pre_query, p).await
+            .and_then(|r| Self::results_context(&self.config.pre_query, p, &r))?;
+        self.config.inform.context_to_result(&c, self.strict_mappings).map(Option::Some)
+    }
+
+    fn strict_mappings(&mut self, strict: bool) {
+        self.strict_mappings = strict;
+    }
+
+    fn limits(&mut self, limits: &Limits) {
+        for query in Self::schemas_mut(&mut self.config).into_iter().flat_map(|s| s.queries.iter_mut()) {
+            query.cache.capacity = limits.cache_entries();
+        }
+    }
+
+    /// Pooled caches are counted once
+    async fn entries(&mut self) -> SourceEntries {
+        let mut counted = Vec::new();
+        let mut entries = SourceEntries::default();
+        for (_, schema) in self.schemas() {
+            for q in &schema.queries {
+                let cache = Arc::as_ptr(&q.cache.cache);
+                if !counted.contains(&cache) {
+                    counted.push(cache);
+                    entries.cache_entries += q.cache.len().await;
+                }
+            }
+        }
+        entries
+    }
+
+    fn script_limiter(&mut self, limiter: ConcurrencyLimiter) {
+        self.script_limiter = Some(limiter);
+    }
+
+    fn http_clients(&mut self, clients: &HashMap<String, Client>) -> DhcpResult<()> {
+        for query in Self::schemas_mut(&mut self.config).into_iter().flat_map(|s| s.queries.iter_mut()) {
+            if let Some(name) = &query.http_client {
+                query.cache.http = clients.get(name)
+                    .ok_or_else(|| DhcpError::ConfigInvalid(format!("http client profile {} unknown", name)))?
+                    .clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Expired cache entries are dropped instead of waiting for the next request of the same url
+    async fn maintenance(&mut self) -> DhcpResult<()> {
+        let mut swept = 0;
+        for (_, schema) in self.schemas() {
+            for q in &schema.queries {
+                swept += q.cache.sweep().await;
+            }
+        }
+        let sticky = self.sticky.len();
+        self.sticky.retain(|_, item| !item.expired());
+        swept += sticky - self.sticky.len();
+        log::debug!("{} expired rest cache entries swept", swept);
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> DhcpResult<()> {
+        if let Some(snapshot) = self.snapshot.as_mut() {
+            snapshot.persist()?;
+        }
+
+        match &self.config.cache_persist {
+            Some(path) => self.persist_cache(path).await,
+            None => Ok(()),
+        }
+    }
+
+    fn check_templates(&self) -> DhcpResult<()> {
+        for v in &self.config.pre_query {
+            check_templates(&Value::from(v.value.as_str()), &v.name)?;
+        }
+
+        for (_, schema) in self.schemas() {
+            for query in &schema.queries {
+                check_templates(&Value::from(query.url.as_str()), &query.name)?;
+                check_templates(&query.body, &query.name)?;
+            }
+            schema.mapping.check_templates()?;
+        }
+        Ok(())
     }
 
     fn from_config<'a, T: Deserializer<'a> + Send>(config: T) -> DhcpResult<Self> where Self: Sized {
         let mut s = Self {
-            config: Deserialize::deserialize(config).map_err(|e| DhcpError::SerdeErrorString(e.to_string()))?
+            config: Deserialize::deserialize(config).map_err(|e| DhcpError::SerdeErrorString(e.to_string()))?,
+            snapshot: None,
+            cache_pools: Default::default(),
+            sticky: HashMap::new(),
+            strict_mappings: false,
+            script_limiter: None,
         };
+        if let Some(rate_limit) = &s.config.rate_limit {
+            rate_limit.validate()?;
+        }
+        let rate_limit = s.config.rate_limit.as_ref().map(RateLimiter::from);
+        let correlation_header = s.config.correlation_header.as_deref().map(HeaderName::from_str).transpose()?;
+        let background_refresh = s.config.background_refresh;
 
         // init cache clients
-        for queries in [
-            &mut s.config.decline.queries,
-            &mut s.config.release.queries,
-            &mut s.config.inform.queries,
-            &mut s.config.reserve.queries,
-            &mut s.config.offer.queries
-        ] {
-            for query in queries.iter_mut() {
+        for schema in Self::schemas_mut(&mut s.config) {
+            for query in schema.queries.iter_mut() {
                 query.init()?;
+                query.cache.rate_limit = rate_limit.clone(); // shared by all queries of the source
+                query.cache.background_refresh = background_refresh;
+                query.cache.correlation_header = correlation_header.clone();
+
+                if let Some(pool) = &query.cache_pool { // share cache with other queries
+                    query.cache.cache = s.cache_pools.entry(pool.clone()).or_default().clone();
+                }
             }
         }
 
+        if let Some(path) = &s.config.cache_persist {
+            s.restore_cache(path);
+        }
+
+        let interval = Duration::from_secs(s.config.fallback_snapshot_interval);
+        s.snapshot = s.config.fallback_snapshot.clone().map(|path| DhcpRestSourceSnapshot::load(path, interval));
+
         Ok(s)
     }
 }
@@ -581,18 +1044,18 @@ async fn test() {
     let mut m = HashMap::new();
 
     m.insert("client_ip_address".to_string(), Value::from("{{ results.test.device1.ip }}"));
-    m.insert("subnet_mask".to_string(), serde_yaml::to_value(DhcpRestMappingItem {
+    m.insert("subnet_mask".to_string(), serde_yaml::to_value(crate::sources::mapping::DhcpMappingItem {
         data: Value::from("{{ results.test.device1.mask }}"),
         required: false,
     }).unwrap());
-    m.insert("router".to_string(), serde_yaml::to_value(DhcpRestMappingItem {
+    m.insert("router".to_string(), serde_yaml::to_value(crate::sources::mapping::DhcpMappingItem {
         data: Value::from("{{ results.test.device1.router }}"),
         required: false,
     }).unwrap());
-    m.insert("custom1".to_string(), serde_yaml::to_value(DhcpRestMappingItemCustom {
+    m.insert("custom1".to_string(), serde_yaml::to_value(crate::sources::mapping::DhcpMappingItemCustom {
         tag: 200,
-        kind: DhcpRestMappingItemCustomKind::Integer,
-        item: DhcpRestMappingItem { data: Value::from("1234567890"), required: false },
+        kind: crate::sources::mapping::DhcpMappingItemCustomKind::Integer,
+        item: crate::sources::mapping::DhcpMappingItem { data: Value::from("1234567890"), required: false },
     }).unwrap());
 
     let mut headers = HashMap::new();
@@ -601,33 +1064,58 @@ async fn test() {
     let mut query = DhcpRestConfigSchemaQuery {
         url: format!("{}/", url),
         name: "test".to_string(),
-        ssl_verify: false,
+        ssl_verify: Some(false),
         headers: Some(headers),
         cache: Default::default(),
+        cache_ttl_field: None,
+        cache_pool: None,
+        error_field: None,
+        http_client: None,
         method: Method::POST,
         body: serde_yaml::to_value(body).unwrap(),
+        required: true,
+        retries: 0,
+        retry_delay_ms: 0,
+        auth: None,
+        timeout_ms: DhcpRestConfigSchemaQuery::timeout_ms(),
     };
 
     query.init().unwrap();
     let s = DhcpRestSourceConfigSchema {
         scripts: vec![],
         queries: vec![query],
-        mapping: m,
+        mapping: m.into(),
     };
 
     let mut s = DhcpRestSource {
+        cache_pools: Default::default(),
+        sticky: HashMap::new(),
+        strict_mappings: false,
+        script_limiter: None,
+        snapshot: None,
         config: DhcpRestSourceConfig {
+            rate_limit: None,
+            background_refresh: false,
+            cache_persist: None,
+            sticky_ttl: None,
+            fallback_snapshot: None,
+            fallback_snapshot_interval: 60,
+            correlation_header: None,
+            pre_query: vec![],
             offer: s,
             reserve: DhcpRestSourceConfigSchema {
                 scripts: vec![],
                 queries: vec![],
                 mapping: Default::default(),
             },
+            reserve_initial: None,
+            reserve_renew: None,
             release: DhcpRestSourceConfigSchema {
                 scripts: vec![],
                 queries: vec![],
                 mapping: Default::default(),
             },
+            release_confirm: None,
             decline: DhcpRestSourceConfigSchema {
                 scripts: vec![],
                 queries: vec![],
@@ -665,3 +1153,626 @@ async fn test() {
     assert_eq!(result.options.try_ipv4vec_option(dhcplib::option::ROUTER).unwrap(), vec![std::net::Ipv4Addr::new(1, 1, 1, 1), std::net::Ipv4Addr::new(2, 2, 2, 2)]);
     assert_eq!(result.options.option(200).unwrap(), &dhcplib::option::DhcpOption::Unknown(200, vec![0, 0, 0, 0, 73, 150, 2, 210]));
 }
+
+#[tokio::test]
+async fn test_cache_max_age() {
+    let _m = mockito::mock("GET", "/max-age")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_header("cache-control", "public, max-age=1")
+        .with_body(serde_json::json!({"ttl": 2}).to_string())
+        .expect(1)
+        .create();
+
+    let url: Url = format!("{}/max-age", mockito::server_url()).parse().unwrap();
+    let key = DhcpRestSourceHttpCacheKey { url: url.clone(), method: Method::GET };
+    let mut http = DhcpRestSourceHttp { expiration: Duration::from_secs(60), ..Default::default() };
+
+    let _: serde_json::Value = http.json(Method::GET, url.clone(), &Value::Null).await.unwrap();
+    let _: serde_json::Value = http.json(Method::GET, url.clone(), &Value::Null).await.unwrap(); // cached
+    _m.assert();
+
+    let mut cache = http.cache.lock().await;
+    let item = cache.get_mut(&key).unwrap();
+    assert_eq!(item.ttl, Duration::from_secs(1));
+    assert!(!item.expired());
+    item.time -= Duration::from_secs(2);
+    assert!(item.expired());
+    drop(cache);
+
+    assert_eq!(http.sweep().await, 1);
+    assert!(http.cache.lock().await.is_empty());
+
+    // json field takes precedence over the header
+    http.ttl_field = Some("/ttl".to_string());
+    assert_eq!(http.ttl(&serde_json::json!({"ttl": 2}), Some(Duration::from_secs(1))), Duration::from_secs(2));
+    assert_eq!(http.ttl(&serde_json::json!({}), None), Duration::from_secs(60));
+}
+
+#[tokio::test]
+async fn test_cache_max_age_oversized() {
+    let _m = mockito::mock("GET", "/max-age-oversized")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_header("cache-control", "max-age=18446744073709551615")
+        .with_body("{}")
+        .create();
+
+    let url: Url = format!("{}/max-age-oversized", mockito::server_url()).parse().unwrap();
+    let key = DhcpRestSourceHttpCacheKey { url: url.clone(), method: Method::GET };
+    let mut http = DhcpRestSourceHttp { expiration: Duration::from_secs(60), background_refresh: true, ..Default::default() };
+
+    let _: serde_json::Value = http.json(Method::GET, url.clone(), &Value::Null).await.unwrap();
+    let _: serde_json::Value = http.json(Method::GET, url.clone(), &Value::Null).await.unwrap(); // cached
+    assert_eq!(http.cache.lock().await.get(&key).unwrap().ttl, Duration::from_secs(MAX_TTL_SECS));
+    assert_eq!(http.sweep().await, 0);
+
+    http.ttl_field = Some("/ttl".to_string());
+    assert_eq!(http.ttl(&serde_json::json!({"ttl": 1e300}), None), Duration::from_secs(MAX_TTL_SECS));
+    // an entry beyond the representable time never expires instead of panicking
+    let item = DhcpRestSourceHttpCacheItem::new(serde_json::Value::Null, Duration::MAX);
+    assert!(!item.expired());
+    assert!(!item.near_expiry());
+}
+
+#[tokio::test]
+async fn test_cache_capacity() {
+    let mocks: Vec<_> = ["a", "b", "c"].iter()
+        .map(|path| mockito::mock("GET", format!("/capacity/{}", path).as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body("{}")
+            .create())
+        .collect();
+
+    let url = |path: &str| -> Url { format!("{}/capacity/{}", mockito::server_url(), path).parse().unwrap() };
+    let mut http = DhcpRestSourceHttp { expiration: Duration::from_secs(60), capacity: Some(2), ..Default::default() };
+
+    let _: serde_json::Value = http.json(Method::GET, url("a"), &Value::Null).await.unwrap();
+    let _: serde_json::Value = http.json(Method::GET, url("b"), &Value::Null).await.unwrap();
+    let _: serde_json::Value = http.json(Method::GET, url("a"), &Value::Null).await.unwrap(); // b is now the least recently used
+    let _: serde_json::Value = http.json(Method::GET, url("c"), &Value::Null).await.unwrap();
+    drop(mocks);
+
+    let cache = http.cache.lock().await;
+    assert_eq!(cache.len(), 2);
+    assert!(cache.contains_key(&DhcpRestSourceHttpCacheKey { url: url("a"), method: Method::GET }));
+    assert!(!cache.contains_key(&DhcpRestSourceHttpCacheKey { url: url("b"), method: Method::GET }));
+}
+
+#[tokio::test]
+async fn test_background_refresh() {
+    let _m = mockito::mock("GET", "/refresh")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(serde_json::json!({"ip": "10.0.0.5"}).to_string())
+        .expect(2)
+        .create();
+
+    let url: Url = format!("{}/refresh", mockito::server_url()).parse().unwrap();
+    let key = DhcpRestSourceHttpCacheKey { url: url.clone(), method: Method::GET };
+    let mut http = DhcpRestSourceHttp { expiration: Duration::from_secs(10), background_refresh: true, ..Default::default() };
+
+    let _: serde_json::Value = http.json(Method::GET, url.clone(), &Value::Null).await.unwrap();
+    http.cache.lock().await.get_mut(&key).unwrap().time -= Duration::from_secs(9); // near expiry
+
+    let value: serde_json::Value = http.json(Method::GET, url.clone(), &Value::Null).await.unwrap(); // answered from cache
+    assert_eq!(value["ip"], "10.0.0.5");
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    _m.assert();
+    let cache = http.cache.lock().await;
+    let item = cache.get(&key).unwrap();
+    assert!(!item.near_expiry());
+    assert!(!item.refreshing);
+}
+
+#[test]
+fn test_accept_header() {
+    let headers = DhcpRestConfigSchemaQuery::map_to_headers(&HashMap::new()).unwrap();
+    assert_eq!(headers.get(ACCEPT).unwrap(), "application/json");
+
+    let mut map = HashMap::new();
+    map.insert("accept".to_string(), "application/vnd.api+json".to_string());
+    let headers = DhcpRestConfigSchemaQuery::map_to_headers(&map).unwrap();
+    assert_eq!(headers.get_all(ACCEPT).iter().collect::<Vec<_>>(), vec!["application/vnd.api+json"]);
+}
+
+#[tokio::test]
+async fn test_cache_pool() {
+    let _m = mockito::mock("GET", "/catalog")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(serde_json::json!({"ip": "10.0.0.5"}).to_string())
+        .expect(1)
+        .create();
+
+    let config: Value = serde_yaml::from_str(&format!(r#"
+offer: &schema
+  scripts: []
+  queries:
+    - url: "{url}/catalog"
+      name: first
+      method: GET
+      cache: 60
+      cache_pool: catalog
+    - url: "{url}/catalog"
+      name: second
+      method: GET
+      cache: 60
+      cache_pool: catalog
+  mapping:
+    client_ip_address: "{{{{ results.second.ip }}}}"
+reserve: *schema
+release: *schema
+decline: *schema
+inform: *schema
+"#, url = mockito::server_url())).unwrap();
+
+    let mut s = DhcpRestSource::from_config(config).unwrap();
+    let result = s.offer(&crate::sources::test_packet(0, vec![])).await.unwrap().unwrap();
+    let _ = s.reserve(&crate::sources::test_packet(0, vec![])).await.unwrap();
+
+    _m.assert();
+    assert_eq!(result.client_ip_address(), &Some(std::net::Ipv4Addr::new(10, 0, 0, 5)));
+}
+
+#[tokio::test]
+async fn test_cache_persist() {
+    let path = std::env::temp_dir().join("dhcpserver-test-cache-persist.json");
+    let config = || -> Value { serde_yaml::from_str(&format!(r#"
+cache_persist: {}
+offer: &schema
+  scripts: []
+  queries:
+    - url: "http://localhost/hosts"
+      name: hosts
+      method: GET
+      cache: 60
+  mapping: {{}}
+reserve: *schema
+release: *schema
+decline: *schema
+inform: *schema
+"#, path.display())).unwrap() };
+
+    let url: Url = "http://localhost/hosts".parse().unwrap();
+    let expired: Url = "http://localhost/expired".parse().unwrap();
+
+    let mut s = DhcpRestSource::from_config(config()).unwrap();
+    {
+        let mut cache = s.config.offer.queries[0].cache.cache.lock().await;
+        cache.insert(DhcpRestSourceHttpCacheKey { url: url.clone(), method: Method::GET },
+                     DhcpRestSourceHttpCacheItem::new(serde_json::json!({"ip": "10.0.0.5"}), Duration::from_secs(60)));
+        cache.insert(DhcpRestSourceHttpCacheKey { url: expired.clone(), method: Method::GET },
+                     DhcpRestSourceHttpCacheItem::new(serde_json::json!({}), Duration::from_secs(0)));
+    }
+    s.shutdown().await.unwrap();
+
+    let s = DhcpRestSource::from_config(config()).unwrap();
+    let cache = s.config.offer.queries[0].cache.cache.lock().await;
+    assert_eq!(cache.get(&DhcpRestSourceHttpCacheKey { url, method: Method::GET }).unwrap().data, serde_json::json!({"ip": "10.0.0.5"}));
+    assert!(cache.get(&DhcpRestSourceHttpCacheKey { url: expired, method: Method::GET }).is_none());
+    assert!(s.config.reserve.queries[0].cache.cache.lock().await.is_empty());
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_fallback_snapshot() {
+    let path = std::env::temp_dir().join("dhcpserver-test-fallback-snapshot.json");
+    let _ = std::fs::remove_file(&path);
+    let config = || -> Value { serde_yaml::from_str(&format!(r#"
+fallback_snapshot: {}
+fallback_snapshot_interval: 0
+offer: &schema
+  scripts: []
+  queries:
+    - url: "{}/snapshot"
+      name: hosts
+      method: GET
+  mapping:
+    client_ip_address: "{{{{ results.hosts.ip }}}}"
+reserve: *schema
+release: *schema
+decline: *schema
+inform: *schema
+"#, path.display(), mockito::server_url())).unwrap() };
+    let p = crate::sources::test_packet(0, vec![]);
+
+    let m = mockito::mock("GET", "/snapshot")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(serde_json::json!({"ip": "10.0.0.5"}).to_string())
+        .create();
+
+    let mut s = DhcpRestSource::from_config(config()).unwrap();
+    let result = s.offer(&p).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(std::net::Ipv4Addr::new(10, 0, 0, 5)));
+    drop(m); // backend unreachable from here on
+
+    let result = s.offer(&p).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(std::net::Ipv4Addr::new(10, 0, 0, 5)));
+    assert!(s.reserve(&p).await.is_err()); // nothing known for reserve yet
+
+    let mut s = DhcpRestSource::from_config(config()).unwrap(); // loaded from disk
+    let result = s.offer(&p).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(std::net::Ipv4Addr::new(10, 0, 0, 5)));
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_retries() {
+    let _unavailable = mockito::mock("GET", "/retry/unavailable")
+        .with_status(503)
+        .with_body("unavailable")
+        .expect(3)
+        .create();
+    let _missing = mockito::mock("GET", "/retry/missing")
+        .with_status(404)
+        .with_body("missing")
+        .expect(1)
+        .create();
+
+    let mut http = DhcpRestSourceHttp { retries: 2, retry_delay: Duration::from_millis(1), ..Default::default() };
+    let url = |path: &str| -> Url { format!("{}/retry/{}", mockito::server_url(), path).parse().unwrap() };
+
+    // the last server error is final, client errors are not retried
+    assert!(http.json::<serde_json::Value>(Method::GET, url("unavailable"), &Value::Null).await.is_err());
+    assert!(http.json::<serde_json::Value>(Method::GET, url("missing"), &Value::Null).await.is_err());
+    _unavailable.assert();
+    _missing.assert();
+}
+
+#[tokio::test]
+async fn test_rate_limited_requests() {
+    let _m = mockito::mock("GET", "/rate-limited")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body("{}")
+        .expect(3)
+        .create();
+
+    let rate_limit: RateLimitConfig = serde_yaml::from_str("rate: 20\nburst: 1").unwrap();
+    let mut http = DhcpRestSourceHttp { rate_limit: Some(RateLimiter::from(&rate_limit)), ..Default::default() };
+    let url: Url = format!("{}/rate-limited", mockito::server_url()).parse().unwrap();
+
+    let start = tokio::time::Instant::now();
+    for _ in 0..3 { // not cached, each is a request
+        let _: serde_json::Value = http.json(Method::GET, url.clone(), &Value::Null).await.unwrap();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(100)); // 2 paced requests at 20/s
+    _m.assert();
+
+    let config: Value = serde_yaml::from_str(r#"
+rate_limit:
+  rate: 0
+offer: &schema
+  scripts: []
+  queries: []
+  mapping:
+    client_ip_address: 10.0.0.1
+reserve: *schema
+release: *schema
+decline: *schema
+inform: *schema
+"#).unwrap();
+    assert!(matches!(DhcpRestSource::from_config(config), Err(DhcpError::ConfigInvalid(_))));
+}
+
+#[tokio::test]
+async fn test_query_auth() {
+    let _bearer = mockito::mock("GET", "/auth/bearer")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .match_header("authorization", "Bearer token-010203050607")
+        .with_body("{}")
+        .expect(1)
+        .create();
+    let _basic = mockito::mock("GET", "/auth/basic")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .match_header("authorization", "Basic ZGhjcDpzZWNyZXQ=") // dhcp:secret
+        .with_body("{}")
+        .expect(1)
+        .create();
+
+    let mut context = Context::new();
+    context.insert("mac", "010203050607");
+    let auth: DhcpRestQueryAuth = serde_yaml::from_str("type: bearer\ntoken: \"token-{{ mac }}\"").unwrap();
+    assert_eq!(auth.render(&context).unwrap(), DhcpRestQueryAuth::Bearer { token: "token-010203050607".to_string() });
+
+    let url = |path: &str| -> Url { format!("{}/auth/{}", mockito::server_url(), path).parse().unwrap() };
+    let mut http = DhcpRestSourceHttp { auth: Some(auth.render(&context).unwrap()), ..Default::default() };
+    let _: serde_json::Value = http.json(Method::GET, url("bearer"), &Value::Null).await.unwrap();
+
+    let auth: DhcpRestQueryAuth = serde_yaml::from_str("type: basic\nusername: dhcp\npassword: secret").unwrap();
+    http.auth = Some(auth.render(&context).unwrap());
+    let _: serde_json::Value = http.json(Method::GET, url("basic"), &Value::Null).await.unwrap();
+
+    _bearer.assert();
+    _basic.assert();
+}
+
+#[test]
+fn test_query_ssl_verify() {
+    let query = |yaml: &str| -> DhcpResult<()> {
+        let mut query: DhcpRestConfigSchemaQuery = serde_yaml::from_str(&format!("url: https://localhost/\nname: host\nmethod: get\n{}", yaml)).unwrap();
+        query.init()
+    };
+
+    assert!(query("ssl_verify: true").is_ok());
+    assert!(query("ssl_verify: false").is_ok());
+    assert!(query("http_client: backend").is_ok());
+    // the profile decides, a query setting it too is a config error
+    assert!(matches!(query("http_client: backend\nssl_verify: false"), Err(DhcpError::ConfigInvalid(_))));
+}
+
+#[tokio::test]
+async fn test_query_timeout() {
+    // accepts connections but never answers
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut connections = vec![];
+        while let Ok((c, _)) = listener.accept().await {
+            connections.push(c);
+        }
+    });
+
+    let mut query: DhcpRestConfigSchemaQuery = serde_yaml::from_str(&format!("url: http://{}/slow\nname: slow\nmethod: get\ntimeout_ms: 50", addr)).unwrap();
+    query.init().unwrap();
+    assert!(matches!(query.run(&Context::new()).await, Err(DhcpError::QueryTimeout(name)) if name == "slow"));
+}
+
+#[tokio::test]
+async fn test_correlation_header() {
+    let _m = mockito::mock("GET", "/correlation")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .match_header("x-request-id", "abc-1")
+        .with_body("{}")
+        .expect(1)
+        .create();
+
+    let url: Url = format!("{}/correlation", mockito::server_url()).parse().unwrap();
+    let mut http = DhcpRestSourceHttp { correlation_header: Some(HeaderName::from_static("x-request-id")), ..Default::default() };
+
+    let _: serde_json::Value = crate::server::CORRELATION_ID.scope("abc-1".to_string(), http.json(Method::GET, url, &Value::Null)).await.unwrap();
+    _m.assert();
+}
+
+#[tokio::test]
+async fn test_pre_query() {
+    let config: Value = serde_yaml::from_str(&format!(r#"
+pre_query:
+  - name: device_id
+    value: "{{{{ client_hardware_address | replace(from=':', to='') | lower }}}}"
+  - name: device_path
+    value: "devices/{{{{ device_id }}}}"
+offer: &schema
+  scripts: []
+  queries:
+    - url: "{}/{{{{ device_path }}}}"
+      name: device
+      method: GET
+  mapping:
+    client_ip_address: "{{{{ results.device.ip }}}}"
+reserve: *schema
+release: *schema
+decline: *schema
+inform: *schema
+"#, mockito::server_url())).unwrap();
+
+    let _m = mockito::mock("GET", "/devices/010203050607")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(serde_json::json!({"ip": "10.0.0.6"}).to_string())
+        .expect(1)
+        .create();
+
+    let mut s = DhcpRestSource::from_config(config).unwrap();
+    let result = s.offer(&crate::sources::test_packet(0, vec![])).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(std::net::Ipv4Addr::new(10, 0, 0, 6)));
+    _m.assert();
+}
+
+#[tokio::test]
+async fn test_optional_query() {
+    let config: Value = serde_yaml::from_str(&format!(r#"
+offer: &schema
+  scripts: []
+  queries:
+    - url: "{0}/optional/hosts"
+      name: hosts
+      method: GET
+    - url: "{0}/optional/enrichment"
+      name: enrichment
+      method: GET
+      required: false
+  mapping:
+    client_ip_address: "{{{{ results.hosts.ip }}}}"
+reserve: *schema
+release: *schema
+decline: *schema
+inform: *schema
+"#, mockito::server_url())).unwrap();
+
+    let _hosts = mockito::mock("GET", "/optional/hosts")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(serde_json::json!({"ip": "10.0.0.7"}).to_string())
+        .create();
+    let _enrichment = mockito::mock("GET", "/optional/enrichment")
+        .with_status(500)
+        .create();
+
+    let mut s = DhcpRestSource::from_config(config).unwrap();
+    let result = s.offer(&crate::sources::test_packet(0, vec![])).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(std::net::Ipv4Addr::new(10, 0, 0, 7)));
+}
+
+#[tokio::test]
+async fn test_reserve_by_request_state() {
+    use dhcplib::option::DhcpOption;
+    use std::net::Ipv4Addr;
+
+    let config: Value = serde_yaml::from_str(r#"
+offer: &schema
+  scripts: []
+  queries: []
+  mapping:
+    client_ip_address: 10.0.0.1
+reserve: *schema
+reserve_initial:
+  scripts: []
+  queries: []
+  mapping:
+    client_ip_address: 10.0.0.2
+    message:
+      data: "{{ request.state }}"
+release: *schema
+decline: *schema
+inform: *schema
+"#).unwrap();
+    let mut s = DhcpRestSource::from_config(config).unwrap();
+
+    let selecting = crate::sources::test_packet(0, vec![DhcpOption::ServerIdentifier(Ipv4Addr::new(10, 0, 0, 254))]);
+    let result = s.reserve(&selecting).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 2)));
+    assert_eq!(result.options().try_ascii_option(dhcplib::option::MESSAGE).unwrap().as_str(), "selecting");
+
+    let result = s.reserve(&crate::sources::test_packet(0, vec![])).await.unwrap().unwrap(); // no state, initial
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 2)));
+}
+
+#[tokio::test]
+async fn test_error_field() {
+    let config: Value = serde_yaml::from_str(&format!(r#"
+offer: &schema
+  scripts: []
+  queries:
+    - url: "{}/registered"
+      name: host
+      method: GET
+      error_field: /error
+  mapping:
+    client_ip_address: "{{{{ results.host.ip }}}}"
+reserve: *schema
+release: *schema
+decline: *schema
+inform: *schema
+"#, mockito::server_url())).unwrap();
+
+    let _m = mockito::mock("GET", "/registered")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(serde_json::json!({"error": "not registered"}).to_string())
+        .create();
+
+    let mut s = DhcpRestSource::from_config(config).unwrap();
+    match s.reserve(&crate::sources::test_packet(0, vec![])).await {
+        Err(DhcpError::Rejected(reason)) => assert_eq!(reason, "not registered"),
+        _ => panic!("backend error not turned into a rejection"),
+    }
+}
+
+#[tokio::test]
+async fn test_release_confirm() {
+    let config: Value = serde_yaml::from_str(&format!(r#"
+offer: &schema
+  scripts: []
+  queries: []
+  mapping: {{}}
+reserve: *schema
+release:
+  scripts: []
+  queries:
+    - url: "{0}/release/{{{{ client_hardware_address | replace(from=':', to='') }}}}"
+      name: release
+      method: POST
+  mapping: {{}}
+release_confirm:
+  scripts: []
+  queries:
+    - url: "{0}/leases/{{{{ client_hardware_address | replace(from=':', to='') }}}}"
+      name: lease
+      method: GET
+      error_field: /error
+  mapping: {{}}
+decline: *schema
+inform: *schema
+"#, mockito::server_url())).unwrap();
+
+    let _release = mockito::mock("POST", "/release/010203050607")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body("{}")
+        .expect(1)
+        .create();
+    let _lease = mockito::mock("GET", "/leases/010203050607")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(serde_json::json!({"error": "still leased"}).to_string())
+        .expect(1)
+        .create();
+
+    let mut s = DhcpRestSource::from_config(config).unwrap();
+    match s.release(&crate::sources::test_packet(0, vec![])).await {
+        Err(DhcpError::ReleaseUnconfirmed(reason)) => assert!(reason.contains("still leased")),
+        _ => panic!("unconfirmed release not reported"),
+    }
+    _release.assert();
+    _lease.assert();
+}
+
+#[tokio::test]
+async fn test_sticky_result() {
+    let config: Value = serde_yaml::from_str(&format!(r#"
+sticky_ttl: 10
+offer: &schema
+  scripts: []
+  queries:
+    - url: "{0}/sticky/{{{{ client_hardware_address | replace(from=':', to='') }}}}"
+      name: host
+      method: GET
+  mapping:
+    client_ip_address: "{{{{ results.host.ip }}}}"
+reserve: *schema
+release:
+  scripts: []
+  queries:
+    - url: "{0}/sticky-release"
+      name: release
+      method: POST
+  mapping: {{}}
+decline: *schema
+inform: *schema
+"#, mockito::server_url())).unwrap();
+
+    let _host = mockito::mock("GET", "/sticky/010203050607")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(serde_json::json!({"ip": "10.0.0.7"}).to_string())
+        .expect(2)
+        .create();
+    let _release = mockito::mock("POST", "/sticky-release")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body("{}")
+        .create();
+
+    let mut s = DhcpRestSource::from_config(config).unwrap();
+    let p = crate::sources::test_packet(0, vec![]);
+    let ip = Some(std::net::Ipv4Addr::new(10, 0, 0, 7));
+
+    // discover then request, the backend is asked once
+    assert_eq!(s.offer(&p).await.unwrap().unwrap().client_ip_address(), &ip);
+    assert_eq!(s.reserve(&p).await.unwrap().unwrap().client_ip_address(), &ip);
+
+    // released, asked again
+    s.release(&p).await.unwrap();
+    assert_eq!(s.reserve(&p).await.unwrap().unwrap().client_ip_address(), &ip);
+    _host.assert();
+}
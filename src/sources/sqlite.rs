@@ -0,0 +1,258 @@
+//! Hosts and leases in a sqlite database, a source without an http backend
+
+use crate::sources::{DhcpHostSource, DhcpSourceResult, SourceEntries};
+use crate::sources::mapping::{DhcpMapping, packet_context, INFINITE_LEASE_TIME};
+use crate::error::{DhcpResult, DhcpError};
+use serde::{Deserializer, Deserialize};
+use dhcplib::DhcpPacket;
+use dhcplib::option::IP_ADDRESS_LEASE_TIME;
+use rusqlite::{Connection, OptionalExtension, params};
+use rusqlite::types::ValueRef;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Deserialize)]
+struct DhcpSqliteSourceConfig {
+    path: String,
+    /// hosts table, looked up by the mac column
+    table: String,
+    #[serde(default = "DhcpSqliteSourceConfig::default_mac_column")]
+    mac_column: String,
+    /// created if missing
+    #[serde(default = "DhcpSqliteSourceConfig::default_leases_table")]
+    leases_table: String,
+    /// lease expiry of results without lease time
+    #[serde(default = "DhcpSqliteSourceConfig::default_lease_time")]
+    lease_time: u64,
+    /// the columns of the host row are the template variable `row`
+    mapping: DhcpMapping,
+}
+
+impl DhcpSqliteSourceConfig {
+    fn default_mac_column() -> String { "mac".to_string() }
+    fn default_leases_table() -> String { "leases".to_string() }
+    fn default_lease_time() -> u64 { 3600 }
+}
+
+/// Table and column names are part of the statements, parameters can't replace them
+fn identifier(name: &str) -> DhcpResult<&str> {
+    let valid = name.chars().next().map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if valid {
+        Ok(name)
+    } else {
+        Err(DhcpError::ConfigInvalid(format!("sqlite identifier '{}' not made of letters, digits and underscores", name)))
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or_default()
+}
+
+fn json_value(value: ValueRef) -> serde_json::Value {
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(i) => i.into(),
+        ValueRef::Real(f) => f.into(),
+        ValueRef::Text(t) => String::from_utf8_lossy(t).into(),
+        ValueRef::Blob(b) => b.iter().map(|b| format!("{:02x}", b)).collect::<String>().into(),
+    }
+}
+
+/// Hosts by mac from a table, reserved addresses recorded with their expiry in a leases table
+pub(crate) struct DhcpSqliteSource {
+    connection: Connection,
+    select: String,
+    leases_table: String,
+    lease_time: u64,
+    mapping: DhcpMapping,
+    strict_mappings: bool,
+}
+
+impl DhcpSqliteSource {
+    pub const NAME: &'static str = "sqlite";
+
+    /// Columns of the host row by name
+    fn row(&self, mac: &str) -> DhcpResult<Option<HashMap<String, serde_json::Value>>> {
+        let mut statement = self.connection.prepare_cached(&self.select)?;
+        let names = statement.column_names().into_iter().map(String::from).collect::<Vec<String>>();
+
+        Ok(statement.query_row(params![mac], |row| {
+            names.iter().enumerate()
+                .map(|(i, name)| Ok((name.clone(), json_value(row.get_ref(i)?))))
+                .collect()
+        }).optional()?)
+    }
+
+    fn host(&self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        let row = match self.row(&p.client_hardware().to_string())? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        let mut context = packet_context(p);
+        context.insert("row", &row);
+        Ok(Some(self.mapping.to_result(&context, self.strict_mappings)?))
+    }
+
+    /// Declined or leased to another client until expiry
+    fn taken(&self, ip: Ipv4Addr, mac: &str) -> DhcpResult<bool> {
+        let taken = self.connection.query_row(
+            &format!("SELECT COUNT(*) FROM {} WHERE ip = ?1 AND (declined = 1 OR (lower(mac) <> lower(?2) AND (expires IS NULL OR expires > ?3)))", self.leases_table),
+            params![ip.to_string(), mac, now()],
+            |row| row.get::<_, i64>(0),
+        )?;
+        Ok(taken > 0)
+    }
+
+    fn lease(&self, mac: &str, ip: Ipv4Addr, lease_time: Option<u32>) -> DhcpResult<()> {
+        let expires = match lease_time.unwrap_or(self.lease_time as u32) {
+            INFINITE_LEASE_TIME => None,
+            seconds => Some(now() + seconds as i64),
+        };
+
+        self.connection.execute(
+            &format!("INSERT INTO {} (mac, ip, expires, declined) VALUES (lower(?1), ?2, ?3, 0) \
+                      ON CONFLICT(mac) DO UPDATE SET ip = excluded.ip, expires = excluded.expires, declined = 0", self.leases_table),
+            params![mac, ip.to_string(), expires],
+        )?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl DhcpHostSource for DhcpSqliteSource {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    async fn offer(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        self.host(p)
+    }
+
+    /// Records the lease, an address declined or leased to another client is refused
+    async fn reserve(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        let result = match self.host(p)? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+
+        let mac = p.client_hardware().to_string();
+        if let Some(ip) = *result.client_ip_address() {
+            if self.taken(ip, &mac)? {
+                log::warn!("sqlite address {} of {} is declined or leased to another client", ip, mac);
+                return Ok(None);
+            }
+            self.lease(&mac, ip, result.options().try_u32_option(IP_ADDRESS_LEASE_TIME).ok())?;
+        }
+        Ok(Some(result))
+    }
+
+    async fn release(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        self.connection.execute(&format!("DELETE FROM {} WHERE mac = lower(?1) AND declined = 0", self.leases_table),
+                                params![p.client_hardware().to_string()])?;
+        Ok(())
+    }
+
+    /// Kept as declined, the address is not reserved again until the row is removed
+    async fn decline(&mut self, p: &DhcpPacket) -> DhcpResult<()> {
+        self.connection.execute(&format!("UPDATE {} SET declined = 1 WHERE mac = lower(?1)", self.leases_table),
+                                params![p.client_hardware().to_string()])?;
+        Ok(())
+    }
+
+    async fn inform(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        self.host(p)
+    }
+
+    fn from_config<'a, T: Deserializer<'a> + Send>(config: T) -> DhcpResult<Self> where Self: Sized {
+        let config: DhcpSqliteSourceConfig = Deserialize::deserialize(config).map_err(|e| DhcpError::SerdeErrorString(e.to_string()))?;
+        let table = identifier(&config.table)?;
+        let mac_column = identifier(&config.mac_column)?;
+        let leases_table = identifier(&config.leases_table)?.to_string();
+
+        let connection = Connection::open(&config.path)?;
+        connection.execute_batch(&format!("CREATE TABLE IF NOT EXISTS {} (mac TEXT PRIMARY KEY, ip TEXT NOT NULL, expires INTEGER, declined INTEGER NOT NULL DEFAULT 0)", leases_table))?;
+
+        Ok(Self {
+            select: format!("SELECT * FROM {} WHERE lower({}) = lower(?1) LIMIT 1", table, mac_column),
+            connection,
+            leases_table,
+            lease_time: config.lease_time,
+            mapping: config.mapping,
+            strict_mappings: false,
+        })
+    }
+
+    fn strict_mappings(&mut self, strict: bool) {
+        self.strict_mappings = strict;
+    }
+
+    fn check_templates(&self) -> DhcpResult<()> {
+        self.mapping.check_templates()
+    }
+
+    async fn entries(&mut self) -> SourceEntries {
+        let leases = self.connection.query_row(&format!("SELECT COUNT(*) FROM {}", self.leases_table), [], |row| row.get::<_, i64>(0));
+
+        SourceEntries {
+            lease_entries: leases.unwrap_or_default() as usize,
+            ..SourceEntries::default()
+        }
+    }
+
+    /// Drops expired leases, declined addresses stay
+    async fn maintenance(&mut self) -> DhcpResult<()> {
+        let expired = self.connection.execute(&format!("DELETE FROM {} WHERE declined = 0 AND expires <= ?1", self.leases_table), params![now()])?;
+        if expired > 0 {
+            log::debug!("sqlite dropped {} expired leases", expired);
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_sqlite() {
+    use dhcplib::option::SUBNET_MASK;
+
+    let path = std::env::temp_dir().join("dhcpserver-test-sqlite.db");
+    let _ = std::fs::remove_file(&path);
+    let db = Connection::open(&path).unwrap();
+    db.execute_batch("CREATE TABLE hosts (mac TEXT, ip TEXT, mask TEXT);
+                      INSERT INTO hosts VALUES ('01:02:03:05:06:07', '10.0.0.5', '255.255.255.0');
+                      INSERT INTO hosts VALUES ('01:02:03:05:06:08', '10.0.0.5', '255.255.255.0');").unwrap();
+
+    let config: serde_yaml::Value = serde_yaml::from_str(&format!(r#"
+path: {}
+table: hosts
+mapping:
+  client_ip_address: "{{{{ row.ip }}}}"
+  subnet_mask:
+    data: "{{{{ row.mask }}}}"
+"#, path.display())).unwrap();
+    let mut s = DhcpSqliteSource::from_config(config).unwrap();
+    let p = crate::sources::test_packet(0, vec![]);
+    let other = crate::sources::test_packet_from_mac([1, 2, 3, 5, 6, 8]);
+
+    assert!(s.offer(&crate::sources::test_packet_from_mac([9, 9, 9, 9, 9, 9])).await.unwrap().is_none());
+
+    let result = s.offer(&p).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 5)));
+    assert_eq!(result.options().try_ipv4_option(SUBNET_MASK).unwrap(), Ipv4Addr::new(255, 255, 255, 0));
+
+    // the address is leased until released
+    assert!(s.reserve(&p).await.unwrap().is_some());
+    assert_eq!(s.entries().await.lease_entries, 1);
+    assert!(s.reserve(&other).await.unwrap().is_none());
+    s.release(&p).await.unwrap();
+    assert!(s.reserve(&other).await.unwrap().is_some());
+
+    // declined addresses are not reserved again
+    s.decline(&other).await.unwrap();
+    assert!(s.reserve(&p).await.unwrap().is_none());
+
+    let invalid: serde_yaml::Value = serde_yaml::from_str(&format!("path: {}\ntable: \"hosts; DROP TABLE hosts\"\nmapping: {{}}", path.display())).unwrap();
+    assert!(matches!(DhcpSqliteSource::from_config(invalid), Err(DhcpError::ConfigInvalid(_))));
+}
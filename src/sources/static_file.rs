@@ -0,0 +1,206 @@
+use crate::sources::{DhcpHostSource, DhcpSourceResult};
+use crate::sources::mapping::{DhcpMapping, packet_context};
+use crate::error::{DhcpResult, DhcpError};
+use serde::{Deserializer, Deserialize};
+use dhcplib::DhcpPacket;
+use pnet::ipnetwork::Ipv4Network;
+use std::collections::HashMap;
+use std::fs::File;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use tera::Context;
+
+#[derive(Deserialize)]
+struct DhcpStaticSubnet {
+    network: String,
+    #[serde(default)]
+    options: DhcpMapping,
+}
+
+#[derive(Deserialize)]
+struct DhcpStaticSourceConfig {
+    #[serde(default)]
+    hosts: HashMap<String, DhcpMapping>,
+    /// yaml map of hosts by mac address, loaded at startup below the inline `hosts`
+    hosts_file: Option<PathBuf>,
+    #[serde(default)]
+    options: DhcpMapping,
+    #[serde(default)]
+    subnets: Vec<DhcpStaticSubnet>,
+}
+
+/// Fixed hosts by mac address, shared options for every host and informing client of a subnet
+pub(crate) struct DhcpStaticSource {
+    hosts: HashMap<String, DhcpMapping>,
+    options: DhcpMapping,
+    subnets: Vec<(Ipv4Network, DhcpMapping)>,
+    strict_mappings: bool,
+}
+
+impl DhcpStaticSource {
+    pub const NAME: &'static str = "static";
+
+    fn load_hosts(path: &Path) -> DhcpResult<HashMap<String, DhcpMapping>> {
+        serde_yaml::from_reader(File::open(path)?)
+            .map_err(|e| DhcpError::SerdeErrorString(format!("hosts file {}: {}", path.display(), e)))
+    }
+
+    /// Shared options below the options of the first subnet containing the address
+    fn shared(&self, ip: Option<Ipv4Addr>, context: &Context) -> DhcpResult<DhcpSourceResult> {
+        let mut result = self.options.to_result(context, self.strict_mappings)?;
+
+        if let Some((_, mapping)) = ip.and_then(|ip| self.subnets.iter().find(|(network, _)| network.contains(ip))) {
+            result.merge(mapping.to_result(context, self.strict_mappings)?);
+        }
+        Ok(result)
+    }
+
+    fn host(&self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        let mac = p.client_hardware().to_string();
+        let mapping = match self.hosts.iter().find(|(k, _)| k.eq_ignore_ascii_case(&mac)) {
+            Some((_, mapping)) => mapping,
+            None => return Ok(None),
+        };
+
+        let context = packet_context(p);
+        let host = mapping.to_result(&context, self.strict_mappings)?;
+        let mut result = self.shared(*host.client_ip_address(), &context)?;
+        result.merge(host);
+        Ok(Some(result))
+    }
+}
+
+#[async_trait::async_trait]
+impl DhcpHostSource for DhcpStaticSource {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    async fn offer(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        self.host(p)
+    }
+
+    async fn reserve(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        self.host(p)
+    }
+
+    async fn release(&mut self, _: &DhcpPacket) -> DhcpResult<()> {
+        Ok(())
+    }
+
+    async fn decline(&mut self, _: &DhcpPacket) -> DhcpResult<()> {
+        Ok(())
+    }
+
+    /// Unknown clients keep their address (ciaddr) and get the shared options of its subnet
+    async fn inform(&mut self, p: &DhcpPacket) -> DhcpResult<Option<DhcpSourceResult>> {
+        if let Some(result) = self.host(p)? {
+            return Ok(Some(result));
+        }
+
+        let ciaddr = *p.client();
+        let shared = self.shared(Some(ciaddr), &packet_context(p))?;
+        if shared.options().iter().next().is_none() {
+            return Ok(None);
+        }
+
+        let next_server = *shared.next_server();
+        let provenance = shared.provenance().clone();
+        Ok(Some(DhcpSourceResult::new(Some(ciaddr), shared.into()).with_next_server(next_server).with_provenance(provenance)))
+    }
+
+    fn from_config<'a, T: Deserializer<'a> + Send>(config: T) -> DhcpResult<Self> where Self: Sized {
+        let config: DhcpStaticSourceConfig = Deserialize::deserialize(config).map_err(|e| DhcpError::SerdeErrorString(e.to_string()))?;
+
+        let mut hosts = match &config.hosts_file {
+            Some(path) => Self::load_hosts(path)?,
+            None => HashMap::new(),
+        };
+        hosts.extend(config.hosts);
+
+        Ok(Self {
+            hosts,
+            options: config.options,
+            subnets: config.subnets.into_iter()
+                .map(|s| Ok((s.network.parse::<Ipv4Network>()?, s.options)))
+                .collect::<DhcpResult<Vec<_>>>()?,
+            strict_mappings: false,
+        })
+    }
+
+    fn strict_mappings(&mut self, strict: bool) {
+        self.strict_mappings = strict;
+    }
+
+    fn check_templates(&self) -> DhcpResult<()> {
+        self.options.check_templates()?;
+        self.subnets.iter().try_for_each(|(_, mapping)| mapping.check_templates())?;
+        self.hosts.values().try_for_each(DhcpMapping::check_templates)
+    }
+}
+
+#[tokio::test]
+async fn test_static() {
+    use dhcplib::option::{DOMAIN_NAME, DOMAIN_NAME_SERVER, SUBNET_MASK};
+
+    let config: serde_yaml::Value = serde_yaml::from_str(r#"
+options:
+  domain_name_server:
+    data: 10.0.0.2
+  domain_name:
+    data: example.local
+subnets:
+  - network: 10.0.0.0/24
+    options:
+      subnet_mask:
+        data: 255.255.255.0
+hosts:
+  "AA:BB:CC:DD:EE:FF":
+    client_ip_address: 10.0.0.5
+"#).unwrap();
+    let mut s = DhcpStaticSource::from_config(config).unwrap();
+
+    // unknown mac
+    assert!(s.offer(&crate::sources::test_packet(0, vec![])).await.unwrap().is_none());
+
+    let p = crate::sources::test_packet_with_client(Ipv4Addr::new(10, 0, 0, 9), vec![]);
+    let result = s.inform(&p).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 9)));
+    assert_eq!(result.options().try_ipv4vec_option(DOMAIN_NAME_SERVER).unwrap(), vec![Ipv4Addr::new(10, 0, 0, 2)]);
+    assert_eq!(result.options().try_ascii_option(DOMAIN_NAME).unwrap().as_str(), "example.local");
+    assert_eq!(result.options().try_ipv4_option(SUBNET_MASK).unwrap(), Ipv4Addr::new(255, 255, 255, 0));
+
+    let p = crate::sources::test_packet_with_client(Ipv4Addr::new(192, 168, 0, 9), vec![]);
+    let result = s.inform(&p).await.unwrap().unwrap(); // outside of any subnet, shared options only
+    assert!(result.options().try_ipv4_option(SUBNET_MASK).is_err());
+}
+
+#[tokio::test]
+async fn test_static_hosts_file() {
+    let path = std::env::temp_dir().join(format!("dhcpserver-test-hosts-{}.yml", std::process::id()));
+    std::fs::write(&path, r#"
+"01:02:03:05:06:07":
+  client_ip_address: 10.0.0.7
+"00:00:00:00:00:09":
+  client_ip_address: 10.0.0.9
+"#).unwrap();
+
+    let config: serde_yaml::Value = serde_yaml::from_str(&format!(r#"
+hosts_file: {}
+hosts:
+  "00:00:00:00:00:09":
+    client_ip_address: 10.0.0.19
+"#, path.display())).unwrap();
+    let mut s = DhcpStaticSource::from_config(config).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let result = s.offer(&crate::sources::test_packet(0, vec![])).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 7)));
+    // inline hosts win
+    let result = s.reserve(&crate::sources::test_packet_from_mac([0, 0, 0, 0, 0, 9])).await.unwrap().unwrap();
+    assert_eq!(result.client_ip_address(), &Some(Ipv4Addr::new(10, 0, 0, 19)));
+    assert!(s.offer(&crate::sources::test_packet_from_mac([0, 0, 0, 0, 0, 1])).await.unwrap().is_none());
+
+    let config: serde_yaml::Value = serde_yaml::from_str("hosts_file: /nonexistent/hosts.yml").unwrap();
+    assert!(DhcpStaticSource::from_config(config).is_err());
+}
@@ -0,0 +1,456 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// What the server has seen from a single client, timestamps in unix seconds
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct ClientStats {
+    last_seen: Option<u64>,
+    last_discover: Option<u64>,
+    offers_sent: u64,
+    acks_sent: u64,
+    naks_sent: u64,
+    naks_suppressed: u64,
+    last_nak: Option<u64>,
+    last_assigned_ip: Option<Ipv4Addr>,
+    last_nak_reason: Option<String>,
+}
+
+/// Counters of the whole server, not bound to a client
+#[derive(Serialize, Default, Clone, Debug, PartialEq)]
+pub struct ServerStats {
+    non_dhcpv4_ignored: u64,
+    own_packets_dropped: u64,
+    /// packets of subnets outside `serve_subnets`
+    unserved_dropped: u64,
+    /// addresses handed to a client while held by another
+    address_conflicts: u64,
+    /// releases a source failed to confirm after all retries
+    releases_failed: u64,
+    /// requested (option 55) but not provided options by tag
+    unprovided_options: BTreeMap<u8, u64>,
+}
+
+/// Per mac client stats, the least recently seen client is evicted when full
+pub struct DhcpStats {
+    capacity: usize,
+    tick: u64,
+    server: ServerStats,
+    clients: HashMap<String, (u64, ClientStats)>,
+    /// clients to nak on their next request, set through the management api
+    pending_release: HashSet<String>,
+    /// client and unix time of the last assignment by address, only with conflict detection
+    assigned: HashMap<Ipv4Addr, (String, u64)>,
+}
+
+impl DhcpStats {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            server: ServerStats::default(),
+            clients: HashMap::new(),
+            pending_release: HashSet::new(),
+            assigned: HashMap::new(),
+        }
+    }
+
+    fn key(mac: &str) -> String {
+        mac.parse::<macaddr::MacAddr6>()
+            .map(|m| m.to_string())
+            .unwrap_or_else(|_| mac.to_string())
+            .to_lowercase()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+    }
+
+    fn entry(&mut self, mac: &str) -> &mut ClientStats {
+        let key = Self::key(mac);
+        self.tick += 1;
+
+        if !self.clients.contains_key(&key) && self.clients.len() >= self.capacity {
+            if let Some(oldest) = self.clients.iter().min_by_key(|(_, (tick, _))| *tick).map(|(k, _)| k.clone()) {
+                log::warn!("client stats full ({} entries), evicting {}", self.capacity, oldest);
+                self.clients.remove(&oldest);
+            }
+        }
+
+        let tick = self.tick;
+        let (used, stats) = self.clients.entry(key).or_default();
+        *used = tick;
+        stats.last_seen = Some(Self::now());
+        stats
+    }
+
+    pub fn get(&self, mac: &str) -> Option<&ClientStats> {
+        self.clients.get(&Self::key(mac)).map(|(_, stats)| stats)
+    }
+
+    /// Clients currently tracked
+    pub fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn server(&self) -> &ServerStats {
+        &self.server
+    }
+
+    /// Counts a payload ignored for not being DHCPv4, returns the total
+    pub fn non_dhcpv4(&mut self) -> u64 {
+        self.server.non_dhcpv4_ignored += 1;
+        self.server.non_dhcpv4_ignored
+    }
+
+    /// Counts a packet of this server received back, returns the total
+    pub fn own_packet(&mut self) -> u64 {
+        self.server.own_packets_dropped += 1;
+        self.server.own_packets_dropped
+    }
+
+    /// Counts a packet of a subnet not served, returns the total
+    pub fn unserved(&mut self) -> u64 {
+        self.server.unserved_dropped += 1;
+        self.server.unserved_dropped
+    }
+
+    /// Records the address handed to the client, returns the client holding it if assigned to another within the window
+    pub fn assign(&mut self, mac: &str, ip: Ipv4Addr, window: Duration) -> Option<String> {
+        let now = Self::now();
+        self.assigned.retain(|_, (_, time)| now.saturating_sub(*time) < window.as_secs());
+
+        let key = Self::key(mac);
+        match self.assigned.get(&ip) {
+            Some((holder, _)) if *holder != key => Some(holder.clone()),
+            _ => {
+                self.assigned.insert(ip, (key, now));
+                None
+            }
+        }
+    }
+
+    /// Counts an address handed to a client while held by another, returns the total
+    pub fn address_conflict(&mut self) -> u64 {
+        self.server.address_conflicts += 1;
+        self.server.address_conflicts
+    }
+
+    /// Addresses of a releasing or declining client are free again
+    pub fn unassign(&mut self, mac: &str) {
+        let key = Self::key(mac);
+        self.assigned.retain(|_, (holder, _)| *holder != key);
+    }
+
+    /// Counts a release a source failed to confirm, returns the total
+    pub fn release_failed(&mut self) -> u64 {
+        self.server.releases_failed += 1;
+        self.server.releases_failed
+    }
+
+    /// Counts each option requested by a client that no source provided
+    pub fn unprovided(&mut self, tags: &[u8]) {
+        for tag in tags {
+            *self.server.unprovided_options.entry(*tag).or_default() += 1;
+        }
+    }
+
+    pub fn discover(&mut self, mac: &str) {
+        self.entry(mac).last_discover = Some(Self::now());
+    }
+
+    pub fn offer(&mut self, mac: &str, ip: Ipv4Addr) {
+        let stats = self.entry(mac);
+        stats.offers_sent += 1;
+        stats.last_assigned_ip = Some(ip);
+    }
+
+    pub fn ack(&mut self, mac: &str, ip: Ipv4Addr) {
+        let stats = self.entry(mac);
+        stats.acks_sent += 1;
+        stats.last_assigned_ip = Some(ip);
+    }
+
+    pub fn nak(&mut self, mac: &str, reason: &str) {
+        let stats = self.entry(mac);
+        stats.naks_sent += 1;
+        stats.last_nak = Some(Self::now());
+        stats.last_nak_reason = Some(reason.to_string());
+    }
+
+    /// Naks the next request of the client given by mac or last assigned ip address, `None` for an unknown ip address
+    pub fn force_release(&mut self, target: &str) -> Option<String> {
+        let mac = match target.parse::<Ipv4Addr>() {
+            Ok(ip) => self.clients.iter()
+                .find(|(_, (_, stats))| stats.last_assigned_ip == Some(ip))
+                .map(|(mac, _)| mac.clone())?,
+            Err(_) => Self::key(target),
+        };
+
+        self.pending_release.insert(mac.clone());
+        Some(mac)
+    }
+
+    /// True once after a release of the client was forced
+    pub fn take_force_release(&mut self, mac: &str) -> bool {
+        self.pending_release.remove(&Self::key(mac))
+    }
+
+    /// True if the client got a nak within the window, the suppressed nak is counted
+    pub fn nak_suppressed(&mut self, mac: &str, window: Duration) -> bool {
+        let now = Self::now();
+        let stats = self.entry(mac);
+
+        match stats.last_nak {
+            Some(last) if now.saturating_sub(last) < window.as_secs() => {
+                stats.naks_suppressed += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Window of the per second rates
+const RATE_WINDOW: u64 = 60;
+
+/// Events of the last `RATE_WINDOW` seconds in one bucket per second, a bucket is reused once its second passed
+struct RollingRate {
+    seconds: Vec<AtomicU64>,
+    counts: Vec<AtomicU64>,
+}
+
+impl RollingRate {
+    fn new() -> Self {
+        Self {
+            seconds: (0..RATE_WINDOW).map(|_| AtomicU64::new(0)).collect(),
+            counts: (0..RATE_WINDOW).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Events racing a bucket reset may be lost, close enough for a rate
+    fn add(&self, now: u64) {
+        let i = (now % RATE_WINDOW) as usize;
+        let second = self.seconds[i].load(Ordering::Relaxed);
+        if second != now && self.seconds[i].compare_exchange(second, now, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            self.counts[i].store(0, Ordering::Relaxed);
+        }
+        self.counts[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn per_second(&self, now: u64) -> f64 {
+        let events: u64 = self.seconds.iter().zip(&self.counts)
+            .filter(|(second, _)| now.saturating_sub(second.load(Ordering::Relaxed)) < RATE_WINDOW)
+            .map(|(_, count)| count.load(Ordering::Relaxed))
+            .sum();
+        events as f64 / RATE_WINDOW as f64
+    }
+}
+
+/// Answers of a source
+#[derive(Serialize)]
+pub struct SourceHits {
+    name: &'static str,
+    hits: u64,
+    /// share of all lookups answered by the source
+    hit_rate: f64,
+}
+
+/// Lock free counters updated on every packet
+#[derive(Serialize, Debug, PartialEq)]
+pub struct LiveCounters {
+    offers: u64,
+    acks: u64,
+    naks: u64,
+    lookups: u64,
+    offers_per_second: f64,
+    acks_per_second: f64,
+    naks_per_second: f64,
+}
+
+/// Totals and rates of the last minute of all clients, not bound by the client stats capacity
+pub struct LiveStats {
+    offers: AtomicU64,
+    acks: AtomicU64,
+    naks: AtomicU64,
+    lookups: AtomicU64,
+    offer_rate: RollingRate,
+    ack_rate: RollingRate,
+    nak_rate: RollingRate,
+    /// name and answers by source index
+    sources: Vec<(&'static str, AtomicU64)>,
+}
+
+impl LiveStats {
+    pub fn new(sources: Vec<&'static str>) -> Self {
+        Self {
+            offers: AtomicU64::new(0),
+            acks: AtomicU64::new(0),
+            naks: AtomicU64::new(0),
+            lookups: AtomicU64::new(0),
+            offer_rate: RollingRate::new(),
+            ack_rate: RollingRate::new(),
+            nak_rate: RollingRate::new(),
+            sources: sources.into_iter().map(|name| (name, AtomicU64::new(0))).collect(),
+        }
+    }
+
+    pub fn offer(&self) {
+        self.offers.fetch_add(1, Ordering::Relaxed);
+        self.offer_rate.add(DhcpStats::now());
+    }
+
+    pub fn ack(&self) {
+        self.acks.fetch_add(1, Ordering::Relaxed);
+        self.ack_rate.add(DhcpStats::now());
+    }
+
+    pub fn nak(&self) {
+        self.naks.fetch_add(1, Ordering::Relaxed);
+        self.nak_rate.add(DhcpStats::now());
+    }
+
+    /// Counts a lookup of the sources and the source answering it, if any
+    pub fn lookup(&self, answered_by: Option<usize>) {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        if let Some((_, hits)) = answered_by.and_then(|i| self.sources.get(i)) {
+            hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn counters(&self) -> LiveCounters {
+        let now = DhcpStats::now();
+        LiveCounters {
+            offers: self.offers.load(Ordering::Relaxed),
+            acks: self.acks.load(Ordering::Relaxed),
+            naks: self.naks.load(Ordering::Relaxed),
+            lookups: self.lookups.load(Ordering::Relaxed),
+            offers_per_second: self.offer_rate.per_second(now),
+            acks_per_second: self.ack_rate.per_second(now),
+            naks_per_second: self.nak_rate.per_second(now),
+        }
+    }
+
+    pub fn sources(&self) -> Vec<SourceHits> {
+        let lookups = self.lookups.load(Ordering::Relaxed);
+        self.sources.iter().map(|(name, hits)| {
+            let hits = hits.load(Ordering::Relaxed);
+            SourceHits { name: *name, hits, hit_rate: if lookups == 0 { 0.0 } else { hits as f64 / lookups as f64 } }
+        }).collect()
+    }
+}
+
+#[test]
+fn test_stats() {
+    let mut stats = DhcpStats::new(2);
+
+    stats.discover("AA:BB:CC:DD:EE:01");
+    stats.offer("aa:bb:cc:dd:ee:01", Ipv4Addr::new(10, 0, 0, 5));
+    stats.nak("aa:bb:cc:dd:ee:01", "no source answered");
+
+    let client = stats.get("aa-bb-cc-dd-ee-01").unwrap();
+    assert!(client.last_discover.is_some());
+    assert_eq!(client.offers_sent, 1);
+    assert_eq!(client.naks_sent, 1);
+    assert_eq!(client.last_assigned_ip, Some(Ipv4Addr::new(10, 0, 0, 5)));
+    assert_eq!(client.last_nak_reason.as_deref(), Some("no source answered"));
+
+    stats.discover("aa:bb:cc:dd:ee:02");
+    stats.discover("aa:bb:cc:dd:ee:01");
+    stats.discover("aa:bb:cc:dd:ee:03"); // evicts 02, least recently seen
+
+    assert!(stats.get("aa:bb:cc:dd:ee:01").is_some());
+    assert!(stats.get("aa:bb:cc:dd:ee:02").is_none());
+    assert!(stats.get("aa:bb:cc:dd:ee:03").is_some());
+    assert_eq!(stats.clients(), 2);
+
+    assert_eq!(stats.non_dhcpv4(), 1);
+    assert_eq!(stats.non_dhcpv4(), 2);
+    assert_eq!(stats.own_packet(), 1);
+}
+
+#[test]
+fn test_nak_suppressed() {
+    let mut stats = DhcpStats::new(8);
+    let window = Duration::from_secs(60);
+
+    assert!(!stats.nak_suppressed("aa:bb:cc:dd:ee:01", window));
+    stats.nak("aa:bb:cc:dd:ee:01", "no source answered");
+    assert!(stats.nak_suppressed("aa:bb:cc:dd:ee:01", window));
+    assert!(stats.nak_suppressed("aa:bb:cc:dd:ee:01", window));
+    assert!(!stats.nak_suppressed("aa:bb:cc:dd:ee:02", window)); // other client
+    assert!(!stats.nak_suppressed("aa:bb:cc:dd:ee:01", Duration::from_secs(0))); // disabled
+
+    let client = stats.get("aa:bb:cc:dd:ee:01").unwrap();
+    assert_eq!(client.naks_sent, 1);
+    assert_eq!(client.naks_suppressed, 2);
+}
+
+#[test]
+fn test_unprovided() {
+    let mut stats = DhcpStats::new(8);
+
+    stats.unprovided(&[42, 119]);
+    stats.unprovided(&[42]);
+    stats.unprovided(&[]);
+
+    assert_eq!(stats.server().unprovided_options.get(&42), Some(&2));
+    assert_eq!(stats.server().unprovided_options.get(&119), Some(&1));
+    assert_eq!(serde_json::to_value(stats.server()).unwrap()["unprovided_options"], serde_json::json!({"42": 2, "119": 1}));
+}
+
+#[test]
+fn test_force_release() {
+    let mut stats = DhcpStats::new(8);
+    stats.ack("aa:bb:cc:dd:ee:01", Ipv4Addr::new(10, 0, 0, 5));
+
+    assert_eq!(stats.force_release("AA-BB-CC-DD-EE-02").as_deref(), Some("aa:bb:cc:dd:ee:02"));
+    assert!(stats.take_force_release("aa:bb:cc:dd:ee:02"));
+    assert!(!stats.take_force_release("aa:bb:cc:dd:ee:02")); // once
+
+    assert_eq!(stats.force_release("10.0.0.5").as_deref(), Some("aa:bb:cc:dd:ee:01"));
+    assert_eq!(stats.force_release("10.0.0.6"), None);
+    assert!(stats.take_force_release("aa:bb:cc:dd:ee:01"));
+}
+
+#[test]
+fn test_live_stats() {
+    let live = std::sync::Arc::new(LiveStats::new(vec!["static", "rest"]));
+
+    let threads: Vec<_> = (0..8).map(|i| {
+        let live = live.clone();
+        std::thread::spawn(move || {
+            for _ in 0..1000 {
+                live.offer();
+                live.lookup(Some(i % 2));
+            }
+            live.nak();
+            live.lookup(None);
+        })
+    }).collect();
+    threads.into_iter().for_each(|t| t.join().unwrap());
+
+    let counters = live.counters();
+    assert_eq!((counters.offers, counters.acks, counters.naks, counters.lookups), (8000, 0, 8, 8008));
+    assert!(counters.offers_per_second <= 8000.0 / RATE_WINDOW as f64);
+    assert!(counters.naks_per_second > 0.0);
+
+    let sources = live.sources();
+    assert_eq!(sources.iter().map(|s| (s.name, s.hits)).collect::<Vec<_>>(), vec![("static", 4000), ("rest", 4000)]);
+    assert!((sources[0].hit_rate - 4000.0 / 8008.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_rolling_rate() {
+    let rate = RollingRate::new();
+    rate.add(1000);
+    rate.add(1000);
+    rate.add(1030);
+    assert_eq!(rate.per_second(1030), 3.0 / RATE_WINDOW as f64);
+    assert_eq!(rate.per_second(1061), 1.0 / RATE_WINDOW as f64); // the first second left the window
+
+    rate.add(1060); // reuses the bucket of second 1000
+    assert_eq!(rate.per_second(1060), 2.0 / RATE_WINDOW as f64);
+}